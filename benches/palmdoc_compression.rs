@@ -0,0 +1,92 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use deku::DekuContainerRead;
+use kf8::{
+    compression::palmdoc::{self, CompressionLevel},
+    serialization::{MobiHeader, PalmDoc},
+};
+
+/// Decompressed text records of the fixture, each at most 4096 bytes.
+fn text_records() -> Vec<Vec<u8>> {
+    let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+    let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+    let mobi_header: MobiHeader =
+        binrw::BinRead::read(&mut std::io::Cursor::new(&palmdoc.records[0])).unwrap();
+
+    palmdoc.records[1..=mobi_header.num_of_text_records as usize]
+        .iter()
+        .map(|record| {
+            let len = record.len() - mobi_header.sizeof_trailing_section_entries(record).unwrap();
+            palmdoc::decompress(&record[..len]).unwrap()
+        })
+        .collect()
+}
+
+fn bench_compression(c: &mut Criterion) {
+    let records = text_records();
+    let text_len = records.iter().map(Vec::len).sum::<usize>();
+
+    let compressed_len = |compress: &dyn Fn(&[u8]) -> Vec<u8>| {
+        records.iter().map(|r| compress(r).len()).sum::<usize>()
+    };
+    println!(
+        "compressed size of {} bytes: palmdoc-compression {}, fast {}, thorough {}",
+        text_len,
+        compressed_len(&|r| palmdoc_compression::compress(r)),
+        compressed_len(&|r| palmdoc::compress(r, CompressionLevel::Fast)),
+        compressed_len(&|r| palmdoc::compress(r, CompressionLevel::Thorough)),
+    );
+
+    let mut group = c.benchmark_group("compress");
+    group.throughput(Throughput::Bytes(text_len as u64));
+    group.sample_size(10);
+    group.bench_function("palmdoc-compression", |b| {
+        b.iter(|| {
+            for record in &records {
+                black_box(palmdoc_compression::compress(black_box(record)));
+            }
+        })
+    });
+    for (name, level) in [
+        ("fast", CompressionLevel::Fast),
+        ("thorough", CompressionLevel::Thorough),
+    ] {
+        group.bench_function(name, |b| {
+            let mut out = Vec::new();
+            b.iter(|| {
+                for record in &records {
+                    out.clear();
+                    palmdoc::compress_into(black_box(record), level, &mut out);
+                }
+            })
+        });
+    }
+    group.finish();
+
+    let compressed = records
+        .iter()
+        .map(|r| palmdoc::compress(r, CompressionLevel::Fast))
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("decompress");
+    group.throughput(Throughput::Bytes(text_len as u64));
+    group.bench_function("palmdoc-compression", |b| {
+        b.iter(|| {
+            for record in &compressed {
+                black_box(palmdoc_compression::decompress(black_box(record)).unwrap());
+            }
+        })
+    });
+    group.bench_function("native", |b| {
+        let mut out = Vec::new();
+        b.iter(|| {
+            for record in &compressed {
+                out.clear();
+                palmdoc::decompress_into(black_box(record), &mut out).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_compression);
+criterion_main!(benches);
@@ -1,13 +1,16 @@
 use chrono::DateTime;
 use epub_builder::{EpubBuilder, EpubContent, EpubVersion, Result, ZipLibrary};
 use kf8::constants::MetadataId;
+use kf8::serialization::{page_list_nav, EmbedReference, FlowReference, PageListItem};
 use kf8::{parse_book, ImageResourceKind, MobiBook, ResourceKind};
 use quick_xml::events::attributes::Attribute;
 use quick_xml::events::{BytesStart, Event};
 use quick_xml::name::QName;
 use regex::{Regex, RegexBuilder};
-use std::io::{Cursor, Read};
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek, Write};
 use std::iter::once;
+use zip::{write::FileOptions, ZipArchive, ZipWriter};
 
 #[macro_use]
 extern crate lazy_static;
@@ -34,14 +37,6 @@ fn main() {
     process(args).unwrap();
 }
 
-lazy_static! {
-    static ref FLOW_PATTERN: Regex =
-        RegexBuilder::new(r#"kindle:flow:([0-9|A-V]+)\?mime=([^'"]+)"#)
-            .case_insensitive(true)
-            .build()
-            .unwrap();
-}
-
 lazy_static! {
     static ref POSITION_FID_PATTERN: Regex =
         RegexBuilder::new(r#"kindle:pos:fid:([0-9|A-V]+):off:([0-9|A-V]+)"#)
@@ -58,15 +53,12 @@ lazy_static! {
             .unwrap();
 }
 
-lazy_static! {
-    static ref EMBED_PATTERN: Regex =
-        RegexBuilder::new(r#"kindle:embed:([0-9|A-V]+)\?mime=([^'"]+)"#)
-            .case_insensitive(true)
-            .build()
-            .unwrap();
-}
-
-fn transform_element(element: &mut BytesStart, image_paths: &Vec<String>, book: &MobiBook) {
+fn transform_element(
+    element: &mut BytesStart,
+    image_paths: &HashMap<usize, String>,
+    flow_paths: &HashMap<usize, String>,
+    book: &MobiBook,
+) {
     let cloned = element.clone();
     let attributes = cloned.attributes();
     element.clear_attributes();
@@ -93,20 +85,13 @@ fn transform_element(element: &mut BytesStart, image_paths: &Vec<String>, book:
             QName(b"href") if element.name() == QName(b"link") => {
                 let value = attribute.value.to_vec();
                 let value = String::from_utf8(value).unwrap();
-                let captures = FLOW_PATTERN.captures(&value).unwrap();
-                let flow_index = usize::from_str_radix(&captures[1], 10).unwrap();
-                let mime_type = &captures[2];
-
-                let href = match mime_type {
-                    "text/css" => {
-                        format!("styles_{}.css", flow_index)
-                    }
-                    _ => {
-                        panic!("Unsupported flow type {}", mime_type)
-                    }
-                };
+                let path = FlowReference::parse(&value)
+                    .and_then(|reference| flow_paths.get(&reference.flow));
 
-                element.push_attribute(Attribute::from(("href".as_bytes(), href.as_bytes())));
+                // todo: should log a warning here?
+                if let Some(path) = path {
+                    element.push_attribute(Attribute::from(("href".as_bytes(), path.as_bytes())));
+                }
             }
             // Remap kindle:pos:fid:... to filenames and anchors (href="filename#anchor")
             QName(b"href") if attribute.value.starts_with(b"kindle:pos:fid") => {
@@ -119,40 +104,25 @@ fn transform_element(element: &mut BytesStart, image_paths: &Vec<String>, book:
                 let fragment = book.fragment_table.get(absolute_fragment_index).unwrap();
                 let position = fragment.insert_position as usize + offset;
 
-                let part = book
-                    .parts
-                    .iter()
-                    .find(|part| position >= part.start_offset && position < part.end_offset)
-                    .unwrap();
-
-                let offset = position - part.start_offset;
-                let content = part.get_content();
-                let selected_content = String::from_utf8_lossy(&content[offset..]);
-
-                let captures = ID_OR_NAME_OR_AID_PATTERN
-                    .captures(&selected_content)
-                    .unwrap();
-                let attribute_value = captures.get(2).unwrap().as_str();
-
                 element.push_attribute(Attribute::from((
                     "href".as_bytes(),
-                    format!("{}#{}", part.filename, attribute_value).as_bytes(),
+                    href_for_position(book, position).as_bytes(),
                 )));
             }
-            // Map kindle:embed:... to local resources (for images)
+            // Map kindle:embed:... and kindle:flow:... to local resources (for images and SVGs)
             QName(b"src") if element.name() == QName(b"img") => {
                 let value = attribute.value.to_vec();
                 let value = String::from_utf8(value).unwrap();
-                let captures = EMBED_PATTERN.captures(&value);
+                let path = EmbedReference::parse(&value)
+                    .and_then(|reference| image_paths.get(&reference.record))
+                    .or_else(|| {
+                        FlowReference::parse(&value)
+                            .and_then(|reference| flow_paths.get(&reference.flow))
+                    });
 
                 // todo: should log a warning here?
-                if let Some(captures) = captures {
-                    let image_index = usize::from_str_radix(&captures[1], 32).unwrap() - 1;
-
-                    element.push_attribute(Attribute::from((
-                        "src".as_bytes(),
-                        image_paths[image_index].as_bytes(),
-                    )));
+                if let Some(path) = path {
+                    element.push_attribute(Attribute::from(("src".as_bytes(), path.as_bytes())));
                 }
             }
             _ => {
@@ -162,6 +132,45 @@ fn transform_element(element: &mut BytesStart, image_paths: &Vec<String>, book:
     }
 }
 
+/// Maps a position in the text to `filename#anchor`, using the first anchor at or after it.
+fn href_for_position(book: &MobiBook, position: usize) -> String {
+    let part = book
+        .parts
+        .iter()
+        .find(|part| position >= part.start_offset && position < part.end_offset)
+        .unwrap();
+
+    let offset = position - part.start_offset;
+    let content = part.get_content();
+    let selected_content = String::from_utf8_lossy(&content[offset..]);
+
+    match ID_OR_NAME_OR_AID_PATTERN.captures(&selected_content) {
+        Some(captures) => format!("{}#{}", part.filename, &captures[2]),
+        None => part.filename.clone(),
+    }
+}
+
+/// Copies the EPUB generated by epub-builder, adding the page list to its navigation document.
+fn add_page_list<W: Write + Seek>(epub: &[u8], page_list: &str, writer: W) -> Result<()> {
+    let mut archive = ZipArchive::new(Cursor::new(epub))?;
+    let mut writer = ZipWriter::new(writer);
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.name() == "OEBPS/nav.xhtml" {
+            let mut nav = String::new();
+            file.read_to_string(&mut nav)?;
+            let end = nav.rfind("</body>").unwrap_or(nav.len());
+            nav.insert_str(end, page_list);
+            writer.start_file(file.name(), FileOptions::default())?;
+            writer.write_all(nav.as_bytes())?;
+        } else {
+            writer.raw_copy_file(file)?;
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
 fn process(args: Args) -> Result<()> {
     let mut reader = std::fs::File::open(args.input).unwrap();
     let mut data = Vec::new();
@@ -169,18 +178,35 @@ fn process(args: Args) -> Result<()> {
 
     let (_, book) = parse_book(&data).unwrap();
 
+    // Page list
+    let page_list = book.page_map.as_ref().map(|page_map| {
+        let pages = page_map
+            .labels()
+            .into_iter()
+            .zip(&page_map.offsets)
+            .map(|(label, offset)| PageListItem {
+                label,
+                href: href_for_position(&book, *offset as usize),
+            })
+            .collect::<Vec<_>>();
+        page_list_nav(&pages)
+    });
+
     let mut builder = EpubBuilder::new(ZipLibrary::new()?)?;
     builder.epub_version(EpubVersion::V30);
 
     // Resources
-    let mut image_paths = Vec::new();
+    let mut image_paths = HashMap::new();
+    let mut flow_paths = HashMap::new();
     // todo: cleaner
     let mut image_i = 0;
     for resource in &book.resources {
         match resource.kind {
             ResourceKind::Image(ImageResourceKind::Cover) => {
                 let path = format!("cover.{}", resource.file_type.extension());
-                image_paths.push(path.clone());
+                if let Some(record) = resource.record {
+                    image_paths.insert(record, path.clone());
+                }
 
                 builder.add_cover_image(
                     path,
@@ -191,7 +217,9 @@ fn process(args: Args) -> Result<()> {
             // todo: handle thumbnail separately?
             ResourceKind::Image(..) => {
                 let path = format!("images/{}.{}", image_i, resource.file_type.extension());
-                image_paths.push(path.clone());
+                if let Some(record) = resource.record {
+                    image_paths.insert(record, path.clone());
+                }
 
                 builder.add_resource(
                     path,
@@ -202,9 +230,16 @@ fn process(args: Args) -> Result<()> {
             ResourceKind::Font => {
                 todo!()
             }
-            ResourceKind::Stylesheet => {
+            ResourceKind::Stylesheet | ResourceKind::Svg => {
+                let flow_index = resource.flow_index.unwrap_or_default();
+                let path = match resource.kind {
+                    ResourceKind::Svg => format!("images/flow_{}.svg", flow_index),
+                    _ => format!("styles_{}.css", flow_index),
+                };
+                flow_paths.insert(flow_index, path.clone());
+
                 builder.add_resource(
-                    format!("styles_{}.css", resource.flow_index.unwrap_or_default()),
+                    path,
                     Cursor::new(resource.data.clone()),
                     resource.file_type.mime_type(),
                 )?;
@@ -237,12 +272,12 @@ fn process(args: Args) -> Result<()> {
                     break;
                 }
                 Ok(Event::Start(mut element)) => {
-                    transform_element(&mut element, &image_paths, &book);
+                    transform_element(&mut element, &image_paths, &flow_paths, &book);
 
                     writer.write_event(&Event::Start(element)).unwrap();
                 }
                 Ok(Event::Empty(mut element)) => {
-                    transform_element(&mut element, &image_paths, &book);
+                    transform_element(&mut element, &image_paths, &flow_paths, &book);
 
                     writer.write_event(&Event::Empty(element)).unwrap();
                 }
@@ -260,7 +295,7 @@ fn process(args: Args) -> Result<()> {
         builder.add_content(EpubContent::new(part.filename.clone(), cursor))?;
     }
 
-    builder.set_title(&book.book_header.title);
+    builder.set_title(book.book_header.title.to_string());
 
     // Metadata
     // if let Some(ref metadata) = book.book_header.standard_metadata {
@@ -308,7 +343,14 @@ fn process(args: Args) -> Result<()> {
     // todo: set the ID using the book_header.unique_id (unsupported by epub library?)
 
     let writer = std::fs::File::create(args.output).unwrap();
-    builder.generate(writer)?;
+    match page_list {
+        Some(page_list) => {
+            let mut epub = vec![];
+            builder.generate(&mut epub)?;
+            add_page_list(&epub, &page_list, writer)?;
+        }
+        None => builder.generate(writer)?,
+    }
 
     Ok(())
 }
@@ -1,15 +1,12 @@
-use std::{
-    borrow::Borrow,
-    io::{Cursor, Write},
-};
+use std::io::Write;
 
-use binrw::BinRead;
-use deku::{writer::Writer, DekuContainerRead, DekuWriter};
+use deku::{writer::Writer, DekuWriter};
 use kf8::{
     constants::MainLanguage,
-    serialization::{book::Book, BookPart, CompressionType, MobiHeader, PalmDoc},
+    serialization::{
+        parse_page_list_nav, BookBuilder, BookWriter, CompressionType, Metadata, TocItem,
+    },
 };
-use rand::Rng;
 
 const CSS_CONTENT: &str = r#"
 .calibre {
@@ -35,24 +32,36 @@ const CSS_CONTENT: &str = r#"
     }
 "#;
 
-fn main() {
-    let mut rng = rand::thread_rng();
-
-    let uid: u32 = rng.gen();
-    // let uid: u32 = 0x9CDB8CF6;
+const NAV_CONTENT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+  <head>
+    <title>Navigation</title>
+  </head>
+  <body>
+    <nav epub:type="toc">
+      <ol>
+        <li><a href="titlepage.xhtml#titlepage">Titlepage</a></li>
+      </ol>
+    </nav>
+    <nav epub:type="page-list" hidden="">
+      <ol>
+        <li><a href="titlepage.xhtml#titlepage">1</a></li>
+      </ol>
+    </nav>
+  </body>
+</html>
+"#;
 
-    let skeleton_head = r#"<?xml version="1.0" encoding="UTF-8"?>
+fn main() {
+    let titlepage = r#"<?xml version="1.0" encoding="UTF-8"?>
 <html xmlns="http://www.w3.org/1999/xhtml" lang="en-US">
   <head>
     <title>Titlepage</title>
     <meta http-equiv="Content-Type" content="text/html; charset=utf-8"/>
+    <link rel="stylesheet" type="text/css" href="styles.css"/>
 </head>
   <body class="calibre" aid="0">
-"#;
-    let skeleton_tail = r#"</body>
-</html>
-"#;
-    let slice = r#"<section class="epub-type-titlepage" id="titlepage" aid="1">
+<section class="epub-type-titlepage" id="titlepage" aid="1">
 <h1 class="calibre1" aid="2">hello world</h1>
 <p class="calibre2" aid="3">This e-book was created by Ignite, a from-scratch KF8 (.azw3) convertor for Kindle written in Rust.</p>
 <p>To my knowledge, Calibre is the only open-source KF8 convertor.</p>
@@ -67,25 +76,30 @@ fn main() {
 
 <p>Check out <b>github.com/codetheweb/ignite</b> if you want to follow progress!</p>
 </section>
+</body>
+</html>
 "#;
 
-    let book = Book {
+    let book = BookBuilder::new(Metadata {
         title: "Sample_.epub_Book".to_string(),
-        uid,
-        main_language: Some(MainLanguage::English),
-        sub_language: None,
-        book_parts: vec![BookPart {
-            skeleton_head: skeleton_head.to_string(),
-            content: slice.to_string(),
-            skeleton_tail: skeleton_tail.to_string(),
-        }],
-        resources: vec![CSS_CONTENT.to_string()],
-        compression: CompressionType::None,
-    };
+        authors: vec!["Ignite".to_string()],
+        ..Default::default()
+    })
+    .language(MainLanguage::English, None)
+    .compression(CompressionType::None)
+    .chapter("titlepage.xhtml", titlepage)
+    .stylesheet("styles.css", CSS_CONTENT)
+    .toc(vec![TocItem::new("Titlepage", "titlepage.xhtml#titlepage")])
+    .landmark("titlepage", "Titlepage", "titlepage.xhtml")
+    .page_list(parse_page_list_nav(NAV_CONTENT).unwrap())
+    .build()
+    .unwrap();
 
     let mut output = std::fs::File::create("hello_world.azw3").unwrap();
     let mut writer = Writer::new(&mut output);
-    book.to_writer(&mut writer, ()).unwrap();
+    // The uid is derived from the content, so converting the same book twice gives the same file
+    let palmdoc = BookWriter::reproducible().write(&book).unwrap();
+    palmdoc.to_writer(&mut writer, ()).unwrap();
     writer.finalize().unwrap();
     output.flush().unwrap();
 
@@ -0,0 +1,15 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use deku::{reader::Reader, DekuReader};
+use kf8::serialization::{Book, ParseOptions};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut cursor = Cursor::new(data);
+    let mut reader = Reader::new(&mut cursor);
+    let _ = Book::from_reader_with_ctx(&mut reader, ());
+
+    let _ = Book::from_bytes_with_options(data, &ParseOptions { strict: false });
+});
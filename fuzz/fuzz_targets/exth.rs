@@ -0,0 +1,13 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use deku::{reader::Reader, DekuReader};
+use kf8::serialization::Exth;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut cursor = Cursor::new(data);
+    let mut reader = Reader::new(&mut cursor);
+    let _ = Exth::from_reader_with_ctx(&mut reader, ());
+});
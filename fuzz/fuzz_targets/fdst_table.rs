@@ -0,0 +1,9 @@
+#![no_main]
+
+use deku::DekuContainerRead;
+use kf8::serialization::FDSTTable;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = FDSTTable::from_bytes((data, 0));
+});
@@ -0,0 +1,25 @@
+#![no_main]
+
+use kf8::serialization::{ChunkTagMapEntry, SkeletonTagMapEntry, TotalIndexEntry};
+use libfuzzer_sys::fuzz_target;
+
+/// Splits the input into records, each prefixed with its length as a big-endian u32.
+fn split_records(mut data: &[u8]) -> Vec<Vec<u8>> {
+    let mut records = Vec::new();
+    while let Some((len, rest)) = data.split_first_chunk::<4>() {
+        let len = (u32::from_be_bytes(*len) as usize).min(rest.len());
+        let (record, rest) = rest.split_at(len);
+        records.push(record.to_vec());
+        data = rest;
+    }
+    records
+}
+
+fuzz_target!(|data: &[u8]| {
+    let records = split_records(data);
+
+    if let Ok(index) = TotalIndexEntry::from_records(&records) {
+        let _ = index.parse_as::<ChunkTagMapEntry>();
+        let _ = index.parse_as::<SkeletonTagMapEntry>();
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use binrw::BinRead;
+use kf8::serialization::MobiHeader;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(header) = MobiHeader::read(&mut Cursor::new(data)) {
+        // Also exercise the trailing entry parser against the same bytes.
+        if let Ok(len) = header.sizeof_trailing_section_entries(data) {
+            assert!(len <= data.len());
+        }
+    }
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use deku::DekuContainerRead;
+use kf8::serialization::PalmDoc;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = PalmDoc::from_bytes((data, 0));
+});
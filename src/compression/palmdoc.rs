@@ -0,0 +1,313 @@
+//! PalmDoc (LZ77) compression, as used for KF8 text records.
+//!
+//! Compressed data is a sequence of:
+//! - `0x00`, `0x09..=0x7f`: a literal byte
+//! - `0x01..=0x08`: that many literal bytes follow
+//! - `0x80..=0xbf`: together with the next byte, a back reference with an 11 bit distance and a 3 bit length (plus 3)
+//! - `0xc0..=0xff`: a space followed by the byte XOR `0x80`
+
+use thiserror::Error;
+
+const MAX_DISTANCE: usize = 2047;
+const MIN_MATCH_LEN: usize = 3;
+const MAX_MATCH_LEN: usize = 10;
+
+const HASH_BITS: u32 = 12;
+const NO_POSITION: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompressionLevel {
+    /// Only tries the most recent earlier occurrence of each 3 byte sequence.
+    Fast,
+    /// Searches the whole window for the longest match, and defers a match by one byte if that finds a longer one.
+    #[default]
+    Thorough,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum DecompressionError {
+    #[error("Literal run at {0} extends past the end of the input")]
+    TruncatedLiteral(usize),
+    #[error("Back reference at {0} is missing its second byte")]
+    TruncatedBackReference(usize),
+    #[error("Back reference at {position} has distance {distance}, but only {available} bytes have been decompressed")]
+    InvalidDistance {
+        position: usize,
+        distance: usize,
+        available: usize,
+    },
+}
+
+/// Hash chains over every 3 byte sequence of the input.
+struct Matcher<'a> {
+    input: &'a [u8],
+    level: CompressionLevel,
+    head: Vec<u32>,
+    prev: Vec<u32>,
+    /// Every position before this has been inserted.
+    inserted: usize,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(input: &'a [u8], level: CompressionLevel) -> Self {
+        Self {
+            input,
+            level,
+            head: vec![NO_POSITION; 1 << HASH_BITS],
+            prev: vec![NO_POSITION; input.len()],
+            inserted: 0,
+        }
+    }
+
+    fn hash(&self, pos: usize) -> Option<usize> {
+        let bytes = self.input.get(pos..pos + MIN_MATCH_LEN)?;
+        let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+        Some((value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize)
+    }
+
+    /// Returns the `(distance, length)` of the best match for the bytes at `pos`, considering only earlier positions.
+    fn find(&mut self, pos: usize) -> Option<(usize, usize)> {
+        while self.inserted < pos {
+            if let Some(hash) = self.hash(self.inserted) {
+                self.prev[self.inserted] = self.head[hash];
+                self.head[hash] = self.inserted as u32;
+            }
+            self.inserted += 1;
+        }
+
+        let max_len = MAX_MATCH_LEN.min(self.input.len() - pos);
+        let mut candidate = self.head[self.hash(pos)?];
+        let mut best: Option<(usize, usize)> = None;
+
+        while candidate != NO_POSITION {
+            let candidate_pos = candidate as usize;
+            let distance = pos - candidate_pos;
+            if distance > MAX_DISTANCE {
+                break;
+            }
+
+            // Matches may overlap the bytes being encoded
+            let len = (0..max_len)
+                .take_while(|&i| self.input[candidate_pos + i] == self.input[pos + i])
+                .count();
+            if len >= MIN_MATCH_LEN && best.is_none_or(|(_, best_len)| len > best_len) {
+                best = Some((distance, len));
+            }
+
+            if self.level == CompressionLevel::Fast || len == max_len {
+                break;
+            }
+            candidate = self.prev[candidate_pos];
+        }
+
+        best
+    }
+}
+
+fn needs_escape(byte: u8) -> bool {
+    (0x01..=0x08).contains(&byte) || byte >= 0x80
+}
+
+pub fn compress(input: &[u8], level: CompressionLevel) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    compress_into(input, level, &mut out);
+    out
+}
+
+/// Compresses `input`, appending to `out`.
+pub fn compress_into(input: &[u8], level: CompressionLevel, out: &mut Vec<u8>) {
+    let mut matcher = Matcher::new(input, level);
+
+    let mut pos = 0;
+    while pos < input.len() {
+        if let Some((distance, len)) = matcher.find(pos) {
+            let deferred = level == CompressionLevel::Thorough
+                && matcher
+                    .find(pos + 1)
+                    .is_some_and(|(_, next_len)| next_len > len);
+
+            if !deferred {
+                let code = 0x8000 | (distance << 3) as u16 | (len - MIN_MATCH_LEN) as u16;
+                out.extend_from_slice(&code.to_be_bytes());
+                pos += len;
+                continue;
+            }
+
+            // Emit a single literal so that the longer match at the next byte can be used
+            let byte = input[pos];
+            if needs_escape(byte) {
+                out.push(1);
+            }
+            out.push(byte);
+            pos += 1;
+            continue;
+        }
+
+        let byte = input[pos];
+        match input.get(pos + 1) {
+            Some(&next) if byte == b' ' && (0x40..=0x7f).contains(&next) => {
+                out.push(next ^ 0x80);
+                pos += 2;
+            }
+            _ if !needs_escape(byte) => {
+                out.push(byte);
+                pos += 1;
+            }
+            _ => {
+                let mut end = pos + 1;
+                while end < input.len()
+                    && end - pos < 8
+                    && needs_escape(input[end])
+                    && (level == CompressionLevel::Fast || matcher.find(end).is_none())
+                {
+                    end += 1;
+                }
+
+                out.push((end - pos) as u8);
+                out.extend_from_slice(&input[pos..end]);
+                pos = end;
+            }
+        }
+    }
+}
+
+pub fn decompress(input: &[u8]) -> Result<Vec<u8>, DecompressionError> {
+    let mut out = Vec::with_capacity(input.len() * 2);
+    decompress_into(input, &mut out)?;
+    Ok(out)
+}
+
+/// Decompresses `input`, appending to `out`. Back references can't reach into what `out` already contained.
+pub fn decompress_into(input: &[u8], out: &mut Vec<u8>) -> Result<(), DecompressionError> {
+    let base = out.len();
+
+    let mut pos = 0;
+    while pos < input.len() {
+        let byte = input[pos];
+        match byte {
+            0x00 | 0x09..=0x7f => {
+                out.push(byte);
+                pos += 1;
+            }
+            0x01..=0x08 => {
+                let literal = input
+                    .get(pos + 1..pos + 1 + byte as usize)
+                    .ok_or(DecompressionError::TruncatedLiteral(pos))?;
+                out.extend_from_slice(literal);
+                pos += 1 + literal.len();
+            }
+            0x80..=0xbf => {
+                let next = *input
+                    .get(pos + 1)
+                    .ok_or(DecompressionError::TruncatedBackReference(pos))?;
+                let code = u16::from_be_bytes([byte, next]) & 0x3fff;
+                let distance = (code >> 3) as usize;
+                let len = (code & 0x7) as usize + MIN_MATCH_LEN;
+
+                let available = out.len() - base;
+                if distance == 0 || distance > available {
+                    return Err(DecompressionError::InvalidDistance {
+                        position: pos,
+                        distance,
+                        available,
+                    });
+                }
+
+                let start = out.len() - distance;
+                if distance >= len {
+                    out.extend_from_within(start..start + len);
+                } else {
+                    // The match overlaps the bytes it produces, so it has to be copied byte by byte
+                    for i in 0..len {
+                        out.push(out[start + i]);
+                    }
+                }
+                pos += 2;
+            }
+            0xc0..=0xff => {
+                out.push(b' ');
+                out.push(byte ^ 0x80);
+                pos += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deku::DekuContainerRead;
+    use pretty_assertions::assert_eq;
+    use proptest::{
+        arbitrary::any,
+        prop_oneof, proptest,
+        strategy::{Just, Strategy},
+    };
+
+    use crate::serialization::{MobiHeader, PalmDoc};
+
+    fn any_level() -> impl Strategy<Value = CompressionLevel> {
+        prop_oneof![
+            Just(CompressionLevel::Fast),
+            Just(CompressionLevel::Thorough)
+        ]
+    }
+
+    proptest! {
+      #[test]
+      fn test_roundtrip(data in proptest::collection::vec(any::<u8>(), 0..4096), level in any_level()) {
+        let compressed = compress(&data, level);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        assert_eq!(palmdoc_compression::decompress(&compressed).unwrap(), data);
+      }
+
+      #[test]
+      fn test_roundtrip_text(text in "[a-e \\n<>/é日]{0,4096}", level in any_level()) {
+        let compressed = compress(text.as_bytes(), level);
+        assert_eq!(decompress(&compressed).unwrap(), text.as_bytes());
+      }
+
+      #[test]
+      fn test_decompress_arbitrary(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+        let _ = decompress(&data);
+      }
+    }
+
+    #[test]
+    fn test_fixture() {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+        let mobi_header = binrw::BinRead::read(&mut std::io::Cursor::new(&palmdoc.records[0]))
+            .map(|header: MobiHeader| header)
+            .unwrap();
+
+        let (mut fast_len, mut thorough_len) = (0, 0);
+        for record in &palmdoc.records[1..=mobi_header.num_of_text_records as usize] {
+            let len = record.len() - mobi_header.sizeof_trailing_section_entries(record).unwrap();
+            let text = decompress(&record[..len]).unwrap();
+            assert_eq!(
+                text,
+                palmdoc_compression::decompress(&record[..len]).unwrap()
+            );
+
+            fast_len += compress(&text, CompressionLevel::Fast).len();
+            thorough_len += compress(&text, CompressionLevel::Thorough).len();
+        }
+
+        assert!(thorough_len <= fast_len);
+    }
+
+    #[test]
+    fn test_invalid_distance() {
+        assert_eq!(
+            decompress(&[b'a', 0x80, 0x10]),
+            Err(DecompressionError::InvalidDistance {
+                position: 1,
+                distance: 2,
+                available: 1
+            })
+        );
+    }
+}
@@ -1,16 +1,26 @@
 use binrw::BinRead;
 use deku::prelude::*;
-use nom::{bytes::complete::take, error::Error, IResult};
+use nom::{
+    bytes::complete::take,
+    error::{Error, ErrorKind},
+    IResult,
+};
 use serialization::{
-    ChunkTagMapEntry, FDSTTable, IndexDefinitionRecord, MobiHeader, PalmDoc, SkeletonTagMapEntry,
+    classify_flows, read_srcs_record, ChunkTagMapEntry, CompilationLog, DatpRecord, FDSTTable,
+    FlowKind, KindRecord, MobiHeader, PageMapRecord, PalmDoc, Provenance, ResCRecord,
+    SkeletonTagMapEntry, TotalIndexEntry,
 };
 use std::io::Cursor;
 
-use crate::constants::MetadataIdValue;
+use crate::{compression::palmdoc, constants::MetadataIdValue};
 
+pub mod compression;
 pub mod constants;
 pub mod serialization;
 mod utils;
+pub mod validate;
+
+pub use validate::{validate, validate_bytes};
 
 #[derive(Debug, PartialEq)]
 pub struct MobiBookFragment {
@@ -52,6 +62,8 @@ pub enum ResourceKind {
     Image(ImageResourceKind),
     Font,
     Stylesheet,
+    /// An SVG image kept in its own flow.
+    Svg,
 }
 
 #[derive(Debug)]
@@ -60,6 +72,8 @@ pub struct Resource {
     pub data: Vec<u8>,
     pub file_type: infer::Type,
     pub flow_index: Option<usize>,
+    /// The record index relative to the first resource record, which is what `kindle:embed` references count.
+    pub record: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -70,261 +84,299 @@ pub struct MobiBook {
     content: String,
     pub parts: Vec<MobiBookPart>,
     pub resources: Vec<Resource>,
+    pub resc: Option<ResCRecord>,
+    pub page_map: Option<PageMapRecord>,
+    /// Zip archive of the files the book was built from.
+    pub source_archive: Option<Vec<u8>>,
+    pub provenance: Provenance,
+    /// Resources that are only in the HD container, see `ResourceContainer::resolve_placeholders`. Counted from the first resource record.
+    pub placeholders: Vec<usize>,
+    pub datp: Option<DatpRecord>,
+}
+
+fn fail(input: &[u8]) -> nom::Err<Error<&[u8]>> {
+    nom::Err::Failure(Error::new(input, ErrorKind::Verify))
+}
+
+fn parse_index(palmdoc: &PalmDoc, record_i: u32) -> Option<TotalIndexEntry> {
+    let records = palmdoc.records.get(record_i as usize..)?;
+    TotalIndexEntry::from_records(records).ok()
 }
 
 pub fn parse_book(input: &[u8]) -> IResult<&[u8], MobiBook> {
-    let original_input = input;
-    let (_, palmdoc) = PalmDoc::from_bytes((&input, 0)).expect("could not parse header");
+    let (_, palmdoc) = PalmDoc::from_bytes((input, 0)).map_err(|_| fail(input))?;
 
     let (input, _) = take(2usize)(input)?; // Skip 2 bytes
 
     // todo: use first section offset instead of manually skipping bytes above?
-    let mut first_record = Cursor::new(&palmdoc.records[0]);
-    let book_header =
-        crate::serialization::MobiHeader::read(&mut first_record).expect("could not parse header");
+    let first_record = palmdoc.records.first().ok_or_else(|| fail(input))?;
+    let book_header = crate::serialization::MobiHeader::read(&mut Cursor::new(first_record))
+        .map_err(|_| fail(input))?;
 
     // todo: assert that header is k8?
 
-    let mut raw_ml = Vec::new();
-    for (i, section_header) in palmdoc.records.iter().enumerate().skip(1) {
-        if i > book_header.num_of_text_records as usize {
-            break;
-        }
-
-        let section_data = palmdoc.records[i].as_slice();
-        let section_data = &section_data
-            [..section_data.len() - book_header.sizeof_trailing_section_entries(section_data)];
+    let text_records = palmdoc
+        .records
+        .get(1..=book_header.num_of_text_records as usize)
+        .ok_or_else(|| fail(input))?;
+    let decompressed = utils::parallel::map(text_records, |section_data| {
+        let trailing_entries_len = book_header
+            .sizeof_trailing_section_entries(section_data)
+            .ok()?;
+        let section_data = &section_data[..section_data.len() - trailing_entries_len];
 
-        let decompressed = palmdoc_compression::decompress(section_data).unwrap();
+        palmdoc::decompress(section_data).ok()
+    });
 
-        raw_ml.extend_from_slice(&decompressed);
+    let mut raw_ml = Vec::new();
+    for decompressed in decompressed {
+        raw_ml.extend_from_slice(&decompressed.ok_or_else(|| fail(input))?);
     }
 
     // Parse flow boundaries
-    let fdst_section_data = palmdoc.records[book_header.fdst_record as usize].as_slice();
+    let fdst_section_data = palmdoc
+        .records
+        .get(book_header.fdst_record as usize)
+        .ok_or_else(|| fail(input))?;
 
-    let (_, fdst_table) = FDSTTable::from_bytes((fdst_section_data, 0)).unwrap();
+    let (_, fdst_table) = FDSTTable::from_bytes((fdst_section_data, 0)).map_err(|_| fail(input))?;
 
     let mut flows = Vec::new();
 
     for entry in fdst_table.entries {
-        let flow = &raw_ml[(entry.start as usize)..(entry.end as usize)];
+        let flow = raw_ml
+            .get((entry.start as usize)..(entry.end as usize))
+            .ok_or_else(|| fail(input))?;
         flows.push(flow);
     }
 
-    let text = *flows.first().unwrap();
-
-    // let (_, skeleton_table) = parse_index_data(&palmdoc, book_header.skel_index as usize).unwrap();
-
-    // let (_, fragment_table) = parse_index_data(&palmdoc, book_header.chunk_index as usize).unwrap();
-
-    // let fragment_table = fragment_table.parse_as::<ChunkTagMapEntry>().unwrap();
-
-    // let mut parts = vec![];
-
-    // let mut fragment_i = 0;
-    // for (i, skeleton_entry) in skeleton_table
-    //     .parse_as::<SkeletonTagMapEntry>()
-    //     .unwrap()
-    //     .iter()
-    //     .enumerate()
-    // {
-    //     let mut base_ptr = (skeleton_entry.start_offset + skeleton_entry.length) as usize;
-
-    //     let mut fragments: Vec<MobiBookFragment> = vec![];
-
-    //     let first_fragment = fragment_table.get(fragment_i).unwrap();
-    //     let split_skeleton_at = first_fragment.insert_position as usize;
-
-    //     // todo: zip?
-    //     let mut filename: String = "".to_string();
-    //     for i in 0..skeleton_entry.chunk_count {
-    //         let fragment_entry = fragment_table.get(fragment_i).unwrap();
-
-    //         if i == 0 {
-    //             filename = format!("part{}.xhtml", fragment_entry.file_number);
-    //         }
-
-    //         let fragment_text = &text[base_ptr..base_ptr + fragment_entry.length as usize];
-
-    //         fragments.push(MobiBookFragment {
-    //             index: fragment_i,
-    //             content: fragment_text.to_vec(),
-    //         });
-
-    //         base_ptr += fragment_entry.length as usize;
-    //         fragment_i += 1;
-    //     }
-
-    //     let skeleton_head = &text[skeleton_entry.start_offset as usize..split_skeleton_at];
-    //     let skeleton_tail = &text
-    //         [split_skeleton_at..(skeleton_entry.start_offset + skeleton_entry.length) as usize];
-
-    //     parts.push(MobiBookPart {
-    //         filename,
-    //         skeleton_head: skeleton_head.to_vec(),
-    //         fragments,
-    //         skeleton_tail: skeleton_tail.to_vec(),
-    //         start_offset: skeleton_entry.start_offset as usize,
-    //         end_offset: base_ptr,
-    //     });
-    // }
-
-    // // Resources
-    // let mut resources: Vec<Resource> = vec![];
-
-    // // todo: handle SVGs/images, CDATA?
-    // let stylesheets = flows.iter().skip(1);
-
-    // let mut info = infer::Infer::new();
-    // info.add("text/css", "css", |_| true);
-
-    // for (i, stylesheet) in stylesheets.enumerate() {
-    //     resources.push(Resource {
-    //         kind: ResourceKind::Stylesheet,
-    //         data: stylesheet.to_vec(),
-    //         file_type: info.get(stylesheet).unwrap(),
-    //         flow_index: Some(i + 1),
-    //     });
-    // }
-
-    // let cover_offset = book_header.first_resource_record as usize
-    //     + *book_header
-    //         .exth
-    //         .as_ref()
-    //         .unwrap()
-    //         .metadata_value
-    //         .get(&MetadataIdValue::CoverOffset)
-    //         .unwrap()
-    //         .first()
-    //         .unwrap() as usize;
-
-    // let thumbnail_offset = book_header.first_resource_record as usize
-    //     + *book_header
-    //         .exth
-    //         .as_ref()
-    //         .unwrap()
-    //         .metadata_value
-    //         .get(&MetadataIdValue::ThumbOffset)
-    //         .unwrap()
-    //         .first()
-    //         .unwrap() as usize;
-
-    // for section_i in book_header.first_resource_record as usize..palmdoc.records.len() {
-    //     let data = palmdoc.records[section_i].as_slice();
-    //     let (input, resource_type) = take::<usize, &[u8], Error<&[u8]>>(4usize)(data).unwrap();
-
-    //     match resource_type {
-    //         b"FLIS" | b"FCIS" | b"FDST" | b"DATP" => {
-    //             // todo?
-    //         }
-    //         b"SRCS" => {
-    //             // todo
-    //         }
-    //         b"PAGE" => {
-    //             // todo
-    //         }
-    //         b"CMET" => {
-    //             // todo
-    //         }
-    //         b"FONT" => {
-    //             // todo
-    //         }
-    //         b"CRES" => {
-    //             // todo
-    //         }
-    //         b"CONT" => {
-    //             // todo
-    //         }
-    //         b"kind" => {
-    //             // todo
-    //         }
-    //         [0xa0, 0xa0, 0xa0, 0xa0] => {
-    //             // todo
-    //             println!("byte pattern, empty image?")
-    //         }
-    //         b"RESC" => {
-    //             // todo
-    //         }
-    //         // EOF
-    //         [0xe9, 0x8e, 0x0d, 0x0a] => {
-    //             // todo
-    //         }
-    //         b"BOUN" => {
-    //             // todo
-    //         }
-    //         _ => {
-    //             // Should be an image
-    //             let file_type = infer::get(data);
-
-    //             if section_i == cover_offset {
-    //                 resources.push(Resource {
-    //                     kind: ResourceKind::Image(ImageResourceKind::Cover),
-    //                     data: data.to_vec(),
-    //                     file_type: file_type.unwrap(),
-    //                     flow_index: None,
-    //                 })
-    //             } else if section_i == thumbnail_offset {
-    //                 resources.push(Resource {
-    //                     kind: ResourceKind::Image(ImageResourceKind::Thumbnail),
-    //                     data: data.to_vec(),
-    //                     file_type: file_type.unwrap(),
-    //                     flow_index: None,
-    //                 })
-    //             } else {
-    //                 resources.push(Resource {
-    //                     kind: ResourceKind::Image(ImageResourceKind::Other),
-    //                     data: data.to_vec(),
-    //                     file_type: file_type.unwrap(),
-    //                     flow_index: None,
-    //                 })
-    //             }
-    //         }
-    //     }
-    // }
-
-    // Ok((
-    //     input,
-    //     MobiBook {
-    //         palmdoc: palmdoc.clone(),
-    //         book_header,
-    //         fragment_table,
-    //         // todo: this should not be lossy
-    //         content: String::from_utf8_lossy(&raw_ml).to_string(),
-    //         parts,
-    //         resources,
-    //     },
-    // ))
-
-    todo!()
-}
+    let text = *flows.first().ok_or_else(|| fail(input))?;
+
+    let skeleton_table =
+        parse_index(&palmdoc, book_header.skel_index).ok_or_else(|| fail(input))?;
+    let fragment_table =
+        parse_index(&palmdoc, book_header.chunk_index).ok_or_else(|| fail(input))?;
+
+    let fragment_table = fragment_table
+        .parse_as::<ChunkTagMapEntry>()
+        .map_err(|_| fail(input))?;
+
+    let mut parts = vec![];
+
+    let mut fragment_i = 0;
+    for skeleton_entry in skeleton_table
+        .parse_as::<SkeletonTagMapEntry>()
+        .map_err(|_| fail(input))?
+    {
+        let start_offset = skeleton_entry.start_offset as usize;
+        let skeleton_end = start_offset + skeleton_entry.length as usize;
+        let mut base_ptr = skeleton_end;
+
+        let mut fragments: Vec<MobiBookFragment> = vec![];
 
-// fn parse_index_data<'a>(
-//     palmdoc: &'a PalmDoc,
-//     section_i: usize,
-// ) -> IResult<&'a [u8], IndexDataRecord> {
-//     // Parse INDX header
-//     let indx_section_data = palmdoc.records[section_i].as_slice();
-//     let (_, index_definition_record) =
-//         IndexDefinitionRecord::from_bytes((indx_section_data, 0)).unwrap();
-
-//     for i in (section_i + 1)..(section_i + 1 + index_definition_record.num_of_records as usize) {
-//         let data = palmdoc.records[i].as_slice();
-
-//         let mut cursor = Cursor::new(&data);
-//         let mut reader = Reader::new(&mut cursor);
-//         let index_record = IndexDataRecord::from_reader_with_ctx(
-//             &mut reader,
-//             &index_definition_record.definition.tag_definitions,
-//         )
-//         .unwrap();
-
-//         // todo: handle multiple records
-//         return Ok((&[], index_record));
-//     }
-
-//     todo!()
-// }
+        let first_fragment = fragment_table.get(fragment_i).ok_or_else(|| fail(input))?;
+        let split_skeleton_at = first_fragment.insert_position as usize;
+
+        // todo: zip?
+        let mut filename: String = "".to_string();
+        for i in 0..skeleton_entry.chunk_count {
+            let fragment_entry = fragment_table.get(fragment_i).ok_or_else(|| fail(input))?;
+
+            if i == 0 {
+                filename = format!("part{}.xhtml", fragment_entry.file_number);
+            }
+
+            let fragment_text = text
+                .get(base_ptr..base_ptr + fragment_entry.length as usize)
+                .ok_or_else(|| fail(input))?;
+
+            fragments.push(MobiBookFragment {
+                index: fragment_i,
+                content: fragment_text.to_vec(),
+            });
+
+            base_ptr += fragment_entry.length as usize;
+            fragment_i += 1;
+        }
+
+        let skeleton_head = text
+            .get(start_offset..split_skeleton_at)
+            .ok_or_else(|| fail(input))?;
+        let skeleton_tail = text
+            .get(split_skeleton_at..skeleton_end)
+            .ok_or_else(|| fail(input))?;
+
+        parts.push(MobiBookPart {
+            filename,
+            skeleton_head: skeleton_head.to_vec(),
+            fragments,
+            skeleton_tail: skeleton_tail.to_vec(),
+            start_offset,
+            end_offset: base_ptr,
+        });
+    }
+
+    // Resources
+    let mut resources: Vec<Resource> = vec![];
+
+    // Flows are classified by the `kindle:flow` references to them
+    let flow_texts = flows
+        .iter()
+        .map(|flow| String::from_utf8_lossy(flow))
+        .collect::<Vec<_>>();
+    let flow_kinds = classify_flows(
+        flow_texts.iter().map(|text| &**text),
+        &flow_texts[1..]
+            .iter()
+            .map(|text| &**text)
+            .collect::<Vec<_>>(),
+    );
+
+    let mut css = infer::Infer::new();
+    css.add("text/css", "css", |_| true);
+    let mut svg = infer::Infer::new();
+    svg.add("image/svg+xml", "svg", |_| true);
+
+    for (i, (flow, flow_kind)) in flows.iter().skip(1).zip(flow_kinds).enumerate() {
+        let (kind, info) = match flow_kind {
+            FlowKind::Stylesheet => (ResourceKind::Stylesheet, &css),
+            FlowKind::Svg => (ResourceKind::Svg, &svg),
+            // todo: surface other flows
+            FlowKind::Other(_) => continue,
+        };
+        resources.push(Resource {
+            kind,
+            data: flow.to_vec(),
+            file_type: info.get(flow).ok_or_else(|| fail(input))?,
+            flow_index: Some(i + 1),
+            record: None,
+        });
+    }
+
+    let resource_offset = |id: MetadataIdValue| {
+        book_header
+            .exth
+            .as_ref()
+            .and_then(|exth| exth.value(id))
+            .map(|offset| book_header.first_resource_record as usize + offset as usize)
+    };
+    let cover_offset = resource_offset(MetadataIdValue::CoverOffset);
+    let thumbnail_offset = resource_offset(MetadataIdValue::ThumbOffset);
+
+    let mut resc = None;
+    let mut page_map = None;
+    let mut source_archive = None;
+    let mut kind = None;
+    let mut compilation_log = None;
+    let mut placeholders = vec![];
+    let mut datp = None;
+    let first_resource_record = book_header.first_resource_record as usize;
+    for (section_i, data) in palmdoc
+        .records
+        .iter()
+        .enumerate()
+        .skip(first_resource_record)
+    {
+        let resource_type = data.get(..4).unwrap_or_default();
+
+        match resource_type {
+            b"FLIS" | b"FCIS" | b"FDST" => {
+                // todo?
+            }
+            b"DATP" => match DatpRecord::parse(data) {
+                Ok(record) => datp = Some(record),
+                Err(e) => log::warn!("Skipping malformed DATP record {}: {}", section_i, e),
+            },
+            b"SRCS" => match read_srcs_record(data) {
+                Ok(archive) => source_archive = Some(archive.to_vec()),
+                Err(e) => log::warn!("Skipping malformed SRCS record {}: {}", section_i, e),
+            },
+            b"PAGE" => match PageMapRecord::parse(data) {
+                Ok(record) => page_map = Some(record),
+                Err(e) => log::warn!("Skipping malformed PAGE record {}: {}", section_i, e),
+            },
+            b"CMET" => {
+                compilation_log = Some(CompilationLog::parse(data).map_err(|_| fail(input))?);
+            }
+            b"FONT" => {
+                // todo
+            }
+            b"CRES" => {
+                // todo
+            }
+            b"CONT" => {
+                // todo
+            }
+            b"kind" => {
+                kind = Some(KindRecord::parse(data).map_err(|_| fail(input))?);
+            }
+            [0xa0, 0xa0, 0xa0, 0xa0] => {
+                placeholders.push(section_i - first_resource_record);
+            }
+            b"RESC" => match ResCRecord::parse(data) {
+                Ok(record) => resc = Some(record),
+                Err(e) => log::warn!("Skipping malformed RESC record {}: {}", section_i, e),
+            },
+            // EOF
+            [0xe9, 0x8e, 0x0d, 0x0a] => {
+                // todo
+            }
+            b"BOUN" => {
+                // todo
+            }
+            _ => {
+                // Should be an image
+                let Some(file_type) = infer::get(data) else {
+                    // todo: surface unknown records
+                    continue;
+                };
+
+                let kind = if Some(section_i) == cover_offset {
+                    ImageResourceKind::Cover
+                } else if Some(section_i) == thumbnail_offset {
+                    ImageResourceKind::Thumbnail
+                } else {
+                    ImageResourceKind::Other
+                };
+
+                resources.push(Resource {
+                    kind: ResourceKind::Image(kind),
+                    data: data.to_vec(),
+                    file_type,
+                    flow_index: None,
+                    record: Some(section_i - first_resource_record),
+                });
+            }
+        }
+    }
+
+    let content = book_header
+        .text_encoding
+        .decode(&raw_ml)
+        .unwrap_or_else(|e| {
+            log::warn!("Could not decode the text, replacing invalid bytes: {}", e);
+            String::from_utf8_lossy(&raw_ml).into_owned()
+        });
+    let provenance = Provenance::new(book_header.exth.as_ref(), kind, compilation_log);
+
+    Ok((
+        input,
+        MobiBook {
+            palmdoc,
+            book_header,
+            fragment_table,
+            content,
+            parts,
+            resources,
+            resc,
+            page_map,
+            source_archive,
+            provenance,
+            placeholders,
+            datp,
+        },
+    ))
+}
 
 #[cfg(test)]
 mod tests {
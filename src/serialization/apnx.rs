@@ -0,0 +1,445 @@
+//! APNX files, the sidecars that Kindle reads page numbers of sideloaded books from.
+
+use deku::DekuError;
+
+use crate::{constants::MetadataId, utils::json};
+
+use super::{
+    book::{read_flows, read_text},
+    page_map::{format_ranges, parse_ranges},
+    parse_options::{Diagnostics, ParseOptions, ParseWarning},
+    MobiHeader, PageLabelRange, PageLabels, PageMapRecord, PalmDoc,
+};
+
+const VERSION: u32 = 0x0001_0001;
+/// Bytes of text per page for `PageAlgorithm::Fast`, the same as Kindle's own estimate.
+const FAST_PAGE_LEN: usize = 2240;
+const LINE_LEN: usize = 70;
+const LINES_PER_PAGE: usize = 32;
+/// Tags that end a line of text.
+const BLOCK_TAGS: [&[u8]; 13] = [
+    b"p",
+    b"div",
+    b"br",
+    b"li",
+    b"tr",
+    b"blockquote",
+    b"h1",
+    b"h2",
+    b"h3",
+    b"h4",
+    b"h5",
+    b"h6",
+    b"hr",
+];
+
+/// How to split the text into pages when the book has no `PAGE` record.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum PageAlgorithm {
+    /// A page every 2240 bytes.
+    #[default]
+    Fast,
+    /// Lays the visible text out in lines of 70 characters, with 32 lines per page. Block elements start a new line.
+    Accurate,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Apnx {
+    pub content_guid: String,
+    pub asin: String,
+    pub cde_type: String,
+    /// `MOBI_8` for KF8 books, `MOBI_7` for older ones.
+    pub format: String,
+    /// The name of the PalmDoc database.
+    pub acr: String,
+    /// Offsets are positions in the uncompressed text.
+    pub page_map: PageMapRecord,
+}
+
+/// Start of every page, splitting the text into lines and pages like a reader would.
+fn accurate_pages(text: &[u8]) -> Vec<u32> {
+    let mut pages = vec![0];
+    let mut lines = 0;
+    let mut line_len = 0;
+    let mut in_head = false;
+    let mut i = 0;
+
+    let mut end_line = |i: usize, lines: &mut usize, line_len: &mut usize| {
+        *line_len = 0;
+        *lines += 1;
+        if *lines == LINES_PER_PAGE {
+            *lines = 0;
+            pages.push(i as u32);
+        }
+    };
+
+    while i < text.len() {
+        if text[i] == b'<' {
+            let end = text[i..]
+                .iter()
+                .position(|b| *b == b'>')
+                .map_or(text.len(), |end| i + end + 1);
+            let tag = &text[i + 1..end.saturating_sub(1).max(i + 1)];
+            let is_closing = tag.starts_with(b"/");
+            let name = tag
+                .strip_prefix(b"/")
+                .unwrap_or(tag)
+                .split(|b| b.is_ascii_whitespace() || *b == b'/')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+
+            if name == b"head" {
+                in_head = !is_closing;
+            } else if BLOCK_TAGS.contains(&name.as_slice())
+                && (line_len > 0 || name == b"br" || name == b"hr")
+            {
+                end_line(end, &mut lines, &mut line_len);
+            }
+
+            i = end;
+            continue;
+        }
+
+        // Count characters, not bytes
+        let is_char_start = text[i] & 0b1100_0000 != 0b1000_0000;
+        let is_newline = matches!(text[i], b'\n' | b'\r');
+        if !in_head && is_char_start && !is_newline {
+            // Wrap before the character that doesn't fit
+            if line_len == LINE_LEN {
+                end_line(i, &mut lines, &mut line_len);
+            }
+            line_len += 1;
+        }
+        i += 1;
+    }
+
+    // Drop a page that would start at the very end
+    if pages.len() > 1 && pages.last() == Some(&(text.len() as u32)) {
+        pages.pop();
+    }
+    pages
+}
+
+/// Splits the text flow into pages with `algorithm`, labelled 1, 2, 3 and so on.
+fn estimate_page_map(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    algorithm: PageAlgorithm,
+    diagnostics: &mut Diagnostics,
+) -> Result<PageMapRecord, DekuError> {
+    let text = read_text(palmdoc, mobi_header, diagnostics)?;
+    // Only the first flow is text, the others are stylesheets and images
+    let flow = match read_flows(palmdoc, mobi_header, &text) {
+        Ok(flows) => flows[0],
+        Err(reason) => {
+            diagnostics.recover(ParseWarning::InvalidFdst { reason })?;
+            text.as_slice()
+        }
+    };
+
+    let offsets = match algorithm {
+        PageAlgorithm::Fast => (0..flow.len().max(1))
+            .step_by(FAST_PAGE_LEN)
+            .map(|offset| offset as u32)
+            .collect(),
+        PageAlgorithm::Accurate => accurate_pages(flow),
+    };
+    Ok(PageMapRecord {
+        ranges: vec![PageLabelRange {
+            first_page: 0,
+            labels: PageLabels::Arabic(1),
+        }],
+        offsets,
+    })
+}
+
+impl Apnx {
+    /// Uses the book's `PAGE` record if it has one, or else splits its text flow into pages with `algorithm`. An invalid `PAGE` record is an error in strict mode, and is replaced by `algorithm`'s pages otherwise.
+    pub fn from_palmdoc(
+        palmdoc: &PalmDoc,
+        algorithm: PageAlgorithm,
+        options: &ParseOptions,
+    ) -> Result<(Self, Vec<ParseWarning>), DekuError> {
+        let mut diagnostics = Diagnostics::new(options);
+
+        let mobi_header = palmdoc.mobi_header()?;
+
+        let page_record = palmdoc
+            .records
+            .iter()
+            .enumerate()
+            .skip(mobi_header.first_resource_record as usize)
+            .find(|(_, data)| data.starts_with(b"PAGE"));
+        let page_map = match page_record {
+            Some((record, data)) => match PageMapRecord::parse(data) {
+                Ok(page_map) => page_map,
+                Err(e) => {
+                    diagnostics.recover(ParseWarning::InvalidPageMap {
+                        record,
+                        reason: e.to_string(),
+                    })?;
+                    estimate_page_map(palmdoc, &mobi_header, algorithm, &mut diagnostics)?
+                }
+            },
+            None => estimate_page_map(palmdoc, &mobi_header, algorithm, &mut diagnostics)?,
+        };
+
+        let exth = mobi_header.exth.as_ref();
+        let apnx = Apnx {
+            content_guid: format!("{:08x}", mobi_header.uid),
+            asin: exth
+                .and_then(|exth| exth.asin())
+                .unwrap_or_default()
+                .to_string(),
+            cde_type: exth
+                .and_then(|exth| exth.string(MetadataId::CdeType))
+                .unwrap_or("EBOK")
+                .to_string(),
+            format: if mobi_header.file_version >= 8 {
+                "MOBI_8"
+            } else {
+                "MOBI_7"
+            }
+            .to_string(),
+            acr: palmdoc.title.clone(),
+            page_map,
+        };
+
+        Ok((apnx, diagnostics.into_warnings()))
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, DekuError> {
+        let invalid = |reason: &str| DekuError::Parse(format!("Invalid APNX: {}", reason).into());
+        let read = |offset: usize, len: usize| {
+            data.get(offset..offset + len)
+                .ok_or_else(|| invalid("truncated file"))
+        };
+        let read_u16 =
+            |offset: usize| read(offset, 2).map(|b| u16::from_be_bytes(b.try_into().unwrap()));
+        let read_u32 =
+            |offset: usize| read(offset, 4).map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+        let read_str = |offset: usize, len: usize| {
+            read(offset, len).and_then(|b| {
+                std::str::from_utf8(b).map_err(|_| invalid("header is not valid UTF-8"))
+            })
+        };
+
+        if read_u32(0)? != VERSION {
+            return Err(invalid("unknown version"));
+        }
+        let page_section = read_u32(4)? as usize;
+        let content_header = read_str(12, read_u32(8)? as usize)?;
+
+        let page_header_len = read_u16(page_section + 2)? as usize;
+        let page_count = read_u16(page_section + 4)? as usize;
+        if read_u16(page_section + 6)? != 32 {
+            return Err(invalid("offsets are not 32 bits"));
+        }
+        let page_header = read_str(page_section + 8, page_header_len)?;
+        let offsets = read(page_section + 8 + page_header_len, page_count * 4)?
+            .chunks(4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+            .collect();
+
+        let field = |key: &str| json::value(content_header, key).unwrap_or_default();
+        let ranges = parse_ranges(&json::value(page_header, "pageMap").unwrap_or_default())
+            .map_err(|reason| invalid(&reason))?;
+
+        Ok(Apnx {
+            content_guid: field("contentGuid"),
+            asin: field("asin"),
+            cde_type: field("cdeType"),
+            format: field("format"),
+            acr: field("acr"),
+            page_map: PageMapRecord { ranges, offsets },
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DekuError> {
+        let content_header = format!(
+            r#"{{"contentGuid":"{}","asin":"{}","cdeType":"{}","format":"{}","fileRevisionId":"1","acr":"{}"}}"#,
+            json::escape(&self.content_guid),
+            json::escape(&self.asin),
+            json::escape(&self.cde_type),
+            json::escape(&self.format),
+            json::escape(&self.acr)
+        );
+        let page_header = format!(
+            r#"{{"asin":"{}","pageMap":"{}"}}"#,
+            json::escape(&self.asin),
+            json::escape(&format_ranges(&self.page_map.ranges)?)
+        );
+
+        let too_large = |what: &str| DekuError::Parse(format!("Too many {} for APNX", what).into());
+        let page_header_len =
+            u16::try_from(page_header.len()).map_err(|_| too_large("page labels"))?;
+        let page_count =
+            u16::try_from(self.page_map.offsets.len()).map_err(|_| too_large("pages"))?;
+
+        let mut apnx = VERSION.to_be_bytes().to_vec();
+        apnx.extend_from_slice(&(12 + content_header.len() as u32).to_be_bytes());
+        apnx.extend_from_slice(&(content_header.len() as u32).to_be_bytes());
+        apnx.extend_from_slice(content_header.as_bytes());
+        for value in [1, page_header_len, page_count, 32] {
+            apnx.extend_from_slice(&value.to_be_bytes());
+        }
+        apnx.extend_from_slice(page_header.as_bytes());
+        for offset in &self.page_map.offsets {
+            apnx.extend_from_slice(&offset.to_be_bytes());
+        }
+        Ok(apnx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{BookBuilder, BookWriter, CompressionType, Metadata, PageListItem};
+    use deku::DekuContainerRead;
+    use pretty_assertions::assert_eq;
+
+    fn book(page_list: Vec<PageListItem>) -> PalmDoc {
+        let paragraphs =
+            "<p>Lorem ipsum dolor sit amet, consectetur adipiscing elit.</p>\n".repeat(200);
+        let xhtml = format!(
+            r#"<html><head><title>Chapter 1</title></head><body><h1 id="start">Chapter 1</h1>{}</body></html>"#,
+            paragraphs
+        );
+        let book = BookBuilder::new(Metadata {
+            title: "Lorem Ipsum".to_string(),
+            ..Default::default()
+        })
+        .compression(CompressionType::PalmDoc)
+        .chapter("chapter.xhtml", xhtml)
+        .page_list(page_list)
+        .build()
+        .unwrap();
+        BookWriter::reproducible().write(&book).unwrap()
+    }
+
+    #[test]
+    fn test_apnx_roundtrip() {
+        let (apnx, warnings) =
+            Apnx::from_palmdoc(&book(vec![]), PageAlgorithm::Fast, &ParseOptions::default())
+                .unwrap();
+        assert_eq!(warnings, []);
+        assert_eq!(apnx.format, "MOBI_8");
+        assert_eq!(apnx.cde_type, "EBOK");
+        assert_eq!(apnx.page_map.offsets[..3], [0, 2240, 4480]);
+
+        assert_eq!(Apnx::parse(&apnx.to_bytes().unwrap()).unwrap(), apnx);
+    }
+
+    #[test]
+    fn test_apnx_uses_page_map() {
+        let page_list = vec![
+            PageListItem {
+                label: "i".to_string(),
+                href: "chapter.xhtml".to_string(),
+            },
+            PageListItem {
+                label: "1".to_string(),
+                href: "chapter.xhtml#start".to_string(),
+            },
+        ];
+        let palmdoc = book(page_list);
+        let (apnx, _) =
+            Apnx::from_palmdoc(&palmdoc, PageAlgorithm::Fast, &ParseOptions::default()).unwrap();
+
+        assert_eq!(apnx.page_map.labels(), ["i", "1"]);
+        assert_eq!(apnx.page_map.offsets.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_page_map_falls_back() {
+        let mut palmdoc = book(vec![PageListItem {
+            label: "1".to_string(),
+            href: "chapter.xhtml#start".to_string(),
+        }]);
+        let record = palmdoc
+            .records
+            .iter()
+            .position(|data| data.starts_with(b"PAGE"))
+            .unwrap();
+        palmdoc.records[record].truncate(8);
+
+        assert!(
+            Apnx::from_palmdoc(&palmdoc, PageAlgorithm::Fast, &ParseOptions::default()).is_err()
+        );
+
+        let (apnx, warnings) = Apnx::from_palmdoc(
+            &palmdoc,
+            PageAlgorithm::Fast,
+            &ParseOptions { strict: false },
+        )
+        .unwrap();
+        assert!(matches!(
+            warnings.as_slice(),
+            [ParseWarning::InvalidPageMap { record: r, .. }] if *r == record
+        ));
+        assert_eq!(apnx.page_map.offsets[..3], [0, 2240, 4480]);
+    }
+
+    #[test]
+    fn test_accurate_pages() {
+        let (apnx, _) = Apnx::from_palmdoc(
+            &book(vec![]),
+            PageAlgorithm::Accurate,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        // 201 lines, with one line per paragraph
+        assert_eq!(apnx.page_map.offsets.len(), 7);
+        assert!(apnx.page_map.offsets.windows(2).all(|w| w[0] < w[1]));
+
+        // Long paragraphs wrap, and the head isn't counted
+        let text = format!(
+            "<html><head><title>{}</title></head><body><p>{}</p></body></html>",
+            "x".repeat(500),
+            "é".repeat(LINE_LEN * LINES_PER_PAGE + 1)
+        );
+        let pages = accurate_pages(text.as_bytes());
+        assert_eq!(pages.len(), 2);
+        assert_eq!(
+            &text.as_bytes()[pages[1] as usize..pages[1] as usize + 2],
+            "é".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_parse_calibre_apnx() {
+        let content_header = r#"{"contentGuid":"d9a2c31e","asin":"B000FC0PDA","cdeType":"EBOK","format":"MOBI_8","fileRevisionId":"1","acr":"War_and_Peace"}"#;
+        let page_header = r#"{"asin":"B000FC0PDA","pageMap":"(1,a,1)"}"#;
+        let mut data = VERSION.to_be_bytes().to_vec();
+        data.extend_from_slice(&(12 + content_header.len() as u32).to_be_bytes());
+        data.extend_from_slice(&(content_header.len() as u32).to_be_bytes());
+        data.extend_from_slice(content_header.as_bytes());
+        for value in [1, page_header.len() as u16, 2, 32] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data.extend_from_slice(page_header.as_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 8, 0xc0]);
+
+        let apnx = Apnx::parse(&data).unwrap();
+        assert_eq!(apnx.content_guid, "d9a2c31e");
+        assert_eq!(apnx.acr, "War_and_Peace");
+        assert_eq!(apnx.page_map.offsets, [0, 2240]);
+        assert_eq!(apnx.page_map.labels(), ["1", "2"]);
+        assert_eq!(apnx.to_bytes().unwrap(), data);
+
+        assert!(Apnx::parse(&data[..20]).is_err());
+    }
+
+    #[test]
+    fn test_apnx_from_fixture() {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+        let (apnx, warnings) =
+            Apnx::from_palmdoc(&palmdoc, PageAlgorithm::Fast, &ParseOptions::default()).unwrap();
+
+        assert_eq!(warnings, []);
+        assert!(apnx.page_map.offsets.len() > 100);
+        assert_eq!(apnx.acr, palmdoc.title);
+    }
+}
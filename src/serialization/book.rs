@@ -1,24 +1,46 @@
 use byteorder::WriteBytesExt;
 use std::{
-    io::{Cursor, Read, Seek, SeekFrom, Write},
+    borrow::Cow,
+    cell::RefCell,
+    collections::BTreeSet,
+    io::{Cursor, Read, Write},
     iter::once,
     time::{SystemTime, UNIX_EPOCH},
     u32, vec,
 };
 
-use binrw::{BinRead, BinWrite};
+use binrw::{BinWrite, NullString};
 use deku::prelude::*;
 #[cfg(test)]
 use proptest_derive::Arbitrary;
 
 use crate::{
-    constants::{MainLanguage, MetadataId, SubLanguage},
-    serialization::{tag_map::TagMapEntry, FDSTEntry, SkeletonTagMapEntry, TotalIndexEntry},
+    compression::palmdoc::{self, CompressionLevel},
+    constants::{MainLanguage, MetadataId, MetadataIdValue, SubLanguage},
+    serialization::{
+        tag_map::TagMapEntry, CNCXRecords, FDSTEntry, GuideTagMapEntry, NcxTagMapEntry,
+        SerializedCNCXRecords, SkeletonTagMapEntry, TotalIndexEntry,
+    },
+    utils::parallel,
 };
 
 use super::{
-    exth::Exth, BookType, ChunkTagMapEntry, Codepage, CompressionType, ExthFlags, ExtraDataFlags,
-    FDSTTable, LanguageCode, MobiHeader, PalmDoc,
+    book_metadata::Metadata,
+    datp::DatpRecord,
+    embed::{rewrite_embeds, EmbedReference},
+    fixed_layout::{magnification_regions, FixedLayout, MagnificationRegion},
+    flow::{classify_flows, Flow},
+    font_record::{read_font_record, write_font_record},
+    navigation::{
+        location_to_offset, location_to_pos, offset_to_location, Landmark, Location, PageTarget,
+        PartLayout, TocEntry,
+    },
+    page_map::PageMapRecord,
+    parse_options::{Diagnostics, ParseOptions, ParseWarning},
+    resc::{ResCRecord, Spine},
+    srcs_record::{read_srcs_record, write_srcs_record},
+    BookType, ChunkTagMapEntry, Codepage, CompressionType, Exth, ExthFlags, ExtraDataFlags,
+    FDSTTable, LanguageCode, MobiHeader, PalmDoc, TrailingEntries,
 };
 use crate::serialization::index::types::IndexTagMapEntry;
 
@@ -32,106 +54,960 @@ pub struct BookPart {
     pub skeleton_tail: String,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub enum EmbeddedResourceKind {
+    Image,
+    Font,
+}
+
+/// A binary resource, stored in its own record after the text. Text references it as `kindle:embed:XXXX`, where `XXXX` is its index (starting from 1) in base 32.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct EmbeddedResource {
+    pub kind: EmbeddedResourceKind,
+    pub data: Vec<u8>,
+}
+
 #[derive(Debug, PartialEq)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub struct Book {
-    pub title: String,
+    /// Written over `exth`, replacing the records it covers.
+    pub metadata: Metadata,
+    /// The EXTH records of the book this was read from, so that the ones `metadata` doesn't cover are kept.
+    pub exth: Exth,
     pub uid: u32,
     pub main_language: Option<MainLanguage>,
     pub sub_language: Option<SubLanguage>,
     pub book_parts: Vec<BookPart>,
-    pub resources: Vec<String>, // todo
+    /// Flows 1 and up, referenced as `kindle:flow:0001` and so on.
+    pub resources: Vec<Flow>,
+    /// Written in order as the first resource records, so `kindle:embed:0001` in the text is the first one.
+    pub embedded_resources: Vec<EmbeddedResource>,
+    /// Index into `embedded_resources`.
+    pub cover: Option<usize>,
+    pub toc: Vec<TocEntry>,
+    pub landmarks: Vec<Landmark>,
+    /// Written to the `PAGE` record, unless empty.
+    pub page_list: Vec<PageTarget>,
+    /// Written to the `RESC` record, unless empty.
+    pub spine: Spine,
+    /// `None` for reflowable books. Where each page goes in a spread is in `spine`.
+    pub fixed_layout: Option<FixedLayout>,
+    /// Zip archive of the files the book was built from, kept in the `SRCS` record.
+    pub source_archive: Option<Vec<u8>>,
+    /// Kept from the book this was read from, see `DatpRecord`.
+    pub datp: Option<DatpRecord>,
     pub compression: CompressionType,
+    pub text_encoding: Codepage,
+}
+
+/// An empty book with PalmDoc compression and UTF-8 text, like `BookBuilder` starts from.
+impl Default for Book {
+    fn default() -> Self {
+        Book {
+            metadata: Metadata::default(),
+            exth: Exth::default(),
+            uid: 0,
+            main_language: None,
+            sub_language: None,
+            book_parts: vec![],
+            resources: vec![],
+            embedded_resources: vec![],
+            cover: None,
+            toc: vec![],
+            landmarks: vec![],
+            page_list: vec![],
+            spine: Spine::default(),
+            fixed_layout: None,
+            source_archive: None,
+            datp: None,
+            compression: CompressionType::PalmDoc,
+            text_encoding: Codepage::Utf8,
+        }
+    }
 }
 
 impl TryFrom<PalmDoc> for Book {
     type Error = DekuError;
 
     fn try_from(palmdoc: PalmDoc) -> Result<Self, Self::Error> {
-        let first_record = palmdoc
-            .records
-            .first()
-            .ok_or(DekuError::Parse("No records".into()))?;
-        let mobi_header = MobiHeader::read(&mut Cursor::new(first_record)).unwrap();
+        let (book, _) = Book::from_palmdoc(palmdoc, &ParseOptions::default())?;
+        Ok(book)
+    }
+}
 
-        let mut text = Vec::new();
-        for i in 1..(mobi_header.num_of_text_records + 1) as usize {
-            let record = &palmdoc
+impl Book {
+    pub fn from_bytes_with_options(
+        data: &[u8],
+        options: &ParseOptions,
+    ) -> Result<(Self, Vec<ParseWarning>), DekuError> {
+        let (_, palmdoc) = PalmDoc::from_bytes((data, 0))?;
+        Book::from_palmdoc(palmdoc, options)
+    }
+
+    /// Builds a book from its PalmDoc records. In lenient mode this returns the best book it can, along with everything that had to be skipped.
+    pub fn from_palmdoc(
+        palmdoc: PalmDoc,
+        options: &ParseOptions,
+    ) -> Result<(Self, Vec<ParseWarning>), DekuError> {
+        let mut diagnostics = Diagnostics::new(options);
+
+        let mobi_header = palmdoc.mobi_header()?;
+
+        let text = read_text(&palmdoc, &mobi_header, &mut diagnostics)?;
+
+        for (name, record, magic) in [
+            ("FLIS", mobi_header.flis_record, b"FLIS"),
+            ("FCIS", mobi_header.fcis_record, b"FCIS"),
+        ] {
+            let found = palmdoc
                 .records
-                .get(i)
-                .ok_or(DekuError::Parse("No records".into()))?;
+                .get(record as usize)
+                .is_some_and(|data| data.starts_with(magic));
+            if !found {
+                diagnostics.warn(ParseWarning::MissingRecord { name, record });
+            }
+        }
 
-            let record_data =
-                &record[0..record.len() - mobi_header.sizeof_trailing_section_entries(record)];
+        let flows = match read_flows(&palmdoc, &mobi_header, &text) {
+            Ok(flows) => flows,
+            Err(reason) => {
+                diagnostics.recover(ParseWarning::InvalidFdst { reason })?;
+                vec![text.as_slice()]
+            }
+        };
 
-            match mobi_header.compression_type {
-                CompressionType::None => {
-                    text.extend_from_slice(record_data);
-                }
-                CompressionType::HuffCdic => {
-                    todo!()
-                }
-                CompressionType::PalmDoc => {
-                    let decompressed = palmdoc_compression::decompress(record_data)
-                        .map_err(|_| DekuError::Parse("Failed to decompress".into()))?;
-                    text.extend_from_slice(&decompressed);
+        let (book_parts, chunk_starts) = match read_parts(&palmdoc, &mobi_header, flows[0]) {
+            Ok(parts) => parts,
+            Err(warning) => {
+                diagnostics.recover(warning)?;
+                let part = RawBookPart {
+                    skeleton_head: vec![],
+                    content: flows[0].to_vec(),
+                    skeleton_tail: vec![],
+                };
+                (vec![part], vec![])
+            }
+        };
+
+        let page_list = read_page_list(&palmdoc, &mobi_header, &book_parts, &mut diagnostics)?;
+        let toc = read_toc(
+            &palmdoc,
+            &mobi_header,
+            &book_parts,
+            &chunk_starts,
+            &mut diagnostics,
+        )?;
+        let landmarks = read_landmarks(
+            &palmdoc,
+            &mobi_header,
+            &book_parts,
+            &chunk_starts,
+            &mut diagnostics,
+        )?;
+
+        let (embedded_records, embedded_resources): (Vec<_>, Vec<_>) =
+            read_embedded_resources(&palmdoc, &mobi_header, &mut diagnostics)?
+                .into_iter()
+                .unzip();
+        let cover = mobi_header
+            .exth
+            .as_ref()
+            .and_then(|exth| exth.cover_offset())
+            .and_then(|offset| {
+                embedded_records
+                    .iter()
+                    .position(|record| *record == offset as usize)
+            });
+        // `kindle:embed` references count every resource record, so they're renumbered to count only `embedded_resources` when other records are in between
+        let unresolved = RefCell::new(BTreeSet::new());
+        let renumber = |text: String| {
+            if embedded_records
+                .iter()
+                .enumerate()
+                .all(|(i, record)| i == *record)
+            {
+                return text;
+            }
+            rewrite_embeds(&text, |reference| {
+                let index = embedded_records
+                    .iter()
+                    .position(|record| *record == reference.record);
+                if index.is_none() {
+                    unresolved.borrow_mut().insert(reference.record);
                 }
+                Some(EmbedReference::new(index?, reference.mime_type.clone()))
+            })
+        };
+
+        let text_encoding = mobi_header.text_encoding;
+        let mut to_string = |part: usize, bytes: Vec<u8>| match text_encoding.decode(&bytes) {
+            Ok(s) => Ok(s),
+            Err(_) => {
+                diagnostics.recover(ParseWarning::InvalidUtf8 { part })?;
+                Ok::<_, DekuError>(String::from_utf8_lossy(&bytes).into_owned())
             }
+        };
+
+        let book_parts = book_parts
+            .into_iter()
+            .enumerate()
+            .map(|(i, part)| {
+                Ok(BookPart {
+                    skeleton_head: renumber(to_string(i, part.skeleton_head)?),
+                    content: renumber(to_string(i, part.content)?),
+                    skeleton_tail: renumber(to_string(i, part.skeleton_tail)?),
+                })
+            })
+            .collect::<Result<Vec<_>, DekuError>>()?;
+
+        let flow_texts = flows
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(flow, bytes)| match text_encoding.decode(bytes) {
+                Ok(s) => Ok(renumber(s)),
+                Err(_) => {
+                    diagnostics.recover(ParseWarning::InvalidFlowUtf8 { flow })?;
+                    Ok(renumber(String::from_utf8_lossy(bytes).into_owned()))
+                }
+            })
+            .collect::<Result<Vec<_>, DekuError>>()?;
+        let flow_kinds = classify_flows(
+            book_parts
+                .iter()
+                .flat_map(|part| [&*part.skeleton_head, &*part.content, &*part.skeleton_tail])
+                .chain(flow_texts.iter().map(String::as_str)),
+            &flow_texts.iter().map(String::as_str).collect::<Vec<_>>(),
+        );
+        let resources = flow_kinds
+            .into_iter()
+            .zip(flow_texts)
+            .map(|(kind, text)| Flow { kind, text })
+            .collect();
+        for record in unresolved.into_inner() {
+            diagnostics.warn(ParseWarning::UnresolvedEmbed { record });
         }
 
-        Ok(Book {
-            title: mobi_header.title.try_into().unwrap(),
+        let title = text_encoding
+            .decode(&mobi_header.title)
+            .map_err(|_| DekuError::Parse("Title is not valid UTF-8".into()))?;
+        let resc = read_resc(&palmdoc, &mobi_header, &mut diagnostics)?;
+        let metadata = match &mobi_header.exth {
+            Some(exth) => Metadata {
+                series: resc.series,
+                ..Metadata::from_exth(title, exth, &mut diagnostics)
+            },
+            None => Metadata {
+                title,
+                series: resc.series,
+                ..Default::default()
+            },
+        };
+        let fixed_layout = mobi_header
+            .exth
+            .as_ref()
+            .and_then(|exth| FixedLayout::from_exth(exth, &mut diagnostics));
+
+        let source_archive = read_source_archive(&palmdoc, &mobi_header, &mut diagnostics)?;
+        let datp = read_datp(&palmdoc, &mobi_header, &mut diagnostics)?;
+
+        let book = Book {
+            metadata,
+            exth: mobi_header.exth.unwrap_or_default(),
             uid: mobi_header.uid,
             main_language: mobi_header.language_code.main,
             sub_language: mobi_header.language_code.sub,
-            book_parts: vec![], // todo
-            resources: vec![],  // todo
+            book_parts,
+            resources,
+            embedded_resources,
+            cover,
+            toc,
+            landmarks,
+            page_list,
+            spine: resc.spine,
+            fixed_layout,
+            source_archive,
+            datp,
             compression: mobi_header.compression_type,
-        })
+            text_encoding,
+        };
+
+        Ok((book, diagnostics.into_warnings()))
+    }
+
+    /// The panels of a fixed-layout book that can be zoomed into.
+    pub fn magnification_regions(&self) -> Vec<MagnificationRegion> {
+        magnification_regions(&self.book_parts)
+    }
+}
+
+pub(crate) fn read_text(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    diagnostics: &mut Diagnostics,
+) -> Result<Vec<u8>, DekuError> {
+    let num_of_text_records = mobi_header.num_of_text_records as usize;
+
+    if mobi_header.compression_type == CompressionType::HuffCdic {
+        return Err(DekuError::Parse(
+            "HUFF/CDIC compression is not supported".into(),
+        ));
+    }
+
+    let text_records = palmdoc
+        .records
+        .iter()
+        .enumerate()
+        .skip(1)
+        .take(num_of_text_records)
+        .collect::<Vec<_>>();
+
+    let decoded = parallel::map(&text_records, |&(i, record)| {
+        let trailing_entries_len = mobi_header
+            .sizeof_trailing_section_entries(record)
+            .map_err(|_| ParseWarning::InvalidTrailingEntries { record: i })?;
+        let record_data = &record[0..record.len() - trailing_entries_len];
+
+        match mobi_header.compression_type {
+            CompressionType::PalmDoc => palmdoc::decompress(record_data)
+                .map(Cow::Owned)
+                .map_err(|_| ParseWarning::DecompressionFailed { record: i }),
+            _ => Ok(Cow::Borrowed(record_data)),
+        }
+    });
+
+    let mut text = Vec::new();
+    for record in decoded {
+        match record {
+            Ok(record) => text.extend_from_slice(&record),
+            Err(warning) => diagnostics.recover(warning)?,
+        }
+    }
+
+    if text_records.len() < num_of_text_records {
+        diagnostics.recover(ParseWarning::MissingTextRecords {
+            expected: num_of_text_records,
+            found: text_records.len(),
+        })?;
+    }
+
+    if text.len() != mobi_header.text_length as usize {
+        diagnostics.warn(ParseWarning::TextLengthMismatch {
+            expected: mobi_header.text_length as usize,
+            actual: text.len(),
+        });
     }
+
+    Ok(text)
 }
 
-// todo: cleaner?
-fn create_text_record(text: &mut Cursor<&[u8]>) -> (Vec<u8>, Vec<u8>) {
-    let opos = text.position();
-    let npos = std::cmp::min(
-        (opos + TEXT_RECORD_SIZE as u64) as u64,
-        text.get_ref().len() as u64,
-    );
-    let mut extra = 0;
-
-    let mut last = Vec::new();
-    while std::str::from_utf8(&last).is_err() {
-        let size = last.len() + 1;
-        text.seek(SeekFrom::Start(npos - size as u64)).unwrap();
-        last.clear();
-        last.resize(size, 0);
-        text.read_exact(&mut last).unwrap();
-    }
-
-    if std::str::from_utf8(&last).is_err() {
-        let mut prev = last.len();
-        loop {
-            text.seek(SeekFrom::Start(npos - prev as u64)).unwrap();
-            last.resize(last.len() + 1, 0);
-            text.read_exact(&mut last[prev..]).unwrap();
-            if std::str::from_utf8(&last).is_ok() {
-                break;
+/// Reads images and fonts, skipping the other records that can appear among resources. Also returns the index of the cover, and the record of each resource relative to `first_resource_record`.
+fn read_embedded_resources(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    diagnostics: &mut Diagnostics,
+) -> Result<Vec<(usize, EmbeddedResource)>, DekuError> {
+    let first_resource_record = mobi_header.first_resource_record as usize;
+
+    let mut resources = vec![];
+    for (i, data) in palmdoc
+        .records
+        .iter()
+        .enumerate()
+        .skip(first_resource_record)
+    {
+        let resource = if data.starts_with(b"FONT") {
+            match read_font_record(data) {
+                Ok(data) => EmbeddedResource {
+                    kind: EmbeddedResourceKind::Font,
+                    data,
+                },
+                Err(reason) => {
+                    diagnostics.recover(ParseWarning::InvalidFont { record: i, reason })?;
+                    continue;
+                }
+            }
+        } else if infer::is_image(data) {
+            EmbeddedResource {
+                kind: EmbeddedResourceKind::Image,
+                data: data.clone(),
             }
-            prev += 1;
+        } else {
+            continue;
+        };
+
+        resources.push((i - first_resource_record, resource));
+    }
+
+    Ok(resources)
+}
+
+fn read_source_archive(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    diagnostics: &mut Diagnostics,
+) -> Result<Option<Vec<u8>>, DekuError> {
+    if mobi_header.srcs_record == u32::MAX || mobi_header.srcs_count == 0 {
+        return Ok(None);
+    }
+
+    let record = mobi_header.srcs_record as usize;
+    let result = palmdoc
+        .records
+        .get(record)
+        .ok_or_else(|| "record does not exist".to_string())
+        .and_then(|data| read_srcs_record(data));
+    match result {
+        Ok(archive) => Ok(Some(archive.to_vec())),
+        Err(reason) => {
+            diagnostics.recover(ParseWarning::InvalidSrcs { record, reason })?;
+            Ok(None)
         }
-        extra = last.len() - prev;
     }
+}
+
+fn read_datp(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    diagnostics: &mut Diagnostics,
+) -> Result<Option<DatpRecord>, DekuError> {
+    if mobi_header.datp_index == u32::MAX {
+        return Ok(None);
+    }
+
+    let record = mobi_header.datp_index as usize;
+    let result = palmdoc
+        .records
+        .get(record)
+        .ok_or_else(|| DekuError::Parse("record does not exist".into()))
+        .and_then(|data| DatpRecord::parse(data));
+    match result {
+        Ok(datp) => Ok(Some(datp)),
+        Err(e) => {
+            diagnostics.recover(ParseWarning::InvalidDatp {
+                record,
+                reason: e.to_string(),
+            })?;
+            Ok(None)
+        }
+    }
+}
+
+fn read_resc(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    diagnostics: &mut Diagnostics,
+) -> Result<ResCRecord, DekuError> {
+    let resc = palmdoc
+        .records
+        .iter()
+        .enumerate()
+        .skip(mobi_header.first_resource_record as usize)
+        .find(|(_, data)| data.starts_with(b"RESC"));
+
+    match resc {
+        Some((record, data)) => match ResCRecord::parse(data) {
+            Ok(resc) => Ok(resc),
+            Err(e) => {
+                diagnostics.recover(ParseWarning::InvalidResc {
+                    record,
+                    reason: e.to_string(),
+                })?;
+                Ok(ResCRecord::default())
+            }
+        },
+        None => Ok(ResCRecord::default()),
+    }
+}
+
+/// Page offsets are mapped to parts as if each part's fragments followed its skeleton, which holds unless the fragments are inserted out of order.
+fn read_page_list(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    parts: &[RawBookPart],
+    diagnostics: &mut Diagnostics,
+) -> Result<Vec<PageTarget>, DekuError> {
+    let Some((record, data)) = palmdoc
+        .records
+        .iter()
+        .enumerate()
+        .skip(mobi_header.first_resource_record as usize)
+        .find(|(_, data)| data.starts_with(b"PAGE"))
+    else {
+        return Ok(vec![]);
+    };
+
+    let page_map = match PageMapRecord::parse(data) {
+        Ok(page_map) => page_map,
+        Err(e) => {
+            diagnostics.recover(ParseWarning::InvalidPageMap {
+                record,
+                reason: e.to_string(),
+            })?;
+            return Ok(vec![]);
+        }
+    };
+
+    let layout = raw_layout(parts);
+    let mut page_list = vec![];
+    for (label, offset) in page_map.labels().into_iter().zip(page_map.offsets) {
+        match offset_to_location(&layout, offset) {
+            Some(location) => page_list.push(PageTarget { label, location }),
+            None => diagnostics.recover(ParseWarning::InvalidPageMap {
+                record,
+                reason: format!(
+                    "page {:?} is at {}, past the end of the text",
+                    label, offset
+                ),
+            })?,
+        }
+    }
+
+    Ok(page_list)
+}
+
+fn raw_layout(parts: &[RawBookPart]) -> Vec<PartLayout<'_>> {
+    PartLayout::new(parts.iter().map(|part| {
+        (
+            part.skeleton_head.len() + part.skeleton_tail.len(),
+            part.content.as_slice(),
+        )
+    }))
+}
+
+/// Where a `kindle:pos` reference points to, given where each chunk starts in the text.
+fn pos_to_location(
+    layout: &[PartLayout],
+    chunk_starts: &[usize],
+    fid: u32,
+    offset: u32,
+) -> Result<Location, String> {
+    chunk_starts
+        .get(fid as usize)
+        .and_then(|start| u32::try_from(start + offset as usize).ok())
+        .and_then(|position| offset_to_location(layout, position))
+        .ok_or_else(|| format!("chunk {} offset {} is outside of the text", fid, offset))
+}
+
+fn read_toc(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    parts: &[RawBookPart],
+    chunk_starts: &[usize],
+    diagnostics: &mut Diagnostics,
+) -> Result<Vec<TocEntry>, DekuError> {
+    if mobi_header.ncx_index == u32::MAX {
+        return Ok(vec![]);
+    }
+
+    let read = || {
+        let index = read_index(palmdoc, "NCX", mobi_header.ncx_index)?;
+        let invalid = |reason: String| ParseWarning::InvalidIndex {
+            name: "NCX",
+            reason,
+        };
+        let entries = index
+            .parse_as::<NcxTagMapEntry>()
+            .map_err(|e| invalid(e.to_string()))?;
+        let layout = raw_layout(parts);
+
+        let mut toc = entries
+            .iter()
+            .map(|entry| {
+                Ok(Some(TocEntry {
+                    title: index
+                        .cncx_string(entry.label)
+                        .map_err(|e| invalid(e.to_string()))?,
+                    location: pos_to_location(&layout, chunk_starts, entry.fid, entry.fid_offset)
+                        .map_err(invalid)?,
+                    children: vec![],
+                }))
+            })
+            .collect::<Result<Vec<_>, ParseWarning>>()?;
+
+        // Children come after their parent, so the deepest entries are completed first
+        for (i, entry) in entries.iter().enumerate().rev() {
+            let (Some(first), Some(last)) = (entry.first_child, entry.last_child) else {
+                continue;
+            };
+            if first as usize <= i {
+                return Err(invalid(format!(
+                    "entry {} is a child of entry {}",
+                    first, i
+                )));
+            }
+            let children = (first..=last)
+                .map(|child| {
+                    toc.get_mut(child as usize)
+                        .and_then(Option::take)
+                        .ok_or_else(|| invalid(format!("entry {} has no entry {}", i, child)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            toc[i].as_mut().unwrap().children = children;
+        }
+
+        Ok(toc.into_iter().flatten().collect())
+    };
+
+    match read() {
+        Ok(toc) => Ok(toc),
+        Err(warning) => {
+            diagnostics.recover(warning)?;
+            Ok(vec![])
+        }
+    }
+}
+
+fn read_landmarks(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    parts: &[RawBookPart],
+    chunk_starts: &[usize],
+    diagnostics: &mut Diagnostics,
+) -> Result<Vec<Landmark>, DekuError> {
+    if mobi_header.guide_index == u32::MAX {
+        return Ok(vec![]);
+    }
+
+    let read = || {
+        let index = read_index(palmdoc, "Guide", mobi_header.guide_index)?;
+        let invalid = |reason: String| ParseWarning::InvalidIndex {
+            name: "Guide",
+            reason,
+        };
+        let layout = raw_layout(parts);
+
+        index
+            .parse_as::<GuideTagMapEntry>()
+            .map_err(|e| invalid(e.to_string()))?
+            .into_iter()
+            .map(|entry| {
+                let kind = GUIDE_TYPES
+                    .iter()
+                    .find(|(_, guide_type)| *guide_type == entry.kind)
+                    .map_or(entry.kind.clone(), |(landmark_type, _)| {
+                        landmark_type.to_string()
+                    });
+                Ok(Landmark {
+                    kind,
+                    title: index
+                        .cncx_string(entry.title)
+                        .map_err(|e| invalid(e.to_string()))?,
+                    location: pos_to_location(&layout, chunk_starts, entry.fid, entry.fid_offset)
+                        .map_err(invalid)?,
+                })
+            })
+            .collect::<Result<Vec<_>, ParseWarning>>()
+    };
+
+    match read() {
+        Ok(landmarks) => Ok(landmarks),
+        Err(warning) => {
+            diagnostics.recover(warning)?;
+            Ok(vec![])
+        }
+    }
+}
+
+pub(crate) fn read_flows<'a>(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    text: &'a [u8],
+) -> Result<Vec<&'a [u8]>, String> {
+    let record = palmdoc
+        .records
+        .get(mobi_header.fdst_record as usize)
+        .ok_or_else(|| format!("record {} does not exist", mobi_header.fdst_record))?;
+    let (_, fdst_table) = FDSTTable::from_bytes((record, 0)).map_err(|e| e.to_string())?;
+
+    let flows = fdst_table
+        .entries
+        .iter()
+        .map(|entry| {
+            text.get(entry.start as usize..entry.end as usize)
+                .ok_or_else(|| format!("flow {}..{} is out of bounds", entry.start, entry.end))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if flows.is_empty() {
+        return Err("no flows".to_string());
+    }
+
+    Ok(flows)
+}
+
+/// Like `BookPart`, but before the text has been decoded.
+struct RawBookPart {
+    skeleton_head: Vec<u8>,
+    content: Vec<u8>,
+    skeleton_tail: Vec<u8>,
+}
+
+/// Like `BookPart`, after the text has been encoded for writing.
+struct EncodedBookPart<'a> {
+    skeleton_head: Cow<'a, [u8]>,
+    content: Cow<'a, [u8]>,
+    skeleton_tail: Cow<'a, [u8]>,
+}
+
+fn read_index(
+    palmdoc: &PalmDoc,
+    name: &'static str,
+    record: u32,
+) -> Result<TotalIndexEntry, ParseWarning> {
+    let invalid = |reason: String| ParseWarning::InvalidIndex { name, reason };
+    let records = palmdoc
+        .records
+        .get(record as usize..)
+        .ok_or_else(|| invalid(format!("record {} does not exist", record)))?;
+    TotalIndexEntry::from_records(records).map_err(|e| invalid(e.to_string()))
+}
+
+/// Reassembles each skeleton with its fragments, and returns where each chunk starts in the text.
+/// The content spans from the first to the last inserted byte, so it may include skeleton text if fragments aren't inserted contiguously.
+fn read_parts(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    text: &[u8],
+) -> Result<(Vec<RawBookPart>, Vec<usize>), ParseWarning> {
+    let skeleton_index = read_index(palmdoc, "Skeleton", mobi_header.skel_index)?;
+    let skeletons = skeleton_index
+        .parse_as::<SkeletonTagMapEntry>()
+        .map_err(|e| ParseWarning::InvalidIndex {
+            name: "Skeleton",
+            reason: e.to_string(),
+        })?;
+    let chunk_index = read_index(palmdoc, "Chunk", mobi_header.chunk_index)?;
+    let chunks =
+        chunk_index
+            .parse_as::<ChunkTagMapEntry>()
+            .map_err(|e| ParseWarning::InvalidIndex {
+                name: "Chunk",
+                reason: e.to_string(),
+            })?;
+
+    let mut chunks = chunks.iter();
+    let mut parts = Vec::new();
+    let mut chunk_starts = Vec::new();
+    for skeleton in skeletons {
+        let invalid = |reason: String| ParseWarning::InvalidIndex {
+            name: "Skeleton",
+            reason: format!("{}: {}", skeleton.name, reason),
+        };
+
+        let skeleton_start = skeleton.start_offset as usize;
+        let mut fragment_start = skeleton_start + skeleton.length as usize;
+        let mut assembled = text
+            .get(skeleton_start..fragment_start)
+            .ok_or_else(|| invalid("skeleton is out of bounds".to_string()))?
+            .to_vec();
+
+        let mut inserted: Option<(usize, usize)> = None;
+        for _ in 0..skeleton.chunk_count {
+            let chunk = chunks
+                .next()
+                .ok_or_else(|| invalid("not enough chunks".to_string()))?;
+
+            chunk_starts.push(fragment_start);
+            let fragment_end = fragment_start + chunk.length as usize;
+            let fragment = text
+                .get(fragment_start..fragment_end)
+                .ok_or_else(|| invalid("chunk is out of bounds".to_string()))?;
+            let insert_at = (chunk.insert_position as usize)
+                .checked_sub(skeleton_start)
+                .filter(|insert_at| *insert_at <= assembled.len())
+                .ok_or_else(|| invalid("chunk is inserted outside of the skeleton".to_string()))?;
+
+            assembled.splice(insert_at..insert_at, fragment.iter().copied());
+            inserted = Some(match inserted {
+                Some((start, end)) if insert_at <= end => {
+                    (start.min(insert_at), end + fragment.len())
+                }
+                Some((start, _)) => (start, insert_at + fragment.len()),
+                None => (insert_at, insert_at + fragment.len()),
+            });
+
+            fragment_start = fragment_end;
+        }
+
+        let (start, end) = inserted.unwrap_or((assembled.len(), assembled.len()));
+        let skeleton_tail = assembled.split_off(end);
+        let content = assembled.split_off(start);
+        parts.push(RawBookPart {
+            skeleton_head: assembled,
+            content,
+            skeleton_tail,
+        });
+    }
+
+    Ok((parts, chunk_starts))
+}
+
+/// EPUB 3 landmark types and the OPF 2 guide types that KF8 uses instead, where they differ.
+const GUIDE_TYPES: [(&str, &str); 2] = [("bodymatter", "text"), ("titlepage", "title-page")];
+
+/// Each string once, in order of first use.
+fn cncx_records<'a>(strings: impl IntoIterator<Item = &'a String>) -> SerializedCNCXRecords {
+    let mut seen = BTreeSet::new();
+    CNCXRecords {
+        strings: strings
+            .into_iter()
+            .filter(|string| seen.insert(*string))
+            .cloned()
+            .collect(),
+    }
+    .to_records()
+}
+
+fn missing_location(kind: &str, title: &str, location: &Location) -> DekuError {
+    DekuError::Parse(
+        format!(
+            "{} {:?} points to {:?}, which doesn't exist",
+            kind, title, location
+        )
+        .into(),
+    )
+}
+
+/// Writes the entries level by level, so the children of each entry are consecutive.
+fn write_ncx_index(
+    toc: &[TocEntry],
+    layout: &[PartLayout],
+    text_flow_len: usize,
+) -> Result<Vec<Vec<u8>>, DekuError> {
+    // Each entry with its depth and parent
+    let mut entries = toc.iter().map(|entry| (entry, 0, None)).collect::<Vec<_>>();
+    let mut children = vec![];
+    let mut i = 0;
+    while i < entries.len() {
+        let (entry, depth, _) = entries[i];
+        let first_child = entries.len() as u32;
+        entries.extend(
+            entry
+                .children
+                .iter()
+                .map(|child| (child, depth + 1, Some(i as u32))),
+        );
+        children
+            .push((!entry.children.is_empty()).then(|| (first_child, entries.len() as u32 - 1)));
+        i += 1;
+    }
+
+    let positions = entries
+        .iter()
+        .map(|(entry, _, _)| {
+            location_to_offset(layout, &entry.location)
+                .zip(location_to_pos(layout, &entry.location))
+                .ok_or_else(|| missing_location("Entry", &entry.title, &entry.location))
+        })
+        .collect::<Result<Vec<_>, DekuError>>()?;
+    // An entry spans to the next one in the text, or to the end of the text
+    let mut offsets = positions
+        .iter()
+        .map(|(offset, _)| *offset)
+        .collect::<Vec<_>>();
+    offsets.sort_unstable();
+    offsets.dedup();
+
+    let cncx = cncx_records(entries.iter().map(|(entry, _, _)| &entry.title));
+    let ncx_entries = entries
+        .iter()
+        .zip(positions)
+        .zip(children)
+        .enumerate()
+        .map(|(i, (((entry, depth, parent), (offset, pos)), children))| {
+            let end = offsets
+                .get(offsets.partition_point(|other| *other <= offset))
+                .copied()
+                .unwrap_or(text_flow_len as u32);
+            NcxTagMapEntry {
+                name: format!("{:04X}", i),
+                offset,
+                length: end - offset,
+                label: cncx.offsets[&entry.title] as u32,
+                depth: *depth,
+                parent: *parent,
+                first_child: children.map(|(first, _)| first),
+                last_child: children.map(|(_, last)| last),
+                fid: pos.fid as u32,
+                fid_offset: pos.offset as u32,
+            }
+            .into()
+        })
+        .collect::<Vec<TagMapEntry>>();
+
+    Ok(
+        TotalIndexEntry::new(NcxTagMapEntry::get_tag_definitions(), ncx_entries)
+            .with_cncx_records(cncx.records)
+            .into_records(),
+    )
+}
+
+fn write_guide_index(
+    landmarks: &[Landmark],
+    layout: &[PartLayout],
+) -> Result<Vec<Vec<u8>>, DekuError> {
+    let cncx = cncx_records(landmarks.iter().map(|landmark| &landmark.title));
+    let guide_entries = landmarks
+        .iter()
+        .map(|landmark| {
+            let pos = location_to_pos(layout, &landmark.location)
+                .ok_or_else(|| missing_location("Landmark", &landmark.title, &landmark.location))?;
+            let kind = GUIDE_TYPES
+                .iter()
+                .find(|(landmark_type, _)| *landmark_type == landmark.kind)
+                .map_or(landmark.kind.as_str(), |(_, guide_type)| guide_type);
+            Ok(GuideTagMapEntry {
+                kind: kind.to_string(),
+                title: cncx.offsets[&landmark.title] as u32,
+                fid: pos.fid as u32,
+                fid_offset: pos.offset as u32,
+            }
+            .into())
+        })
+        .collect::<Result<Vec<TagMapEntry>, DekuError>>()?;
+
+    Ok(
+        TotalIndexEntry::new(GuideTagMapEntry::get_tag_definitions(), guide_entries)
+            .with_cncx_records(cncx.records)
+            .into_records(),
+    )
+}
+
+/// Splits the text into records of `TEXT_RECORD_SIZE` bytes.
+/// Each record comes with the bytes after it that complete a multibyte character cut off at its end, which become its multibyte overlap.
+fn create_text_records(text: &[u8], text_encoding: Codepage) -> Vec<(&[u8], &[u8])> {
+    let mut records = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let end = (start + TEXT_RECORD_SIZE).min(text.len());
+        let mut overlap: &[u8] = &[];
+
+        if text_encoding == Codepage::Utf8 {
+            // The last character that starts in this record
+            let char_start = (start..end)
+                .rev()
+                .take(4)
+                .find(|&i| text[i] & 0b1100_0000 != 0b1000_0000);
+            if let Some(char_start) = char_start {
+                let char_len = match text[char_start] {
+                    0b1100_0000..=0b1101_1111 => 2,
+                    0b1110_0000..=0b1110_1111 => 3,
+                    0b1111_0000..=0b1111_0111 => 4,
+                    _ => 1,
+                };
+                let char_end = (char_start + char_len).clamp(end, text.len());
+                overlap = &text[end..char_end];
+            }
+        }
 
-    text.seek(SeekFrom::Start(opos)).unwrap();
-    let this_record_size = (npos - opos) as usize;
-    let mut data = vec![0; this_record_size];
-    text.read_exact(&mut data).unwrap();
-    let mut overlap = vec![0; extra];
-    text.read_exact(&mut overlap).unwrap();
-    text.seek(SeekFrom::Start(npos)).unwrap();
+        records.push((&text[start..end], overlap));
+        start = end;
+    }
 
-    (data, overlap)
+    records
 }
 
 const FLIS: &[u8; 36] = b"FLIS\0\0\0\x08\0\x41\0\0\0\0\0\0\xff\xff\xff\xff\0\x01\0\x03\0\0\0\x03\0\0\0\x01\xff\xff\xff\xff";
@@ -162,16 +1038,78 @@ fn create_fcis_record(text_length: usize) -> Vec<u8> {
     fcis
 }
 
-impl TryFrom<&Book> for PalmDoc {
-    type Error = DekuError;
+/// FNV-1a, which (unlike `DefaultHasher`) is guaranteed to give the same result across Rust versions.
+fn stable_hash(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
 
-    fn try_from(book: &Book) -> Result<Self, Self::Error> {
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("couldn't get time since epoch");
-        // todo: allow customizing? builder pattern?
-        let created_at_seconds = since_the_epoch.as_secs();
+/// Converts a `Book` into a `PalmDoc`.
+///
+/// By default, the output is stamped with the current time and uses `Book::uid`. For byte-identical output from identical input, use `BookWriter::reproducible()` or set the timestamps explicitly.
+///
+/// Text records are written without trailing byte sequences (TBS).
+#[derive(Debug, Clone, Default)]
+pub struct BookWriter {
+    created_at: Option<u32>,
+    modified_at: Option<u32>,
+    uid_from_content: bool,
+    compression_level: CompressionLevel,
+}
+
+impl BookWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Timestamps are set to the epoch and the uid is derived from the content.
+    pub fn reproducible() -> Self {
+        Self::new()
+            .created_at(0)
+            .modified_at(0)
+            .uid_from_content(true)
+    }
+
+    /// Seconds since the Unix epoch.
+    pub fn created_at(mut self, seconds: u32) -> Self {
+        self.created_at = Some(seconds);
+        self
+    }
+
+    /// Seconds since the Unix epoch. Defaults to `created_at`.
+    pub fn modified_at(mut self, seconds: u32) -> Self {
+        self.modified_at = Some(seconds);
+        self
+    }
+
+    /// Ignore `Book::uid` and derive the uid from the title and text instead, so that the same content always gets the same uid (and generated ASIN).
+    pub fn uid_from_content(mut self, uid_from_content: bool) -> Self {
+        self.uid_from_content = uid_from_content;
+        self
+    }
+
+    /// Used when `Book::compression` is PalmDoc. Defaults to `CompressionLevel::default()`.
+    pub fn compression_level(mut self, compression_level: CompressionLevel) -> Self {
+        self.compression_level = compression_level;
+        self
+    }
+
+    pub fn write(&self, book: &Book) -> Result<PalmDoc, DekuError> {
+        if book.compression == CompressionType::HuffCdic {
+            return Err(DekuError::Parse(
+                "HUFF/CDIC compression is not supported".into(),
+            ));
+        }
+
+        let created_at = self.created_at.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("couldn't get time since epoch")
+                .as_secs() as u32
+        });
+        let modified_at = self.modified_at.unwrap_or(created_at);
+        let compression_level = self.compression_level;
 
         let mut records = vec![];
 
@@ -179,18 +1117,34 @@ impl TryFrom<&Book> for PalmDoc {
         records.push(vec![]);
 
         // Text records
-        let text_parts_iter = book
+        let encode = |text| book.text_encoding.encode(text);
+        let book_parts = book
             .book_parts
             .iter()
             .map(|part| {
-                format!(
-                    "{}{}{}",
-                    part.skeleton_head, part.skeleton_tail, part.content
-                )
+                Ok(EncodedBookPart {
+                    skeleton_head: encode(&part.skeleton_head)?,
+                    content: encode(&part.content)?,
+                    skeleton_tail: encode(&part.skeleton_tail)?,
+                })
             })
-            .chain(book.resources.iter().map(|r| r.to_string()));
+            .collect::<Result<Vec<_>, DekuError>>()?;
+        let resources = book
+            .resources
+            .iter()
+            .map(|resource| encode(&resource.text))
+            .collect::<Result<Vec<_>, DekuError>>()?;
 
-        let mut text = "".to_string();
+        // Flow 0 holds every part, and the resources follow as flows 1..
+        let text_flow = book_parts
+            .iter()
+            .flat_map(|part| [&*part.skeleton_head, &*part.skeleton_tail, &*part.content])
+            .collect::<Vec<_>>()
+            .concat();
+        let text_flow_len = text_flow.len();
+        let text_parts_iter = once(text_flow).chain(resources.iter().map(|r| r.to_vec()));
+
+        let mut text = Vec::new();
         let mut fdst_entries: Vec<FDSTEntry> = vec![];
 
         let mut pos = 0;
@@ -201,34 +1155,46 @@ impl TryFrom<&Book> for PalmDoc {
                 end: pos + part_len,
             });
             pos += part_len;
-            text.push_str(&part);
+            text.extend_from_slice(&part);
         }
 
-        let mut text_cursor = Cursor::new(text.as_bytes());
-        while text_cursor.position() < text_cursor.get_ref().len() as u64 {
-            let (record, mut overlap) = create_text_record(&mut text_cursor);
+        let uid = if self.uid_from_content {
+            let title = encode(&book.metadata.title)?;
+            let mut content = [&*title, &[0], &text].concat();
+            for resource in &book.embedded_resources {
+                content.extend_from_slice(&resource.data);
+            }
+            stable_hash(&content)
+        } else {
+            book.uid
+        };
 
-            match book.compression {
-                CompressionType::PalmDoc => {
-                    let mut compressed_record = palmdoc_compression::compress(&record);
-                    compressed_record.append(&mut overlap);
-                    compressed_record.push(overlap.len() as u8);
-                    records.push(compressed_record);
-                }
-                CompressionType::HuffCdic => {
-                    todo!()
-                }
-                CompressionType::None => {
-                    let mut record = record;
-                    record.append(&mut overlap);
-                    record.push(overlap.len() as u8);
-                    records.push(record);
-                }
+        let text_records = create_text_records(&text, book.text_encoding);
+
+        // TBS is out of scope: its layout follows how calibre and kindlegen split the NCX across records, which this writer doesn't reproduce
+        let extra_data_flags = ExtraDataFlags {
+            extra_multibyte_bytes_after_text_records: true,
+            has_tbs: false,
+            uncrossable_breaks: false,
+        };
+
+        let compressed = parallel::map(&text_records, |(record, overlap)| {
+            let mut record = match book.compression {
+                CompressionType::PalmDoc => palmdoc::compress(record, compression_level),
+                _ => record.to_vec(),
+            };
+            TrailingEntries {
+                multibyte_overlap: overlap.to_vec(),
+                ..Default::default()
             }
+            .write(&extra_data_flags, &mut record)?;
+            Ok::<_, DekuError>(record)
+        });
+        for record in compressed {
+            records.push(record?);
         }
 
-        let last_text_record = records.len();
-        let mut first_non_text_record = last_text_record + 1;
+        let mut first_non_text_record = records.len();
 
         // Pad to 4 bytes
         let records_data_len = records.iter().map(|r| r.len()).sum::<usize>();
@@ -241,16 +1207,27 @@ impl TryFrom<&Book> for PalmDoc {
         // Metadata records
         let chunk_index_num = records.len();
 
+        // Each part is written as its skeleton followed by a single chunk
+        let skeleton_starts = book_parts
+            .iter()
+            .scan(0, |start, part| {
+                let skeleton_start = *start;
+                *start += part.skeleton_head.len() + part.skeleton_tail.len() + part.content.len();
+                Some(skeleton_start as u32)
+            })
+            .collect::<Vec<_>>();
+
         // Chunk index
-        let chunk_index_entries = book
-            .book_parts
+        let chunk_index_entries = book_parts
             .iter()
-            .map(|part| {
+            .zip(&skeleton_starts)
+            .enumerate()
+            .map(|(i, (part, skeleton_start))| {
                 ChunkTagMapEntry {
-                    insert_position: part.skeleton_head.len() as u32 - 1, // todo?
+                    insert_position: skeleton_start + part.skeleton_head.len() as u32,
                     cncx_offset: 0,
-                    file_number: 0,
-                    sequence_number: 0,
+                    file_number: i as u32,
+                    sequence_number: i as u32,
                     start_offset: 0,
                     length: part.content.len() as u32,
                 }
@@ -265,15 +1242,15 @@ impl TryFrom<&Book> for PalmDoc {
         let skeleton_index_num = records.len();
 
         // Skeleton index
-        let skeleton_index_entries = book
-            .book_parts
+        let skeleton_index_entries = book_parts
             .iter()
+            .zip(&skeleton_starts)
             .enumerate()
-            .map(|(i, part)| {
+            .map(|(i, (part, skeleton_start))| {
                 SkeletonTagMapEntry {
-                    name: "SKEL0000000000".to_string(),
+                    name: format!("SKEL{:010}", i),
                     chunk_count: 1,
-                    start_offset: 0,
+                    start_offset: *skeleton_start,
                     length: part.skeleton_head.len() as u32 + part.skeleton_tail.len() as u32,
                 }
                 .into()
@@ -286,14 +1263,93 @@ impl TryFrom<&Book> for PalmDoc {
         );
         records.extend(skeleton_index.into_records());
 
-        let guide_index = u32::MAX; // todo
-        let ncx_index = u32::MAX; // todo
+        let layout = PartLayout::new(book_parts.iter().map(|part| {
+            (
+                part.skeleton_head.len() + part.skeleton_tail.len(),
+                &*part.content,
+            )
+        }));
 
-        // Resource records
-        // todo
+        let ncx_index = if book.toc.is_empty() {
+            u32::MAX
+        } else {
+            let index = records.len() as u32;
+            records.extend(write_ncx_index(&book.toc, &layout, text_flow_len)?);
+            index
+        };
+
+        let guide_index = if book.landmarks.is_empty() {
+            u32::MAX
+        } else {
+            let index = records.len() as u32;
+            records.extend(write_guide_index(&book.landmarks, &layout)?);
+            index
+        };
+
+        // Resource records. PAGE and RESC go last so that they don't shift `kindle:embed` indexes.
+        let first_resource_record = if book.embedded_resources.is_empty()
+            && book.page_list.is_empty()
+            && book.spine.is_empty()
+            && book.metadata.series.is_none()
+        {
+            u32::MAX
+        } else {
+            records.len() as u32
+        };
+        for resource in &book.embedded_resources {
+            records.push(match resource.kind {
+                EmbeddedResourceKind::Image => resource.data.clone(),
+                EmbeddedResourceKind::Font => write_font_record(&resource.data),
+            });
+        }
+        if !book.page_list.is_empty() {
+            let pages = book
+                .page_list
+                .iter()
+                .map(|page| {
+                    let offset = location_to_offset(&layout, &page.location).ok_or_else(|| {
+                        DekuError::Parse(
+                            format!(
+                                "Page {:?} points to {:?}, which doesn't exist",
+                                page.label, page.location
+                            )
+                            .into(),
+                        )
+                    })?;
+                    Ok((page.label.clone(), offset))
+                })
+                .collect::<Result<Vec<_>, DekuError>>()?;
+            records.push(PageMapRecord::from_pages(pages).to_record()?);
+        }
+        if !book.spine.is_empty() || book.metadata.series.is_some() {
+            records.push(
+                ResCRecord {
+                    series: book.metadata.series.clone(),
+                    spine: book.spine.clone(),
+                }
+                .to_record(),
+            );
+        }
+
+        let srcs_record = match &book.source_archive {
+            Some(archive) => {
+                records.push(write_srcs_record(archive));
+                records.len() as u32 - 1
+            }
+            None => u32::MAX,
+        };
+
+        let datp_index = match &book.datp {
+            Some(datp) => {
+                records.push(datp.to_record());
+                records.len() as u32 - 1
+            }
+            None => u32::MAX,
+        };
 
         // FDST
         let fdst_record = records.len();
+        let fdst_count = fdst_entries.len();
         records.push(
             FDSTTable {
                 entries: fdst_entries,
@@ -313,104 +1369,91 @@ impl TryFrom<&Book> for PalmDoc {
         // EOF
         records.push(b"\xe9\x8e\r\n".to_vec());
 
-        let mut exth = Exth::default();
-        exth.metadata_id.insert(
-            MetadataId::Source,
-            vec!["calibre:c64482f4-2952-4f2c-ae28-b109cb70f5bb".into()],
-        );
-        exth.metadata_id.insert(
-            MetadataId::Contributor,
-            vec!["calibre (7.16.0) [http://calibre-ebook.com]".into()],
-        );
-        exth.metadata_id
-            .insert(MetadataId::UpdatedTitle, vec![book.title.clone()]);
-        exth.metadata_id.insert(
-            MetadataId::ASIN,
-            vec!["c64482f4-2952-4f2c-ae28-b109cb70f5bb".into()],
-        );
-        exth.metadata_id
-            .insert(MetadataId::CdeType, vec!["EBOK".into()]);
-        // exth.metadata_id
-        //     .insert(MetadataId::CreatorBuildTag, vec!["0730-890adc2".into()]);
-        exth.metadata_id
-            .insert(MetadataId::ContentLanguageTag, vec!["en".into()]);
-        exth.metadata_id.insert(
-            MetadataId::Published,
-            vec!["2024-08-13T04:05:03.140745+00:00".into()],
+        let fallback_language = book
+            .sub_language
+            .as_ref()
+            .map(|l| l.to_bcp47())
+            .or(book.main_language.as_ref().map(|l| l.to_bcp47()));
+        let mut exth = book.exth.clone();
+        book.metadata
+            .write_exth(&mut exth, uid, fallback_language)
+            .and_then(|()| exth.set_cover_offset(book.cover.map(|cover| cover as u32)))
+            .map_err(|e| DekuError::Parse(e.to_string().into()))?;
+        match &book.fixed_layout {
+            Some(fixed_layout) => fixed_layout.write_exth(&mut exth),
+            None => exth.remove(MetadataId::FixedLayout.into()),
+        }
+        exth.set_values(
+            MetadataIdValue::EmbeddedRecordCount,
+            [book.embedded_resources.len() as u32],
         );
-        exth.metadata_id
-            .insert(MetadataId::OverrideKindleFonts, vec!["true".into()]);
-        exth.metadata_id
-            .insert(MetadataId::Creator, vec!["kindle".into()]);
-
-        // todo: these aren't serialized correctly?
-        // exth.metadata_value
-        //     .insert(MetadataIdValue::CreatorSoftware, vec![202]);
-        // exth.metadata_value
-        //     .insert(MetadataIdValue::CreatorMajorVersion, vec![2]);
-        // exth.metadata_value
-        //     .insert(MetadataIdValue::CreatorMinorVersion, vec![9]);
-        // exth.metadata_value
-        //     .insert(MetadataIdValue::CreatorBuildNumber, vec![0]);
-        // exth.metadata_value
-        //     .insert(MetadataIdValue::EmbeddedRecordCount, vec![0]);
 
         // todo: consistent terms between _record and _index
         let mobi_header = MobiHeader {
-            title: book.title.clone().into(),
-            compression_type: CompressionType::None, //CompressionType::PalmDoc,
+            title: NullString(encode(&book.metadata.title)?.into_owned()),
+            compression_type: book.compression.clone(),
             text_length: text.len() as u32,
-            num_of_text_records: 1, // todo
+            num_of_text_records: text_records.len() as u16,
             text_record_size: TEXT_RECORD_SIZE as u16,
             book_type: BookType::Book,
-            text_encoding: Codepage::Utf8,
-            uid: book.uid,
+            text_encoding: book.text_encoding,
+            uid,
             file_version: 8,
             first_non_text_record: first_non_text_record as u32,
             language_code: LanguageCode {
                 main: book.main_language.clone(),
                 sub: book.sub_language.clone(),
             },
-            first_resource_record: u32::MAX, // todo
+            first_resource_record,
             exth_flags: ExthFlags {
                 has_exth: true,
-                has_fonts: false,
+                has_fonts: book
+                    .embedded_resources
+                    .iter()
+                    .any(|resource| resource.kind == EmbeddedResourceKind::Font),
                 is_periodical: false,
             },
             fdst_record: fdst_record as u32,
-            fdst_count: fdst_record as u32,
+            fdst_count: fdst_count as u32,
             fcis_record: fcis_record as u32,
             fcis_count: 1,
             flis_record: flis_record as u32,
             flis_count: 1,
-            srcs_record: u32::MAX,
-            srcs_count: 0,
-            extra_data_flags: ExtraDataFlags {
-                extra_multibyte_bytes_after_text_records: true,
-                has_tbs: false,
-                uncrossable_breaks: false,
-            },
+            srcs_record,
+            srcs_count: book.source_archive.is_some() as u32,
+            extra_data_flags,
             ncx_index,
             chunk_index: chunk_index_num as u32,
             skel_index: skeleton_index_num as u32,
-            datp_index: u32::MAX,
+            datp_index,
             guide_index,
             exth: Some(exth),
         };
         let mut header_serialized = Cursor::new(vec![]);
         mobi_header.write(&mut header_serialized).unwrap();
-        records[0] = header_serialized.into_inner();
+        // Keep the text records 4-byte aligned, which the padding after them assumes
+        let mut header_record = header_serialized.into_inner();
+        header_record.resize(header_record.len().next_multiple_of(4), 0);
+        records[0] = header_record;
 
         Ok(PalmDoc {
-            title: book.title.clone(),
-            created_at: created_at_seconds as u32,
-            modified_at: created_at_seconds as u32,
+            title: book.metadata.title.clone(),
+            created_at,
+            modified_at,
             last_backed_up_at: 0,
             records,
         })
     }
 }
 
+impl TryFrom<&Book> for PalmDoc {
+    type Error = DekuError;
+
+    fn try_from(book: &Book) -> Result<Self, Self::Error> {
+        BookWriter::new().write(book)
+    }
+}
+
 // todo: should this be DekuContainerReader?
 impl<'a, Ctx> DekuReader<'a, Ctx> for Book {
     fn from_reader_with_ctx<R: Read>(reader: &mut Reader<R>, ctx: Ctx) -> Result<Self, DekuError>
@@ -454,6 +1497,375 @@ mod tests {
         println!("{:?}", book);
     }
 
+    fn read_fixture() -> PalmDoc {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+        palmdoc
+    }
+
+    #[test]
+    fn test_parts_cover_text_flow() {
+        let palmdoc = read_fixture();
+        let (_, fdst_table) = FDSTTable::from_bytes((&palmdoc.records[1104], 0)).unwrap();
+        let (book, warnings) = Book::from_palmdoc(palmdoc, &ParseOptions::default()).unwrap();
+
+        assert_eq!(warnings, vec![]);
+        assert_eq!(book.book_parts.len(), 393);
+        let parts_len = book
+            .book_parts
+            .iter()
+            .map(|part| part.skeleton_head.len() + part.content.len() + part.skeleton_tail.len())
+            .sum::<usize>();
+        assert_eq!(parts_len, fdst_table.entries[0].end as usize);
+        let first_part = &book.book_parts[0];
+        assert!(first_part.skeleton_head.starts_with("<?xml"));
+        assert_eq!(first_part.skeleton_tail, "</body>\n</html>\n");
+    }
+
+    #[test]
+    fn test_lenient_skips_invalid_text_record() {
+        let mut palmdoc = read_fixture();
+        palmdoc.records[1].clear();
+
+        assert!(Book::from_palmdoc(palmdoc.clone(), &ParseOptions { strict: true }).is_err());
+
+        let (_, warnings) = Book::from_palmdoc(palmdoc, &ParseOptions { strict: false }).unwrap();
+        assert!(warnings.contains(&ParseWarning::InvalidTrailingEntries { record: 1 }));
+    }
+
+    #[test]
+    fn test_lenient_falls_back_to_text_flow() {
+        let mut palmdoc = read_fixture();
+        // Truncate the skeleton index header record
+        palmdoc.records[1095].truncate(16);
+
+        assert!(Book::from_palmdoc(palmdoc.clone(), &ParseOptions { strict: true }).is_err());
+
+        let (book, warnings) =
+            Book::from_palmdoc(palmdoc, &ParseOptions { strict: false }).unwrap();
+        // The table of contents can't be placed without the parts either
+        assert!(matches!(
+            warnings.as_slice(),
+            [
+                ParseWarning::InvalidIndex {
+                    name: "Skeleton",
+                    ..
+                },
+                ParseWarning::InvalidIndex { name: "NCX", .. }
+            ]
+        ));
+        assert!(warnings[0]
+            .to_string()
+            .contains("using the text flow as a single part"));
+        assert!(warnings[1]
+            .to_string()
+            .contains("leaving out the table of contents"));
+        assert_eq!(book.book_parts.len(), 1);
+        assert!(book.book_parts[0].content.starts_with("<?xml"));
+    }
+
+    #[test]
+    fn test_lenient_replaces_invalid_flow_text() {
+        let book = crate::serialization::BookBuilder::new(Metadata {
+            title: "Untitled".to_string(),
+            ..Default::default()
+        })
+        .compression(CompressionType::None)
+        .chapter(
+            "chapter.xhtml",
+            r#"<html><head><link rel="stylesheet" href="main.css"/></head><body><p>Text</p></body></html>"#,
+        )
+        .stylesheet("main.css", "p { color: red }")
+        .build()
+        .unwrap();
+        let mut palmdoc = BookWriter::reproducible().write(&book).unwrap();
+        let record = &mut palmdoc.records[1];
+        let position = record.windows(3).position(|w| w == b"red").unwrap();
+        record[position] = 0xff;
+
+        assert!(Book::from_palmdoc(palmdoc.clone(), &ParseOptions { strict: true }).is_err());
+
+        let (parsed, warnings) =
+            Book::from_palmdoc(palmdoc, &ParseOptions { strict: false }).unwrap();
+        assert_eq!(warnings, [ParseWarning::InvalidFlowUtf8 { flow: 1 }]);
+        assert_eq!(parsed.resources[0].text, "p { color: \u{fffd}ed }");
+    }
+
+    #[test]
+    fn test_decompression_matches_serial() {
+        let palmdoc = read_fixture();
+        let mobi_header = palmdoc.mobi_header().unwrap();
+
+        let mut expected = Vec::new();
+        for record in &palmdoc.records[1..=mobi_header.num_of_text_records as usize] {
+            let len = record.len() - mobi_header.sizeof_trailing_section_entries(record).unwrap();
+            expected.extend(palmdoc::decompress(&record[..len]).unwrap());
+        }
+
+        let options = ParseOptions::default();
+        let text = read_text(&palmdoc, &mobi_header, &mut Diagnostics::new(&options)).unwrap();
+        assert!(text == expected);
+    }
+
+    #[test]
+    fn test_compression_matches_serial() {
+        let (mut book, _) = Book::from_palmdoc(read_fixture(), &ParseOptions::default()).unwrap();
+        book.compression = CompressionType::PalmDoc;
+
+        let text = book
+            .book_parts
+            .iter()
+            .map(|part| {
+                format!(
+                    "{}{}{}",
+                    part.skeleton_head, part.skeleton_tail, part.content
+                )
+            })
+            .chain(book.resources.iter().map(|flow| flow.text.clone()))
+            .collect::<String>();
+
+        for level in [CompressionLevel::Fast, CompressionLevel::Thorough] {
+            let palmdoc = BookWriter::new()
+                .compression_level(level)
+                .write(&book)
+                .unwrap();
+
+            let mut expected = Vec::new();
+            for (record, overlap) in create_text_records(text.as_bytes(), Codepage::Utf8) {
+                let mut record = palmdoc::compress(record, level);
+                record.extend_from_slice(overlap);
+                record.push(overlap.len() as u8);
+                expected.push(record);
+            }
+
+            assert!(palmdoc.records[1..=expected.len()] == expected[..]);
+        }
+
+        let default = BookWriter::new().write(&book).unwrap();
+        let thorough = BookWriter::new()
+            .compression_level(CompressionLevel::Thorough)
+            .write(&book)
+            .unwrap();
+        assert_eq!(CompressionLevel::default(), CompressionLevel::Thorough);
+        assert!(default.records[1..] == thorough.records[1..]);
+    }
+
+    proptest! {
+        #[test]
+        fn test_text_records_split_utf8(text in "[a-z <>/àéüßЖжλ日本語字🙂🚀]{0,6000}") {
+            let records = create_text_records(text.as_bytes(), Codepage::Utf8);
+            assert_eq!(
+                records.iter().map(|(record, _)| *record).collect::<Vec<_>>().concat(),
+                text.as_bytes()
+            );
+
+            let mut end = 0;
+            for (record, overlap) in &records {
+                assert!(record.len() <= TEXT_RECORD_SIZE);
+                assert!(overlap.len() <= 3);
+                end += record.len();
+
+                // The overlap completes the character cut off at the end of the record, and nothing more
+                assert!(text.is_char_boundary(end + overlap.len()));
+                assert_eq!(overlap.is_empty(), text.is_char_boundary(end));
+            }
+        }
+
+        #[test]
+        fn test_cp1252_book_roundtrip(text in "[a-z <>/àéü€–]{0,6000}") {
+            let book = Book {
+                metadata: Metadata {
+                    title: "Café".to_string(),
+                    ..Default::default()
+                },
+                uid: 1,
+                book_parts: vec![BookPart {
+                    skeleton_head: "<html><body>".to_string(),
+                    content: text,
+                    skeleton_tail: "</body></html>".to_string(),
+                }],
+                text_encoding: Codepage::Cp1252,
+                ..Default::default()
+            };
+            let palmdoc = PalmDoc::try_from(&book).unwrap();
+            let mobi_header = palmdoc.mobi_header().unwrap();
+            assert_eq!(mobi_header.text_encoding, Codepage::Cp1252);
+            assert_eq!(mobi_header.title.0, b"Caf\xe9");
+
+            let options = ParseOptions::default();
+            let text = read_text(&palmdoc, &mobi_header, &mut Diagnostics::new(&options)).unwrap();
+            assert_eq!(
+                Codepage::Cp1252.decode(&text).unwrap(),
+                format!(
+                    "{}{}{}",
+                    book.book_parts[0].skeleton_head,
+                    book.book_parts[0].skeleton_tail,
+                    book.book_parts[0].content
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_rewrite_keeps_exth() {
+        let palmdoc = read_fixture();
+        let original = palmdoc.mobi_header().unwrap().exth.unwrap();
+        let (book, _) = Book::from_palmdoc(palmdoc, &ParseOptions::default()).unwrap();
+        let palmdoc = PalmDoc::try_from(&book).unwrap();
+        let mobi_header = palmdoc.mobi_header().unwrap();
+
+        let exth = mobi_header.exth.unwrap();
+        for record in &original.records {
+            assert!(exth.records.contains(record), "{:?} was dropped", record);
+        }
+        assert_eq!(exth.value(MetadataIdValue::CreatorSoftware), Some(201));
+        assert_eq!(exth.value(MetadataIdValue::EmbeddedRecordCount), Some(4));
+        assert_eq!(mobi_header.title.to_string(), book.metadata.title);
+    }
+
+    #[test]
+    fn test_reproducible_output() {
+        let (book, _) = Book::from_palmdoc(read_fixture(), &ParseOptions::default()).unwrap();
+
+        let write = |book: &Book| {
+            let palmdoc = BookWriter::reproducible().write(book).unwrap();
+            let mut output = Cursor::new(Vec::new());
+            let mut writer = Writer::new(&mut output);
+            palmdoc.to_writer(&mut writer, ()).unwrap();
+            writer.finalize().unwrap();
+            output.into_inner()
+        };
+
+        let output = write(&book);
+        assert!(output == write(&book));
+
+        // The uid comes from the content, not from `Book::uid`
+        let renumbered = Book {
+            uid: book.uid.wrapping_add(1),
+            ..book
+        };
+        assert!(output == write(&renumbered));
+
+        let palmdoc = PalmDoc::from_bytes((&output, 0)).unwrap().1;
+        let mobi_header = palmdoc.mobi_header().unwrap();
+        assert_eq!(palmdoc.created_at, 0);
+
+        let retitled = Book {
+            metadata: Metadata {
+                title: "Voina i mir".to_string(),
+                ..renumbered.metadata
+            },
+            ..renumbered
+        };
+        let palmdoc = BookWriter::reproducible().write(&retitled).unwrap();
+        let retitled_header = palmdoc.mobi_header().unwrap();
+        assert_ne!(mobi_header.uid, retitled_header.uid);
+    }
+
+    #[test]
+    fn test_explicit_timestamps() {
+        let book = Book {
+            metadata: Metadata {
+                title: "Untitled".to_string(),
+                ..Default::default()
+            },
+            uid: 1,
+            compression: CompressionType::None,
+            ..Default::default()
+        };
+
+        let palmdoc = BookWriter::new()
+            .created_at(1_000)
+            .modified_at(2_000)
+            .write(&book)
+            .unwrap();
+        assert_eq!(palmdoc.created_at, 1_000);
+        assert_eq!(palmdoc.modified_at, 2_000);
+
+        let mobi_header = palmdoc.mobi_header().unwrap();
+        assert_eq!(mobi_header.uid, 1);
+    }
+
+    #[test]
+    fn test_source_archive_and_datp_roundtrip() {
+        let archive = b"PK\x03\x04kindlegensrc".to_vec();
+        let datp = DatpRecord::parse(b"DATP\0\0\0\x10\0\0\0\x01\0\0\0\x02\xff\xff").unwrap();
+        let book = Book {
+            metadata: Metadata {
+                title: "Untitled".to_string(),
+                ..Default::default()
+            },
+            uid: 1,
+            source_archive: Some(archive.clone()),
+            datp: Some(datp.clone()),
+            compression: CompressionType::None,
+            ..Default::default()
+        };
+
+        let palmdoc = BookWriter::reproducible().write(&book).unwrap();
+        let mobi_header = palmdoc.mobi_header().unwrap();
+        assert_eq!(mobi_header.srcs_count, 1);
+        assert!(palmdoc.records[mobi_header.srcs_record as usize].starts_with(b"SRCS"));
+        assert!(palmdoc.records[mobi_header.datp_index as usize].starts_with(b"DATP"));
+
+        let (parsed, warnings) = Book::from_palmdoc(palmdoc, &ParseOptions::default()).unwrap();
+        assert_eq!(warnings, []);
+        assert_eq!(parsed.source_archive, Some(archive));
+        assert_eq!(parsed.datp, Some(datp));
+    }
+
+    #[test]
+    fn test_embed_references_skip_other_records() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        let book = crate::serialization::BookBuilder::new(Metadata {
+            title: "Untitled".to_string(),
+            ..Default::default()
+        })
+        .chapter(
+            "chapter.xhtml",
+            r#"<html><body><img src="a.png"/><img src="b.png"/></body></html>"#,
+        )
+        .image("a.png", png.clone())
+        .image("b.png", png)
+        .build()
+        .unwrap();
+        let mut palmdoc = BookWriter::reproducible().write(&book).unwrap();
+
+        // A placeholder in place of the first image still counts towards the second image's reference
+        let mobi_header = palmdoc.mobi_header().unwrap();
+        palmdoc.records[mobi_header.first_resource_record as usize] = vec![0xa0; 4];
+
+        let (parsed, warnings) = Book::from_palmdoc(palmdoc, &ParseOptions::default()).unwrap();
+        assert_eq!(parsed.embedded_resources.len(), 1);
+        assert_eq!(warnings, [ParseWarning::UnresolvedEmbed { record: 0 }]);
+        let content = &parsed.book_parts[0].content;
+        assert!(!content.contains("kindle:embed:0002"));
+    }
+
+    #[test]
+    fn test_cp1252_rejects_unencodable_text() {
+        let book = Book {
+            metadata: Metadata {
+                title: "日本語".to_string(),
+                ..Default::default()
+            },
+            uid: 1,
+            compression: CompressionType::None,
+            text_encoding: Codepage::Cp1252,
+            ..Default::default()
+        };
+        assert!(PalmDoc::try_from(&book).is_err());
+    }
+
+    #[test]
+    fn test_rejects_huffcdic() {
+        let book = Book {
+            compression: CompressionType::HuffCdic,
+            ..Default::default()
+        };
+        assert!(BookWriter::new().write(&book).is_err());
+    }
+
     // todo: enable
     // proptest! {
     //     #[test]
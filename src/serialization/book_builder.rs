@@ -0,0 +1,1037 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use thiserror::Error;
+
+use crate::{
+    constants::{MainLanguage, SubLanguage},
+    utils::base32,
+};
+
+use super::{
+    book::{Book, BookPart, EmbeddedResource, EmbeddedResourceKind},
+    book_metadata::Metadata,
+    embed::EmbedReference,
+    exth::{Exth, MetadataError},
+    fixed_layout::{magnification_regions, FixedLayout},
+    flow::{Flow, FlowKind, FlowReference},
+    navigation::{find_anchor, Landmark, Location, PageListItem, PageTarget, TocEntry},
+    pos::PosReference,
+    resc::{ItemRef, PageProgressionDirection, PageSpread, Spine},
+    Codepage, CompressionType,
+};
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum BuildError {
+    #[error("The book has no title")]
+    MissingTitle,
+    #[error("The book has no chapters")]
+    NoChapters,
+    #[error("{0} was added more than once")]
+    DuplicateHref(String),
+    #[error("{href} is not an XHTML document with a <body>")]
+    InvalidChapter { href: String },
+    #[error("{href} is not {expected}")]
+    InvalidResource {
+        href: String,
+        expected: &'static str,
+    },
+    #[error("{reference} (referenced from {href}) was never added")]
+    MissingResource { href: String, reference: String },
+    #[error("{0} is not a chapter")]
+    UnknownChapter(String),
+    #[error("{href} has no element with id {anchor:?}")]
+    UnknownAnchor { href: String, anchor: String },
+    #[error(transparent)]
+    InvalidMetadata(#[from] MetadataError),
+}
+
+/// An entry of the table of contents, pointing to `chapter.xhtml` or `chapter.xhtml#id`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TocItem {
+    pub title: String,
+    pub href: String,
+    pub children: Vec<TocItem>,
+}
+
+impl TocItem {
+    pub fn new(title: impl Into<String>, href: impl Into<String>) -> Self {
+        Self {
+            title: title.into(),
+            href: href.into(),
+            children: vec![],
+        }
+    }
+
+    pub fn child(mut self, child: TocItem) -> Self {
+        self.children.push(child);
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Chapter {
+    href: String,
+    xhtml: String,
+}
+
+/// A stylesheet or SVG image, written to its own flow.
+#[derive(Debug, Clone)]
+struct FlowFile {
+    href: String,
+    flow: Flow,
+}
+
+#[derive(Debug, Clone)]
+struct Embedded {
+    href: String,
+    resource: EmbeddedResource,
+}
+
+/// Assembles a `Book` from XHTML documents and the files they reference.
+///
+/// Every file is identified by its `href`, relative to the root of the book (like in an EPUB's OPF). References between files are relative to the referencing file, and are rewritten to the matching `kindle:flow:`, `kindle:embed:` or `kindle:pos:` reference.
+#[derive(Debug, Clone)]
+pub struct BookBuilder {
+    metadata: Metadata,
+    uid: u32,
+    main_language: Option<MainLanguage>,
+    sub_language: Option<SubLanguage>,
+    chapters: Vec<Chapter>,
+    flows: Vec<FlowFile>,
+    embedded: Vec<Embedded>,
+    cover: Option<String>,
+    toc: Vec<TocItem>,
+    landmarks: Vec<(String, String, String)>,
+    page_list: Vec<PageListItem>,
+    page_progression_direction: Option<PageProgressionDirection>,
+    non_linear: Vec<String>,
+    spine_properties: Vec<(String, Vec<String>)>,
+    page_spreads: Vec<(String, PageSpread)>,
+    fixed_layout: Option<FixedLayout>,
+    source_archive: Option<Vec<u8>>,
+    compression: CompressionType,
+    text_encoding: Codepage,
+}
+
+impl BookBuilder {
+    pub fn new(metadata: Metadata) -> Self {
+        Self {
+            metadata,
+            uid: 0,
+            main_language: None,
+            sub_language: None,
+            chapters: vec![],
+            flows: vec![],
+            embedded: vec![],
+            cover: None,
+            toc: vec![],
+            landmarks: vec![],
+            page_list: vec![],
+            page_progression_direction: None,
+            non_linear: vec![],
+            spine_properties: vec![],
+            page_spreads: vec![],
+            fixed_layout: None,
+            source_archive: None,
+            compression: CompressionType::PalmDoc,
+            text_encoding: Codepage::Utf8,
+        }
+    }
+
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = uid;
+        self
+    }
+
+    pub fn language(mut self, main: MainLanguage, sub: Option<SubLanguage>) -> Self {
+        self.main_language = Some(main);
+        self.sub_language = sub;
+        self
+    }
+
+    /// Defaults to `CompressionType::PalmDoc`.
+    pub fn compression(mut self, compression: CompressionType) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Defaults to `Codepage::Utf8`.
+    pub fn text_encoding(mut self, text_encoding: Codepage) -> Self {
+        self.text_encoding = text_encoding;
+        self
+    }
+
+    /// Adds a complete XHTML document. Chapters are in reading order.
+    pub fn chapter(mut self, href: impl Into<String>, xhtml: impl Into<String>) -> Self {
+        self.chapters.push(Chapter {
+            href: href.into(),
+            xhtml: xhtml.into(),
+        });
+        self
+    }
+
+    pub fn stylesheet(mut self, href: impl Into<String>, css: impl Into<String>) -> Self {
+        self.flows.push(FlowFile {
+            href: href.into(),
+            flow: Flow::stylesheet(css),
+        });
+        self
+    }
+
+    /// An SVG image, which is kept as text in its own flow rather than in a record like other images.
+    pub fn svg(mut self, href: impl Into<String>, svg: impl Into<String>) -> Self {
+        self.flows.push(FlowFile {
+            href: href.into(),
+            flow: Flow::svg(svg),
+        });
+        self
+    }
+
+    pub fn image(self, href: impl Into<String>, data: Vec<u8>) -> Self {
+        self.embed(href.into(), EmbeddedResourceKind::Image, data)
+    }
+
+    pub fn font(self, href: impl Into<String>, data: Vec<u8>) -> Self {
+        self.embed(href.into(), EmbeddedResourceKind::Font, data)
+    }
+
+    fn embed(mut self, href: String, kind: EmbeddedResourceKind, data: Vec<u8>) -> Self {
+        self.embedded.push(Embedded {
+            href,
+            resource: EmbeddedResource { kind, data },
+        });
+        self
+    }
+
+    /// The `href` of an image added with `image()`.
+    pub fn cover(mut self, href: impl Into<String>) -> Self {
+        self.cover = Some(href.into());
+        self
+    }
+
+    pub fn toc(mut self, toc: Vec<TocItem>) -> Self {
+        self.toc = toc;
+        self
+    }
+
+    /// `kind` is the EPUB 3 `epub:type`, e.g. `bodymatter`.
+    pub fn landmark(
+        mut self,
+        kind: impl Into<String>,
+        title: impl Into<String>,
+        href: impl Into<String>,
+    ) -> Self {
+        self.landmarks
+            .push((kind.into(), title.into(), href.into()));
+        self
+    }
+
+    /// The pages of the print edition, in order, e.g. from an EPUB's `page-list` nav.
+    pub fn page_list(mut self, pages: Vec<PageListItem>) -> Self {
+        self.page_list = pages;
+        self
+    }
+
+    pub fn page_progression_direction(mut self, direction: PageProgressionDirection) -> Self {
+        self.page_progression_direction = Some(direction);
+        self
+    }
+
+    /// Marks a chapter as auxiliary content, skipped when reading from start to end.
+    pub fn non_linear(mut self, href: impl Into<String>) -> Self {
+        self.non_linear.push(href.into());
+        self
+    }
+
+    /// Sets the spine properties of a chapter, e.g. `page-spread-left`.
+    pub fn spine_properties(mut self, href: impl Into<String>, properties: Vec<String>) -> Self {
+        self.spine_properties.push((href.into(), properties));
+        self
+    }
+
+    /// Makes this a fixed-layout book, where every chapter is a page.
+    pub fn fixed_layout(mut self, fixed_layout: FixedLayout) -> Self {
+        self.fixed_layout = Some(fixed_layout);
+        self
+    }
+
+    /// Places a page of a fixed-layout book in a spread. Applied after `spine_properties`.
+    pub fn page_spread(mut self, href: impl Into<String>, spread: PageSpread) -> Self {
+        self.page_spreads.push((href.into(), spread));
+        self
+    }
+
+    /// Embeds the zip archive of the source files, e.g. the original EPUB.
+    pub fn source_archive(mut self, archive: Vec<u8>) -> Self {
+        self.source_archive = Some(archive);
+        self
+    }
+
+    /// Checks every reference and the metadata, and splits the chapters into parts.
+    pub fn build(self) -> Result<Book, BuildError> {
+        if self.metadata.title.trim().is_empty() {
+            return Err(BuildError::MissingTitle);
+        }
+        self.metadata
+            .write_exth(&mut Exth::default(), self.uid, None)?;
+
+        if self.chapters.is_empty() {
+            return Err(BuildError::NoChapters);
+        }
+
+        // Maps every file to the reference that replaces it
+        let mut references = HashMap::new();
+        for (i, file) in self.flows.iter().enumerate() {
+            let reference = FlowReference::new(i + 1, Some(file.flow.kind.mime_type().to_string()));
+            references.insert(normalize(&file.href), Some(reference.to_string()));
+        }
+        for (i, embedded) in self.embedded.iter().enumerate() {
+            let (is_valid, expected) = match embedded.resource.kind {
+                EmbeddedResourceKind::Image => {
+                    (infer::is_image(&embedded.resource.data), "an image")
+                }
+                EmbeddedResourceKind::Font => (infer::is_font(&embedded.resource.data), "a font"),
+            };
+            let mime_type = infer::get(&embedded.resource.data)
+                .filter(|_| is_valid)
+                .ok_or_else(|| BuildError::InvalidResource {
+                    href: embedded.href.clone(),
+                    expected,
+                })?
+                .mime_type();
+
+            let reference = EmbedReference::new(i, Some(mime_type.to_string()));
+            references.insert(normalize(&embedded.href), Some(reference.to_string()));
+        }
+        let mut chapter_indexes = HashMap::new();
+        for (i, chapter) in self.chapters.iter().enumerate() {
+            chapter_indexes.insert(normalize(&chapter.href), i);
+            references.insert(normalize(&chapter.href), None);
+        }
+
+        let file_count = self.chapters.len() + self.flows.len() + self.embedded.len();
+        if references.len() != file_count {
+            let mut seen = HashMap::new();
+            let hrefs = self
+                .chapters
+                .iter()
+                .map(|c| &c.href)
+                .chain(self.flows.iter().map(|f| &f.href))
+                .chain(self.embedded.iter().map(|e| &e.href));
+            for href in hrefs {
+                if seen.insert(normalize(href), ()).is_some() {
+                    return Err(BuildError::DuplicateHref(href.clone()));
+                }
+            }
+        }
+
+        // Links to chapters are replaced with placeholders until every part is split, and filled in at the end
+        let links = RefCell::new(vec![]);
+        let resolve = |href: &str, reference: &str| match resolve(href, reference) {
+            None => Ok(None),
+            Some(path) => match references.get(&path) {
+                Some(Some(replacement)) => Ok(Some(replacement.clone())),
+                Some(None) => {
+                    let mut links = links.borrow_mut();
+                    links.push(match reference.split_once('#') {
+                        Some((_, anchor)) => format!("{}#{}", path, anchor),
+                        None => path,
+                    });
+                    Ok(Some(link_placeholder(links.len() - 1)))
+                }
+                None => Err(BuildError::MissingResource {
+                    href: href.to_string(),
+                    reference: reference.to_string(),
+                }),
+            },
+        };
+
+        let mut book_parts = self
+            .chapters
+            .iter()
+            .map(|chapter| {
+                let xhtml = rewrite_attributes(&chapter.xhtml, |reference| {
+                    resolve(&chapter.href, reference)
+                })?;
+                split_chapter(&xhtml).ok_or_else(|| BuildError::InvalidChapter {
+                    href: chapter.href.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for region in magnification_regions(&book_parts) {
+            let part = region.location.part;
+            if find_anchor(book_parts[part].content.as_bytes(), &region.target_id).is_none() {
+                return Err(BuildError::UnknownAnchor {
+                    href: self.chapters[part].href.clone(),
+                    anchor: region.target_id,
+                });
+            }
+        }
+
+        let mut resources = self
+            .flows
+            .iter()
+            .map(|file| {
+                let rewrite = |reference: &str| resolve(&file.href, reference);
+                let text = match file.flow.kind {
+                    FlowKind::Svg => rewrite_attributes(&file.flow.text, rewrite)?,
+                    _ => rewrite_urls(&file.flow.text, rewrite)?,
+                };
+                Ok(Flow {
+                    kind: file.flow.kind.clone(),
+                    text,
+                })
+            })
+            .collect::<Result<Vec<_>, BuildError>>()?;
+
+        let locate = |href: &str| {
+            let (path, anchor) = match href.split_once('#') {
+                Some((path, anchor)) => (path, Some(anchor)),
+                None => (href, None),
+            };
+            let part = *chapter_indexes
+                .get(&normalize(path))
+                .ok_or_else(|| BuildError::UnknownChapter(href.to_string()))?;
+
+            if let Some(anchor) = anchor {
+                if find_anchor(book_parts[part].content.as_bytes(), anchor).is_none() {
+                    return Err(BuildError::UnknownAnchor {
+                        href: path.to_string(),
+                        anchor: anchor.to_string(),
+                    });
+                }
+            }
+
+            Ok(Location {
+                part,
+                anchor: anchor.map(str::to_string),
+            })
+        };
+
+        // Each part is written as a single chunk, so a link points into the chunk of its part
+        let links = links
+            .into_inner()
+            .iter()
+            .map(|href| {
+                let location = locate(href)?;
+                let content = &book_parts[location.part].content;
+                let offset = location
+                    .anchor
+                    .and_then(|anchor| find_anchor(content.as_bytes(), &anchor))
+                    .unwrap_or(0);
+                let offset = self
+                    .text_encoding
+                    .encode(&content[..offset])
+                    .map_or(offset, |encoded| encoded.len());
+                Ok(PosReference::new(location.part, offset).to_string())
+            })
+            .collect::<Result<Vec<_>, BuildError>>()?;
+
+        fn toc_entries(
+            items: &[TocItem],
+            locate: &impl Fn(&str) -> Result<Location, BuildError>,
+        ) -> Result<Vec<TocEntry>, BuildError> {
+            items
+                .iter()
+                .map(|item| {
+                    Ok(TocEntry {
+                        title: item.title.clone(),
+                        location: locate(&item.href)?,
+                        children: toc_entries(&item.children, locate)?,
+                    })
+                })
+                .collect()
+        }
+        let toc = toc_entries(&self.toc, &locate)?;
+
+        let landmarks = self
+            .landmarks
+            .iter()
+            .map(|(kind, title, href)| {
+                Ok(Landmark {
+                    kind: kind.clone(),
+                    title: title.clone(),
+                    location: locate(href)?,
+                })
+            })
+            .collect::<Result<Vec<_>, BuildError>>()?;
+
+        let page_list = self
+            .page_list
+            .iter()
+            .map(|page| {
+                Ok(PageTarget {
+                    label: page.label.clone(),
+                    location: locate(&page.href)?,
+                })
+            })
+            .collect::<Result<Vec<_>, BuildError>>()?;
+
+        let mut itemrefs = (0..book_parts.len())
+            .map(|part| ItemRef {
+                idref: format!("part{}", part),
+                part: Some(part),
+                linear: true,
+                properties: vec![],
+            })
+            .collect::<Vec<_>>();
+        for href in &self.non_linear {
+            itemrefs[locate(href)?.part].linear = false;
+        }
+        for (href, properties) in &self.spine_properties {
+            itemrefs[locate(href)?.part].properties = properties.clone();
+        }
+        for (href, spread) in &self.page_spreads {
+            itemrefs[locate(href)?.part].set_page_spread(Some(*spread));
+        }
+        let spine = Spine {
+            page_progression_direction: self.page_progression_direction,
+            itemrefs,
+        };
+
+        let cover = self
+            .cover
+            .as_ref()
+            .map(|href| {
+                self.embedded
+                    .iter()
+                    .position(|embedded| {
+                        embedded.resource.kind == EmbeddedResourceKind::Image
+                            && normalize(&embedded.href) == normalize(href)
+                    })
+                    .ok_or_else(|| BuildError::InvalidResource {
+                        href: href.clone(),
+                        expected: "an image",
+                    })
+            })
+            .transpose()?;
+
+        for part in &mut book_parts {
+            part.skeleton_head = fill_links(&part.skeleton_head, &links);
+            part.content = fill_links(&part.content, &links);
+            part.skeleton_tail = fill_links(&part.skeleton_tail, &links);
+        }
+        for resource in &mut resources {
+            resource.text = fill_links(&resource.text, &links);
+        }
+
+        Ok(Book {
+            metadata: self.metadata,
+            uid: self.uid,
+            main_language: self.main_language,
+            sub_language: self.sub_language,
+            book_parts,
+            resources,
+            embedded_resources: self
+                .embedded
+                .into_iter()
+                .map(|embedded| embedded.resource)
+                .collect(),
+            cover,
+            toc,
+            landmarks,
+            page_list,
+            spine,
+            fixed_layout: self.fixed_layout,
+            source_archive: self.source_archive,
+            compression: self.compression,
+            text_encoding: self.text_encoding,
+            ..Default::default()
+        })
+    }
+}
+
+const LINK_PLACEHOLDER: &str = "kindle:pos:fid:????:off:";
+
+/// Stands in for the `kindle:pos` reference of the link with the given index, and has the same length.
+fn link_placeholder(index: usize) -> String {
+    format!("{}{}", LINK_PLACEHOLDER, base32::encode(index as u32, 10))
+}
+
+/// Replaces every link placeholder with its `kindle:pos` reference.
+fn fill_links(text: &str, links: &[String]) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(LINK_PLACEHOLDER) {
+        let (before, after) = rest.split_at(start + LINK_PLACEHOLDER.len());
+        let link = after
+            .get(..10)
+            .and_then(base32::decode)
+            .and_then(|index| links.get(index as usize));
+        match link {
+            Some(link) => {
+                output.push_str(&before[..start]);
+                output.push_str(link);
+                rest = &after[10..];
+            }
+            None => {
+                output.push_str(before);
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Removes `.` and `..` segments.
+fn normalize(path: &str) -> String {
+    let mut segments: Vec<&str> = vec![];
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+/// Resolves `reference` against the file at `href`, ignoring fragments and queries. Returns `None` for references that don't point to a file of the book (URLs, `data:` and fragment-only references).
+fn resolve(href: &str, reference: &str) -> Option<String> {
+    let path = reference.split(['#', '?']).next().unwrap_or_default();
+    let has_scheme = path
+        .split_once(':')
+        .is_some_and(|(scheme, _)| !scheme.contains('/'));
+    if path.is_empty() || has_scheme {
+        return None;
+    }
+
+    if path.starts_with('/') {
+        return Some(normalize(path));
+    }
+
+    let directory = href.rsplit_once('/').map_or("", |(directory, _)| directory);
+    Some(normalize(&format!("{}/{}", directory, path)))
+}
+
+/// Replaces the value of every `href` and `src` attribute (including `xlink:href`) for which `rewrite` returns `Some`.
+fn rewrite_attributes(
+    xhtml: &str,
+    mut rewrite: impl FnMut(&str) -> Result<Option<String>, BuildError>,
+) -> Result<String, BuildError> {
+    let bytes = xhtml.as_bytes();
+    let mut output = String::with_capacity(xhtml.len());
+    let mut copied = 0;
+    let mut in_tag = false;
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'<' => in_tag = true,
+            b'>' => in_tag = false,
+            b'=' if in_tag => {
+                let name_end = xhtml[..i].trim_end().len();
+                let name_start = xhtml[..name_end]
+                    .rfind(|c: char| c.is_whitespace())
+                    .map_or(0, |start| start + 1);
+                let name = &xhtml[name_start..name_end];
+
+                let value_start =
+                    i + 1 + (xhtml[i + 1..].len() - xhtml[i + 1..].trim_start().len());
+                let Some(quote) = bytes.get(value_start).filter(|b| matches!(b, b'"' | b'\''))
+                else {
+                    i += 1;
+                    continue;
+                };
+                let Some(value_len) = xhtml[value_start + 1..].find(*quote as char) else {
+                    break;
+                };
+                let value_end = value_start + 1 + value_len;
+
+                if matches!(name, "href" | "src" | "xlink:href") {
+                    if let Some(replacement) = rewrite(&xhtml[value_start + 1..value_end])? {
+                        output.push_str(&xhtml[copied..value_start + 1]);
+                        output.push_str(&replacement);
+                        copied = value_end;
+                    }
+                }
+                i = value_end;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    output.push_str(&xhtml[copied..]);
+    Ok(output)
+}
+
+/// Replaces every CSS `url(...)` for which `rewrite` returns `Some`.
+fn rewrite_urls(
+    css: &str,
+    mut rewrite: impl FnMut(&str) -> Result<Option<String>, BuildError>,
+) -> Result<String, BuildError> {
+    let mut output = String::with_capacity(css.len());
+    let mut rest = css;
+
+    while let Some(start) = rest.find("url(") {
+        let (before, after) = rest.split_at(start + "url(".len());
+        output.push_str(before);
+
+        let Some(end) = after.find(')') else {
+            rest = after;
+            break;
+        };
+        let value = after[..end].trim().trim_matches(['"', '\'']);
+        match rewrite(value)? {
+            Some(replacement) => output.push_str(&replacement),
+            None => output.push_str(&after[..end]),
+        }
+        rest = &after[end..];
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Splits a document into the markup up to and including `<body>`, the content of the body, and the rest.
+fn split_chapter(xhtml: &str) -> Option<BookPart> {
+    let lowercase = xhtml.to_ascii_lowercase();
+    let body_start = lowercase.find("<body")?;
+    let content_start = body_start + xhtml[body_start..].find('>')? + 1;
+    let content_end = lowercase.rfind("</body")?;
+    if content_end < content_start {
+        return None;
+    }
+
+    Some(BookPart {
+        skeleton_head: xhtml[..content_start].to_string(),
+        content: xhtml[content_start..content_end].to_string(),
+        skeleton_tail: xhtml[content_end..].to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parse_book,
+        serialization::{
+            BookWriter, FixedLayoutBookType, MagnificationRegion, Orientation, ParseOptions,
+            Series, Viewport,
+        },
+    };
+    use deku::DekuContainerWrite;
+    use pretty_assertions::assert_eq;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const TTF: &[u8] = b"\x00\x01\x00\x00\x00\x0c";
+
+    fn chapter(body: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><link rel="stylesheet" type="text/css" href="../styles/main.css"/></head>
+<body class="chapter">{}</body>
+</html>
+"#,
+            body
+        )
+    }
+
+    fn builder() -> BookBuilder {
+        BookBuilder::new(Metadata {
+            title: "War and Peace".to_string(),
+            authors: vec!["Leo Tolstoy".to_string()],
+            series: Some(Series {
+                name: "Penguin Classics".to_string(),
+                index: Some(3),
+            }),
+            ..Default::default()
+        })
+        .chapter(
+            "text/cover.xhtml",
+            chapter(r#"<img src="../images/cover.png" alt=""/><a href="chapter-1.xhtml#start">Start</a>"#),
+        )
+        .chapter(
+            "text/chapter-1.xhtml",
+            chapter(
+                r##"<h1 id="start">Book One</h1><p>"Well, Prince..." <a href="#map">Map</a> <a href="chapter-1.xhtml#map">Map</a> <a href="cover.xhtml">Cover</a></p><p id="map">Map</p><img src="../images/map.svg" alt=""/>"##,
+            ),
+        )
+        .stylesheet(
+            "styles/main.css",
+            r#"@font-face { src: url("../fonts/serif.ttf"); } body { background: url(data:image/png;base64,AA==) }"#,
+        )
+        .svg(
+            "images/map.svg",
+            r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><image xlink:href="cover.png"/></svg>"#,
+        )
+        .image("images/cover.png", PNG.to_vec())
+        .font("fonts/serif.ttf", TTF.to_vec())
+        .cover("images/cover.png")
+        .toc(vec![TocItem::new("Book One", "text/chapter-1.xhtml#start")
+            .child(TocItem::new("Chapter 1", "text/chapter-1.xhtml#map"))])
+        .landmark("bodymatter", "Start", "text/chapter-1.xhtml#start")
+        .page_list(vec![
+            PageListItem {
+                label: "i".to_string(),
+                href: "text/cover.xhtml".to_string(),
+            },
+            PageListItem {
+                label: "1".to_string(),
+                href: "text/chapter-1.xhtml#start".to_string(),
+            },
+        ])
+        .page_progression_direction(PageProgressionDirection::RightToLeft)
+        .non_linear("text/cover.xhtml")
+        .spine_properties("text/chapter-1.xhtml", vec!["page-spread-left".to_string()])
+    }
+
+    #[test]
+    fn test_build() {
+        let book = builder().build().unwrap();
+
+        assert_eq!(
+            book.book_parts[0].skeleton_head,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><link rel="stylesheet" type="text/css" href="kindle:flow:0001?mime=text/css"/></head>
+<body class="chapter">"#
+        );
+        assert_eq!(
+            book.book_parts[0].content,
+            r#"<img src="kindle:embed:0001?mime=image/png" alt=""/><a href="kindle:pos:fid:0001:off:0000000000">Start</a>"#
+        );
+        assert_eq!(book.book_parts[0].skeleton_tail, "</body>\n</html>\n");
+        assert_eq!(
+            book.resources,
+            [
+                Flow::stylesheet(
+                    r#"@font-face { src: url(kindle:embed:0002?mime=application/font-sfnt); } body { background: url(data:image/png;base64,AA==) }"#
+                ),
+                Flow::svg(
+                    r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><image xlink:href="kindle:embed:0001?mime=image/png"/></svg>"#
+                )
+            ]
+        );
+        assert!(book.book_parts[1]
+            .content
+            .contains(r#"<img src="kindle:flow:0002?mime=image/svg+xml" alt=""/>"#));
+        let map = find_anchor(book.book_parts[1].content.as_bytes(), "map").unwrap();
+        assert!(book.book_parts[1].content.contains(&format!(
+            r##"<a href="#map">Map</a> <a href="{}">Map</a> <a href="kindle:pos:fid:0000:off:0000000000">Cover</a>"##,
+            PosReference::new(1, map)
+        )));
+        assert_eq!(book.cover, Some(0));
+        assert_eq!(
+            book.toc,
+            [TocEntry {
+                title: "Book One".to_string(),
+                location: Location {
+                    part: 1,
+                    anchor: Some("start".to_string())
+                },
+                children: vec![TocEntry {
+                    title: "Chapter 1".to_string(),
+                    location: Location {
+                        part: 1,
+                        anchor: Some("map".to_string())
+                    },
+                    children: vec![],
+                }],
+            }]
+        );
+        assert_eq!(book.landmarks[0].location.part, 1);
+        assert_eq!(
+            book.spine.itemrefs,
+            [
+                ItemRef {
+                    idref: "part0".to_string(),
+                    part: Some(0),
+                    linear: false,
+                    properties: vec![],
+                },
+                ItemRef {
+                    idref: "part1".to_string(),
+                    part: Some(1),
+                    linear: true,
+                    properties: vec!["page-spread-left".to_string()],
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_roundtrip() {
+        let book = builder().build().unwrap();
+        let palmdoc = BookWriter::reproducible().write(&book).unwrap();
+
+        // Links point to the element they link to in the text the reader reassembles
+        let mobi_book = parse_book(&palmdoc.to_bytes().unwrap()).unwrap().1;
+        let content = &book.book_parts[1].content;
+        let link = content[content.find("kindle:pos:").unwrap()..]
+            .split('"')
+            .next();
+        let link = PosReference::parse(link.unwrap()).unwrap();
+        let position = mobi_book.fragment_table[link.fid].insert_position as usize + link.offset;
+        let part = &mobi_book.parts[1];
+        assert!(part.get_content()[position - part.start_offset..].starts_with(b"<p id=\"map\">"));
+
+        let (parsed, warnings) = Book::from_palmdoc(palmdoc, &ParseOptions::default()).unwrap();
+
+        assert_eq!(warnings, []);
+        assert_eq!(parsed.book_parts, book.book_parts);
+        assert_eq!(parsed.resources, book.resources);
+        assert_eq!(parsed.embedded_resources, book.embedded_resources);
+        assert_eq!(parsed.cover, book.cover);
+        assert_eq!(parsed.toc, book.toc);
+        assert_eq!(parsed.landmarks, book.landmarks);
+        assert_eq!(parsed.page_list, book.page_list);
+        assert_eq!(parsed.spine, book.spine);
+        assert_eq!(parsed.metadata.authors, book.metadata.authors);
+        assert_eq!(parsed.metadata.series, book.metadata.series);
+    }
+
+    #[test]
+    fn test_fixed_layout_roundtrip() {
+        let fixed_layout = FixedLayout {
+            viewport: Some(Viewport {
+                width: 1072,
+                height: 1448,
+            }),
+            orientation: Some(Orientation::Portrait),
+            book_type: Some(FixedLayoutBookType::Comic),
+            region_magnification: true,
+            zero_gutter: true,
+            zero_margin: true,
+        };
+        let page = chapter(
+            r#"<div id="panel-1" data-app-amzn-magnify='{"targetId":"panel-1-zoom", "ordinal":1}'><img src="../images/cover.png" alt=""/></div><div id="panel-1-zoom"/>"#,
+        );
+        let book = builder()
+            .chapter("text/page-1.xhtml", page)
+            .fixed_layout(fixed_layout.clone())
+            .page_spread("text/chapter-1.xhtml", PageSpread::Right)
+            .page_spread("text/page-1.xhtml", PageSpread::Left)
+            .build()
+            .unwrap();
+        assert_eq!(book.spine.itemrefs[1].properties, ["page-spread-right"]);
+
+        let palmdoc = BookWriter::reproducible().write(&book).unwrap();
+        let (parsed, warnings) = Book::from_palmdoc(palmdoc, &ParseOptions::default()).unwrap();
+        assert_eq!(warnings, []);
+        assert_eq!(parsed.fixed_layout, Some(fixed_layout));
+        assert_eq!(
+            parsed.spine.itemrefs[2].page_spread(),
+            Some(PageSpread::Left)
+        );
+        assert_eq!(
+            parsed.magnification_regions(),
+            [MagnificationRegion {
+                location: Location {
+                    part: 2,
+                    anchor: Some("panel-1".to_string())
+                },
+                target_id: "panel-1-zoom".to_string(),
+                ordinal: Some(1),
+            }]
+        );
+
+        assert_eq!(
+            builder()
+                .chapter(
+                    "text/page-2.xhtml",
+                    chapter(r#"<div data-app-amzn-magnify='{"targetId":"missing"}'/>"#)
+                )
+                .build()
+                .unwrap_err(),
+            BuildError::UnknownAnchor {
+                href: "text/page-2.xhtml".to_string(),
+                anchor: "missing".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_build_validates() {
+        let error = |builder: BookBuilder| builder.build().unwrap_err();
+
+        assert_eq!(
+            error(BookBuilder::new(Metadata::default())),
+            BuildError::MissingTitle
+        );
+        assert_eq!(
+            error(BookBuilder::new(Metadata {
+                title: "Untitled".to_string(),
+                ..Default::default()
+            })),
+            BuildError::NoChapters
+        );
+        assert_eq!(
+            error(builder().chapter("text/../text/cover.xhtml", chapter(""))),
+            BuildError::DuplicateHref("text/../text/cover.xhtml".to_string())
+        );
+        assert_eq!(
+            error(builder().chapter("text/broken.xhtml", "<p>No body</p>")),
+            BuildError::InvalidChapter {
+                href: "text/broken.xhtml".to_string()
+            }
+        );
+        assert_eq!(
+            error(builder().chapter("text/2.xhtml", chapter(r#"<img src="missing.png"/>"#))),
+            BuildError::MissingResource {
+                href: "text/2.xhtml".to_string(),
+                reference: "missing.png".to_string()
+            }
+        );
+        assert_eq!(
+            error(builder().cover("fonts/serif.ttf")),
+            BuildError::InvalidResource {
+                href: "fonts/serif.ttf".to_string(),
+                expected: "an image"
+            }
+        );
+        assert_eq!(
+            error(builder().image("images/fake.png", b"not a png".to_vec())),
+            BuildError::InvalidResource {
+                href: "images/fake.png".to_string(),
+                expected: "an image"
+            }
+        );
+        assert_eq!(
+            error(builder().toc(vec![TocItem::new("?", "text/chapter-2.xhtml")])),
+            BuildError::UnknownChapter("text/chapter-2.xhtml".to_string())
+        );
+        assert_eq!(
+            error(builder().non_linear("text/notes.xhtml")),
+            BuildError::UnknownChapter("text/notes.xhtml".to_string())
+        );
+        assert_eq!(
+            error(builder().chapter("text/2.xhtml", chapter(r#"<a href="cover.xhtml#top"/>"#))),
+            BuildError::UnknownAnchor {
+                href: "text/cover.xhtml".to_string(),
+                anchor: "top".to_string()
+            }
+        );
+        assert_eq!(
+            error(builder().landmark("toc", "Contents", "text/chapter-1.xhtml#toc")),
+            BuildError::UnknownAnchor {
+                href: "text/chapter-1.xhtml".to_string(),
+                anchor: "toc".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            resolve("text/chapter.xhtml", "../images/a.png#x"),
+            Some("images/a.png".to_string())
+        );
+        assert_eq!(
+            resolve("chapter.xhtml", "./a.css?v=1"),
+            Some("a.css".to_string())
+        );
+        assert_eq!(
+            resolve("text/chapter.xhtml", "https://example.com/a.png"),
+            None
+        );
+        assert_eq!(resolve("text/chapter.xhtml", "#note-1"), None);
+        assert_eq!(resolve("text/chapter.xhtml", "mailto:a@example.com"), None);
+    }
+}
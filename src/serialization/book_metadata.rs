@@ -0,0 +1,285 @@
+#[cfg(test)]
+use proptest_derive::Arbitrary;
+
+use crate::constants::MetadataId;
+
+use super::{
+    exth::{Date, Exth, MetadataError},
+    parse_options::{Diagnostics, ParseOptions, ParseWarning},
+};
+
+#[derive(Debug, PartialEq, Clone, Default)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct Identifiers {
+    pub isbn: Option<String>,
+    /// When missing, the writer uses `uuid`.
+    pub asin: Option<String>,
+    /// When missing, the writer derives one from the book's `uid`, so that rewriting a book keeps its identity.
+    pub uuid: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct Series {
+    #[cfg_attr(test, proptest(regex = "\\PC{1,20}"))]
+    pub name: String,
+    pub index: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct Metadata {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub publisher: Option<String>,
+    pub published: Option<Date>,
+    /// BCP 47 language tag. When missing, the writer uses the book's `main_language` and `sub_language`.
+    pub language: Option<String>,
+    pub identifiers: Identifiers,
+    pub description: Option<String>,
+    pub subjects: Vec<String>,
+    /// KF8 has no EXTH record for series, so this is written to the OPF metadata in the RESC record.
+    pub series: Option<Series>,
+}
+
+/// Derives a UUID (version 8, i.e. custom) from `uid`. The same `uid` always gives the same UUID.
+pub(crate) fn uuid_from_uid(uid: u32) -> String {
+    // splitmix64
+    let mut state = uid as u64;
+    let mut next = || {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+
+    let high = (next() & !0xf000) | 0x8000;
+    let low = (next() & !(0b11 << 62)) | (0b10 << 62);
+
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+impl Metadata {
+    pub(crate) fn from_exth(title: String, exth: &Exth, diagnostics: &mut Diagnostics) -> Self {
+        let published = exth.published().unwrap_or_else(|e| {
+            diagnostics.warn(ParseWarning::InvalidMetadata {
+                reason: e.to_string(),
+            });
+            None
+        });
+
+        let owned = |value: Option<&str>| value.map(str::to_string);
+        Metadata {
+            title,
+            authors: exth.authors().into_iter().map(str::to_string).collect(),
+            publisher: owned(exth.publisher()),
+            published,
+            language: owned(exth.languages().first().copied()),
+            identifiers: Identifiers {
+                isbn: owned(exth.isbn()),
+                asin: owned(exth.asin()),
+                uuid: owned(
+                    exth.string(MetadataId::Source)
+                        .and_then(|source| source.strip_prefix("urn:uuid:")),
+                ),
+            },
+            description: owned(exth.description()),
+            subjects: exth.subjects().into_iter().map(str::to_string).collect(),
+            // Read from the RESC record
+            series: None,
+        }
+    }
+
+    /// Replaces the records of every field that differs from what `from_exth` reads from `exth`, and keeps every other record as is. Fails if any replaced value is invalid.
+    pub(crate) fn write_exth(
+        &self,
+        exth: &mut Exth,
+        uid: u32,
+        fallback_language: Option<&str>,
+    ) -> Result<(), MetadataError> {
+        let options = ParseOptions { strict: false };
+        let current =
+            Metadata::from_exth(self.title.clone(), exth, &mut Diagnostics::new(&options));
+
+        let uuid = self
+            .identifiers
+            .uuid
+            .clone()
+            .unwrap_or_else(|| uuid_from_uid(uid));
+        let asin = self
+            .identifiers
+            .asin
+            .clone()
+            .unwrap_or_else(|| uuid.clone());
+
+        if exth.string(MetadataId::CdeType).is_none() {
+            exth.set_strings(MetadataId::CdeType, ["EBOK".to_string()]);
+        }
+        if current.identifiers.asin.as_ref() != Some(&asin) {
+            exth.set_strings(MetadataId::CDEContentKey, [asin.clone()]);
+            exth.set_asin(Some(asin))?;
+        }
+        // A source that isn't a UUID is kept unless a UUID is set
+        let has_source = exth.get(MetadataId::Source.into()).next().is_some();
+        if current.identifiers.uuid.as_ref() != Some(&uuid)
+            && (self.identifiers.uuid.is_some() || !has_source)
+        {
+            exth.set_strings(MetadataId::Source, [format!("urn:uuid:{}", uuid)]);
+        }
+        if exth.string(MetadataId::UpdatedTitle) != Some(self.title.as_str()) {
+            exth.set_strings(MetadataId::UpdatedTitle, [self.title.clone()]);
+        }
+        if current.authors != self.authors {
+            exth.set_authors(self.authors.clone())?;
+        }
+        if current.publisher != self.publisher {
+            exth.set_publisher(self.publisher.clone())?;
+        }
+        if current.published != self.published {
+            exth.set_published(self.published)?;
+        }
+        let language = self.language.as_deref().or(fallback_language);
+        if current.language.as_deref() != language {
+            exth.set_languages(language.map(str::to_string).into_iter().collect())?;
+        }
+        if current.identifiers.isbn != self.identifiers.isbn {
+            exth.set_isbn(self.identifiers.isbn.clone())?;
+        }
+        if current.description != self.description {
+            exth.set_description(self.description.clone())?;
+        }
+        if current.subjects != self.subjects {
+            exth.set_subjects(self.subjects.clone())?;
+        }
+        if exth.string(MetadataId::OverrideKindleFonts).is_none() {
+            exth.set_strings(MetadataId::OverrideKindleFonts, ["true".to_string()]);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::MetadataIdValue;
+    use pretty_assertions::assert_eq;
+    use proptest::{arbitrary::any, proptest};
+
+    proptest! {
+        #[test]
+        fn test_uuid_from_uid(uid in any::<u32>()) {
+            let uuid = uuid_from_uid(uid);
+            assert_eq!(uuid, uuid_from_uid(uid));
+            assert_eq!(uuid.len(), 36);
+            assert_eq!(&uuid[14..15], "8");
+            assert!(matches!(&uuid[19..20], "8" | "9" | "a" | "b"));
+        }
+    }
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let metadata = Metadata {
+            title: "War and Peace".to_string(),
+            authors: vec!["Leo Tolstoy".to_string()],
+            publisher: Some("Standard Ebooks".to_string()),
+            published: Date::new(2020, 7, 28),
+            language: Some("en".to_string()),
+            identifiers: Identifiers {
+                isbn: Some("978-0-14-044793-4".to_string()),
+                asin: Some("B000FC0PDA".to_string()),
+                uuid: Some("7d3b6e1a-2f4c-4b8e-9a61-0c5d2e8f3b94".to_string()),
+            },
+            description: Some("The story of five families".to_string()),
+            subjects: vec!["Historical fiction".to_string(), "War stories".to_string()],
+            series: None,
+        };
+
+        let mut exth = Exth::default();
+        metadata.write_exth(&mut exth, 1, None).unwrap();
+        let options = ParseOptions::default();
+        let mut diagnostics = Diagnostics::new(&options);
+        let parsed = Metadata::from_exth(metadata.title.clone(), &exth, &mut diagnostics);
+        assert_eq!(parsed, metadata);
+        assert!(diagnostics.into_warnings().is_empty());
+    }
+
+    #[test]
+    fn test_generated_identifiers() {
+        let metadata = Metadata {
+            title: "Untitled".to_string(),
+            ..Default::default()
+        };
+
+        let mut exth = Exth::default();
+        metadata.write_exth(&mut exth, 42, Some("en-US")).unwrap();
+        let uuid = uuid_from_uid(42);
+        assert_eq!(exth.asin(), Some(uuid.as_str()));
+        assert_eq!(
+            exth.string(MetadataId::Source),
+            Some(format!("urn:uuid:{}", uuid).as_str())
+        );
+        assert_eq!(exth.languages(), ["en-US"]);
+        assert_ne!(uuid, uuid_from_uid(43));
+
+        let options = ParseOptions::default();
+        let mut diagnostics = Diagnostics::new(&options);
+        let parsed = Metadata::from_exth(metadata.title.clone(), &exth, &mut diagnostics);
+        assert_eq!(parsed.identifiers.uuid, Some(uuid));
+    }
+
+    #[test]
+    fn test_write_keeps_other_records() {
+        let mut exth = Exth::default();
+        exth.set_strings(MetadataId::Contributor, ["calibre (5.0)".to_string()]);
+        exth.set_strings(
+            MetadataId::Published,
+            ["2020-07-28T21:53:09+00:00".to_string()],
+        );
+        exth.set_strings(MetadataId::Source, ["calibre:1234".to_string()]);
+        exth.set_values(MetadataIdValue::CreatorSoftware, [201]);
+        exth.set(999, [vec![0xff, 0]]);
+        let original = exth.clone();
+
+        let options = ParseOptions::default();
+        let mut diagnostics = Diagnostics::new(&options);
+        let metadata = Metadata::from_exth("Untitled".to_string(), &exth, &mut diagnostics);
+        metadata.write_exth(&mut exth, 1, None).unwrap();
+        for record in &original.records {
+            assert!(exth.records.contains(record), "{:?} was dropped", record);
+        }
+
+        let metadata = Metadata {
+            published: Date::new(2021, 1, 1),
+            ..metadata
+        };
+        metadata.write_exth(&mut exth, 1, None).unwrap();
+        assert_eq!(exth.string(MetadataId::Published), Some("2021-01-01"));
+        assert_eq!(exth.get(999).next(), Some(&[0xff, 0][..]));
+    }
+
+    #[test]
+    fn test_rejects_invalid_metadata() {
+        let metadata = Metadata {
+            title: "Untitled".to_string(),
+            identifiers: Identifiers {
+                isbn: Some("123".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(
+            metadata.write_exth(&mut Exth::default(), 1, None),
+            Err(MetadataError::InvalidIsbn("123".to_string()))
+        );
+    }
+}
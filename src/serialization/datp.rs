@@ -0,0 +1,101 @@
+//! The `DATP` record, which retail books carry but whose layout isn't documented.
+//!
+//! Past the magic and the header length, nothing about it is known, so the header and body aren't decoded further. It's kept byte for byte so that rewriting a book doesn't drop it.
+
+use deku::DekuError;
+#[cfg(test)]
+use proptest_derive::Arbitrary;
+
+const MIN_HEADER_LEN: usize = 8;
+
+#[derive(Debug, PartialEq, Clone, Default)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct DatpRecord {
+    /// The whole record, starting with the `DATP` magic and a header length that `parse` checked to be in bounds.
+    #[cfg_attr(test, proptest(strategy = "arbitrary_record()"))]
+    record: Vec<u8>,
+}
+
+#[cfg(test)]
+fn arbitrary_record() -> impl proptest::strategy::Strategy<Value = Vec<u8>> {
+    use proptest::{arbitrary::any, collection::vec, strategy::Strategy};
+
+    vec(any::<u8>(), 0..256).prop_map(|rest| {
+        let header_len = MIN_HEADER_LEN + rest.len() / 2;
+        [b"DATP", &(header_len as u32).to_be_bytes()[..], &rest].concat()
+    })
+}
+
+impl DatpRecord {
+    /// Only checks the magic and that the header length is in bounds.
+    pub fn parse(record: &[u8]) -> Result<Self, DekuError> {
+        let invalid =
+            |reason: &str| DekuError::Parse(format!("Invalid DATP record: {}", reason).into());
+
+        if !record.starts_with(b"DATP") {
+            return Err(invalid("missing DATP magic"));
+        }
+        let header_len = record
+            .get(4..8)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+            .ok_or_else(|| invalid("truncated header"))?;
+        if header_len < MIN_HEADER_LEN || header_len > record.len() {
+            return Err(invalid(&format!(
+                "header length {} is out of bounds",
+                header_len
+            )));
+        }
+
+        Ok(DatpRecord {
+            record: record.to_vec(),
+        })
+    }
+
+    /// The length of the header, including the magic and the length itself.
+    pub fn header_len(&self) -> usize {
+        u32::from_be_bytes(self.record[4..8].try_into().unwrap()) as usize
+    }
+
+    /// The header, starting with the `DATP` magic.
+    pub fn header(&self) -> &[u8] {
+        &self.record[..self.header_len()]
+    }
+
+    /// Everything after the header.
+    pub fn body(&self) -> &[u8] {
+        &self.record[self.header_len()..]
+    }
+
+    pub fn to_record(&self) -> Vec<u8> {
+        self.record.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use proptest::{arbitrary::any, proptest};
+
+    proptest! {
+        #[test]
+        fn test_datp_roundtrip(datp in any::<DatpRecord>()) {
+            assert_eq!(DatpRecord::parse(&datp.to_record()).unwrap(), datp);
+        }
+    }
+
+    #[test]
+    fn test_parse_datp() {
+        let record = b"DATP\0\0\0\x0d\0\0\0\x01\0\xff\xff";
+        let datp = DatpRecord::parse(record).unwrap();
+        assert_eq!(datp.header_len(), 13);
+        assert_eq!(datp.header(), b"DATP\0\0\0\x0d\0\0\0\x01\0");
+        assert_eq!(datp.body(), b"\xff\xff");
+        assert_eq!(datp.to_record(), record);
+
+        assert!(DatpRecord::parse(b"DATP").is_err());
+        assert!(DatpRecord::parse(b"DATP\0\0\0\x06\0\0").is_err());
+        assert!(DatpRecord::parse(b"DATP\0\0\0\x40").is_err());
+        assert!(DatpRecord::parse(b"PTAD\0\0\0\x08").is_err());
+    }
+}
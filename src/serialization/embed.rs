@@ -0,0 +1,225 @@
+//! `kindle:embed:XXXX?mime=...` references, which point to resource records.
+//!
+//! The index counts every record from `first_resource_record`, not only images, so fonts, placeholders and other records in between are counted too.
+
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::utils::{base32, kindle_reference};
+
+use super::{font_record::read_font_record, resource_container::PLACEHOLDER, PalmDoc};
+
+const PREFIX: &str = "kindle:embed:";
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum EmbedError {
+    #[error("Record {record} is past the last resource record")]
+    MissingRecord { record: usize },
+    #[error("Record {record} is {actual}, but the reference expects {expected}")]
+    MimeMismatch {
+        record: usize,
+        expected: String,
+        actual: String,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EmbedReference {
+    /// Index of the record, relative to `first_resource_record`.
+    pub record: usize,
+    /// The `mime` hint, e.g. `image/jpeg`.
+    pub mime_type: Option<String>,
+}
+
+/// Treats `image/jpg` as `image/jpeg`, which some converters write.
+fn same_mime_type(expected: &str, actual: &str) -> bool {
+    let normalize = |mime_type: &str| match mime_type.to_ascii_lowercase().as_str() {
+        "image/jpg" => "image/jpeg".to_string(),
+        mime_type => mime_type.to_string(),
+    };
+    normalize(expected) == normalize(actual)
+}
+
+fn is_font_mime_type(mime_type: &str) -> bool {
+    let mime_type = mime_type.to_ascii_lowercase();
+    ["font", "opentype", "truetype", "woff"]
+        .iter()
+        .any(|kind| mime_type.contains(kind))
+}
+
+impl EmbedReference {
+    pub fn new(record: usize, mime_type: Option<String>) -> Self {
+        EmbedReference { record, mime_type }
+    }
+
+    /// Parses a whole reference, like the value of a `src` attribute.
+    pub fn parse(reference: &str) -> Option<Self> {
+        let (index, mime_type) = kindle_reference::parse(reference, PREFIX)?;
+        let record = index.checked_sub(1)? as usize;
+        Some(EmbedReference { record, mime_type })
+    }
+
+    /// Index of the record in the PalmDoc.
+    pub fn record_index(&self, first_resource_record: u32) -> usize {
+        first_resource_record as usize + self.record
+    }
+
+    /// Finds the record this points to, checking it against the `mime` hint if there's one. Fonts are returned as the `FONT` record.
+    pub fn resolve<'a>(
+        &self,
+        palmdoc: &'a PalmDoc,
+        first_resource_record: u32,
+    ) -> Result<&'a [u8], EmbedError> {
+        let data = palmdoc
+            .records
+            .get(self.record_index(first_resource_record))
+            .ok_or(EmbedError::MissingRecord {
+                record: self.record,
+            })?;
+
+        let Some(expected) = &self.mime_type else {
+            return Ok(data);
+        };
+        let actual = if data.starts_with(b"FONT") {
+            let is_font = read_font_record(data).is_ok() && is_font_mime_type(expected);
+            if is_font {
+                return Ok(data);
+            }
+            "a font".to_string()
+        } else if data.starts_with(&PLACEHOLDER) {
+            "a placeholder".to_string()
+        } else {
+            match infer::get(data) {
+                Some(kind) if same_mime_type(expected, kind.mime_type()) => return Ok(data),
+                Some(kind) => kind.mime_type().to_string(),
+                None => "unknown data".to_string(),
+            }
+        };
+
+        Err(EmbedError::MimeMismatch {
+            record: self.record,
+            expected: expected.clone(),
+            actual,
+        })
+    }
+}
+
+impl fmt::Display for EmbedReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", PREFIX, base32::encode(self.record as u32 + 1, 4))?;
+        if let Some(mime_type) = &self.mime_type {
+            write!(f, "?mime={}", mime_type)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replaces every `kindle:embed` reference in `text` with the result of `map`. References that `map` returns `None` for are kept as is.
+pub(crate) fn rewrite_embeds(
+    text: &str,
+    map: impl Fn(&EmbedReference) -> Option<EmbedReference>,
+) -> String {
+    let mut rewritten = String::with_capacity(text.len());
+    let mut copied = 0;
+    for (start, reference) in kindle_reference::find_all(text, PREFIX) {
+        rewritten.push_str(&text[copied..start]);
+        match EmbedReference::parse(reference).and_then(|reference| map(&reference)) {
+            Some(replacement) => rewritten.push_str(&replacement.to_string()),
+            None => rewritten.push_str(reference),
+        }
+        copied = start + reference.len();
+    }
+    rewritten.push_str(&text[copied..]);
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::font_record::write_font_record;
+    use pretty_assertions::assert_eq;
+    use proptest::{option, prop_assert_eq, proptest};
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const JPEG: &[u8] = b"\xff\xd8\xff\xe0\0\x10JFIF\0";
+
+    proptest! {
+        #[test]
+        fn test_embed_roundtrip(record in 0..1_000_000usize, mime_type in option::of("[a-z]{1,10}/[a-z+-]{1,10}")) {
+            let reference = EmbedReference::new(record, mime_type);
+            prop_assert_eq!(EmbedReference::parse(&reference.to_string()), Some(reference));
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            EmbedReference::parse("kindle:embed:000A?mime=image/jpg"),
+            Some(EmbedReference::new(9, Some("image/jpg".to_string())))
+        );
+        assert_eq!(
+            EmbedReference::parse("kindle:embed:0001"),
+            Some(EmbedReference::new(0, None))
+        );
+        assert_eq!(EmbedReference::parse("kindle:embed:0000"), None);
+        assert_eq!(
+            EmbedReference::parse("kindle:flow:0001?mime=text/css"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let palmdoc = PalmDoc {
+            title: "test".to_string(),
+            created_at: 0,
+            modified_at: 0,
+            last_backed_up_at: 0,
+            records: vec![
+                vec![],
+                JPEG.to_vec(),
+                write_font_record(b"\x00\x01\x00\x00\x00\x0c"),
+                PLACEHOLDER.to_vec(),
+                PNG.to_vec(),
+            ],
+        };
+        let resolve = |reference: &str| {
+            EmbedReference::parse(reference)
+                .unwrap()
+                .resolve(&palmdoc, 1)
+        };
+
+        assert_eq!(resolve("kindle:embed:0001?mime=image/jpg"), Ok(JPEG));
+        assert!(resolve("kindle:embed:0002?mime=application/x-font-ttf").is_ok());
+        // The image after the font and placeholder is the fourth record, not the second image
+        assert_eq!(resolve("kindle:embed:0004?mime=image/png"), Ok(PNG));
+        assert_eq!(resolve("kindle:embed:0004"), Ok(PNG));
+        assert_eq!(
+            resolve("kindle:embed:0003?mime=image/png"),
+            Err(EmbedError::MimeMismatch {
+                record: 2,
+                expected: "image/png".to_string(),
+                actual: "a placeholder".to_string(),
+            })
+        );
+        assert_eq!(
+            resolve("kindle:embed:0005"),
+            Err(EmbedError::MissingRecord { record: 4 })
+        );
+    }
+
+    #[test]
+    fn test_rewrite_embeds() {
+        let text = r#"<img src="kindle:embed:0004?mime=image/png"/> url(kindle:embed:0002) kindle:embed:0009"#;
+        let rewritten = rewrite_embeds(text, |reference| match reference.record {
+            3 => Some(EmbedReference::new(1, reference.mime_type.clone())),
+            1 => Some(EmbedReference::new(0, None)),
+            _ => None,
+        });
+        assert_eq!(
+            rewritten,
+            r#"<img src="kindle:embed:0002?mime=image/png"/> url(kindle:embed:0001) kindle:embed:0009"#
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use deku::DekuError;
+
+use crate::constants::{MetadataId, MetadataIdValue};
+
+use super::{decode_value, read::read_exth_record_ref, Exth, ExthRecord};
+
+/// EXTH records borrowed from the header record, see `PalmDocRef::exth`. Lookups walk the records instead of copying them like `Exth` does.
+#[derive(Debug, Clone, Copy)]
+pub struct ExthRef<'a> {
+    /// The records, after the tag, length and record count.
+    data: &'a [u8],
+    count: usize,
+}
+
+impl<'a> ExthRef<'a> {
+    /// Parses the EXTH block at the start of `data`, checking that every record is in bounds.
+    pub fn parse(data: &'a [u8]) -> Result<Self, DekuError> {
+        let invalid = |reason: &str| DekuError::Parse(format!("Invalid EXTH: {}", reason).into());
+
+        if !data.starts_with(b"EXTH") {
+            return Err(invalid("missing EXTH tag"));
+        }
+        let header = data.get(4..12).ok_or_else(|| invalid("truncated header"))?;
+        // The length includes the tag and the length field itself.
+        let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let count = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+        let records = data
+            .get(12..len.max(12))
+            .ok_or_else(|| invalid("length is out of bounds"))?;
+
+        let mut rest = records;
+        for _ in 0..count {
+            (rest, _) =
+                read_exth_record_ref(rest).map_err(|_| invalid("record is out of bounds"))?;
+        }
+
+        Ok(ExthRef {
+            data: records,
+            count,
+        })
+    }
+
+    /// Every record in file order, as `(id, data)`.
+    pub fn records(&self) -> impl Iterator<Item = (u32, &'a [u8])> {
+        let mut rest = self.data;
+        (0..self.count).map_while(move |_| {
+            let (next, record) = read_exth_record_ref(rest).ok()?;
+            rest = next;
+            Some(record)
+        })
+    }
+
+    pub fn get(&self, id: u32) -> impl Iterator<Item = &'a [u8]> {
+        self.records()
+            .filter(move |(record_id, _)| *record_id == id)
+            .map(|(_, data)| data)
+    }
+
+    /// Returns the string records with this id. Records that aren't valid UTF-8 are skipped.
+    pub fn strings(&self, id: MetadataId) -> impl Iterator<Item = &'a str> {
+        self.get(id.into())
+            .filter_map(|data| std::str::from_utf8(data).ok())
+    }
+
+    pub fn string(&self, id: MetadataId) -> Option<&'a str> {
+        self.strings(id).next()
+    }
+
+    /// Returns the numeric records with this id. Records that aren't 1, 2 or 4 bytes long are skipped.
+    pub fn values(&self, id: MetadataIdValue) -> impl Iterator<Item = u32> + 'a {
+        self.get(id.into()).filter_map(decode_value)
+    }
+
+    pub fn value(&self, id: MetadataIdValue) -> Option<u32> {
+        self.values(id).next()
+    }
+
+    /// Copies the records into an owned `Exth`.
+    pub fn to_exth(&self) -> Exth {
+        Exth {
+            records: self
+                .records()
+                .map(|(id, data)| ExthRecord {
+                    id,
+                    data: data.to_vec(),
+                })
+                .collect(),
+        }
+    }
+}
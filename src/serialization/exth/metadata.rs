@@ -0,0 +1,346 @@
+use std::{fmt, str::FromStr};
+
+use thiserror::Error;
+
+#[cfg(test)]
+use proptest_derive::Arbitrary;
+
+use super::Exth;
+use crate::constants::{MetadataId, MetadataIdValue};
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum MetadataError {
+    #[error("{0:?} can't be empty")]
+    Empty(MetadataId),
+    #[error("{0:?} is not a valid ISBN-10 or ISBN-13")]
+    InvalidIsbn(String),
+    #[error("{0:?} is not a valid date")]
+    InvalidDate(String),
+    #[error("{0:?} is not a valid BCP 47 language tag")]
+    InvalidLanguageTag(String),
+    #[error("{0} is reserved to mean that there is no cover")]
+    InvalidCoverOffset(u32),
+}
+
+/// A calendar date, as stored in `MetadataId::Published`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl Date {
+    pub fn new(year: u16, month: u8, day: u8) -> Option<Self> {
+        let is_leap_year =
+            year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+        let days_in_month = match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if is_leap_year => 29,
+            2 => 28,
+            _ => return None,
+        };
+
+        (1..=days_in_month)
+            .contains(&day)
+            .then_some(Self { year, month, day })
+    }
+}
+
+impl FromStr for Date {
+    type Err = MetadataError;
+
+    /// Parses the date at the start of an ISO 8601 timestamp (`2024-08-13` or `2024-08-13T04:05:03+00:00`). The time is ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || MetadataError::InvalidDate(s.to_string());
+
+        let date = s.split_once('T').map_or(s, |(date, _)| date);
+        let mut fields = date.splitn(3, '-');
+        let mut next = |len: usize| {
+            fields
+                .next()
+                .filter(|field| field.len() == len && field.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|field| field.parse::<u16>().ok())
+                .ok_or_else(invalid)
+        };
+
+        let year = next(4)?;
+        let month = next(2)?;
+        let day = next(2)?;
+        Date::new(year, month as u8, day as u8).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+fn is_valid_isbn(isbn: &str) -> bool {
+    let digits = isbn
+        .chars()
+        .filter(|c| *c != '-' && *c != ' ')
+        .collect::<Vec<_>>();
+
+    match digits.len() {
+        10 => {
+            let mut sum = 0;
+            for (i, c) in digits.iter().enumerate() {
+                let value = match c {
+                    '0'..='9' => c.to_digit(10).unwrap(),
+                    'X' | 'x' if i == 9 => 10,
+                    _ => return false,
+                };
+                sum += value * (10 - i as u32);
+            }
+            sum % 11 == 0
+        }
+        13 => {
+            let mut sum = 0;
+            for (i, c) in digits.iter().enumerate() {
+                let Some(value) = c.to_digit(10) else {
+                    return false;
+                };
+                sum += if i % 2 == 0 { value } else { value * 3 };
+            }
+            sum % 10 == 0
+        }
+        _ => false,
+    }
+}
+
+/// Checks the syntax of a BCP 47 tag (e.g. `en`, `en-US`, `zh-Hant-TW`), without checking that its subtags are registered.
+fn is_valid_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let primary_is_valid = subtags.next().is_some_and(|primary| {
+        matches!(primary.len(), 2..=3 | 5..=8) && primary.bytes().all(|b| b.is_ascii_alphabetic())
+    });
+
+    primary_is_valid
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+        })
+}
+
+/// Typed access to well-known records. The setters validate their input, and replace every existing record with the same id.
+impl Exth {
+    fn set_non_empty_strings(
+        &mut self,
+        id: MetadataId,
+        values: Vec<String>,
+    ) -> Result<(), MetadataError> {
+        if values.iter().any(|value| value.trim().is_empty()) {
+            return Err(MetadataError::Empty(id));
+        }
+
+        self.set_strings(id, values);
+        Ok(())
+    }
+
+    fn set_string(&mut self, id: MetadataId, value: Option<String>) -> Result<(), MetadataError> {
+        self.set_non_empty_strings(id, value.into_iter().collect())
+    }
+
+    pub fn authors(&self) -> Vec<&str> {
+        self.strings(MetadataId::Creator).collect()
+    }
+
+    pub fn set_authors(&mut self, authors: Vec<String>) -> Result<(), MetadataError> {
+        self.set_non_empty_strings(MetadataId::Creator, authors)
+    }
+
+    pub fn publisher(&self) -> Option<&str> {
+        self.string(MetadataId::Publisher)
+    }
+
+    pub fn set_publisher(&mut self, publisher: Option<String>) -> Result<(), MetadataError> {
+        self.set_string(MetadataId::Publisher, publisher)
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.string(MetadataId::Description)
+    }
+
+    pub fn set_description(&mut self, description: Option<String>) -> Result<(), MetadataError> {
+        self.set_string(MetadataId::Description, description)
+    }
+
+    pub fn isbn(&self) -> Option<&str> {
+        self.string(MetadataId::ISBN)
+    }
+
+    /// Accepts ISBN-10 and ISBN-13, with or without hyphens, as long as the check digit is correct.
+    pub fn set_isbn(&mut self, isbn: Option<String>) -> Result<(), MetadataError> {
+        if let Some(isbn) = &isbn {
+            if !is_valid_isbn(isbn) {
+                return Err(MetadataError::InvalidIsbn(isbn.clone()));
+            }
+        }
+
+        self.set_string(MetadataId::ISBN, isbn)
+    }
+
+    pub fn subjects(&self) -> Vec<&str> {
+        self.strings(MetadataId::Subject).collect()
+    }
+
+    pub fn set_subjects(&mut self, subjects: Vec<String>) -> Result<(), MetadataError> {
+        self.set_non_empty_strings(MetadataId::Subject, subjects)
+    }
+
+    /// Fails if the record exists but doesn't start with an ISO 8601 date.
+    pub fn published(&self) -> Result<Option<Date>, MetadataError> {
+        self.string(MetadataId::Published)
+            .map(Date::from_str)
+            .transpose()
+    }
+
+    pub fn set_published(&mut self, published: Option<Date>) -> Result<(), MetadataError> {
+        self.set_string(
+            MetadataId::Published,
+            published.map(|date| date.to_string()),
+        )
+    }
+
+    pub fn languages(&self) -> Vec<&str> {
+        self.strings(MetadataId::ContentLanguageTag).collect()
+    }
+
+    pub fn set_languages(&mut self, languages: Vec<String>) -> Result<(), MetadataError> {
+        if let Some(invalid) = languages.iter().find(|tag| !is_valid_language_tag(tag)) {
+            return Err(MetadataError::InvalidLanguageTag(invalid.clone()));
+        }
+
+        self.set_non_empty_strings(MetadataId::ContentLanguageTag, languages)
+    }
+
+    pub fn rights(&self) -> Option<&str> {
+        self.string(MetadataId::Rights)
+    }
+
+    pub fn set_rights(&mut self, rights: Option<String>) -> Result<(), MetadataError> {
+        self.set_string(MetadataId::Rights, rights)
+    }
+
+    pub fn asin(&self) -> Option<&str> {
+        self.string(MetadataId::ASIN)
+    }
+
+    pub fn set_asin(&mut self, asin: Option<String>) -> Result<(), MetadataError> {
+        self.set_string(MetadataId::ASIN, asin)
+    }
+
+    /// Index of the cover image, relative to the first resource record.
+    pub fn cover_offset(&self) -> Option<u32> {
+        self.value(MetadataIdValue::CoverOffset)
+            .filter(|offset| *offset != u32::MAX)
+    }
+
+    pub fn set_cover_offset(&mut self, offset: Option<u32>) -> Result<(), MetadataError> {
+        match offset {
+            Some(u32::MAX) => return Err(MetadataError::InvalidCoverOffset(u32::MAX)),
+            Some(offset) => self.set_values(MetadataIdValue::CoverOffset, [offset]),
+            None => self.remove(MetadataIdValue::CoverOffset.into()),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use binrw::BinRead;
+    use deku::DekuContainerRead;
+
+    use super::*;
+    use crate::serialization::{MobiHeader, PalmDoc};
+    use pretty_assertions::assert_eq;
+    use proptest::proptest;
+
+    proptest! {
+        #[test]
+        fn test_date_roundtrip(year in 0..10000u16, month in 1..=12u8, day in 1..=31u8) {
+            if let Some(date) = Date::new(year, month, day) {
+                assert_eq!(date.to_string().parse::<Date>(), Ok(date));
+            }
+        }
+    }
+
+    #[test]
+    fn test_date() {
+        assert_eq!(
+            "2024-08-13T04:05:03.140745+00:00".parse::<Date>(),
+            Ok(Date {
+                year: 2024,
+                month: 8,
+                day: 13
+            })
+        );
+        assert!("2023-02-29".parse::<Date>().is_err());
+        assert!("2024-2-1".parse::<Date>().is_err());
+        assert!("August 2024".parse::<Date>().is_err());
+        assert!(Date::new(2024, 2, 29).is_some());
+        assert!(Date::new(1900, 2, 29).is_none());
+    }
+
+    #[test]
+    fn test_fixture_metadata() {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+        let mobi_header = MobiHeader::read(&mut Cursor::new(&palmdoc.records[0])).unwrap();
+        let exth = mobi_header.exth.unwrap();
+
+        assert_eq!(exth.authors(), ["Leo Tolstoy"]);
+        assert_eq!(exth.publisher(), Some("Standard Ebooks"));
+        assert_eq!(exth.published(), Ok(Date::new(2020, 7, 28)));
+        assert_eq!(exth.languages(), ["en"]);
+        assert_eq!(exth.cover_offset(), Some(0));
+    }
+
+    #[test]
+    fn test_setters_validate() {
+        let mut exth = Exth::default();
+
+        assert!(exth.set_isbn(Some("978-0-14-044793-4".to_string())).is_ok());
+        assert!(exth.set_isbn(Some("0-14-044793-X".to_string())).is_err());
+        assert!(exth.set_isbn(Some("0-8044-2957-X".to_string())).is_ok());
+        assert!(exth
+            .set_isbn(Some("978-0-14-044793-5".to_string()))
+            .is_err());
+        assert_eq!(exth.isbn(), Some("0-8044-2957-X"));
+
+        assert!(exth
+            .set_languages(vec!["en".to_string(), "zh-Hant-TW".to_string()])
+            .is_ok());
+        assert!(exth.set_languages(vec!["e".to_string()]).is_err());
+        assert!(exth.set_languages(vec!["en_US".to_string()]).is_err());
+
+        assert_eq!(
+            exth.set_authors(vec!["Leo Tolstoy".to_string(), " ".to_string()]),
+            Err(MetadataError::Empty(MetadataId::Creator))
+        );
+        assert!(exth.set_cover_offset(Some(u32::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_setters_update_maps() {
+        let mut exth = Exth::default();
+
+        exth.set_authors(vec!["Leo Tolstoy".to_string()]).unwrap();
+        exth.set_published(Date::new(1869, 1, 1)).unwrap();
+        exth.set_cover_offset(Some(0)).unwrap();
+        assert_eq!(exth.string(MetadataId::Published), Some("1869-01-01"));
+        assert_eq!(exth.value(MetadataIdValue::CoverOffset), Some(0));
+        assert_eq!(exth.authors(), ["Leo Tolstoy"]);
+        assert_eq!(exth.published(), Ok(Date::new(1869, 1, 1)));
+
+        exth.set_authors(vec![]).unwrap();
+        exth.set_published(None).unwrap();
+        exth.set_cover_offset(None).unwrap();
+        assert_eq!(exth, Exth::default());
+    }
+}
@@ -1,8 +1,5 @@
 use crate::constants::{MetadataId, MetadataIdValue};
-use std::{
-    collections::BTreeMap,
-    io::{ErrorKind, Read, Write},
-};
+use std::io::{ErrorKind, Read, Write};
 
 use cookie_factory::gen;
 use deku::{reader::Reader, writer::Writer, DekuError, DekuReader, DekuWriter};
@@ -11,28 +8,101 @@ use proptest::prelude::*;
 #[cfg(test)]
 use proptest_derive::Arbitrary;
 
+mod exth_ref;
+mod metadata;
 mod read;
 mod write;
 
-#[derive(Debug, PartialEq, Clone, Default)]
+pub use exth_ref::ExthRef;
+pub use metadata::{Date, MetadataError};
+
+#[derive(Debug, PartialEq, Clone)]
 #[cfg_attr(test, derive(Arbitrary))]
-pub struct Exth {
-    // Manually specifying strategies so that each Vec in the hashmap has at least one element.
-    // 32 and 64 are fairly arbitrary.
+pub struct ExthRecord {
+    pub id: u32,
     #[cfg_attr(
         test,
-        proptest(
-            strategy = "proptest::collection::btree_map(any::<MetadataId>(), proptest::collection::vec(\".*\", 1..32), 1..64)"
-        )
+        proptest(strategy = "proptest::collection::vec(any::<u8>(), 0..64)")
     )]
-    pub metadata_id: BTreeMap<MetadataId, Vec<String>>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct Exth {
+    /// Every record in file order, including duplicates and ids this crate doesn't know about.
     #[cfg_attr(
         test,
-        proptest(
-            strategy = "proptest::collection::btree_map(any::<MetadataIdValue>(), proptest::collection::vec(0..u32::MAX, 1..32), 1..64)"
-        )
+        proptest(strategy = "proptest::collection::vec(any::<ExthRecord>(), 0..64)")
     )]
-    pub metadata_value: BTreeMap<MetadataIdValue, Vec<u32>>,
+    pub records: Vec<ExthRecord>,
+}
+
+/// Numeric records are always written as 4 bytes, as Kindle readers ignore narrower ones.
+fn encode_value(value: u32) -> Vec<u8> {
+    value.to_be_bytes().to_vec()
+}
+
+/// Older writers sometimes used 1 or 2 bytes, so those are accepted too.
+fn decode_value(data: &[u8]) -> Option<u32> {
+    match *data {
+        [a] => Some(a as u32),
+        [a, b] => Some(u16::from_be_bytes([a, b]) as u32),
+        [a, b, c, d] => Some(u32::from_be_bytes([a, b, c, d])),
+        _ => None,
+    }
+}
+
+impl Exth {
+    pub fn get(&self, id: u32) -> impl Iterator<Item = &[u8]> {
+        self.records
+            .iter()
+            .filter(move |record| record.id == id)
+            .map(|record| record.data.as_slice())
+    }
+
+    /// Returns the string records with this id. Records that aren't valid UTF-8 are skipped.
+    pub fn strings(&self, id: MetadataId) -> impl Iterator<Item = &str> {
+        self.get(id.into())
+            .filter_map(|data| std::str::from_utf8(data).ok())
+    }
+
+    pub fn string(&self, id: MetadataId) -> Option<&str> {
+        self.strings(id).next()
+    }
+
+    /// Returns the numeric records with this id. Records that aren't 1, 2 or 4 bytes long are skipped.
+    pub fn values(&self, id: MetadataIdValue) -> impl Iterator<Item = u32> + '_ {
+        self.get(id.into()).filter_map(decode_value)
+    }
+
+    pub fn value(&self, id: MetadataIdValue) -> Option<u32> {
+        self.values(id).next()
+    }
+
+    /// Replaces every record with this id. The new records take the place of the first existing one, or are appended if there was none.
+    pub fn set(&mut self, id: u32, data: impl IntoIterator<Item = Vec<u8>>) {
+        let position = self.records.iter().position(|record| record.id == id);
+        self.remove(id);
+
+        let position = position.unwrap_or(self.records.len());
+        self.records.splice(
+            position..position,
+            data.into_iter().map(|data| ExthRecord { id, data }),
+        );
+    }
+
+    pub fn set_strings(&mut self, id: MetadataId, values: impl IntoIterator<Item = String>) {
+        self.set(id.into(), values.into_iter().map(String::into_bytes));
+    }
+
+    pub fn set_values(&mut self, id: MetadataIdValue, values: impl IntoIterator<Item = u32>) {
+        self.set(id.into(), values.into_iter().map(encode_value));
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.records.retain(|record| record.id != id);
+    }
 }
 
 impl<'a, Ctx> DekuReader<'a, Ctx> for Exth {
@@ -48,33 +118,38 @@ impl<'a, Ctx> DekuReader<'a, Ctx> for Exth {
             ));
         }
 
+        // The length includes the tag and the length field itself.
         let len = u32::from_reader_with_ctx(reader, deku::ctx::Endian::Big)?;
+        let len = len.checked_sub(8).ok_or_else(|| {
+            DekuError::Parse(format!("EXTH length {} is shorter than its header", len).into())
+        })?;
 
-        let mut buf = vec![0; len as usize];
-        reader.read_bytes(len as usize, &mut buf)?;
+        let buf = crate::utils::deku::read_vec(reader, len as usize)?;
 
-        let (_, (metadata_id, metadata_value)) = read::read_exth(&buf).unwrap();
+        let (_, records) = read::read_exth(&buf)
+            .map_err(|e| DekuError::Parse(format!("Could not parse EXTH records: {}", e).into()))?;
 
-        Ok(Exth {
-            metadata_id,
-            metadata_value,
-        })
+        Ok(Exth { records })
     }
 }
 
 impl<Ctx> DekuWriter<Ctx> for Exth {
     fn to_writer<W: Write>(&self, writer: &mut Writer<W>, _ctx: Ctx) -> Result<(), DekuError> {
         let serialized = Vec::new();
-        let (serialized, _) = gen(write::write_exth(&self), serialized)
+        let (serialized, _) = gen(write::write_exth(self), serialized)
             .map_err(|_| DekuError::Io(ErrorKind::Other))?;
 
         writer.write_bytes(b"EXTH")?;
 
-        let len = serialized.len() as u32 + 16;
+        // The length covers the tag, itself, the record count and the records, but not the padding
+        let len = serialized.len() as u32 + 8;
         len.to_writer(writer, deku::ctx::Endian::Big)?;
 
         writer.write_bytes(&serialized)?;
 
+        let padding = (4 - len as usize % 4) % 4;
+        writer.write_bytes(&[0; 3][..padding])?;
+
         Ok(())
     }
 }
@@ -84,6 +159,11 @@ mod tests {
     use std::io::Cursor;
 
     use super::*;
+    use crate::serialization::{
+        book_metadata::uuid_from_uid, Book, BookWriter, Identifiers, Metadata, PalmDoc,
+        ParseOptions,
+    };
+    use deku::DekuContainerRead;
     use pretty_assertions::assert_eq;
     use proptest::{arbitrary::any, proptest};
 
@@ -101,5 +181,229 @@ mod tests {
 
         assert_eq!(exth, decoded);
       }
+
+      #[test]
+      fn test_exth_padded(exth in any::<Exth>()) {
+        let mut serialized = Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut serialized);
+        exth.to_writer(&mut writer, ()).unwrap();
+        writer.finalize().unwrap();
+
+        let serialized = serialized.into_inner();
+        let len = u32::from_be_bytes(serialized[4..8].try_into().unwrap()) as usize;
+        assert_eq!(serialized.len() % 4, 0);
+        assert!(serialized.len() - len < 4);
+      }
+
+      #[test]
+      fn test_exth_truncated(exth in any::<Exth>(), cut in 1..64usize) {
+        let mut serialized = Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut serialized);
+        exth.to_writer(&mut writer, ()).unwrap();
+        writer.finalize().unwrap();
+
+        // Cut into the records rather than the padding
+        let mut serialized = serialized.into_inner();
+        let len = u32::from_be_bytes(serialized[4..8].try_into().unwrap()) as usize;
+        serialized.truncate(len.saturating_sub(cut));
+
+        let mut serialized = Cursor::new(serialized);
+        let mut reader = Reader::new(&mut serialized);
+        assert!(Exth::from_reader_with_ctx(&mut reader, ()).is_err());
+      }
+    }
+
+    #[test]
+    fn test_fixture_exth_roundtrip() {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+        let record = &palmdoc.records[0];
+        let start = record.windows(4).position(|w| w == b"EXTH").unwrap();
+        let len = u32::from_be_bytes(record[start + 4..start + 8].try_into().unwrap()) as usize;
+        // Followed by a single byte of padding
+        let original = &record[start..start + len + 1];
+
+        let mut original_cursor = Cursor::new(original);
+        let mut reader = Reader::new(&mut original_cursor);
+        let exth = Exth::from_reader_with_ctx(&mut reader, ()).unwrap();
+        assert_eq!(exth.strings(MetadataId::Subject).count(), 7);
+        assert_eq!(exth.value(MetadataIdValue::EmbeddedRecordCount), Some(4));
+
+        let mut serialized = Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut serialized);
+        exth.to_writer(&mut writer, ()).unwrap();
+        writer.finalize().unwrap();
+        assert_eq!(serialized.into_inner(), original);
+    }
+
+    fn serialize(exth: &Exth) -> Vec<u8> {
+        let mut serialized = Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut serialized);
+        exth.to_writer(&mut writer, ()).unwrap();
+        writer.finalize().unwrap();
+        serialized.into_inner()
+    }
+
+    fn reference_metadata() -> Metadata {
+        Metadata {
+            title: "War and Peace".to_string(),
+            authors: vec!["Leo Tolstoy".to_string()],
+            publisher: Some("Standard Ebooks".to_string()),
+            published: Date::new(2020, 7, 28),
+            language: Some("en".to_string()),
+            identifiers: Identifiers {
+                asin: Some("e13a359e316863f1ad6cbf6f3476f4d6c9eea79f".to_string()),
+                ..Default::default()
+            },
+            description: Some(
+                "The story of five families in Russia during the Napoleonic Wars.".to_string(),
+            ),
+            subjects: [
+                "Historical fiction",
+                "War stories",
+                "Napoleonic Wars",
+                "1800-1815 -- Campaigns -- Russia -- Fiction",
+                "Russia -- History -- Alexander I",
+                "1801-1825 -- Fiction",
+                "Aristocracy (Social class) -- Russia -- Fiction",
+            ]
+            .map(String::from)
+            .to_vec(),
+            series: None,
+        }
+    }
+
+    /// Rewriting the fixture with the metadata it already has leaves its EXTH block unchanged.
+    #[test]
+    fn test_rewrite_matches_reference_exth() {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+        let reference = palmdoc.mobi_header().unwrap().exth.unwrap();
+
+        let (mut book, _) = Book::from_palmdoc(palmdoc, &ParseOptions::default()).unwrap();
+        book.metadata = reference_metadata();
+        let written = BookWriter::reproducible().write(&book).unwrap();
+        let written = written.mobi_header().unwrap().exth.unwrap();
+
+        assert_eq!(serialize(&written), serialize(&reference));
+    }
+
+    /// Writes the fixture's metadata into an empty EXTH. The records calibre also wrote must match the fixture's bytes, and every other record is checked on its own.
+    #[test]
+    fn test_matches_reference_exth() {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+        let reference = palmdoc.mobi_header().unwrap().exth.unwrap();
+
+        let (book, _) = Book::from_palmdoc(palmdoc, &ParseOptions::default()).unwrap();
+        let book = Book {
+            metadata: reference_metadata(),
+            exth: Exth::default(),
+            ..book
+        };
+        let written = BookWriter::reproducible().write(&book).unwrap();
+        let uid = written.mobi_header().unwrap().uid;
+        let written = written.mobi_header().unwrap().exth.unwrap();
+
+        let shared_ids: Vec<u32> = [
+            MetadataId::CdeType,
+            MetadataId::CDEContentKey,
+            MetadataId::ASIN,
+            MetadataId::ContentLanguageTag,
+            MetadataId::UpdatedTitle,
+            MetadataId::Creator,
+            MetadataId::Description,
+            MetadataId::Publisher,
+            MetadataId::Subject,
+            MetadataId::OverrideKindleFonts,
+        ]
+        .map(u32::from)
+        .into_iter()
+        .chain(
+            [
+                MetadataIdValue::CoverOffset,
+                MetadataIdValue::EmbeddedRecordCount,
+            ]
+            .map(u32::from),
+        )
+        .collect();
+        let serialize_shared = |exth: &Exth| {
+            // Record order differs between writers, so records are compared by id
+            let mut records = exth
+                .records
+                .iter()
+                .filter(|record| shared_ids.contains(&record.id))
+                .cloned()
+                .collect::<Vec<_>>();
+            records.sort_by_key(|record| record.id);
+            serialize(&Exth { records })
+        };
+        assert_eq!(serialize_shared(&written), serialize_shared(&reference));
+
+        assert_eq!(written.value(MetadataIdValue::EmbeddedRecordCount), Some(4));
+        assert_eq!(written.value(MetadataIdValue::CoverOffset), Some(0));
+        // Only a book that was read keeps the creator records
+        for id in [
+            MetadataIdValue::CreatorSoftware,
+            MetadataIdValue::CreatorMajorVersion,
+            MetadataIdValue::CreatorMinorVersion,
+            MetadataIdValue::CreatorBuildNumber,
+        ] {
+            assert_eq!(written.value(id), None);
+        }
+        assert_eq!(written.string(MetadataId::Published), Some("2020-07-28"));
+        assert_eq!(
+            written.string(MetadataId::Source),
+            Some(format!("urn:uuid:{}", uuid_from_uid(uid)).as_str())
+        );
+
+        let mut other_ids: Vec<u32> = written
+            .records
+            .iter()
+            .map(|record| record.id)
+            .filter(|id| !shared_ids.contains(id))
+            .collect();
+        other_ids.sort();
+        assert_eq!(
+            other_ids,
+            [MetadataId::Published, MetadataId::Source].map(u32::from)
+        );
+    }
+
+    #[test]
+    fn test_set_replaces_in_place() {
+        let record = |id: u32, data: &[u8]| ExthRecord {
+            id,
+            data: data.to_vec(),
+        };
+        let mut exth = Exth {
+            records: vec![
+                record(542, &[0xff, 0xfe]),
+                record(105, b"War"),
+                record(536, &[0, 1, 2]),
+                record(105, b"Peace"),
+            ],
+        };
+
+        exth.set_strings(MetadataId::Subject, ["Fiction".to_string()]);
+        exth.set_values(MetadataIdValue::CoverOffset, [3]);
+        assert_eq!(
+            exth.records,
+            vec![
+                record(542, &[0xff, 0xfe]),
+                record(105, b"Fiction"),
+                record(536, &[0, 1, 2]),
+                record(201, &[0, 0, 0, 3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exth_length_shorter_than_header() {
+        let serialized = [b"EXTH".as_slice(), &4u32.to_be_bytes(), &0u32.to_be_bytes()].concat();
+
+        let mut serialized = Cursor::new(serialized);
+        let mut reader = Reader::new(&mut serialized);
+        assert!(Exth::from_reader_with_ctx(&mut reader, ()).is_err());
     }
 }
@@ -1,66 +1,46 @@
-use std::collections::BTreeMap;
-
 use nom::{
     bytes::complete::take,
+    error::{make_error, ErrorKind},
     multi::count,
-    number::complete::{be_u16, be_u32, be_u8},
+    number::complete::be_u32,
     IResult,
 };
 
-use crate::constants::{MetadataId, MetadataIdValue};
-
-#[derive(Debug)]
-enum ExthKeyValue {
-    ID(MetadataId, String),
-    Value(MetadataIdValue, u32),
-}
+use super::ExthRecord;
 
-fn read_exth_key_value(input: &[u8]) -> IResult<&[u8], ExthKeyValue> {
-    let (input, id) = nom::number::complete::be_u32(input)?;
+/// A record's id and data, borrowed from `input`.
+pub(super) fn read_exth_record_ref(input: &[u8]) -> IResult<&[u8], (u32, &[u8])> {
+    let (input, id) = be_u32(input)?;
 
     let (input, content_len) = be_u32(input)?;
-    let (input, content) = take(content_len as usize - 8)(input)?;
-
-    if let Ok(id) = MetadataId::try_from(id) {
-        let parsed = String::from_utf8(content.to_vec()).unwrap();
+    // The length includes the id and the length field itself.
+    let content_len = (content_len as usize)
+        .checked_sub(8)
+        .ok_or_else(|| nom::Err::Error(make_error(input, ErrorKind::Verify)))?;
+    let (input, content) = take(content_len)(input)?;
 
-        return Ok((input, ExthKeyValue::ID(id, parsed)));
-    } else if let Ok(id) = MetadataIdValue::try_from(id) {
-        let value: u32 = match content_len {
-            9 => be_u8(content)?.1 as u32,
-            10 => be_u16(content)?.1 as u32,
-            12 => be_u32(content)?.1 as u32,
-            _ => panic!(),
-        };
+    Ok((input, (id, content)))
+}
 
-        return Ok((input, ExthKeyValue::Value(id, value)));
-    }
+fn read_exth_record(input: &[u8]) -> IResult<&[u8], ExthRecord> {
+    let (input, (id, content)) = read_exth_record_ref(input)?;
 
-    // todo: don't panic
-    panic!()
+    Ok((
+        input,
+        ExthRecord {
+            id,
+            data: content.to_vec(),
+        },
+    ))
 }
 
-pub(super) fn read_exth(
-    input: &[u8],
-) -> IResult<
-    &[u8],
-    (
-        BTreeMap<MetadataId, Vec<String>>,
-        BTreeMap<MetadataIdValue, Vec<u32>>,
-    ),
-> {
+pub(super) fn read_exth(input: &[u8]) -> IResult<&[u8], Vec<ExthRecord>> {
     let (input, num_items) = be_u32(input)?;
 
-    let (input, items) = count(read_exth_key_value, num_items as usize)(input)?;
-
-    let mut standard_metadata: BTreeMap<MetadataId, Vec<String>> = BTreeMap::new();
-    let mut kf8_metadata: BTreeMap<MetadataIdValue, Vec<u32>> = BTreeMap::new();
-    for item in items {
-        match item {
-            ExthKeyValue::ID(id, content) => standard_metadata.entry(id).or_default().push(content),
-            ExthKeyValue::Value(id, data) => kf8_metadata.entry(id).or_default().push(data),
-        }
+    // Every record is at least 8 bytes, so don't trust the count for the initial allocation
+    if num_items as usize > input.len() / 8 {
+        return Err(nom::Err::Error(make_error(input, ErrorKind::Count)));
     }
 
-    Ok((input, (standard_metadata, kf8_metadata)))
+    count(read_exth_record, num_items as usize)(input)
 }
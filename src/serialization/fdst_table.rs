@@ -20,7 +20,9 @@ pub struct FDSTTable {
     _unused_len: u32,
     #[deku(temp, temp_value = "entries.len() as u32")]
     num_entries: u32,
-    #[deku(count = "num_entries")]
+    #[deku(
+        reader = "crate::utils::deku::read_counted(deku::reader, *num_entries as usize, deku::ctx::Endian::Big)"
+    )]
     pub entries: Vec<FDSTEntry>,
 }
 
@@ -47,4 +49,18 @@ mod tests {
         assert_eq!(table, decoded);
       }
     }
+
+    #[test]
+    fn test_fdst_table_entry_count_past_end() {
+        let mut serialized = FDSTTable {
+            entries: vec![FDSTEntry { start: 0, end: 10 }],
+        }
+        .to_bytes()
+        .unwrap();
+
+        // Claim far more entries than the record holds
+        serialized[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        assert!(FDSTTable::from_bytes((&serialized, 0)).is_err());
+    }
 }
@@ -0,0 +1,290 @@
+//! Fixed-layout books, like comics and children's books, where every part is a page of a set size.
+//!
+//! The settings are EXTH records. Where each page goes in a spread is a spine property, see `PageSpread`, and the panels that can be zoomed into are marked in the text with `data-app-amzn-magnify`.
+
+#[cfg(test)]
+use proptest_derive::Arbitrary;
+
+use crate::{constants::MetadataId, utils::json};
+
+use super::{
+    book::BookPart,
+    exth::Exth,
+    navigation::{ids, Location},
+    parse_options::{Diagnostics, ParseWarning},
+};
+
+const MAGNIFY_ATTRIBUTE: &str = "data-app-amzn-magnify";
+
+/// The size the pages were designed for, in pixels.
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct Viewport {
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub enum Orientation {
+    Portrait,
+    Landscape,
+    /// Follows the device.
+    Auto,
+}
+
+impl Orientation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Orientation::Portrait => "portrait",
+            Orientation::Landscape => "landscape",
+            Orientation::Auto => "none",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub enum FixedLayoutBookType {
+    Comic,
+    Children,
+    Other(#[cfg_attr(test, proptest(regex = "[a-z]{1,10}-[a-z]{1,10}"))] String),
+}
+
+impl FixedLayoutBookType {
+    fn as_str(&self) -> &str {
+        match self {
+            FixedLayoutBookType::Comic => "comic",
+            FixedLayoutBookType::Children => "children",
+            FixedLayoutBookType::Other(book_type) => book_type,
+        }
+    }
+}
+
+/// Written to the `FixedLayout`, `OriginalResolution`, `OrientationLock`, `BookType`, `RegionMagnification`, `ZeroGutter` and `ZeroMargin` EXTH records.
+#[derive(Debug, PartialEq, Clone, Default)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct FixedLayout {
+    pub viewport: Option<Viewport>,
+    /// `None` leaves it to the reader.
+    pub orientation: Option<Orientation>,
+    pub book_type: Option<FixedLayoutBookType>,
+    /// Whether tapping a panel zooms into its magnification target, see `MagnificationRegion`.
+    pub region_magnification: bool,
+    /// Shows facing pages without a gap between them.
+    pub zero_gutter: bool,
+    /// Shows pages without a margin around them.
+    pub zero_margin: bool,
+}
+
+fn is_true(exth: &Exth, id: MetadataId) -> bool {
+    exth.string(id)
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+fn invalid<T>(diagnostics: &mut Diagnostics, id: MetadataId, value: &str) -> Option<T> {
+    diagnostics.warn(ParseWarning::InvalidMetadata {
+        reason: format!("Invalid {}: {:?}", id, value),
+    });
+    None
+}
+
+impl FixedLayout {
+    /// `None` unless the book is marked as fixed-layout.
+    pub(crate) fn from_exth(exth: &Exth, diagnostics: &mut Diagnostics) -> Option<Self> {
+        if !is_true(exth, MetadataId::FixedLayout) {
+            return None;
+        }
+
+        let viewport = exth
+            .string(MetadataId::OriginalResolution)
+            .and_then(|value| {
+                let size = value.trim().split_once(['x', 'X']).and_then(|(w, h)| {
+                    Some(Viewport {
+                        width: w.trim().parse().ok()?,
+                        height: h.trim().parse().ok()?,
+                    })
+                });
+                size.or_else(|| invalid(diagnostics, MetadataId::OriginalResolution, value))
+            });
+        let orientation = exth.string(MetadataId::OrientationLock).and_then(|value| {
+            match value.trim().to_ascii_lowercase().as_str() {
+                "portrait" => Some(Orientation::Portrait),
+                "landscape" => Some(Orientation::Landscape),
+                "none" => Some(Orientation::Auto),
+                _ => invalid(diagnostics, MetadataId::OrientationLock, value),
+            }
+        });
+        let book_type = exth.string(MetadataId::BookType).map(|value| {
+            match value.trim().to_ascii_lowercase().as_str() {
+                "comic" => FixedLayoutBookType::Comic,
+                "children" => FixedLayoutBookType::Children,
+                _ => FixedLayoutBookType::Other(value.to_string()),
+            }
+        });
+
+        Some(FixedLayout {
+            viewport,
+            orientation,
+            book_type,
+            region_magnification: is_true(exth, MetadataId::RegionMagnification),
+            zero_gutter: is_true(exth, MetadataId::ZeroGutter),
+            zero_margin: is_true(exth, MetadataId::ZeroMargin),
+        })
+    }
+
+    pub(crate) fn write_exth(&self, exth: &mut Exth) {
+        let flag = |value: bool| value.then(|| "true".to_string());
+
+        exth.set_strings(MetadataId::FixedLayout, ["true".to_string()]);
+        exth.set_strings(
+            MetadataId::OriginalResolution,
+            self.viewport
+                .map(|viewport| format!("{}x{}", viewport.width, viewport.height)),
+        );
+        exth.set_strings(
+            MetadataId::OrientationLock,
+            self.orientation
+                .map(|orientation| orientation.as_str().to_string()),
+        );
+        exth.set_strings(
+            MetadataId::BookType,
+            self.book_type
+                .as_ref()
+                .map(|book_type| book_type.as_str().to_string()),
+        );
+        exth.set_strings(
+            MetadataId::RegionMagnification,
+            flag(self.region_magnification),
+        );
+        exth.set_strings(MetadataId::ZeroGutter, flag(self.zero_gutter));
+        exth.set_strings(MetadataId::ZeroMargin, flag(self.zero_margin));
+    }
+}
+
+/// A panel that can be zoomed into, marked like `<div id="panel-1" data-app-amzn-magnify='{"targetId":"panel-1-magnified", "ordinal":1}'>`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MagnificationRegion {
+    /// The panel. The anchor is `None` if the panel has no `id`.
+    pub location: Location,
+    /// `id` of the element that is shown zoomed in, in the same part.
+    pub target_id: String,
+    /// Order in which the panels are visited.
+    pub ordinal: Option<u32>,
+}
+
+pub(crate) fn magnification_regions(book_parts: &[BookPart]) -> Vec<MagnificationRegion> {
+    let mut regions = vec![];
+    for (part, book_part) in book_parts.iter().enumerate() {
+        let content = &book_part.content;
+        for (start, _) in content.match_indices(MAGNIFY_ATTRIBUTE) {
+            let value = content[start + MAGNIFY_ATTRIBUTE.len()..].trim_start();
+            let Some(value) = value.strip_prefix('=').map(str::trim_start) else {
+                continue;
+            };
+            let Some(quote) = value.chars().next().filter(|c| matches!(c, '"' | '\'')) else {
+                continue;
+            };
+            let Some((json, _)) = value[1..].split_once(quote) else {
+                continue;
+            };
+            let json = json.replace("&quot;", "\"").replace("&amp;", "&");
+            let Some(target_id) = json::value(&json, "targetId") else {
+                continue;
+            };
+
+            let tag_start = content[..start].rfind('<').unwrap_or(0);
+            let tag_end = content[start..]
+                .find('>')
+                .map_or(content.len(), |end| start + end);
+            let anchor = ids(&content.as_bytes()[tag_start..tag_end])
+                .next()
+                .map(|(_, id)| String::from_utf8_lossy(id).into_owned());
+
+            regions.push(MagnificationRegion {
+                location: Location { part, anchor },
+                target_id,
+                ordinal: json::value(&json, "ordinal").and_then(|ordinal| ordinal.parse().ok()),
+            });
+        }
+    }
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::ParseOptions;
+    use pretty_assertions::assert_eq;
+    use proptest::{arbitrary::any, proptest};
+
+    proptest! {
+        #[test]
+        fn test_fixed_layout_roundtrip(fixed_layout in any::<FixedLayout>()) {
+            let mut exth = Exth::default();
+            fixed_layout.write_exth(&mut exth);
+
+            let options = ParseOptions::default();
+            let mut diagnostics = Diagnostics::new(&options);
+            assert_eq!(FixedLayout::from_exth(&exth, &mut diagnostics), Some(fixed_layout));
+            assert_eq!(diagnostics.into_warnings(), []);
+        }
+    }
+
+    #[test]
+    fn test_from_exth() {
+        let options = ParseOptions::default();
+        let mut diagnostics = Diagnostics::new(&options);
+        let mut exth = Exth::default();
+        assert_eq!(FixedLayout::from_exth(&exth, &mut diagnostics), None);
+
+        exth.set_strings(MetadataId::FixedLayout, ["true".to_string()]);
+        exth.set_strings(MetadataId::OriginalResolution, ["1072x1448".to_string()]);
+        exth.set_strings(MetadataId::OrientationLock, ["sideways".to_string()]);
+        exth.set_strings(MetadataId::BookType, ["comic".to_string()]);
+        let fixed_layout = FixedLayout::from_exth(&exth, &mut diagnostics).unwrap();
+        assert_eq!(
+            fixed_layout,
+            FixedLayout {
+                viewport: Some(Viewport {
+                    width: 1072,
+                    height: 1448
+                }),
+                book_type: Some(FixedLayoutBookType::Comic),
+                ..Default::default()
+            }
+        );
+        assert_eq!(diagnostics.into_warnings().len(), 1);
+    }
+
+    #[test]
+    fn test_magnification_regions() {
+        let book_parts = [BookPart {
+            skeleton_head: String::new(),
+            content: r#"<div id="panel-1" class="app-amzn-magnify" data-app-amzn-magnify='{"targetId":"panel-1-magnified", "ordinal":1}'><img src="kindle:embed:0001"/></div><div data-app-amzn-magnify="{&quot;targetId&quot;:&quot;panel-2-magnified&quot;}"/>"#.to_string(),
+            skeleton_tail: String::new(),
+        }];
+
+        assert_eq!(
+            magnification_regions(&book_parts),
+            [
+                MagnificationRegion {
+                    location: Location {
+                        part: 0,
+                        anchor: Some("panel-1".to_string())
+                    },
+                    target_id: "panel-1-magnified".to_string(),
+                    ordinal: Some(1),
+                },
+                MagnificationRegion {
+                    location: Location {
+                        part: 0,
+                        anchor: None
+                    },
+                    target_id: "panel-2-magnified".to_string(),
+                    ordinal: None,
+                }
+            ]
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Flows after the text, and the `kindle:flow:XXXX?mime=...` references to them.
+//!
+//! Nothing in the FDST table says what a flow holds, so flows are classified by the `mime` of the references to them: `text/css` for stylesheets, `image/svg+xml` for SVG images that were taken out of the text.
+
+use std::fmt;
+
+#[cfg(test)]
+use proptest_derive::Arbitrary;
+
+use crate::utils::{base32, kindle_reference};
+
+const PREFIX: &str = "kindle:flow:";
+const CSS_MIME_TYPE: &str = "text/css";
+const SVG_MIME_TYPE: &str = "image/svg+xml";
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub enum FlowKind {
+    Stylesheet,
+    Svg,
+    /// Referenced with another `mime`.
+    Other(String),
+}
+
+impl FlowKind {
+    pub fn from_mime_type(mime_type: &str) -> Self {
+        match mime_type.to_ascii_lowercase().as_str() {
+            CSS_MIME_TYPE => FlowKind::Stylesheet,
+            SVG_MIME_TYPE => FlowKind::Svg,
+            _ => FlowKind::Other(mime_type.to_string()),
+        }
+    }
+
+    pub fn mime_type(&self) -> &str {
+        match self {
+            FlowKind::Stylesheet => CSS_MIME_TYPE,
+            FlowKind::Svg => SVG_MIME_TYPE,
+            FlowKind::Other(mime_type) => mime_type,
+        }
+    }
+
+    /// For flows that nothing references. Anything that isn't an SVG document is assumed to be a stylesheet.
+    fn sniff(text: &str) -> Self {
+        let text = text.trim_start();
+        let text = match text.strip_prefix("<?xml") {
+            Some(rest) => rest.split_once("?>").map_or("", |(_, rest)| rest),
+            None => text,
+        };
+        if text.trim_start().starts_with("<svg") {
+            FlowKind::Svg
+        } else {
+            FlowKind::Stylesheet
+        }
+    }
+}
+
+/// A flow after the text, written to the FDST table in order after flow 0.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct Flow {
+    pub kind: FlowKind,
+    pub text: String,
+}
+
+impl Flow {
+    pub fn stylesheet(css: impl Into<String>) -> Self {
+        Flow {
+            kind: FlowKind::Stylesheet,
+            text: css.into(),
+        }
+    }
+
+    pub fn svg(svg: impl Into<String>) -> Self {
+        Flow {
+            kind: FlowKind::Svg,
+            text: svg.into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FlowReference {
+    /// Index in the FDST table. Flow 0 is the text, so this starts from 1.
+    pub flow: usize,
+    pub mime_type: Option<String>,
+}
+
+impl FlowReference {
+    pub fn new(flow: usize, mime_type: Option<String>) -> Self {
+        FlowReference { flow, mime_type }
+    }
+
+    /// Parses a whole reference, like the value of an `href` attribute.
+    pub fn parse(reference: &str) -> Option<Self> {
+        let (index, mime_type) = kindle_reference::parse(reference, PREFIX)?;
+        let flow = Some(index).filter(|flow| *flow > 0)? as usize;
+        Some(FlowReference { flow, mime_type })
+    }
+}
+
+impl fmt::Display for FlowReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", PREFIX, base32::encode(self.flow as u32, 4))?;
+        if let Some(mime_type) = &self.mime_type {
+            write!(f, "?mime={}", mime_type)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every `kindle:flow` reference in `text`.
+pub(crate) fn flow_references(text: &str) -> impl Iterator<Item = FlowReference> + '_ {
+    kindle_reference::find_all(text, PREFIX)
+        .filter_map(|(_, reference)| FlowReference::parse(reference))
+}
+
+/// The kind of each of `flows` (starting from flow 1), from the first reference to it with a `mime` in `texts`.
+pub(crate) fn classify_flows<'a>(
+    texts: impl IntoIterator<Item = &'a str>,
+    flows: &[&str],
+) -> Vec<FlowKind> {
+    let mut kinds = vec![None; flows.len()];
+    for reference in texts.into_iter().flat_map(flow_references) {
+        let (Some(kind), Some(mime_type)) =
+            (kinds.get_mut(reference.flow - 1), reference.mime_type)
+        else {
+            continue;
+        };
+        kind.get_or_insert_with(|| FlowKind::from_mime_type(&mime_type));
+    }
+
+    kinds
+        .into_iter()
+        .zip(flows)
+        .map(|(kind, text)| kind.unwrap_or_else(|| FlowKind::sniff(text)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use proptest::{option, prop_assert_eq, proptest};
+
+    proptest! {
+        #[test]
+        fn test_flow_reference_roundtrip(flow in 1..1_000_000usize, mime_type in option::of("[a-z]{1,10}/[a-z+-]{1,10}")) {
+            let reference = FlowReference::new(flow, mime_type);
+            prop_assert_eq!(FlowReference::parse(&reference.to_string()), Some(reference));
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            FlowReference::parse("kindle:flow:000A?mime=image/svg+xml"),
+            Some(FlowReference::new(10, Some(SVG_MIME_TYPE.to_string())))
+        );
+        assert_eq!(FlowReference::parse("kindle:flow:0000"), None);
+        assert_eq!(FlowReference::parse("kindle:embed:0001"), None);
+    }
+
+    #[test]
+    fn test_classify_flows() {
+        let text = r#"<link href="kindle:flow:0002?mime=text/css"/><img src="kindle:flow:0001?mime=image/svg+xml"/>"#;
+        let flows = [
+            r#"<svg xmlns="http://www.w3.org/2000/svg"/>"#,
+            "body { margin: 0 }",
+            r#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg"/>"#,
+            "p { text-indent: 1em }",
+        ];
+
+        assert_eq!(
+            classify_flows([text], &flows),
+            [
+                FlowKind::Svg,
+                FlowKind::Stylesheet,
+                FlowKind::Svg,
+                FlowKind::Stylesheet
+            ]
+        );
+        assert_eq!(
+            classify_flows(["kindle:flow:0001?mime=text/plain"], &flows[..1]),
+            [FlowKind::Other("text/plain".to_string())]
+        );
+    }
+}
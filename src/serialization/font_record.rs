@@ -0,0 +1,128 @@
+//! `FONT` records, which hold an embedded font after an optional XOR obfuscation and zlib compression.
+
+const HEADER_LEN: usize = 24;
+const FLAG_ZLIB: u32 = 0b01;
+const FLAG_XOR: u32 = 0b10;
+/// Only the start of the font is obfuscated.
+const XOR_LEN: usize = 1040;
+
+fn read_u32(record: &[u8], offset: usize) -> Option<u32> {
+    let bytes = record.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Returns the font data, deobfuscated and decompressed.
+pub(crate) fn read_font_record(record: &[u8]) -> Result<Vec<u8>, String> {
+    if !record.starts_with(b"FONT") {
+        return Err("Missing FONT magic".to_string());
+    }
+
+    let header = (1..6)
+        .map(|i| read_u32(record, i * 4))
+        .collect::<Option<Vec<_>>>()
+        .ok_or("FONT header is truncated")?;
+    let [decoded_len, flags, data_start, xor_len, xor_start] = header[..] else {
+        unreachable!()
+    };
+
+    let mut data = record
+        .get(data_start as usize..)
+        .ok_or("FONT data starts after the end of the record")?
+        .to_vec();
+
+    if flags & FLAG_XOR != 0 {
+        let xor_end = xor_start
+            .checked_add(xor_len)
+            .ok_or("FONT XOR key is out of bounds")?;
+        let key = record
+            .get(xor_start as usize..xor_end as usize)
+            .filter(|key| !key.is_empty())
+            .ok_or("FONT XOR key is out of bounds")?;
+        for (i, byte) in data.iter_mut().take(XOR_LEN).enumerate() {
+            *byte ^= key[i % key.len()];
+        }
+    }
+
+    if flags & FLAG_ZLIB != 0 {
+        data = miniz_oxide::inflate::decompress_to_vec_zlib(&data)
+            .map_err(|e| format!("Could not decompress FONT data: {:?}", e.status))?;
+    }
+
+    if data.len() != decoded_len as usize {
+        return Err(format!(
+            "Expected {} bytes of font data, found {}",
+            decoded_len,
+            data.len()
+        ));
+    }
+
+    Ok(data)
+}
+
+/// Writes the font as is, without obfuscation or compression.
+pub(crate) fn write_font_record(data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + data.len());
+    record.extend_from_slice(b"FONT");
+    for value in [
+        data.len() as u32,
+        0,
+        HEADER_LEN as u32,
+        0,
+        HEADER_LEN as u32,
+    ] {
+        record.extend_from_slice(&value.to_be_bytes());
+    }
+    record.extend_from_slice(data);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::any, proptest};
+
+    proptest! {
+        #[test]
+        fn test_font_record_roundtrip(data in vec(any::<u8>(), 0..2000)) {
+            assert_eq!(read_font_record(&write_font_record(&data)), Ok(data));
+        }
+    }
+
+    #[test]
+    fn test_read_obfuscated_font() {
+        let font = (0..1500).map(|i| i as u8).collect::<Vec<_>>();
+        let key = [0x12, 0x34, 0x56];
+
+        // The key sits between the header and the data
+        let mut record = write_font_record(&font);
+        record[8..12].copy_from_slice(&FLAG_XOR.to_be_bytes());
+        record[12..16].copy_from_slice(&((HEADER_LEN + key.len()) as u32).to_be_bytes());
+        record[16..20].copy_from_slice(&(key.len() as u32).to_be_bytes());
+        for (i, byte) in record[HEADER_LEN..HEADER_LEN + XOR_LEN]
+            .iter_mut()
+            .enumerate()
+        {
+            *byte ^= key[i % key.len()];
+        }
+        record.splice(HEADER_LEN..HEADER_LEN, key);
+
+        assert_eq!(read_font_record(&record), Ok(font));
+
+        // A key that ends past u32::MAX
+        record[16..20].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(read_font_record(&record).is_err());
+    }
+
+    #[test]
+    fn test_read_compressed_font() {
+        let font = b"OTTO".repeat(500);
+        let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&font, 6);
+
+        let mut record = write_font_record(&compressed);
+        record[4..8].copy_from_slice(&(font.len() as u32).to_be_bytes());
+        record[8..12].copy_from_slice(&FLAG_ZLIB.to_be_bytes());
+
+        assert_eq!(read_font_record(&record), Ok(font));
+        assert!(read_font_record(&record[..30]).is_err());
+    }
+}
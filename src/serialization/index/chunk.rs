@@ -26,21 +26,25 @@ impl<'a> TryFrom<&'a TagMapEntry> for ChunkTagMapEntry {
             .parse()
             .map_err(|_| TagMapEntryParseError::ParseError)?;
 
-        let cncx_offset = entry
+        let cncx_offset = *entry
             .tag_map
             .get(&2)
-            .ok_or_else(|| TagMapEntryParseError::TagNotFound("cncx_offset".to_string()))?[0];
-        let file_number = entry
+            .and_then(|values| values.first())
+            .ok_or_else(|| TagMapEntryParseError::TagNotFound("cncx_offset".to_string()))?;
+        let file_number = *entry
             .tag_map
             .get(&3)
-            .ok_or_else(|| TagMapEntryParseError::TagNotFound("file_number".to_string()))?[0];
-        let sequence_number = entry
+            .and_then(|values| values.first())
+            .ok_or_else(|| TagMapEntryParseError::TagNotFound("file_number".to_string()))?;
+        let sequence_number = *entry
             .tag_map
             .get(&4)
-            .ok_or_else(|| TagMapEntryParseError::TagNotFound("sequence_number".to_string()))?[0];
+            .and_then(|values| values.first())
+            .ok_or_else(|| TagMapEntryParseError::TagNotFound("sequence_number".to_string()))?;
         let geometry_pair = entry
             .tag_map
             .get(&6)
+            .filter(|values| values.len() >= 2)
             .ok_or_else(|| TagMapEntryParseError::TagNotFound("geometry".to_string()))?;
         let start_offset = geometry_pair[0];
         let length = geometry_pair[1];
@@ -98,7 +102,7 @@ mod tests {
 
             let mut serialized = Cursor::new(Vec::new());
             let mut writer = Writer::new(&mut serialized);
-            downcasted_entry.to_writer(&mut writer, &ChunkTagMapEntry::get_tag_definitions()).unwrap();
+            downcasted_entry.to_writer(&mut writer, (deku::ctx::Endian::Big, &ChunkTagMapEntry::get_tag_definitions())).unwrap();
             writer.finalize().unwrap();
 
             serialized.set_position(0);
@@ -13,6 +13,8 @@ fn align_bytes(mut bytes: Vec<u8>, alignment: usize) -> Vec<u8> {
 
 const MAX_STRING_LENGTH: usize = 500;
 const MAX_RECORD_LENGTH: usize = 0x10000 - 1024; // kindlegen appears to use 1024, PDB limit is 0x10000
+/// Offsets into the CNCX records count this much per record.
+const RECORD_OFFSET_STRIDE: usize = 0x10000;
 
 #[deku_derive(DekuRead, DekuWrite)]
 #[derive(Debug, PartialEq)]
@@ -57,6 +59,7 @@ pub struct CNCXRecords {
 #[derive(Debug, PartialEq)]
 pub struct SerializedCNCXRecords {
     pub records: Vec<Vec<u8>>,
+    /// Where each string starts, as referenced from index entries.
     pub offsets: HashMap<String, usize>,
 }
 
@@ -66,7 +69,6 @@ impl CNCXRecords {
         let mut offsets = HashMap::new();
 
         let mut current_record = Vec::new();
-        let mut current_offset = 0;
 
         for string in self.strings {
             // todo: handle string length overflow
@@ -75,12 +77,13 @@ impl CNCXRecords {
             if current_record.len() + serialized.len() > MAX_RECORD_LENGTH {
                 records.push(align_bytes(current_record, 4));
                 current_record = Vec::new();
-                current_offset = records.len() * 0x10000;
             }
 
-            current_offset += serialized.len();
+            offsets.insert(
+                string,
+                records.len() * RECORD_OFFSET_STRIDE + current_record.len(),
+            );
             current_record.append(&mut serialized);
-            offsets.insert(string, current_offset);
         }
 
         if current_record.len() > 0 {
@@ -90,7 +93,7 @@ impl CNCXRecords {
         SerializedCNCXRecords { records, offsets }
     }
 
-    pub fn from_records(serialized: &SerializedCNCXRecords) -> Self {
+    pub fn from_records(serialized: &SerializedCNCXRecords) -> Result<Self, DekuError> {
         let mut strings = Vec::new();
 
         for record in &serialized.records {
@@ -101,14 +104,27 @@ impl CNCXRecords {
                 }
 
                 let ((leftover, _), serialized_string) =
-                    SerializedString::from_bytes((&record[offset..], 0)).unwrap();
+                    SerializedString::from_bytes((&record[offset..], 0))?;
 
                 offset += record.len() - offset - leftover.len();
                 strings.push(serialized_string.value);
             }
         }
 
-        CNCXRecords { strings }
+        Ok(CNCXRecords { strings })
+    }
+
+    /// Reads the string at an offset from `SerializedCNCXRecords::offsets`.
+    pub fn read_string<T: AsRef<[u8]>>(records: &[T], offset: u32) -> Result<String, DekuError> {
+        let offset = offset as usize;
+        let data = records
+            .get(offset / RECORD_OFFSET_STRIDE)
+            .and_then(|record| record.as_ref().get(offset % RECORD_OFFSET_STRIDE..))
+            .ok_or_else(|| {
+                DekuError::Parse(format!("CNCX offset {:#x} is out of bounds", offset).into())
+            })?;
+        let (_, serialized_string) = SerializedString::from_bytes((data, 0))?;
+        Ok(serialized_string.value)
     }
 }
 
@@ -122,8 +138,11 @@ mod tests {
         fn test_cncx_records_roundtrip(records in any::<CNCXRecords>()) {
             env_logger::try_init();
             let serialized = records.clone().to_records();
-            let decoded = CNCXRecords::from_records(&serialized);
+            let decoded = CNCXRecords::from_records(&serialized).unwrap();
 
+            for (string, offset) in &serialized.offsets {
+                assert_eq!(&CNCXRecords::read_string(&serialized.records, *offset as u32).unwrap(), string);
+            }
             assert_eq!(records, decoded);
         }
     }
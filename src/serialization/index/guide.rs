@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+use crate::serialization::tag_map::{TagDefinition, TagMapEntry, END_TAG_DEFINITION};
+
+use super::types::{IndexTagMapEntry, TagMapEntryParseError};
+#[cfg(test)]
+use proptest_derive::Arbitrary;
+
+/// An entry of the OPF guide, like the start of the text or the cover.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct GuideTagMapEntry {
+    /// The OPF guide type, e.g. `text` or `toc`.
+    pub kind: String,
+    /// CNCX offset of the title.
+    pub title: u32,
+    /// Index of the chunk, as in `kindle:pos:fid:`.
+    pub fid: u32,
+    /// Offset in the chunk, as in `kindle:pos:fid:XXXX:off:`.
+    pub fid_offset: u32,
+}
+
+impl TryFrom<&TagMapEntry> for GuideTagMapEntry {
+    type Error = TagMapEntryParseError;
+
+    fn try_from(entry: &TagMapEntry) -> Result<Self, Self::Error> {
+        let title = *entry
+            .tag_map
+            .get(&1)
+            .and_then(|values| values.first())
+            .ok_or_else(|| TagMapEntryParseError::TagNotFound("title".to_string()))?;
+        let pos_fid = entry
+            .tag_map
+            .get(&6)
+            .filter(|values| values.len() >= 2)
+            .ok_or_else(|| TagMapEntryParseError::TagNotFound("pos_fid".to_string()))?;
+
+        Ok(GuideTagMapEntry {
+            kind: entry.text.clone(),
+            title,
+            fid: pos_fid[0],
+            fid_offset: pos_fid[1],
+        })
+    }
+}
+
+impl From<GuideTagMapEntry> for TagMapEntry {
+    fn from(entry: GuideTagMapEntry) -> Self {
+        TagMapEntry {
+            text: entry.kind,
+            tag_map: HashMap::from([
+                (1, vec![entry.title]),
+                (6, vec![entry.fid, entry.fid_offset]),
+            ]),
+        }
+    }
+}
+
+impl<'a> IndexTagMapEntry<'a> for GuideTagMapEntry {
+    fn get_tag_definitions() -> Vec<TagDefinition> {
+        vec![
+            TagDefinition::new(1, 1, 1).unwrap(),
+            TagDefinition::new(6, 2, 2).unwrap(),
+            END_TAG_DEFINITION,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use deku::{reader::Reader, writer::Writer, DekuReader, DekuWriter};
+    use pretty_assertions::assert_eq;
+    use proptest::{arbitrary::any, proptest};
+
+    proptest! {
+        #[test]
+        fn test_guide_entry_roundtrip(entry in any::<super::GuideTagMapEntry>()) {
+            let downcasted_entry: TagMapEntry = entry.clone().into();
+
+            let mut serialized = Cursor::new(Vec::new());
+            let mut writer = Writer::new(&mut serialized);
+            downcasted_entry.to_writer(&mut writer, (deku::ctx::Endian::Big, &GuideTagMapEntry::get_tag_definitions())).unwrap();
+            writer.finalize().unwrap();
+
+            serialized.set_position(0);
+            let len = serialized.get_ref().len();
+            let mut reader = Reader::new(&mut serialized);
+            let decoded = TagMapEntry::from_reader_with_ctx(&mut reader, (len, &GuideTagMapEntry::get_tag_definitions())).unwrap();
+            let decoded = GuideTagMapEntry::try_from(&decoded).unwrap();
+
+            assert_eq!(entry, decoded);
+        }
+    }
+}
@@ -1,14 +1,18 @@
 mod chunk;
 mod cncx;
+mod guide;
 mod index_definition_record;
 mod index_meta_definition_record;
+mod ncx;
 mod new_index;
 mod skeleton;
 pub mod types;
 
 pub use chunk::*;
 pub use cncx::*;
+pub use guide::*;
 pub use index_definition_record::*;
 pub use index_meta_definition_record::*;
+pub use ncx::*;
 pub use new_index::*;
 pub use skeleton::*;
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use crate::serialization::tag_map::{TagDefinition, TagMapEntry, END_TAG_DEFINITION};
+
+use super::types::{IndexTagMapEntry, TagMapEntryParseError};
+#[cfg(test)]
+use proptest_derive::Arbitrary;
+
+/// An entry of the table of contents. Entries are ordered level by level, so the children of an entry are consecutive.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct NcxTagMapEntry {
+    /// Index of the entry, as a key.
+    pub name: String,
+    /// Position in the text.
+    pub offset: u32,
+    pub length: u32,
+    /// CNCX offset of the title.
+    pub label: u32,
+    pub depth: u32,
+    /// Indexes of other entries.
+    pub parent: Option<u32>,
+    pub first_child: Option<u32>,
+    pub last_child: Option<u32>,
+    /// Index of the chunk, as in `kindle:pos:fid:`.
+    pub fid: u32,
+    /// Offset in the chunk, as in `kindle:pos:fid:XXXX:off:`.
+    pub fid_offset: u32,
+}
+
+impl TryFrom<&TagMapEntry> for NcxTagMapEntry {
+    type Error = TagMapEntryParseError;
+
+    fn try_from(entry: &TagMapEntry) -> Result<Self, Self::Error> {
+        let value = |tag: u8| entry.tag_map.get(&tag).and_then(|values| values.first());
+        let required = |tag: u8, name: &str| {
+            value(tag)
+                .copied()
+                .ok_or_else(|| TagMapEntryParseError::TagNotFound(name.to_string()))
+        };
+        let pos_fid = entry
+            .tag_map
+            .get(&6)
+            .filter(|values| values.len() >= 2)
+            .ok_or_else(|| TagMapEntryParseError::TagNotFound("pos_fid".to_string()))?;
+
+        Ok(NcxTagMapEntry {
+            name: entry.text.clone(),
+            offset: required(1, "offset")?,
+            length: required(2, "length")?,
+            label: required(3, "label")?,
+            depth: required(4, "depth")?,
+            parent: value(21).copied(),
+            first_child: value(22).copied(),
+            last_child: value(23).copied(),
+            fid: pos_fid[0],
+            fid_offset: pos_fid[1],
+        })
+    }
+}
+
+impl From<NcxTagMapEntry> for TagMapEntry {
+    fn from(entry: NcxTagMapEntry) -> Self {
+        let mut tag_map = HashMap::from([
+            (1, vec![entry.offset]),
+            (2, vec![entry.length]),
+            (3, vec![entry.label]),
+            (4, vec![entry.depth]),
+            (6, vec![entry.fid, entry.fid_offset]),
+        ]);
+        for (tag, value) in [
+            (21, entry.parent),
+            (22, entry.first_child),
+            (23, entry.last_child),
+        ] {
+            if let Some(value) = value {
+                tag_map.insert(tag, vec![value]);
+            }
+        }
+        TagMapEntry {
+            text: entry.name,
+            tag_map,
+        }
+    }
+}
+
+impl<'a> IndexTagMapEntry<'a> for NcxTagMapEntry {
+    fn get_tag_definitions() -> Vec<TagDefinition> {
+        vec![
+            TagDefinition::new(1, 1, 1).unwrap(),
+            TagDefinition::new(2, 1, 2).unwrap(),
+            TagDefinition::new(3, 1, 4).unwrap(),
+            TagDefinition::new(4, 1, 8).unwrap(),
+            TagDefinition::new(21, 1, 16).unwrap(),
+            TagDefinition::new(22, 1, 32).unwrap(),
+            TagDefinition::new(23, 1, 64).unwrap(),
+            TagDefinition::new(6, 2, 128).unwrap(),
+            END_TAG_DEFINITION,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use deku::{reader::Reader, writer::Writer, DekuReader, DekuWriter};
+    use pretty_assertions::assert_eq;
+    use proptest::{arbitrary::any, proptest};
+
+    proptest! {
+        #[test]
+        fn test_ncx_entry_roundtrip(entry in any::<super::NcxTagMapEntry>()) {
+            let downcasted_entry: TagMapEntry = entry.clone().into();
+
+            let mut serialized = Cursor::new(Vec::new());
+            let mut writer = Writer::new(&mut serialized);
+            downcasted_entry.to_writer(&mut writer, (deku::ctx::Endian::Big, &NcxTagMapEntry::get_tag_definitions())).unwrap();
+            writer.finalize().unwrap();
+
+            serialized.set_position(0);
+            let len = serialized.get_ref().len();
+            let mut reader = Reader::new(&mut serialized);
+            let decoded = TagMapEntry::from_reader_with_ctx(&mut reader, (len, &NcxTagMapEntry::get_tag_definitions())).unwrap();
+            let decoded = NcxTagMapEntry::try_from(&decoded).unwrap();
+
+            assert_eq!(entry, decoded);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::iter::once;
+use std::{io::Cursor, iter::once};
 
 use deku::prelude::*;
 
@@ -13,7 +13,10 @@ use crate::{
     utils::deku::serialize_variable_width_value,
 };
 
-use super::types::IndexTagMapEntry;
+use super::{
+    types::{IndexTagMapEntry, TagMapEntryParseError},
+    CNCXRecords, IndexMetaDefinitionRecord,
+};
 
 #[deku_derive(DekuRead, DekuWrite)]
 #[deku(ctx = "endian: deku::ctx::Endian", endian = "endian")]
@@ -113,11 +116,77 @@ struct IndexRecord {
     // todo: idxt block
 }
 
+/// Number of index records after an INDX header record.
+pub(crate) fn index_record_count(header_record: &[u8]) -> Result<usize, DekuError> {
+    header_record
+        .strip_prefix(b"INDX")
+        .and_then(|header| header.get(20..24))
+        .map(|count| u32::from_be_bytes(count.try_into().unwrap()) as usize)
+        .ok_or_else(|| DekuError::Parse("Invalid INDX header record".into()))
+}
+
+/// The entries of an index record, as located by its IDXT block.
+pub(crate) fn record_entries(record: &[u8]) -> impl Iterator<Item = Result<&[u8], DekuError>> {
+    let idxt = IndexMetaDefinitionRecord::from_bytes((record, 0)).and_then(|(_, index_header)| {
+        let idxt_offset = index_header.idxt_block_offset as usize;
+        let num_entries = index_header.num_index_entries as usize;
+        let offsets = record
+            .get(idxt_offset..)
+            .and_then(|idxt| idxt.strip_prefix(b"IDXT"))
+            .and_then(|idxt| idxt.get(..2 * num_entries))
+            .ok_or_else(|| DekuError::Parse("IDXT block is out of bounds".into()))?;
+        Ok((idxt_offset, offsets))
+    });
+
+    let (entries, error) = match idxt {
+        Ok((idxt_offset, offsets)) => {
+            let offset =
+                |i: usize| u16::from_be_bytes([offsets[2 * i], offsets[2 * i + 1]]) as usize;
+            let num_entries = offsets.len() / 2;
+            let entries = (0..num_entries).map(move |i| {
+                let end = if i + 1 < num_entries {
+                    offset(i + 1)
+                } else {
+                    idxt_offset
+                };
+                record
+                    .get(offset(i)..end)
+                    .ok_or_else(|| DekuError::Parse("Index entry is out of bounds".into()))
+            });
+            (Some(entries), None)
+        }
+        Err(e) => (None, Some(Err(e))),
+    };
+    entries.into_iter().flatten().chain(error)
+}
+
+/// An index entry borrowed from its index record, see `PalmDocRef::index_entries`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct IndexEntryRef<'a> {
+    pub key: &'a [u8],
+    /// The control bytes and tag values, which `TotalIndexEntry` decodes with the index's TAGX definitions.
+    pub tag_data: &'a [u8],
+}
+
+impl<'a> IndexEntryRef<'a> {
+    pub(crate) fn parse(entry: &'a [u8]) -> Result<Self, DekuError> {
+        let (&key_len, rest) = entry
+            .split_first()
+            .ok_or_else(|| DekuError::Parse("Empty index entry".into()))?;
+        if rest.len() < key_len as usize {
+            return Err(DekuError::Parse("Index entry key is out of bounds".into()));
+        }
+        let (key, tag_data) = rest.split_at(key_len as usize);
+        Ok(IndexEntryRef { key, tag_data })
+    }
+}
+
 #[derive(Debug, PartialEq)]
 // #[cfg_attr(test, derive(Arbitrary))]
 pub struct TotalIndexEntry {
     tag_definitions: Vec<TagDefinition>,
     entries: Vec<TagMapEntry>,
+    cncx_records: Vec<Vec<u8>>,
 }
 
 impl TotalIndexEntry {
@@ -125,7 +194,90 @@ impl TotalIndexEntry {
         Self {
             tag_definitions,
             entries,
+            cncx_records: vec![],
+        }
+    }
+
+    /// The CNCX records written after the index records, holding the strings that entries reference by offset (see `CNCXRecords`).
+    pub fn with_cncx_records(mut self, cncx_records: Vec<Vec<u8>>) -> Self {
+        self.cncx_records = cncx_records;
+        self
+    }
+
+    /// Parses an index from its header record followed by its index records and CNCX records.
+    /// Records after the ones referenced by the header are ignored.
+    pub fn from_records<T: AsRef<[u8]>>(records: &[T]) -> Result<Self, DekuError> {
+        let header_record = records
+            .first()
+            .ok_or_else(|| DekuError::Parse("Missing INDX header record".into()))?;
+        let (_, header) = Header::from_bytes((header_record.as_ref(), 0))?;
+        let tag_definitions = header.tagx.tag_definitions;
+
+        let index_records = records
+            .get(1..)
+            .and_then(|records| records.get(..header.num_of_records as usize))
+            .ok_or_else(|| {
+                DekuError::Parse(
+                    format!(
+                        "INDX header references {} records, but only {} follow it",
+                        header.num_of_records,
+                        records.len() - 1
+                    )
+                    .into(),
+                )
+            })?;
+
+        let cncx_start = 1 + index_records.len();
+        let cncx_records = records
+            .get(cncx_start..)
+            .and_then(|records| records.get(..header.num_of_cncx_records as usize))
+            .ok_or_else(|| {
+                DekuError::Parse(
+                    format!(
+                        "INDX header references {} CNCX records, but only {} follow the index records",
+                        header.num_of_cncx_records,
+                        records.len() - cncx_start
+                    )
+                    .into(),
+                )
+            })?
+            .iter()
+            .map(|record| record.as_ref().to_vec())
+            .collect();
+
+        let mut entries = Vec::new();
+        for record in index_records {
+            for entry in record_entries(record.as_ref()) {
+                let data = entry?;
+                let mut cursor = Cursor::new(data);
+                let mut reader = Reader::new(&mut cursor);
+                entries.push(TagMapEntry::from_reader_with_ctx(
+                    &mut reader,
+                    (data.len(), &tag_definitions),
+                )?);
+            }
         }
+
+        Ok(Self {
+            tag_definitions,
+            entries,
+            cncx_records,
+        })
+    }
+
+    pub fn entries(&self) -> &[TagMapEntry] {
+        &self.entries
+    }
+
+    /// The CNCX string at the offset an entry references it by.
+    pub fn cncx_string(&self, offset: u32) -> Result<String, DekuError> {
+        CNCXRecords::read_string(&self.cncx_records, offset)
+    }
+
+    pub fn parse_as<'a, T: IndexTagMapEntry<'a>>(
+        &'a self,
+    ) -> Result<Vec<T>, TagMapEntryParseError> {
+        self.entries.iter().map(T::try_from).collect()
     }
 
     // todo: should record be a type alias?
@@ -147,6 +299,18 @@ impl TotalIndexEntry {
             // }
         }
 
+        let cncx_records = if self.cncx_records.is_empty() {
+            // todo: cncx records
+            let cncx_text = "P-//*[@aid='0']";
+            vec![[
+                serialize_variable_width_value(cncx_text.len() as u32, deku::ctx::Endian::Big),
+                cncx_text.as_bytes().to_vec(),
+            ]
+            .concat()]
+        } else {
+            self.cncx_records.clone()
+        };
+
         // todo: implement splitting for blocks over max record size
         let mut header = Header {
             idxt_offset: 0, // replaced later
@@ -155,7 +319,7 @@ impl TotalIndexEntry {
             ordt_offset: 0,
             ligt_offset: 0,
             num_of_ordt_ligt_entries: 0,
-            num_of_cncx_records: 1,
+            num_of_cncx_records: cncx_records.len() as u32,
             tagx: TagMapDefinition {
                 tag_definitions: self.tag_definitions.clone(),
             },
@@ -174,8 +338,11 @@ impl TotalIndexEntry {
 
         let idxt_block = IdxtBlock {
             key_offsets: once(0)
-                .chain(geometry.iter().map(|x| x.key.len() as u16))
-                .map(|x| header_bytes.len() as u16 + x)
+                .chain(geometry.iter().map(|x| (1 + x.key.len() + 2) as u16))
+                .scan(header_bytes.len() as u16, |offset, len| {
+                    *offset += len;
+                    Some(*offset)
+                })
                 // skip last
                 .enumerate()
                 .filter_map(|(i, x)| if i == geometry.len() { None } else { Some(x) })
@@ -183,13 +350,13 @@ impl TotalIndexEntry {
         };
 
         let header_bytes = [header_bytes, geometry_bytes, idxt_block.to_bytes().unwrap()].concat();
-        records.push(header_bytes);
+        records.push(pad_to_4(header_bytes));
 
         // Create index record
         let mut index_record = IndexRecord {
             len: 192,
-            idxt_block_offset: 0,   // updated later
-            num_of_idxt_entries: 1, // todo
+            idxt_block_offset: 0, // updated later
+            num_of_idxt_entries: self.entries.len() as u32,
             tag_definitions: self.tag_definitions.clone(),
             tag_map_entries: self.entries.clone(),
         };
@@ -207,7 +374,10 @@ impl TotalIndexEntry {
 
                     cursor.into_inner().len() as u16
                 }))
-                .map(|x| 192 as u16 + x)
+                .scan(192, |offset, len| {
+                    *offset += len;
+                    Some(*offset)
+                })
                 // skip last
                 .enumerate()
                 .filter_map(|(i, x)| {
@@ -221,17 +391,115 @@ impl TotalIndexEntry {
         };
 
         let index_record_bytes = [index_record_bytes, idxt_block.to_bytes().unwrap()].concat();
-        records.push(index_record_bytes);
-
-        // todo: cncx records
-        let cncx_text = "P-//*[@aid='0']";
-        let cncx_record = [
-            serialize_variable_width_value(cncx_text.len() as u32, deku::ctx::Endian::Big),
-            cncx_text.as_bytes().to_vec(),
-        ]
-        .concat();
-        records.push(cncx_record);
+        records.push(pad_to_4(index_record_bytes));
+        records.extend(cncx_records);
 
         records
     }
 }
+
+/// INDX records are padded with zeros after the IDXT block, like kindlegen does.
+fn pad_to_4(mut record: Vec<u8>) -> Vec<u8> {
+    record.resize(record.len().next_multiple_of(4), 0);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{ChunkTagMapEntry, NcxTagMapEntry, PalmDoc};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_index_roundtrip() {
+        let chunks = (0..3)
+            .map(|i| ChunkTagMapEntry {
+                insert_position: i * 10,
+                cncx_offset: 0,
+                file_number: i,
+                sequence_number: i,
+                start_offset: i * 100,
+                length: 100,
+            })
+            .collect::<Vec<_>>();
+
+        let index = TotalIndexEntry::new(
+            ChunkTagMapEntry::get_tag_definitions(),
+            chunks.iter().cloned().map(Into::into).collect(),
+        );
+        let records = index.into_records();
+
+        let parsed = TotalIndexEntry::from_records(&records).unwrap();
+        assert_eq!(parsed.parse_as::<ChunkTagMapEntry>().unwrap(), chunks);
+    }
+
+    #[test]
+    fn test_index_cncx_roundtrip() {
+        let cncx = CNCXRecords {
+            strings: vec!["Book One".to_string(), "Chapter 1".to_string()],
+        }
+        .to_records();
+        let entry = NcxTagMapEntry {
+            name: "0000".to_string(),
+            offset: 0,
+            length: 10,
+            label: cncx.offsets["Chapter 1"] as u32,
+            depth: 0,
+            parent: None,
+            first_child: None,
+            last_child: None,
+            fid: 0,
+            fid_offset: 0,
+        };
+        let records = TotalIndexEntry::new(
+            NcxTagMapEntry::get_tag_definitions(),
+            vec![entry.clone().into()],
+        )
+        .with_cncx_records(cncx.records)
+        .into_records();
+
+        let parsed = TotalIndexEntry::from_records(&records).unwrap();
+        let entries = parsed.parse_as::<NcxTagMapEntry>().unwrap();
+        assert_eq!(entries, [entry]);
+        assert_eq!(parsed.cncx_string(entries[0].label).unwrap(), "Chapter 1");
+        // Without its CNCX record
+        assert!(TotalIndexEntry::from_records(&records[..2]).is_err());
+    }
+
+    #[test]
+    fn test_index_from_truncated_records() {
+        let index = TotalIndexEntry::new(
+            ChunkTagMapEntry::get_tag_definitions(),
+            vec![ChunkTagMapEntry {
+                insert_position: 0,
+                cncx_offset: 0,
+                file_number: 0,
+                sequence_number: 0,
+                start_offset: 0,
+                length: 0,
+            }
+            .into()],
+        );
+        let mut records = index.into_records();
+
+        assert!(TotalIndexEntry::from_records(&records[..1]).is_err());
+
+        let len = records[1].len();
+        records[1].truncate(len - 4);
+        assert!(TotalIndexEntry::from_records(&records).is_err());
+    }
+
+    #[test]
+    fn test_parse_fixture_index() {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+
+        // The skeleton index of the fixture starts at record 1095.
+        let index = TotalIndexEntry::from_records(&palmdoc.records[1095..]).unwrap();
+        let skeletons = index
+            .parse_as::<crate::serialization::SkeletonTagMapEntry>()
+            .unwrap();
+        assert!(!skeletons.is_empty());
+        assert_eq!(skeletons[0].start_offset, 0);
+    }
+}
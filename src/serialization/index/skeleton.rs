@@ -29,13 +29,15 @@ impl<'a> TryFrom<&'a TagMapEntry> for SkeletonTagMapEntry {
     type Error = TagMapEntryParseError;
 
     fn try_from(entry: &TagMapEntry) -> Result<Self, Self::Error> {
-        let chunk_count = entry
+        let chunk_count = *entry
             .tag_map
             .get(&1)
-            .ok_or_else(|| TagMapEntryParseError::TagNotFound("chunk_count".to_string()))?[0];
+            .and_then(|values| values.first())
+            .ok_or_else(|| TagMapEntryParseError::TagNotFound("chunk_count".to_string()))?;
         let geometry_pair = entry
             .tag_map
             .get(&6)
+            .filter(|values| values.len() >= 2)
             .ok_or_else(|| TagMapEntryParseError::TagNotFound("geometry".to_string()))?;
         let start_offset = geometry_pair[0];
         let length = geometry_pair[1];
@@ -85,7 +87,7 @@ mod tests {
 
             let mut serialized = Cursor::new(Vec::new());
             let mut writer = Writer::new(&mut serialized);
-            downcasted_entry.to_writer(&mut writer, &SkeletonTagMapEntry::get_tag_definitions()).unwrap();
+            downcasted_entry.to_writer(&mut writer, (deku::ctx::Endian::Big, &SkeletonTagMapEntry::get_tag_definitions())).unwrap();
             writer.finalize().unwrap();
 
             serialized.set_position(0);
@@ -1,13 +1,19 @@
-use std::io::SeekFrom;
+use std::{
+    borrow::Cow,
+    io::{Read, SeekFrom},
+};
 
-use binrw::{prelude::*, NullString, PosValue};
-use deku::{DekuReader, DekuWriter};
+use binrw::{prelude::*, NullString};
+use deku::{DekuError, DekuReader, DekuWriter};
 #[cfg(test)]
 use proptest_derive::Arbitrary;
 
-use crate::constants::{MainLanguage, SubLanguage};
+use crate::{
+    constants::{MainLanguage, SubLanguage},
+    utils::cp1252,
+};
 
-use super::exth::Exth;
+use super::{exth::Exth, TrailingEntries};
 
 #[derive(Debug, PartialEq)]
 #[cfg_attr(test, derive(Arbitrary))]
@@ -21,16 +27,23 @@ struct SerializedExth {
 
 #[binrw::parser(reader)]
 fn parse_exth() -> BinResult<Exth> {
+    let pos = reader.stream_position()?;
     let mut deku_reader = deku::reader::Reader::new(reader);
-    let parsed = Exth::from_reader_with_ctx(&mut deku_reader, ()).unwrap();
-    Ok(parsed)
+    Exth::from_reader_with_ctx(&mut deku_reader, ()).map_err(|err| binrw::Error::Custom {
+        pos,
+        err: Box::new(err),
+    })
 }
 
 #[binrw::writer(writer)]
 fn write_exth(exth: &Exth) -> BinResult<()> {
+    let pos = writer.stream_position()?;
     let mut deku_writer = deku::writer::Writer::new(writer);
-    exth.to_writer(&mut deku_writer, ()).unwrap();
-    Ok(())
+    exth.to_writer(&mut deku_writer, ())
+        .map_err(|err| binrw::Error::Custom {
+            pos,
+            err: Box::new(err),
+        })
 }
 
 #[binrw::parser(reader, endian)]
@@ -193,7 +206,7 @@ fn write_exth_flags(flags: &ExthFlags) -> BinResult<()> {
     encoded.write_options(writer, endian, ())
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[cfg_attr(test, derive(Arbitrary))]
 #[binrw]
 #[brw(big, repr(u32))]
@@ -202,6 +215,26 @@ pub enum Codepage {
     Utf8 = 0x0000fde9,
 }
 
+impl Codepage {
+    /// Decodes text in this encoding. Only fails for invalid UTF-8, as every byte is a valid CP1252 character.
+    pub fn decode(&self, bytes: &[u8]) -> Result<String, DekuError> {
+        match self {
+            Codepage::Cp1252 => Ok(cp1252::decode(bytes)),
+            Codepage::Utf8 => String::from_utf8(bytes.to_vec())
+                .map_err(|e| DekuError::Parse(format!("Invalid UTF-8: {}", e).into())),
+        }
+    }
+
+    pub fn encode<'a>(&self, text: &'a str) -> Result<Cow<'a, [u8]>, DekuError> {
+        match self {
+            Codepage::Cp1252 => cp1252::encode(text).map(Cow::Owned).map_err(|c| {
+                DekuError::Parse(format!("{:?} can't be encoded as CP1252", c).into())
+            }),
+            Codepage::Utf8 => Ok(Cow::Borrowed(text.as_bytes())),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 #[cfg_attr(test, derive(Arbitrary))]
 #[binrw]
@@ -232,6 +265,27 @@ fn any_null_string() -> impl proptest::prelude::Strategy<Value = NullString> {
     "[a-zA-Z0-9]{0, 64}".prop_map(|v| NullString(v.into()))
 }
 
+// Like the writer below, this assumes the header starts at the beginning of the stream.
+#[binrw::parser(reader)]
+fn parse_title(offset: u32, len: u32) -> BinResult<NullString> {
+    reader.seek(SeekFrom::Start(offset as u64))?;
+
+    let mut title = Vec::new();
+    reader.take(len as u64).read_to_end(&mut title)?;
+    if title.len() != len as usize {
+        return Err(binrw::Error::AssertFail {
+            pos: offset as u64,
+            message: format!(
+                "title is {} bytes long but the record ends after {}",
+                len,
+                title.len()
+            ),
+        });
+    }
+
+    Ok(NullString(title))
+}
+
 #[binrw::writer(writer, endian)]
 fn write_string_and_offset(s: &NullString) -> BinResult<()> {
     let current_position = writer.stream_position()?;
@@ -384,10 +438,12 @@ pub struct MobiHeader {
     #[bw(if(exth_flags.flags.has_exth), map = |v: &Option<Exth>| v.clone().map(|v| SerializedExth { exth: v }))]
     pub exth: Option<Exth>,
     #[cfg_attr(test, proptest(strategy = "any_null_string()"))]
+    #[br(parse_with = parse_title, args(title_offset, title_length))]
     #[bw(write_with = write_string_and_offset)]
     pub title: NullString,
-    #[br(temp, count = 8192)] // todo?
-    #[bw(calc = vec![0x00; 8192])]
+    // Not required when reading, as not every writer pads the header record.
+    #[br(temp, ignore)]
+    #[bw(calc = vec![0x00; 8192])] // todo?
     padding: Vec<u8>,
 }
 
@@ -438,46 +494,18 @@ impl Default for MobiHeader {
 }
 
 impl MobiHeader {
-    pub fn sizeof_trailing_section_entries(&self, section_data: &[u8]) -> usize {
-        let mut num = 0;
-        let size = section_data.len();
-
-        fn sizeof_trailing_section_entry(section_data: &[u8], offset: usize) -> usize {
-            let mut offset = offset;
-            let mut bitpos = 0;
-            let mut result: usize = 0;
-
-            loop {
-                let v = section_data[offset - 1] as usize;
-                result |= (v & 0x7f) << bitpos;
-                bitpos += 7;
-                offset -= 1;
-
-                if (v & 0x80) != 0 || (bitpos >= 28) || offset == 0 {
-                    return result;
-                }
-            }
-        }
-
-        let mut encoded_flags = self.extra_data_flags.encode() >> 1;
-
-        while encoded_flags > 0 {
-            if encoded_flags & 1 > 0 {
-                num += sizeof_trailing_section_entry(section_data, size - num);
-            }
-
-            encoded_flags >>= 1;
-        }
-
-        if self
-            .extra_data_flags
-            .extra_multibyte_bytes_after_text_records
-        {
-            let offset = size - num - 1;
-            num += (section_data[offset] as usize & 0x3) + 1;
-        }
+    /// Returns the number of bytes at the end of a text record that are not part of the compressed text.
+    /// This is never larger than the record itself.
+    pub fn sizeof_trailing_section_entries(&self, section_data: &[u8]) -> Result<usize, DekuError> {
+        super::trailing_entries::sizeof_trailing_entries(section_data, &self.extra_data_flags)
+    }
 
-        num
+    /// Splits a text record into its text and its parsed trailing entries.
+    pub fn trailing_entries<'a>(
+        &self,
+        section_data: &'a [u8],
+    ) -> Result<(&'a [u8], TrailingEntries), DekuError> {
+        TrailingEntries::parse(section_data, &self.extra_data_flags)
     }
 
     pub fn get_bcp47_language_tag(&self) -> Option<&'static str> {
@@ -506,5 +534,15 @@ mod tests {
             let parsed = MobiHeader::read(&mut serialized).expect("could not parse");
             assert_eq!(parsed, header);
         }
+
+        #[test]
+        fn test_trailing_entries_within_record(
+            header in any::<MobiHeader>(),
+            record in proptest::collection::vec(any::<u8>(), 0..16),
+        ) {
+            if let Ok(len) = header.sizeof_trailing_section_entries(&record) {
+                assert!(len <= record.len());
+            }
+        }
     }
 }
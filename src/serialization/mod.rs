@@ -1,15 +1,61 @@
+mod apnx;
 pub mod book;
+mod book_builder;
+mod book_metadata;
+mod datp;
+mod embed;
 mod exth;
 mod fdst_table;
+mod fixed_layout;
+mod flow;
+mod font_record;
 mod index;
 mod mobi_header;
+mod navigation;
+mod page_map;
 mod palmdoc;
+mod palmdoc_reader;
+mod palmdoc_ref;
+mod parse_options;
+mod pos;
+mod provenance;
+mod resc;
+mod resource_container;
+mod srcs_record;
 mod tag_map;
 mod tag_section;
+mod trailing_entries;
 
+pub use apnx::{Apnx, PageAlgorithm};
 pub use book::*;
+pub use book_builder::{BookBuilder, BuildError, TocItem};
+pub use book_metadata::{Identifiers, Metadata, Series};
+pub use datp::DatpRecord;
+pub use embed::{EmbedError, EmbedReference};
+pub use exth::{Date, Exth, ExthRef, MetadataError};
 pub use fdst_table::*;
+pub use fixed_layout::{
+    FixedLayout, FixedLayoutBookType, MagnificationRegion, Orientation, Viewport,
+};
+pub(crate) use flow::classify_flows;
+pub use flow::{Flow, FlowKind, FlowReference};
 pub use index::*;
 pub use mobi_header::*;
+pub use navigation::{
+    page_list_nav, parse_page_list_nav, Landmark, Location, NavError, PageListItem, PageTarget,
+    TocEntry,
+};
+pub use page_map::{PageLabelRange, PageLabels, PageMapRecord};
 pub use palmdoc::*;
+pub use palmdoc_reader::*;
+pub use palmdoc_ref::*;
+pub use parse_options::{ParseOptions, ParseWarning};
+pub use pos::PosReference;
+pub use provenance::{
+    CompilationLog, Creator, CreatorSoftware, KindRecord, LogEntry, LogLevel, Provenance,
+};
+pub use resc::{ItemRef, PageProgressionDirection, PageSpread, ResCRecord, Spine};
+pub use resource_container::ResourceContainer;
+pub(crate) use srcs_record::read_srcs_record;
 pub use tag_section::*;
+pub use trailing_entries::{TbsSequence, TrailingEntries};
@@ -0,0 +1,288 @@
+#[cfg(test)]
+use proptest_derive::Arbitrary;
+use quick_xml::{
+    escape::escape,
+    events::{BytesStart, Event},
+    Reader,
+};
+use thiserror::Error;
+
+use super::pos::PosReference;
+
+/// A position in the book: the start of a part, or the element with the given `id` in it.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct Location {
+    /// Index into `Book::book_parts`.
+    pub part: usize,
+    pub anchor: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct TocEntry {
+    pub title: String,
+    pub location: Location,
+    #[cfg_attr(test, proptest(value = "Vec::new()"))]
+    pub children: Vec<TocEntry>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct Landmark {
+    /// The EPUB 3 `epub:type`, e.g. `cover`, `toc` or `bodymatter`.
+    pub kind: String,
+    pub title: String,
+    pub location: Location,
+}
+
+/// A page of the print edition, as in an EPUB `page-list`.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct PageTarget {
+    pub label: String,
+    pub location: Location,
+}
+
+/// Where a part is in the text flow, where it's written as its skeleton followed by its content.
+pub(crate) struct PartLayout<'a> {
+    start: usize,
+    skeleton_len: usize,
+    content: &'a [u8],
+}
+
+impl<'a> PartLayout<'a> {
+    /// Takes the length of the skeleton and the content of each part, in order.
+    pub(crate) fn new(parts: impl IntoIterator<Item = (usize, &'a [u8])>) -> Vec<Self> {
+        let mut start = 0;
+        parts
+            .into_iter()
+            .map(|(skeleton_len, content)| {
+                let layout = PartLayout {
+                    start,
+                    skeleton_len,
+                    content,
+                };
+                start += skeleton_len + content.len();
+                layout
+            })
+            .collect()
+    }
+}
+
+/// Every `id` attribute (but not `aid` or `data-id`), as the offset of the start of its tag and its value.
+pub(crate) fn ids(content: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    (1..content.len()).filter_map(move |i| {
+        let rest = &content[i..];
+        if !rest.starts_with(b"id=") || !content[i - 1].is_ascii_whitespace() {
+            return None;
+        }
+
+        let quote = *rest.get(3).filter(|b| matches!(b, b'"' | b'\''))?;
+        let value = &rest[4..];
+        let value = &value[..value.iter().position(|b| *b == quote)?];
+        let tag_start = content[..i].iter().rposition(|b| *b == b'<')?;
+        Some((tag_start, value))
+    })
+}
+
+/// Offset of the tag that has the given `id`.
+pub(crate) fn find_anchor(content: &[u8], anchor: &str) -> Option<usize> {
+    ids(content)
+        .find(|(_, id)| *id == anchor.as_bytes())
+        .map(|(tag_start, _)| tag_start)
+}
+
+pub(crate) fn location_to_offset(layout: &[PartLayout], location: &Location) -> Option<u32> {
+    let part = layout.get(location.part)?;
+    let offset = match &location.anchor {
+        Some(anchor) => find_anchor(part.content, anchor)?,
+        None => 0,
+    };
+    u32::try_from(part.start + part.skeleton_len + offset).ok()
+}
+
+/// The chunk and offset in it to link to a location, for parts written as a single chunk each.
+pub(crate) fn location_to_pos(layout: &[PartLayout], location: &Location) -> Option<PosReference> {
+    let part = layout.get(location.part)?;
+    let offset = location_to_offset(layout, location)? as usize - part.start - part.skeleton_len;
+    Some(PosReference::new(location.part, offset))
+}
+
+/// Points to the last element with an `id` that starts at or before `offset`, or to the start of the part if there's none.
+pub(crate) fn offset_to_location(layout: &[PartLayout], offset: u32) -> Option<Location> {
+    let offset = offset as usize;
+    let part = layout
+        .iter()
+        .rposition(|part| part.start <= offset)
+        .filter(|part| {
+            offset < layout[*part].start + layout[*part].skeleton_len + layout[*part].content.len()
+        })?;
+
+    let content_offset = offset.checked_sub(layout[part].start + layout[part].skeleton_len);
+    let anchor = content_offset.and_then(|content_offset| {
+        ids(layout[part].content)
+            .take_while(|(tag_start, _)| *tag_start <= content_offset)
+            .last()
+            .map(|(_, id)| String::from_utf8_lossy(id).into_owned())
+    });
+
+    Some(Location { part, anchor })
+}
+
+/// An entry of an EPUB 3 `page-list`, pointing to `chapter.xhtml#id`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PageListItem {
+    pub label: String,
+    pub href: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("Invalid navigation document: {0}")]
+pub struct NavError(String);
+
+/// Writes the `<nav epub:type="page-list">` element of an EPUB 3 navigation document.
+pub fn page_list_nav(pages: &[PageListItem]) -> String {
+    let mut nav = String::from("<nav epub:type=\"page-list\" hidden=\"\">\n  <ol>\n");
+    for page in pages {
+        nav.push_str(&format!(
+            "    <li><a href=\"{}\">{}</a></li>\n",
+            escape(&page.href),
+            escape(&page.label)
+        ));
+    }
+    nav.push_str("  </ol>\n</nav>\n");
+    nav
+}
+
+/// Reads the `<nav epub:type="page-list">` element of an EPUB 3 navigation document. Returns no pages if there's none.
+pub fn parse_page_list_nav(xhtml: &str) -> Result<Vec<PageListItem>, NavError> {
+    let mut reader = Reader::from_str(xhtml);
+    let mut pages = vec![];
+    // Depth of nested elements inside the page-list nav
+    let mut depth: Option<usize> = None;
+    let mut link: Option<PageListItem> = None;
+
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|e| NavError(format!("{} at position {}", e, reader.buffer_position())))?;
+        let attribute = |element: &BytesStart, name: &[u8]| {
+            element
+                .try_get_attribute(name)
+                .ok()
+                .flatten()
+                .and_then(|attribute| attribute.unescape_value().ok())
+                .map(|value| value.into_owned())
+        };
+
+        match event {
+            Event::Start(element) => match depth.as_mut() {
+                Some(depth) => {
+                    *depth += 1;
+                    if element.local_name().as_ref() == b"a" {
+                        link = Some(PageListItem {
+                            label: String::new(),
+                            href: attribute(&element, b"href").unwrap_or_default(),
+                        });
+                    }
+                }
+                None => {
+                    let is_page_list = element.local_name().as_ref() == b"nav"
+                        && attribute(&element, b"epub:type").is_some_and(|types| {
+                            types.split_whitespace().any(|t| t == "page-list")
+                        });
+                    if is_page_list {
+                        depth = Some(0);
+                    }
+                }
+            },
+            Event::Text(text) => {
+                if let Some(link) = link.as_mut() {
+                    let text = text.unescape().map_err(|e| NavError(e.to_string()))?;
+                    link.label.push_str(&text);
+                }
+            }
+            Event::End(element) => match depth {
+                Some(0) => depth = None,
+                Some(ref mut depth) => {
+                    *depth -= 1;
+                    if element.local_name().as_ref() == b"a" {
+                        if let Some(mut link) = link.take() {
+                            link.label = link.label.trim().to_string();
+                            pages.push(link);
+                        }
+                    }
+                }
+                None => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_offsets() {
+        let content = br#"<p>Zero</p><p aid="x" id="a">One</p><span id='page-2'/><p>Two</p>"#;
+        let layout = PartLayout::new([(10, &b"<p>Zero</p>"[..]), (20, &content[..])]);
+
+        let location = |part, anchor: Option<&str>| Location {
+            part,
+            anchor: anchor.map(str::to_string),
+        };
+        for expected in [
+            location(0, None),
+            location(1, None),
+            location(1, Some("a")),
+            location(1, Some("page-2")),
+        ] {
+            let offset = location_to_offset(&layout, &expected).unwrap();
+            assert_eq!(offset_to_location(&layout, offset), Some(expected));
+        }
+
+        assert_eq!(
+            location_to_pos(&layout, &location(1, Some("a"))),
+            Some(PosReference::new(1, 11))
+        );
+        assert_eq!(location_to_offset(&layout, &location(1, Some("x"))), None);
+        assert_eq!(location_to_offset(&layout, &location(2, None)), None);
+        // Inside the skeleton
+        assert_eq!(offset_to_location(&layout, 25), Some(location(1, None)));
+        // After "page-2"
+        assert_eq!(
+            offset_to_location(&layout, 98),
+            Some(location(1, Some("page-2")))
+        );
+        assert_eq!(offset_to_location(&layout, 1000), None);
+    }
+
+    #[test]
+    fn test_page_list_nav_roundtrip() {
+        let pages = vec![
+            PageListItem {
+                label: "i".to_string(),
+                href: "text/preface.xhtml#page-i".to_string(),
+            },
+            PageListItem {
+                label: "Plate <1>".to_string(),
+                href: "text/chapter.xhtml?a=1&b=2#p".to_string(),
+            },
+        ];
+        let nav = format!(
+            r#"<html xmlns:epub="http://www.idpf.org/2007/ops"><body><nav epub:type="toc"><ol><li><a href="a.xhtml">A</a></li></ol></nav>{}</body></html>"#,
+            page_list_nav(&pages)
+        );
+
+        assert_eq!(parse_page_list_nav(&nav), Ok(pages));
+        assert_eq!(parse_page_list_nav("<html></html>"), Ok(vec![]));
+        assert!(parse_page_list_nav("<nav epub:type=\"page-list\"><a></nav>").is_err());
+    }
+}
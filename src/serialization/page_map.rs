@@ -0,0 +1,373 @@
+//! The `PAGE` record, which maps the page numbers of a print edition to positions in the text.
+
+use deku::DekuError;
+
+const HEADER_LEN: usize = 0x14;
+const ROMAN_NUMERALS: [(u32, &str); 13] = [
+    (1000, "m"),
+    (900, "cm"),
+    (500, "d"),
+    (400, "cd"),
+    (100, "c"),
+    (90, "xc"),
+    (50, "l"),
+    (40, "xl"),
+    (10, "x"),
+    (9, "ix"),
+    (5, "v"),
+    (4, "iv"),
+    (1, "i"),
+];
+
+fn to_roman(mut value: u32) -> String {
+    let mut roman = String::new();
+    for (numeral_value, numeral) in ROMAN_NUMERALS {
+        while value >= numeral_value {
+            roman.push_str(numeral);
+            value -= numeral_value;
+        }
+    }
+    roman
+}
+
+/// Only accepts lowercase numerals in canonical form, so that `to_roman` gives back the same string.
+fn from_roman(roman: &str) -> Option<u32> {
+    let mut rest = roman;
+    let mut value = 0;
+    for (numeral_value, numeral) in ROMAN_NUMERALS {
+        while let Some(stripped) = rest.strip_prefix(numeral) {
+            value += numeral_value;
+            rest = stripped;
+        }
+    }
+
+    (rest.is_empty() && value > 0 && to_roman(value) == roman).then_some(value)
+}
+
+fn from_arabic(label: &str) -> Option<u32> {
+    let canonical = !label.is_empty()
+        && label.bytes().all(|b| b.is_ascii_digit())
+        && (label == "0" || !label.starts_with('0'));
+    canonical.then(|| label.parse().ok()).flatten()
+}
+
+/// Parses ranges like `(1,a,1),(5,r,10),(8,c,Plate I|Plate II)`, with 1-based page numbers.
+pub(crate) fn parse_ranges(labels: &str) -> Result<Vec<PageLabelRange>, String> {
+    let mut ranges = vec![];
+    for range in labels.split('(').skip(1) {
+        let range = range
+            .split_once(')')
+            .map(|(range, _)| range)
+            .ok_or_else(|| "unterminated range".to_string())?;
+        let mut fields = range.splitn(3, ',');
+        let (Some(page), Some(kind), Some(value)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(format!("range {:?} has too few fields", range));
+        };
+
+        let first_page = page
+            .parse::<u32>()
+            .ok()
+            .and_then(|page| page.checked_sub(1))
+            .ok_or_else(|| format!("invalid page {:?}", page))?;
+        let number = || {
+            value
+                .parse::<u32>()
+                .map_err(|_| format!("invalid page number {:?}", value))
+        };
+        let labels = match kind {
+            "a" => PageLabels::Arabic(number()?),
+            "r" => PageLabels::Roman(number()?),
+            "c" => PageLabels::Custom(value.split('|').map(str::to_string).collect()),
+            kind => return Err(format!("unknown range type {:?}", kind)),
+        };
+        ranges.push(PageLabelRange { first_page, labels });
+    }
+
+    Ok(ranges)
+}
+
+/// Fails if a custom label is empty or contains `|`, `(` or `)`, which can't be represented.
+pub(crate) fn format_ranges(ranges: &[PageLabelRange]) -> Result<String, DekuError> {
+    let ranges = ranges
+        .iter()
+        .map(|range| {
+            let (kind, value) = match &range.labels {
+                PageLabels::Arabic(start) => ("a", start.to_string()),
+                PageLabels::Roman(start) => ("r", start.to_string()),
+                PageLabels::Custom(labels) => {
+                    if let Some(label) = labels
+                        .iter()
+                        .find(|label| label.is_empty() || label.contains(['|', '(', ')']))
+                    {
+                        return Err(DekuError::Parse(
+                            format!("Page label {:?} can't be written", label).into(),
+                        ));
+                    }
+                    ("c", labels.join("|"))
+                }
+            };
+            Ok(format!("({},{},{})", range.first_page + 1, kind, value))
+        })
+        .collect::<Result<Vec<_>, DekuError>>()?;
+    Ok(ranges.join(","))
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PageLabels {
+    /// Numbered from the given value.
+    Arabic(u32),
+    /// Lowercase roman numerals, numbered from the given value.
+    Roman(u32),
+    /// One label per page.
+    Custom(Vec<String>),
+}
+
+/// Labels for the pages from `first_page` until the next range.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PageLabelRange {
+    /// Index of the first page, starting from 0.
+    pub first_page: u32,
+    pub labels: PageLabels,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct PageMapRecord {
+    /// Sorted by `first_page`.
+    pub ranges: Vec<PageLabelRange>,
+    /// Where each page starts, in bytes from the start of the text flow.
+    pub offsets: Vec<u32>,
+}
+
+impl PageMapRecord {
+    /// Builds the smallest set of ranges that gives each page its label.
+    pub fn from_pages(pages: impl IntoIterator<Item = (String, u32)>) -> Self {
+        let mut ranges: Vec<PageLabelRange> = vec![];
+        let mut offsets = vec![];
+
+        for (page, (label, offset)) in pages.into_iter().enumerate() {
+            offsets.push(offset);
+
+            if let Some(range) = ranges.last_mut() {
+                let next = range.first_page as usize;
+                let continues = match &mut range.labels {
+                    PageLabels::Arabic(start) => {
+                        from_arabic(&label) == Some(*start + (page - next) as u32)
+                    }
+                    PageLabels::Roman(start) => {
+                        from_roman(&label) == Some(*start + (page - next) as u32)
+                    }
+                    PageLabels::Custom(labels) => {
+                        if from_arabic(&label).is_none() && from_roman(&label).is_none() {
+                            labels.push(label.clone());
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                };
+                if continues {
+                    continue;
+                }
+            }
+
+            let labels = if let Some(value) = from_arabic(&label) {
+                PageLabels::Arabic(value)
+            } else if let Some(value) = from_roman(&label) {
+                PageLabels::Roman(value)
+            } else {
+                PageLabels::Custom(vec![label])
+            };
+            ranges.push(PageLabelRange {
+                first_page: page as u32,
+                labels,
+            });
+        }
+
+        PageMapRecord { ranges, offsets }
+    }
+
+    /// The label of every page. Pages before the first range are numbered from 1.
+    pub fn labels(&self) -> Vec<String> {
+        (0..self.offsets.len())
+            .map(|page| {
+                let range = self
+                    .ranges
+                    .iter()
+                    .rev()
+                    .find(|range| range.first_page as usize <= page);
+                let Some(range) = range else {
+                    return (page + 1).to_string();
+                };
+
+                let index = page - range.first_page as usize;
+                match &range.labels {
+                    PageLabels::Arabic(start) => (*start as usize + index).to_string(),
+                    PageLabels::Roman(start) => to_roman(*start + index as u32),
+                    PageLabels::Custom(labels) => labels.get(index).cloned().unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
+    pub fn parse(record: &[u8]) -> Result<Self, DekuError> {
+        let invalid =
+            |reason: &str| DekuError::Parse(format!("Invalid PAGE record: {}", reason).into());
+        let read_u16 = |offset: usize| {
+            record
+                .get(offset..offset + 2)
+                .map(|bytes| u16::from_be_bytes(bytes.try_into().unwrap()))
+                .ok_or_else(|| invalid("truncated header"))
+        };
+
+        if !record.starts_with(b"PAGE") {
+            return Err(invalid("missing PAGE magic"));
+        }
+
+        // Skip the revision string
+        let revision_len = record
+            .get(0x10..HEADER_LEN)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+            .ok_or_else(|| invalid("truncated header"))?;
+        let start = HEADER_LEN + revision_len as usize;
+
+        let labels_len = read_u16(start + 2)? as usize;
+        let page_count = read_u16(start + 4)? as usize;
+        let offset_width = match read_u16(start + 6)? {
+            16 => 2,
+            32 => 4,
+            bits => return Err(invalid(&format!("{}-bit offsets", bits))),
+        };
+
+        let labels_start = start + 8;
+        let labels = record
+            .get(labels_start..labels_start + labels_len)
+            .ok_or_else(|| invalid("labels are out of bounds"))?;
+        let labels =
+            std::str::from_utf8(labels).map_err(|_| invalid("labels are not valid UTF-8"))?;
+
+        let offsets_start = labels_start + labels_len;
+        let offsets = record
+            .get(offsets_start..offsets_start + page_count * offset_width)
+            .ok_or_else(|| invalid("offsets are out of bounds"))?
+            .chunks(offset_width)
+            .map(|bytes| match bytes {
+                [a, b] => u16::from_be_bytes([*a, *b]) as u32,
+                bytes => u32::from_be_bytes(bytes.try_into().unwrap()),
+            })
+            .collect();
+
+        let ranges = parse_ranges(labels).map_err(|reason| invalid(&reason))?;
+
+        Ok(PageMapRecord { ranges, offsets })
+    }
+
+    /// Fails if a custom label can't be represented, see `format_ranges`.
+    pub fn to_record(&self) -> Result<Vec<u8>, DekuError> {
+        let ranges = format_ranges(&self.ranges)?;
+
+        let too_large =
+            |what: &str| DekuError::Parse(format!("Too many {} for a PAGE record", what).into());
+        let labels_len = u16::try_from(ranges.len()).map_err(|_| too_large("page labels"))?;
+        let page_count = u16::try_from(self.offsets.len()).map_err(|_| too_large("pages"))?;
+
+        let mut record = b"PAGE".to_vec();
+        record.resize(HEADER_LEN, 0);
+        for value in [1, labels_len, page_count, 32] {
+            record.extend_from_slice(&value.to_be_bytes());
+        }
+        record.extend_from_slice(ranges.as_bytes());
+        for offset in &self.offsets {
+            record.extend_from_slice(&offset.to_be_bytes());
+        }
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use proptest::{collection::vec, prop_assert_eq, proptest};
+
+    proptest! {
+        #[test]
+        fn test_roman_roundtrip(value in 1..4000u32) {
+            prop_assert_eq!(from_roman(&to_roman(value)), Some(value));
+        }
+
+        #[test]
+        fn test_page_map_roundtrip(
+            labels in vec("[0-9]{1,3}|[ivxlc]{1,4}|[A-Z][a-z ]{0,6}", 0..40),
+        ) {
+            let pages = labels.iter().cloned().zip((0..).map(|i| i * 100));
+            let page_map = PageMapRecord::from_pages(pages);
+            prop_assert_eq!(page_map.labels(), labels);
+
+            let parsed = PageMapRecord::parse(&page_map.to_record().unwrap()).unwrap();
+            prop_assert_eq!(parsed, page_map);
+        }
+    }
+
+    #[test]
+    fn test_from_pages() {
+        let labels = [
+            "i", "ii", "iii", "1", "2", "3", "Plate I", "Plate II", "4", "x",
+        ];
+        let page_map = PageMapRecord::from_pages(labels.iter().map(|label| (label.to_string(), 0)));
+
+        assert_eq!(
+            page_map.ranges,
+            [
+                PageLabelRange {
+                    first_page: 0,
+                    labels: PageLabels::Roman(1)
+                },
+                PageLabelRange {
+                    first_page: 3,
+                    labels: PageLabels::Arabic(1)
+                },
+                PageLabelRange {
+                    first_page: 6,
+                    labels: PageLabels::Custom(vec!["Plate I".to_string(), "Plate II".to_string()])
+                },
+                PageLabelRange {
+                    first_page: 8,
+                    labels: PageLabels::Arabic(4)
+                },
+                PageLabelRange {
+                    first_page: 9,
+                    labels: PageLabels::Roman(10)
+                },
+            ]
+        );
+        assert_eq!(page_map.labels(), labels);
+    }
+
+    #[test]
+    fn test_parse_16_bit_offsets() {
+        let labels = b"(1,r,1),(3,a,1)";
+        let mut record = b"PAGE".to_vec();
+        record.resize(0x10, 0);
+        record.extend_from_slice(&3u32.to_be_bytes());
+        record.extend_from_slice(b"1.0");
+        for value in [1, labels.len() as u16, 4, 16] {
+            record.extend_from_slice(&value.to_be_bytes());
+        }
+        record.extend_from_slice(labels);
+        for offset in [0u16, 10, 20, 30] {
+            record.extend_from_slice(&offset.to_be_bytes());
+        }
+
+        let page_map = PageMapRecord::parse(&record).unwrap();
+        assert_eq!(page_map.offsets, [0, 10, 20, 30]);
+        assert_eq!(page_map.labels(), ["i", "ii", "1", "2"]);
+    }
+
+    #[test]
+    fn test_rejects_invalid_labels() {
+        let page_map = PageMapRecord::from_pages([("Plate|I".to_string(), 0)]);
+        assert!(page_map.to_record().is_err());
+        assert!(PageMapRecord::parse(b"PAGE").is_err());
+    }
+}
@@ -1,11 +1,25 @@
-use std::io::{Read, Write};
+use std::io::{Cursor, Read, Write};
+use std::ops::Range;
 
+use binrw::BinRead;
 use deku::bitvec::*;
 use deku::prelude::*;
+use nom::{
+    bytes::complete::take,
+    multi::count,
+    number::complete::{be_u16, be_u32},
+    sequence::terminated,
+    IResult,
+};
 #[cfg(test)]
 use proptest_derive::Arbitrary;
 
-#[deku_derive(DekuRead, DekuWrite)]
+use super::MobiHeader;
+
+/// Length of the header before the record table.
+pub(super) const FIXED_HEADER_LEN: usize = 78;
+
+#[deku_derive(DekuWrite)]
 #[deku(ctx = "endian: deku::ctx::Endian", endian = "endian")]
 #[derive(Debug, PartialEq)]
 struct PalmDocRecordOffset {
@@ -24,43 +38,117 @@ fn escape_title(title: &String) -> String {
         .replace(' ', "_")
 }
 
-fn read_records<R: Read>(
-    reader: &mut deku::reader::Reader<R>,
-    num_records: u16,
-    record_offsets: &Vec<PalmDocRecordOffset>,
-) -> Result<Vec<Vec<u8>>, DekuError> {
-    let mut records = Vec::new();
-
-    for (start, end) in record_offsets.iter().zip(record_offsets.iter().skip(1)) {
-        let len = end.offset as usize - start.offset as usize;
-        let mut record = vec![0; len];
-        reader.read_bytes(len, &mut record)?;
-        records.push(record);
-    }
+/// Length of the header, the record table and the 2 bytes of padding after it.
+pub(crate) fn header_len(num_records: u16) -> usize {
+    FIXED_HEADER_LEN + 8 * num_records as usize + 2
+}
 
-    // Last record
-    if records.len() < num_records as usize {
-        let mut last_record = Vec::new();
-        // todo: more efficient
+/// The header fields and record table, shared by `PalmDoc`, `PalmDocReader` and `PalmDocRef`.
+pub(super) struct Header<'a> {
+    /// Without the trailing NUL padding.
+    pub(super) title: &'a [u8],
+    pub(super) created_at: u32,
+    pub(super) modified_at: u32,
+    pub(super) last_backed_up_at: u32,
+    record_offsets: Vec<u32>,
+}
 
-        let mut byte = [0; 1];
-        while let Ok(r) = reader.read_bytes_const(&mut byte) {
-            match r {
-                deku::reader::ReaderRet::Bytes => {
-                    last_record.push(byte[0]);
-                }
-                deku::reader::ReaderRet::Bits(b) => {
-                    // WHY!!!!
-                    let value: u8 = b.unwrap().load_be::<u8>();
-                    last_record.push(value);
-                }
+impl Header<'_> {
+    pub(super) fn title_string(&self) -> Result<String, DekuError> {
+        String::from_utf8(self.title.to_vec()).map_err(|_| DekuError::Parse("Invalid UTF-8".into()))
+    }
+
+    /// Where each record is in a file of `len` bytes. The last record runs to the end of the file.
+    pub(super) fn record_ranges(&self, len: u64) -> Result<Vec<Range<u64>>, DekuError> {
+        let header_len = header_len(self.record_offsets.len() as u16) as u64;
+        if let Some(&first) = self.record_offsets.first() {
+            if (first as u64) < header_len {
+                return Err(DekuError::Parse(
+                    format!("Record 0 starts inside the header (offset {})", first).into(),
+                ));
             }
         }
 
-        records.push(last_record);
+        let ends = self
+            .record_offsets
+            .iter()
+            .skip(1)
+            .map(|&offset| offset as u64)
+            .chain(std::iter::once(len));
+        self.record_offsets
+            .iter()
+            .zip(ends)
+            .enumerate()
+            .map(|(i, (&start, end))| {
+                let start = start as u64;
+                if start > end || end > len {
+                    return Err(DekuError::Parse(
+                        format!(
+                            "Record {} spans {}..{} of a {} byte file",
+                            i, start, end, len
+                        )
+                        .into(),
+                    ));
+                }
+                Ok(start..end)
+            })
+            .collect()
     }
+}
+
+fn parse_header_fields(input: &[u8]) -> IResult<&[u8], Header<'_>> {
+    let (input, title) = take(32usize)(input)?;
+    let (input, _) = take(4usize)(input)?; // attributes and version
+    let (input, created_at) = be_u32(input)?;
+    let (input, modified_at) = be_u32(input)?;
+    let (input, last_backed_up_at) = be_u32(input)?;
+    let (input, _) = take(28usize)(input)?;
+    let (input, num_records) = be_u16(input)?;
+    // Each offset is followed by the record's flags and unique id
+    let (input, record_offsets) =
+        count(terminated(be_u32, take(4usize)), num_records as usize)(input)?;
+    let (input, _) = take(2usize)(input)?;
 
-    Ok(records)
+    let title_len = title.iter().position(|&b| b == 0).unwrap_or(title.len());
+    Ok((
+        input,
+        Header {
+            title: &title[..title_len],
+            created_at,
+            modified_at,
+            last_backed_up_at,
+            record_offsets,
+        },
+    ))
+}
+
+/// Parses the header from the start of `input`, which must hold at least `header_len` bytes.
+pub(super) fn parse_header(input: &[u8]) -> Result<Header<'_>, DekuError> {
+    parse_header_fields(input)
+        .map(|(_, header)| header)
+        .map_err(|e| DekuError::Parse(format!("Could not parse PalmDoc header: {}", e).into()))
+}
+
+/// Number of records, from the first `FIXED_HEADER_LEN` bytes of the header.
+pub(super) fn num_records(fixed_header: &[u8]) -> u16 {
+    u16::from_be_bytes([fixed_header[76], fixed_header[77]])
+}
+
+/// Reads the rest of the input. deku's `Reader` can't tell how much is left, so this goes a byte at a time; `PalmDoc::from_bytes` and `PalmDoc::from_reader` avoid it.
+fn read_to_end<R: Read>(reader: &mut deku::reader::Reader<R>) -> Result<Vec<u8>, DekuError> {
+    let mut rest = Vec::new();
+    while !reader.end() {
+        let mut byte = [0; 1];
+        match reader.read_bytes_const(&mut byte)? {
+            deku::reader::ReaderRet::Bytes => rest.push(byte[0]),
+            // `end` buffers the byte it peeked at as bits
+            deku::reader::ReaderRet::Bits(bits) => {
+                let bits = bits.ok_or(DekuError::Parse("Missing byte".into()))?;
+                rest.push(bits.load_be::<u8>());
+            }
+        }
+    }
+    Ok(rest)
 }
 
 fn write_records<W: Write>(
@@ -88,14 +176,14 @@ fn write_record_offsets<W: Write>(
     Ok(())
 }
 
-#[deku_derive(DekuRead, DekuWrite)]
+/// Reading is implemented by hand below, so that the last record, which runs to the end of the input, can be read at once.
+#[deku_derive(DekuWrite)]
 #[deku(endian = "big")]
 // todo: should not be Cloneable
 #[derive(Debug, PartialEq, Clone)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub struct PalmDoc {
     #[deku(
-        reader = "crate::utils::deku::read_string(deku::reader, 32)",
         writer = "crate::utils::deku::write_fixed_length_string(deku::writer, escape_title(title).as_str(), 32)"
     )]
     #[cfg_attr(test, proptest(strategy = "\"[a-zA-Z0-9]{0, 32}\""))]
@@ -126,21 +214,85 @@ pub struct PalmDoc {
     _unused_next_record_list_id: u32,
     #[deku(temp, temp_value = "records.len() as u16")]
     num_records: u16,
-    #[deku(
-        temp,
-        count = "num_records",
-        writer = "write_record_offsets(deku::writer, records)"
-    )]
+    #[deku(temp, writer = "write_record_offsets(deku::writer, records)")]
     record_offsets: Vec<PalmDocRecordOffset>,
     #[deku(temp, temp_value = "[0; 2]")]
     _padding: [u8; 2],
-    #[deku(
-        reader = "read_records(deku::reader, *num_records, record_offsets)",
-        writer = "write_records(deku::writer, records)"
-    )]
+    #[deku(writer = "write_records(deku::writer, records)")]
     pub records: Vec<Vec<u8>>,
 }
 
+impl PalmDoc {
+    fn from_header_and_data(header: &Header, data: &[u8]) -> Result<Self, DekuError> {
+        let records = header
+            .record_ranges(data.len() as u64)?
+            .into_iter()
+            .map(|range| data[range.start as usize..range.end as usize].to_vec())
+            .collect();
+
+        Ok(PalmDoc {
+            title: header.title_string()?,
+            created_at: header.created_at,
+            modified_at: header.modified_at,
+            last_backed_up_at: header.last_backed_up_at,
+            records,
+        })
+    }
+
+    /// Parses the MOBI header from record 0.
+    pub fn mobi_header(&self) -> Result<MobiHeader, DekuError> {
+        let first_record = self
+            .records
+            .first()
+            .ok_or(DekuError::Parse("No records".into()))?;
+        MobiHeader::read(&mut Cursor::new(first_record))
+            .map_err(|e| DekuError::Parse(format!("Could not parse MOBI header: {}", e).into()))
+    }
+}
+
+impl<'a> DekuReader<'a, ()> for PalmDoc {
+    fn from_reader_with_ctx<R: Read>(
+        reader: &mut deku::reader::Reader<R>,
+        _ctx: (),
+    ) -> Result<Self, DekuError> {
+        let mut data = crate::utils::deku::read_vec(reader, FIXED_HEADER_LEN)?;
+        let header_len = header_len(num_records(&data));
+        data.extend(crate::utils::deku::read_vec(
+            reader,
+            header_len - FIXED_HEADER_LEN,
+        )?);
+        data.extend(read_to_end(reader)?);
+
+        Self::from_header_and_data(&parse_header(&data)?, &data)
+    }
+}
+
+impl<'a> DekuContainerRead<'a> for PalmDoc {
+    fn from_reader<R: Read>(input: (&'a mut R, usize)) -> Result<(usize, Self), DekuError> {
+        let (reader, bit_offset) = input;
+        if bit_offset != 0 {
+            return Err(DekuError::Parse("PalmDoc must start on a byte".into()));
+        }
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .map_err(|e| DekuError::Io(e.kind()))?;
+
+        let palmdoc = Self::from_header_and_data(&parse_header(&data)?, &data)?;
+        Ok((data.len() * 8, palmdoc))
+    }
+
+    fn from_bytes(input: (&'a [u8], usize)) -> Result<((&'a [u8], usize), Self), DekuError> {
+        let (data, bit_offset) = input;
+        if bit_offset != 0 {
+            return Err(DekuError::Parse("PalmDoc must start on a byte".into()));
+        }
+
+        let palmdoc = Self::from_header_and_data(&parse_header(data)?, data)?;
+        Ok(((&data[data.len()..], 0), palmdoc))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
@@ -160,8 +312,34 @@ mod tests {
         serialized.set_position(0);
         let mut reader = Reader::new(&mut serialized);
         let decoded = PalmDoc::from_reader_with_ctx(&mut reader, ()).expect("could not parse");
+        assert_eq!(header, decoded);
 
+        let serialized = serialized.into_inner();
+        let (_, decoded) = PalmDoc::from_bytes((&serialized, 0)).expect("could not parse");
+        assert_eq!(header, decoded);
+        let (_, decoded) = PalmDoc::from_reader((&mut Cursor::new(&serialized), 0)).expect("could not parse");
         assert_eq!(header, decoded);
       }
     }
+
+    #[test]
+    fn test_palmdoc_descending_record_offsets() {
+        let palmdoc = PalmDoc {
+            title: "test".to_string(),
+            created_at: 0,
+            modified_at: 0,
+            last_backed_up_at: 0,
+            records: vec![vec![1; 4], vec![2; 4], vec![3; 4]],
+        };
+        let mut serialized = palmdoc.to_bytes().unwrap();
+
+        // Point record 1 before record 0
+        serialized[86..90].copy_from_slice(&0u32.to_be_bytes());
+
+        assert!(PalmDoc::from_bytes((&serialized, 0)).is_err());
+
+        // Point record 0 into the record table
+        serialized[78..82].copy_from_slice(&80u32.to_be_bytes());
+        assert!(PalmDoc::from_bytes((&serialized, 0)).is_err());
+    }
 }
@@ -0,0 +1,180 @@
+use std::{
+    io::{Cursor, Read, Seek, SeekFrom},
+    ops::Range,
+};
+
+use binrw::BinRead;
+use deku::DekuError;
+
+use super::{
+    palmdoc::{header_len, num_records, parse_header, FIXED_HEADER_LEN},
+    CompressionType, MobiHeader,
+};
+
+fn io_error(e: std::io::Error) -> DekuError {
+    DekuError::Io(e.kind())
+}
+
+/// Reads records on demand from a seekable source instead of loading the whole file like `PalmDoc` does.
+/// Only the record offset table is kept in memory.
+pub struct PalmDocReader<R> {
+    reader: R,
+    pub title: String,
+    /// seconds since epoch
+    pub created_at: u32,
+    /// seconds since epoch
+    pub modified_at: u32,
+    /// seconds since epoch
+    pub last_backed_up_at: u32,
+    record_ranges: Vec<Range<u64>>,
+}
+
+impl<R: Read + Seek> PalmDocReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, DekuError> {
+        reader.seek(SeekFrom::Start(0)).map_err(io_error)?;
+        let mut data = vec![0; FIXED_HEADER_LEN];
+        reader.read_exact(&mut data).map_err(io_error)?;
+        data.resize(header_len(num_records(&data)), 0);
+        reader
+            .read_exact(&mut data[FIXED_HEADER_LEN..])
+            .map_err(io_error)?;
+        let header = parse_header(&data)?;
+
+        let len = reader.seek(SeekFrom::End(0)).map_err(io_error)?;
+        let record_ranges = header.record_ranges(len)?;
+
+        Ok(Self {
+            reader,
+            title: header.title_string()?,
+            created_at: header.created_at,
+            modified_at: header.modified_at,
+            last_backed_up_at: header.last_backed_up_at,
+            record_ranges,
+        })
+    }
+
+    pub fn num_records(&self) -> usize {
+        self.record_ranges.len()
+    }
+
+    pub fn record(&mut self, i: usize) -> Result<Vec<u8>, DekuError> {
+        let range = self
+            .record_ranges
+            .get(i)
+            .ok_or_else(|| DekuError::Parse(format!("Record {} does not exist", i).into()))?;
+
+        self.reader
+            .seek(SeekFrom::Start(range.start))
+            .map_err(io_error)?;
+        let mut record = vec![0; (range.end - range.start) as usize];
+        self.reader.read_exact(&mut record).map_err(io_error)?;
+
+        Ok(record)
+    }
+
+    pub fn mobi_header(&mut self) -> Result<MobiHeader, DekuError> {
+        let record = self.record(0)?;
+        MobiHeader::read(&mut Cursor::new(record))
+            .map_err(|e| DekuError::Parse(format!("Could not parse MOBI header: {}", e).into()))
+    }
+
+    /// Iterates over the decompressed text records, reading one record at a time.
+    pub fn text_records<'a>(&'a mut self, mobi_header: &'a MobiHeader) -> TextRecords<'a, R> {
+        TextRecords {
+            reader: self,
+            mobi_header,
+            next: 1,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+pub struct TextRecords<'a, R> {
+    reader: &'a mut PalmDocReader<R>,
+    mobi_header: &'a MobiHeader,
+    next: usize,
+}
+
+impl<'a, R: Read + Seek> Iterator for TextRecords<'a, R> {
+    type Item = Result<Vec<u8>, DekuError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next > self.mobi_header.num_of_text_records as usize {
+            return None;
+        }
+
+        let i = self.next;
+        self.next += 1;
+
+        Some(self.reader.record(i).and_then(|record| {
+            let trailing_entries_len = self.mobi_header.sizeof_trailing_section_entries(&record)?;
+            let record_data = &record[..record.len() - trailing_entries_len];
+
+            match self.mobi_header.compression_type {
+                CompressionType::None => Ok(record_data.to_vec()),
+                CompressionType::PalmDoc => crate::compression::palmdoc::decompress(record_data)
+                    .map_err(|_| DekuError::Parse("Failed to decompress".into())),
+                CompressionType::HuffCdic => Err(DekuError::Parse(
+                    "HUFF/CDIC compression is not supported".into(),
+                )),
+            }
+        }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining =
+            (self.mobi_header.num_of_text_records as usize + 1).saturating_sub(self.next);
+        (remaining, Some(remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+    use crate::serialization::PalmDoc;
+    use deku::DekuContainerRead;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_reader_matches_palmdoc() {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+
+        let mut reader =
+            PalmDocReader::new(File::open("resources/war_and_peace.azw3").unwrap()).unwrap();
+
+        assert_eq!(reader.title, palmdoc.title);
+        assert_eq!(reader.created_at, palmdoc.created_at);
+        assert_eq!(reader.num_records(), palmdoc.records.len());
+        for i in [0, 1, 1100, palmdoc.records.len() - 1] {
+            assert_eq!(reader.record(i).unwrap(), palmdoc.records[i]);
+        }
+        assert!(reader.record(palmdoc.records.len()).is_err());
+    }
+
+    #[test]
+    fn test_text_records() {
+        let mut reader =
+            PalmDocReader::new(File::open("resources/war_and_peace.azw3").unwrap()).unwrap();
+        let mobi_header = reader.mobi_header().unwrap();
+
+        let text_len = reader
+            .text_records(&mobi_header)
+            .map(|record| record.unwrap().len())
+            .sum::<usize>();
+        assert_eq!(text_len, mobi_header.text_length as usize);
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        let mut data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        data.truncate(data.len() / 2);
+
+        assert!(PalmDocReader::new(Cursor::new(data)).is_err());
+    }
+}
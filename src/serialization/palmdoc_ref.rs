@@ -0,0 +1,206 @@
+use std::{io::Cursor, ops::Range};
+
+use super::{
+    index::{index_record_count, record_entries},
+    palmdoc::parse_header,
+    ExthRef, IndexEntryRef, MobiHeader, PalmDoc, TotalIndexEntry,
+};
+use binrw::BinRead;
+use deku::DekuError;
+
+/// A PalmDoc database borrowed from a byte slice (e.g. a memory-mapped file).
+/// Records, the EXTH block and index entries are handed out as slices of the input rather than copied. `mobi_header` and `index` still parse into owned values.
+#[derive(Debug, Clone)]
+pub struct PalmDocRef<'a> {
+    /// The raw title, without the trailing NUL padding.
+    pub title: &'a [u8],
+    /// seconds since epoch
+    pub created_at: u32,
+    /// seconds since epoch
+    pub modified_at: u32,
+    /// seconds since epoch
+    pub last_backed_up_at: u32,
+    records: Vec<&'a [u8]>,
+    record_ranges: Vec<Range<usize>>,
+}
+
+impl<'a> PalmDocRef<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, DekuError> {
+        let header = parse_header(data)?;
+        let record_ranges: Vec<Range<usize>> = header
+            .record_ranges(data.len() as u64)?
+            .into_iter()
+            .map(|range| range.start as usize..range.end as usize)
+            .collect();
+        let records = record_ranges
+            .iter()
+            .map(|range| &data[range.clone()])
+            .collect();
+
+        Ok(Self {
+            title: header.title,
+            created_at: header.created_at,
+            modified_at: header.modified_at,
+            last_backed_up_at: header.last_backed_up_at,
+            records,
+            record_ranges,
+        })
+    }
+
+    pub fn records(&self) -> &[&'a [u8]] {
+        &self.records
+    }
+
+    pub fn record(&self, i: usize) -> Option<&'a [u8]> {
+        self.records.get(i).copied()
+    }
+
+    /// Where record `i` is in the input.
+    pub fn record_range(&self, i: usize) -> Option<Range<usize>> {
+        self.record_ranges.get(i).cloned()
+    }
+
+    pub fn mobi_header(&self) -> Result<MobiHeader, DekuError> {
+        let record = self
+            .record(0)
+            .ok_or_else(|| DekuError::Parse("No records".into()))?;
+        MobiHeader::read(&mut Cursor::new(record))
+            .map_err(|e| DekuError::Parse(format!("Could not parse MOBI header: {}", e).into()))
+    }
+
+    /// Returns the (still compressed) contents of text record `i`, without its trailing entries.
+    pub fn text_record(&self, mobi_header: &MobiHeader, i: usize) -> Result<&'a [u8], DekuError> {
+        let record = self
+            .record(i)
+            .ok_or_else(|| DekuError::Parse(format!("Record {} does not exist", i).into()))?;
+        let trailing_entries_len = mobi_header.sizeof_trailing_section_entries(record)?;
+        Ok(&record[..record.len() - trailing_entries_len])
+    }
+
+    /// The EXTH block of the MOBI header, borrowed from record 0. `None` if the header says there is none.
+    pub fn exth(&self) -> Result<Option<ExthRef<'a>>, DekuError> {
+        let record = self
+            .record(0)
+            .ok_or_else(|| DekuError::Parse("No records".into()))?;
+        let field = |offset: usize| {
+            record
+                .get(offset..offset + 4)
+                .map(|field| u32::from_be_bytes(field.try_into().unwrap()))
+                .ok_or_else(|| DekuError::Parse("MOBI header is truncated".into()))
+        };
+
+        let exth_flags = field(0x80)?;
+        if exth_flags & 0b1010000 == 0 {
+            return Ok(None);
+        }
+        // EXTH follows the MOBI header, which starts at 16 and gives its own length
+        let start = 16 + field(20)? as usize;
+        let exth = record
+            .get(start..)
+            .ok_or_else(|| DekuError::Parse("EXTH is out of bounds".into()))?;
+        ExthRef::parse(exth).map(Some)
+    }
+
+    /// Iterates over the entries of the index whose header is at record `i`, without decoding their tags. Use `index` for the decoded entries.
+    pub fn index_entries(
+        &self,
+        i: usize,
+    ) -> Result<impl Iterator<Item = Result<IndexEntryRef<'a>, DekuError>> + '_, DekuError> {
+        let header_record = self
+            .record(i)
+            .ok_or_else(|| DekuError::Parse(format!("Record {} does not exist", i).into()))?;
+        let num_records = index_record_count(header_record)?;
+        let index_records = self
+            .records
+            .get(i + 1..)
+            .and_then(|records| records.get(..num_records))
+            .ok_or_else(|| {
+                DekuError::Parse(format!("INDX header references {} records", num_records).into())
+            })?;
+
+        Ok(index_records.iter().flat_map(|record| {
+            record_entries(record).map(|entry| entry.and_then(IndexEntryRef::parse))
+        }))
+    }
+
+    /// Parses the index whose header is at record `i`.
+    pub fn index(&self, i: usize) -> Result<TotalIndexEntry, DekuError> {
+        let records = self
+            .records
+            .get(i..)
+            .ok_or_else(|| DekuError::Parse(format!("Record {} does not exist", i).into()))?;
+        TotalIndexEntry::from_records(records)
+    }
+
+    /// Copies the records into an owned `PalmDoc`.
+    pub fn to_palmdoc(&self) -> Result<PalmDoc, DekuError> {
+        Ok(PalmDoc {
+            title: String::from_utf8(self.title.to_vec())
+                .map_err(|_| DekuError::Parse("Invalid UTF-8".into()))?,
+            created_at: self.created_at,
+            modified_at: self.modified_at,
+            last_backed_up_at: self.last_backed_up_at,
+            records: self.records.iter().map(|record| record.to_vec()).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{MetadataId, MetadataIdValue},
+        serialization::{ChunkTagMapEntry, PalmDoc},
+    };
+    use deku::DekuContainerRead;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_palmdoc_ref_matches_palmdoc() {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+
+        let palmdoc_ref = PalmDocRef::parse(&data).unwrap();
+        assert_eq!(palmdoc_ref.to_palmdoc().unwrap(), palmdoc);
+
+        let mobi_header = palmdoc_ref.mobi_header().unwrap();
+        let chunks = palmdoc_ref
+            .index(mobi_header.chunk_index as usize)
+            .unwrap()
+            .parse_as::<ChunkTagMapEntry>()
+            .unwrap()
+            .len();
+        assert!(chunks > 0);
+    }
+
+    #[test]
+    fn test_borrowed_views() {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let palmdoc_ref = PalmDocRef::parse(&data).unwrap();
+        let mobi_header = palmdoc_ref.mobi_header().unwrap();
+
+        let exth = palmdoc_ref.exth().unwrap().unwrap();
+        assert_eq!(Some(exth.to_exth()), mobi_header.exth);
+        assert_eq!(exth.string(MetadataId::UpdatedTitle), Some("War and Peace"));
+        assert_eq!(exth.value(MetadataIdValue::CoverOffset), Some(0));
+
+        let index = palmdoc_ref.index(mobi_header.skel_index as usize).unwrap();
+        let entries = palmdoc_ref
+            .index_entries(mobi_header.skel_index as usize)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(entries.len(), index.entries().len());
+        for (entry, parsed) in entries.iter().zip(index.entries()) {
+            assert_eq!(entry.key, parsed.text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_palmdoc_ref_truncated() {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+
+        assert!(PalmDocRef::parse(&data[..data.len() / 2]).is_err());
+        assert!(PalmDocRef::parse(&data[..100]).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use deku::DekuError;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseOptions {
+    /// Fail on any problem that would drop or replace content. When `false`, the reader skips what it can't parse and reports it as a `ParseWarning` instead.
+    pub strict: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self { strict: true }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ParseWarning {
+    #[error("Trailing entries of text record {record} are invalid")]
+    InvalidTrailingEntries { record: usize },
+    #[error("Text record {record} could not be decompressed")]
+    DecompressionFailed { record: usize },
+    #[error("Expected {expected} text records, found {found}")]
+    MissingTextRecords { expected: usize, found: usize },
+    #[error("Expected {expected} bytes of text, found {actual}")]
+    TextLengthMismatch { expected: usize, actual: usize },
+    #[error("FDST record is invalid, using the whole text as a single flow: {reason}")]
+    InvalidFdst { reason: String },
+    #[error("{name} index is invalid, {}: {reason}", index_fallback(.name))]
+    InvalidIndex { name: &'static str, reason: String },
+    #[error("Part {part} is not valid UTF-8")]
+    InvalidUtf8 { part: usize },
+    #[error("Flow {flow} is not valid UTF-8")]
+    InvalidFlowUtf8 { flow: usize },
+    #[error("{name} record {record} is missing")]
+    MissingRecord { name: &'static str, record: u32 },
+    #[error("FONT record {record} could not be read: {reason}")]
+    InvalidFont { record: usize, reason: String },
+    #[error("RESC record {record} could not be read: {reason}")]
+    InvalidResc { record: usize, reason: String },
+    #[error("DATP record {record} could not be read: {reason}")]
+    InvalidDatp { record: usize, reason: String },
+    #[error("SRCS record {record} could not be read: {reason}")]
+    InvalidSrcs { record: usize, reason: String },
+    #[error("PAGE record {record} could not be read: {reason}")]
+    InvalidPageMap { record: usize, reason: String },
+    #[error(
+        "kindle:embed reference to resource record {record} does not point to an image or font"
+    )]
+    UnresolvedEmbed { record: usize },
+    #[error("Metadata is invalid and was skipped: {reason}")]
+    InvalidMetadata { reason: String },
+}
+
+/// What the reader does instead of using an invalid index.
+fn index_fallback(name: &str) -> &'static str {
+    match name {
+        "NCX" => "leaving out the table of contents",
+        "Guide" => "leaving out the guide",
+        _ => "using the text flow as a single part",
+    }
+}
+
+/// Collects warnings while reading, or turns them into errors in strict mode.
+pub(crate) struct Diagnostics<'a> {
+    options: &'a ParseOptions,
+    warnings: Vec<ParseWarning>,
+}
+
+impl<'a> Diagnostics<'a> {
+    pub fn new(options: &'a ParseOptions) -> Self {
+        Self {
+            options,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Reports a problem the reader is about to work around by dropping or replacing content.
+    pub fn recover(&mut self, warning: ParseWarning) -> Result<(), DekuError> {
+        if self.options.strict {
+            return Err(DekuError::Parse(warning.to_string().into()));
+        }
+
+        self.warnings.push(warning);
+        Ok(())
+    }
+
+    /// Reports a problem that doesn't affect the parsed content, so it is never fatal.
+    pub fn warn(&mut self, warning: ParseWarning) {
+        self.warnings.push(warning);
+    }
+
+    pub fn into_warnings(self) -> Vec<ParseWarning> {
+        self.warnings
+    }
+}
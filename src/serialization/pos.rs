@@ -0,0 +1,70 @@
+//! `kindle:pos:fid:XXXX:off:XXXXXXXXXX` references, which link to a position in the text.
+
+use std::fmt;
+
+use crate::utils::base32;
+
+const PREFIX: &str = "kindle:pos:fid:";
+
+/// Every reference has the same length, so it can be filled in once the text around it is laid out.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PosReference {
+    /// Index into the chunk index.
+    pub fid: usize,
+    /// Offset from the start of the chunk, in bytes of the encoded text.
+    pub offset: usize,
+}
+
+impl PosReference {
+    pub fn new(fid: usize, offset: usize) -> Self {
+        PosReference { fid, offset }
+    }
+
+    /// Parses a whole reference, like the value of an `href` attribute.
+    pub fn parse(reference: &str) -> Option<Self> {
+        let rest = reference.trim().strip_prefix(PREFIX)?;
+        let (fid, offset) = rest.split_once(":off:")?;
+        Some(PosReference {
+            fid: base32::decode(fid)? as usize,
+            offset: base32::decode(offset)? as usize,
+        })
+    }
+}
+
+impl fmt::Display for PosReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}:off:{}",
+            PREFIX,
+            base32::encode(self.fid as u32, 4),
+            base32::encode(self.offset as u32, 10)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use proptest::{prop_assert_eq, proptest};
+
+    proptest! {
+        #[test]
+        fn test_pos_roundtrip(fid in 0..1_000_000usize, offset in 0..u32::MAX as usize) {
+            let reference = PosReference::new(fid, offset);
+            prop_assert_eq!(reference.to_string().len(), "kindle:pos:fid:0000:off:0000000000".len());
+            prop_assert_eq!(PosReference::parse(&reference.to_string()), Some(reference));
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            PosReference::parse("kindle:pos:fid:000A:off:00000000V0"),
+            Some(PosReference::new(10, 992))
+        );
+        assert_eq!(PosReference::parse("kindle:pos:fid:000A"), None);
+        assert_eq!(PosReference::parse("kindle:flow:0001"), None);
+    }
+}
@@ -0,0 +1,311 @@
+//! Which tool built a book, from the creator EXTH records and kindlegen's `CMET` and `kind` records.
+
+use std::fmt;
+
+use deku::DekuError;
+
+use crate::constants::{MetadataId, MetadataIdValue};
+
+use super::{Exth, PalmDoc};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CreatorSoftware {
+    Mobigen,
+    MobipocketCreator,
+    KindlegenWindows,
+    KindlegenLinux,
+    KindlegenMac,
+    Unknown(u32),
+}
+
+impl From<u32> for CreatorSoftware {
+    fn from(value: u32) -> Self {
+        match value {
+            1 => CreatorSoftware::Mobigen,
+            2 => CreatorSoftware::MobipocketCreator,
+            200 => CreatorSoftware::KindlegenWindows,
+            201 => CreatorSoftware::KindlegenLinux,
+            202 => CreatorSoftware::KindlegenMac,
+            value => CreatorSoftware::Unknown(value),
+        }
+    }
+}
+
+impl fmt::Display for CreatorSoftware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CreatorSoftware::Mobigen => write!(f, "mobigen"),
+            CreatorSoftware::MobipocketCreator => write!(f, "Mobipocket Creator"),
+            CreatorSoftware::KindlegenWindows => write!(f, "kindlegen (Windows)"),
+            CreatorSoftware::KindlegenLinux => write!(f, "kindlegen (Linux)"),
+            CreatorSoftware::KindlegenMac => write!(f, "kindlegen (Mac)"),
+            CreatorSoftware::Unknown(value) => write!(f, "unknown software {}", value),
+        }
+    }
+}
+
+/// From the `CreatorSoftware` and `Creator*Version` EXTH records.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Creator {
+    pub software: CreatorSoftware,
+    pub major_version: Option<u32>,
+    pub minor_version: Option<u32>,
+    pub build_number: Option<u32>,
+}
+
+impl fmt::Display for Creator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.software)?;
+        if let Some(major) = self.major_version {
+            write!(f, " {}.{}", major, self.minor_version.unwrap_or(0))?;
+        }
+        if let Some(build) = self.build_number {
+            write!(f, " build {}", build)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LogLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+/// A line of the build log like `Warning(prcgen):W14001: Hyperlink not resolved: ...`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LogEntry {
+    pub level: LogLevel,
+    /// The kindlegen component, e.g. `prcgen`.
+    pub source: String,
+    /// e.g. `W14001`.
+    pub code: String,
+    pub message: String,
+}
+
+impl LogEntry {
+    fn parse(line: &str) -> Option<Self> {
+        let (level, rest) = line.trim().split_once('(')?;
+        let level = match level {
+            "Info" => LogLevel::Info,
+            "Warning" => LogLevel::Warning,
+            "Error" => LogLevel::Error,
+            _ => return None,
+        };
+        let (source, rest) = rest.split_once("):")?;
+        let (code, message) = rest.split_once(':').unwrap_or((rest, ""));
+
+        Some(LogEntry {
+            level,
+            source: source.to_string(),
+            code: code.trim().to_string(),
+            message: message.trim().to_string(),
+        })
+    }
+}
+
+/// The `CMET` record, which holds kindlegen's build log.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompilationLog {
+    pub text: String,
+    /// The lines of `text` that are log entries.
+    pub entries: Vec<LogEntry>,
+}
+
+impl CompilationLog {
+    pub fn parse(record: &[u8]) -> Result<Self, DekuError> {
+        if !record.starts_with(b"CMET") {
+            return Err(DekuError::Parse("Missing CMET magic".into()));
+        }
+
+        // The header is the magic, its own length and the length of the log. Without a plausible length, the log starts at the first printable character.
+        let header_len = record
+            .get(4..8)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+            .filter(|len| (8..=record.len()).contains(len));
+        let start = header_len.unwrap_or_else(|| {
+            record[4..]
+                .iter()
+                .position(|b| b.is_ascii_graphic())
+                .map_or(record.len(), |start| start + 4)
+        });
+        let text = String::from_utf8_lossy(&record[start..])
+            .trim_end_matches('\0')
+            .to_string();
+        let entries = text.lines().filter_map(LogEntry::parse).collect();
+
+        Ok(CompilationLog { text, entries })
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &LogEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.level == LogLevel::Warning)
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &LogEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.level == LogLevel::Error)
+    }
+}
+
+/// The `kind` record, where kindlegen writes its version, e.g. `kindlegen V2.9 build 1028-0897292`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct KindRecord {
+    pub text: String,
+    pub version: Option<String>,
+    pub build: Option<String>,
+}
+
+impl KindRecord {
+    pub fn parse(record: &[u8]) -> Result<Self, DekuError> {
+        if !record.starts_with(b"kind") {
+            return Err(DekuError::Parse("Missing kind magic".into()));
+        }
+
+        let text = String::from_utf8_lossy(record.split(|b| *b == 0).next().unwrap_or_default())
+            .trim()
+            .to_string();
+        let mut words = text.split_whitespace();
+        let version = words
+            .find_map(|word| word.strip_prefix(['V', 'v']))
+            .filter(|version| version.starts_with(|c: char| c.is_ascii_digit()))
+            .map(str::to_string);
+        let build = text
+            .split_once("build ")
+            .and_then(|(_, rest)| rest.split_whitespace().next())
+            .map(str::to_string);
+
+        Ok(KindRecord {
+            text,
+            version,
+            build,
+        })
+    }
+}
+
+/// Everything a book records about the tool that built it. Any part can be missing, e.g. books from other converters have no `CMET` record.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Provenance {
+    pub creator: Option<Creator>,
+    /// The `CreatorBuildTag` EXTH record, e.g. `0730-890adc2`.
+    pub build_tag: Option<String>,
+    pub kind: Option<KindRecord>,
+    pub compilation_log: Option<CompilationLog>,
+}
+
+impl Provenance {
+    pub(crate) fn new(
+        exth: Option<&Exth>,
+        kind: Option<KindRecord>,
+        compilation_log: Option<CompilationLog>,
+    ) -> Self {
+        let creator = exth.and_then(|exth| {
+            Some(Creator {
+                software: exth.value(MetadataIdValue::CreatorSoftware)?.into(),
+                major_version: exth.value(MetadataIdValue::CreatorMajorVersion),
+                minor_version: exth.value(MetadataIdValue::CreatorMinorVersion),
+                build_number: exth.value(MetadataIdValue::CreatorBuildNumber),
+            })
+        });
+
+        Provenance {
+            creator,
+            build_tag: exth
+                .and_then(|exth| exth.string(MetadataId::CreatorBuildTag))
+                .map(str::to_string),
+            kind,
+            compilation_log,
+        }
+    }
+
+    pub fn from_palmdoc(palmdoc: &PalmDoc) -> Result<Self, DekuError> {
+        let mobi_header = palmdoc.mobi_header()?;
+
+        let mut kind = None;
+        let mut compilation_log = None;
+        for data in palmdoc
+            .records
+            .iter()
+            .skip(mobi_header.first_non_text_record as usize)
+        {
+            match data.get(..4) {
+                Some(b"kind") => kind = Some(KindRecord::parse(data)?),
+                Some(b"CMET") => compilation_log = Some(CompilationLog::parse(data)?),
+                _ => {}
+            }
+        }
+
+        Ok(Provenance::new(
+            mobi_header.exth.as_ref(),
+            kind,
+            compilation_log,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deku::DekuContainerRead;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_compilation_log() {
+        let mut record = b"CMET\0\0\0\x0c\0\0\x01\x2c".to_vec();
+        record.extend_from_slice(
+            b"Amazon kindlegen(Linux) V2.9 build 1028-0897292\r\n\
+            Info(prcgen):I1047: Added metadata dc:Title        \"War and Peace\"\r\n\
+            Warning(prcgen):W14001: Hyperlink not resolved: /tmp/mobi/toc.xhtml#ch1\r\n\
+            Info(prcgen):I15000:  Approximate Standard Mobi Deliverable file size :   0000158KB\r\n\0\0",
+        );
+
+        let log = CompilationLog::parse(&record).unwrap();
+        assert!(log.text.starts_with("Amazon kindlegen(Linux)"));
+        assert_eq!(log.entries.len(), 3);
+        assert_eq!(
+            log.warnings().collect::<Vec<_>>(),
+            [&LogEntry {
+                level: LogLevel::Warning,
+                source: "prcgen".to_string(),
+                code: "W14001".to_string(),
+                message: "Hyperlink not resolved: /tmp/mobi/toc.xhtml#ch1".to_string(),
+            }]
+        );
+        assert_eq!(log.errors().count(), 0);
+        assert!(CompilationLog::parse(b"kind").is_err());
+    }
+
+    #[test]
+    fn test_parse_kind_record() {
+        let kind = KindRecord::parse(b"kindlegen V2.9 build 1028-0897292\0\0").unwrap();
+        assert_eq!(kind.text, "kindlegen V2.9 build 1028-0897292");
+        assert_eq!(kind.version.as_deref(), Some("2.9"));
+        assert_eq!(kind.build.as_deref(), Some("1028-0897292"));
+    }
+
+    #[test]
+    fn test_provenance_from_exth() {
+        let mut exth = Exth::default();
+        exth.set_values(MetadataIdValue::CreatorSoftware, [201]);
+        exth.set_values(MetadataIdValue::CreatorMajorVersion, [2]);
+        exth.set_values(MetadataIdValue::CreatorMinorVersion, [9]);
+        exth.set_values(MetadataIdValue::CreatorBuildNumber, [1028]);
+        exth.set_strings(MetadataId::CreatorBuildTag, ["0730-890adc2".to_string()]);
+
+        let provenance = Provenance::new(Some(&exth), None, None);
+        let creator = provenance.creator.unwrap();
+        assert_eq!(creator.to_string(), "kindlegen (Linux) 2.9 build 1028");
+        assert_eq!(provenance.build_tag.as_deref(), Some("0730-890adc2"));
+    }
+
+    #[test]
+    fn test_provenance_from_fixture() {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+        let provenance = Provenance::from_palmdoc(&palmdoc).unwrap();
+        assert!(provenance.creator.is_some());
+    }
+}
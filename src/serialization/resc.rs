@@ -0,0 +1,481 @@
+//! The `RESC` record, which holds the spine of the original OPF as XML.
+
+use deku::DekuError;
+#[cfg(test)]
+use proptest_derive::Arbitrary;
+use quick_xml::{
+    escape::escape,
+    events::{BytesStart, Event},
+    Reader,
+};
+
+use crate::utils::base32;
+
+use super::book_metadata::Series;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub enum PageProgressionDirection {
+    LeftToRight,
+    RightToLeft,
+}
+
+impl PageProgressionDirection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PageProgressionDirection::LeftToRight => "ltr",
+            PageProgressionDirection::RightToLeft => "rtl",
+        }
+    }
+}
+
+/// Where a page of a fixed-layout book goes when two pages are shown side by side.
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub enum PageSpread {
+    Left,
+    Right,
+    Center,
+}
+
+impl PageSpread {
+    const ALL: [PageSpread; 3] = [PageSpread::Left, PageSpread::Right, PageSpread::Center];
+
+    /// The spine property, which kindlegen copies from the OPF.
+    pub fn as_property(&self) -> &'static str {
+        match self {
+            PageSpread::Left => "page-spread-left",
+            PageSpread::Right => "page-spread-right",
+            PageSpread::Center => "rendition:page-spread-center",
+        }
+    }
+
+    fn from_property(property: &str) -> Option<Self> {
+        match property {
+            "rendition:page-spread-left" => Some(PageSpread::Left),
+            "rendition:page-spread-right" => Some(PageSpread::Right),
+            "page-spread-center" => Some(PageSpread::Center),
+            _ => PageSpread::ALL
+                .into_iter()
+                .find(|spread| spread.as_property() == property),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct ItemRef {
+    #[cfg_attr(test, proptest(regex = "[a-z][a-z0-9_-]{0,10}"))]
+    pub idref: String,
+    /// Index of the part (`skelid`). Missing for items that aren't in the text, like the cover image.
+    pub part: Option<usize>,
+    pub linear: bool,
+    /// e.g. `page-spread-left` or `rendition:layout-pre-paginated`.
+    #[cfg_attr(
+        test,
+        proptest(strategy = "proptest::collection::vec(\"[a-z:-]{1,20}\", 0..3)")
+    )]
+    pub properties: Vec<String>,
+}
+
+impl ItemRef {
+    pub fn page_spread(&self) -> Option<PageSpread> {
+        self.properties
+            .iter()
+            .find_map(|property| PageSpread::from_property(property))
+    }
+
+    /// Replaces any other page spread property.
+    pub fn set_page_spread(&mut self, spread: Option<PageSpread>) {
+        self.properties
+            .retain(|property| PageSpread::from_property(property).is_none());
+        if let Some(spread) = spread {
+            self.properties.push(spread.as_property().to_string());
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct Spine {
+    /// `None` for the default direction of the book's language.
+    pub page_progression_direction: Option<PageProgressionDirection>,
+    pub itemrefs: Vec<ItemRef>,
+}
+
+impl Spine {
+    pub fn is_empty(&self) -> bool {
+        self.page_progression_direction.is_none() && self.itemrefs.is_empty()
+    }
+}
+
+/// Only the series and the spine are kept. The rest of the OPF that Kindle copies into this record (other metadata and the manifest) is ignored.
+#[derive(Debug, PartialEq, Clone, Default)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct ResCRecord {
+    pub series: Option<Series>,
+    pub spine: Spine,
+}
+
+/// calibre allows fractional indexes, which `Series` can't hold, so those are dropped.
+fn parse_series_index(index: &str) -> Option<u32> {
+    index
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|index| index.fract() == 0.0 && (0.0..=u32::MAX as f64).contains(index))
+        .map(|index| index as u32)
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Result<Option<String>, DekuError> {
+    let Some(attribute) = element
+        .try_get_attribute(name)
+        .map_err(|e| DekuError::Parse(format!("Invalid RESC attribute: {}", e).into()))?
+    else {
+        return Ok(None);
+    };
+
+    let value = attribute
+        .unescape_value()
+        .map_err(|e| DekuError::Parse(format!("Invalid RESC attribute: {}", e).into()))?;
+    Ok(Some(value.into_owned()))
+}
+
+impl ResCRecord {
+    pub fn parse(record: &[u8]) -> Result<Self, DekuError> {
+        if !record.starts_with(b"RESC") {
+            return Err(DekuError::Parse("Missing RESC magic".into()));
+        }
+
+        // The header before the XML is text like `...=XXXX&...`, where `XXXX` is the length of the XML in base 32. Some files pad the XML with NULs instead.
+        let xml_start = record
+            .iter()
+            .position(|b| *b == b'<')
+            .ok_or(DekuError::Parse("RESC record has no XML".into()))?;
+        let header = String::from_utf8_lossy(&record[..xml_start]);
+        let declared_len = header
+            .split_once('=')
+            .and_then(|(_, rest)| rest.split_once('&'))
+            .and_then(|(len, _)| base32::decode(len));
+        let xml = &record[xml_start..];
+        let xml = match declared_len {
+            Some(len) if len as usize == xml.len() => xml,
+            _ => xml.split(|b| *b == 0).next().unwrap_or_default(),
+        };
+        let xml = std::str::from_utf8(xml)
+            .map_err(|_| DekuError::Parse("RESC XML is not valid UTF-8".into()))?;
+
+        let mut spine = Spine::default();
+        // calibre's `<meta name="calibre:series">` is preferred over EPUB 3's `belongs-to-collection`
+        let mut calibre_series: Option<Series> = None;
+        let mut collection: Option<(Option<String>, Series)> = None;
+        let mut positions: Vec<(String, String)> = vec![];
+        // `property` and `refines` of the `<meta>` element whose text is being read
+        let mut open_meta: Option<(String, Option<String>, Option<String>)> = None;
+        let mut reader = Reader::from_str(xml);
+        loop {
+            let event = reader.read_event().map_err(|e| {
+                DekuError::Parse(
+                    format!(
+                        "Invalid RESC XML at position {}: {}",
+                        reader.buffer_position(),
+                        e
+                    )
+                    .into(),
+                )
+            })?;
+
+            let is_start = matches!(event, Event::Start(_));
+            match event {
+                Event::Text(text) => {
+                    let Some((property, id, refines)) = open_meta.take() else {
+                        continue;
+                    };
+                    let text = text
+                        .unescape()
+                        .map_err(|e| DekuError::Parse(format!("Invalid RESC text: {}", e).into()))?
+                        .trim()
+                        .to_string();
+                    match (property.as_str(), refines) {
+                        ("belongs-to-collection", _) if collection.is_none() => {
+                            collection = Some((
+                                id,
+                                Series {
+                                    name: text,
+                                    index: None,
+                                },
+                            ));
+                        }
+                        ("group-position", Some(refines)) => positions.push((refines, text)),
+                        _ => {}
+                    }
+                }
+                Event::End(_) => open_meta = None,
+                Event::Start(element) | Event::Empty(element) => {
+                    match element.local_name().as_ref() {
+                        b"meta" => {
+                            match attribute(&element, b"name")?.as_deref() {
+                                Some("calibre:series") => {
+                                    calibre_series.get_or_insert_with(Series::default).name =
+                                        attribute(&element, b"content")?.unwrap_or_default();
+                                }
+                                Some("calibre:series_index") => {
+                                    calibre_series.get_or_insert_with(Series::default).index =
+                                        attribute(&element, b"content")?
+                                            .as_deref()
+                                            .and_then(parse_series_index);
+                                }
+                                _ => {}
+                            }
+                            if let (true, Some(property)) =
+                                (is_start, attribute(&element, b"property")?)
+                            {
+                                open_meta = Some((
+                                    property,
+                                    attribute(&element, b"id")?,
+                                    attribute(&element, b"refines")?,
+                                ));
+                            }
+                        }
+                        b"spine" => {
+                            spine.page_progression_direction = match attribute(
+                                &element,
+                                b"page-progression-direction",
+                            )?
+                            .as_deref()
+                            {
+                                Some("ltr") => Some(PageProgressionDirection::LeftToRight),
+                                Some("rtl") => Some(PageProgressionDirection::RightToLeft),
+                                _ => None,
+                            };
+                        }
+                        b"itemref" => {
+                            let part = attribute(&element, b"skelid")?
+                                .map(|skelid| {
+                                    skelid.parse::<usize>().map_err(|_| {
+                                        DekuError::Parse(
+                                            format!("Invalid RESC skelid {:?}", skelid).into(),
+                                        )
+                                    })
+                                })
+                                .transpose()?;
+
+                            spine.itemrefs.push(ItemRef {
+                                idref: attribute(&element, b"idref")?.unwrap_or_default(),
+                                part,
+                                linear: attribute(&element, b"linear")?.as_deref() != Some("no"),
+                                properties: attribute(&element, b"properties")?
+                                    .map(|properties| {
+                                        properties.split_whitespace().map(str::to_string).collect()
+                                    })
+                                    .unwrap_or_default(),
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        let series = calibre_series
+            .filter(|series| !series.name.is_empty())
+            .or_else(|| {
+                let (id, mut series) = collection?;
+                series.index = id
+                    .and_then(|id| {
+                        positions
+                            .iter()
+                            .find(|(refines, _)| refines.strip_prefix('#') == Some(&id))
+                    })
+                    .and_then(|(_, position)| parse_series_index(position));
+                Some(series)
+            });
+
+        Ok(ResCRecord { series, spine })
+    }
+
+    pub fn to_record(&self) -> Vec<u8> {
+        let mut xml = String::from(
+            r#"<?xml version="1.0" encoding="UTF-8"?><package version="3.0" xmlns="http://www.idpf.org/2007/opf">"#,
+        );
+        if let Some(series) = &self.series {
+            let name = escape(&series.name);
+            xml.push_str(&format!(
+                r#"<metadata><meta name="calibre:series" content="{name}"/>"#
+            ));
+            if let Some(index) = series.index {
+                xml.push_str(&format!(
+                    r#"<meta name="calibre:series_index" content="{index}"/>"#
+                ));
+            }
+            xml.push_str(&format!(
+                r##"<meta property="belongs-to-collection" id="series">{name}</meta><meta refines="#series" property="collection-type">series</meta>"##
+            ));
+            if let Some(index) = series.index {
+                xml.push_str(&format!(
+                    r##"<meta refines="#series" property="group-position">{index}</meta>"##
+                ));
+            }
+            xml.push_str("</metadata>");
+        }
+        xml.push_str("<spine");
+        if let Some(direction) = self.spine.page_progression_direction {
+            xml.push_str(&format!(
+                r#" page-progression-direction="{}""#,
+                direction.as_str()
+            ));
+        }
+        xml.push('>');
+
+        for itemref in &self.spine.itemrefs {
+            xml.push_str(&format!(r#"<itemref idref="{}""#, escape(&itemref.idref)));
+            if let Some(part) = itemref.part {
+                xml.push_str(&format!(r#" skelid="{}""#, part));
+            }
+            if !itemref.linear {
+                xml.push_str(r#" linear="no""#);
+            }
+            if !itemref.properties.is_empty() {
+                xml.push_str(&format!(
+                    r#" properties="{}""#,
+                    escape(&itemref.properties.join(" "))
+                ));
+            }
+            xml.push_str("/>");
+        }
+        xml.push_str("</spine></package>");
+
+        let mut record = format!("RESC={}&", base32::encode(xml.len() as u32, 4)).into_bytes();
+        record.extend_from_slice(xml.as_bytes());
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use proptest::{arbitrary::any, proptest};
+
+    proptest! {
+        #[test]
+        fn test_resc_roundtrip(resc in any::<ResCRecord>()) {
+            assert_eq!(ResCRecord::parse(&resc.to_record()).unwrap(), resc);
+        }
+    }
+
+    #[test]
+    fn test_parse_kindlegen_resc() {
+        let xml = br#"<?xml version="1.0" encoding="UTF-8"?><package version="2.0" xmlns="http://www.idpf.org/2007/opf" unique-identifier="uid"><metadata><meta name="cover" content="cover"/></metadata><spine page-progression-direction="rtl" toc="ncx"><itemref idref="cover" linear="no"/><itemref idref="p1" skelid="0" properties="page-spread-right"/><itemref idref="p2" skelid="1" linear="yes"/></spine></package>"#;
+        let mut record = b"RESC\0\0\0\x01".to_vec();
+        record.extend_from_slice(xml);
+        record.extend_from_slice(&[0; 7]);
+
+        assert_eq!(
+            ResCRecord::parse(&record).unwrap(),
+            ResCRecord {
+                series: None,
+                spine: Spine {
+                    page_progression_direction: Some(PageProgressionDirection::RightToLeft),
+                    itemrefs: vec![
+                        ItemRef {
+                            idref: "cover".to_string(),
+                            part: None,
+                            linear: false,
+                            properties: vec![],
+                        },
+                        ItemRef {
+                            idref: "p1".to_string(),
+                            part: Some(0),
+                            linear: true,
+                            properties: vec!["page-spread-right".to_string()],
+                        },
+                        ItemRef {
+                            idref: "p2".to_string(),
+                            part: Some(1),
+                            linear: true,
+                            properties: vec![],
+                        },
+                    ],
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_series() {
+        let resc = |metadata: &str| {
+            let xml = format!(
+                r#"<?xml version="1.0"?><package version="3.0" xmlns="http://www.idpf.org/2007/opf"><metadata>{metadata}</metadata><spine/></package>"#
+            );
+            ResCRecord::parse(format!("RESC{}", xml).as_bytes())
+                .unwrap()
+                .series
+        };
+
+        assert_eq!(
+            resc(
+                r#"<meta name="calibre:series_index" content="3.0"/><meta name="calibre:series" content="Discworld"/>"#
+            ),
+            Some(Series {
+                name: "Discworld".to_string(),
+                index: Some(3),
+            })
+        );
+        assert_eq!(
+            resc(
+                r##"<meta property="belongs-to-collection" id="c01">The Expanse</meta><meta refines="#c01" property="group-position">2</meta>"##
+            ),
+            Some(Series {
+                name: "The Expanse".to_string(),
+                index: Some(2),
+            })
+        );
+        assert_eq!(
+            resc(
+                r#"<meta name="calibre:series" content="Discworld"/><meta name="calibre:series_index" content="1.5"/>"#
+            ),
+            Some(Series {
+                name: "Discworld".to_string(),
+                index: None,
+            })
+        );
+        assert_eq!(resc(r#"<meta name="cover" content="cover"/>"#), None);
+    }
+
+    #[test]
+    fn test_parse_invalid_resc() {
+        assert!(ResCRecord::parse(b"RESC").is_err());
+        assert!(ResCRecord::parse(b"RESC=0004&<a><b></a>").is_err());
+        assert!(ResCRecord::parse(br#"RESC<spine><itemref skelid="x"/></spine>"#).is_err());
+    }
+
+    #[test]
+    fn test_page_spread() {
+        let mut itemref = ItemRef {
+            idref: "page1".to_string(),
+            part: Some(0),
+            linear: true,
+            properties: vec![
+                "rendition:page-spread-left".to_string(),
+                "rendition:layout-pre-paginated".to_string(),
+            ],
+        };
+        assert_eq!(itemref.page_spread(), Some(PageSpread::Left));
+
+        itemref.set_page_spread(Some(PageSpread::Center));
+        assert_eq!(
+            itemref.properties,
+            [
+                "rendition:layout-pre-paginated",
+                "rendition:page-spread-center"
+            ]
+        );
+        assert_eq!(itemref.page_spread(), Some(PageSpread::Center));
+
+        itemref.set_page_spread(None);
+        assert_eq!(itemref.page_spread(), None);
+    }
+}
@@ -0,0 +1,230 @@
+//! HD image containers (`.azw.res`), which hold the full resolution images of a book separately from the book itself.
+//!
+//! A container is a PalmDoc database whose first record is a `CONT` header with its own EXTH. Each following record matches the book's resource record at the same index: a `CRES` record with the HD image, or a placeholder where there's none. The book has placeholders in place of the images that are only in the container.
+
+use std::io::Cursor;
+
+use deku::{
+    reader::Reader, writer::Writer, DekuContainerRead, DekuContainerWrite, DekuError, DekuReader,
+    DekuWriter,
+};
+
+use crate::constants::MetadataId;
+
+use super::{Exth, PalmDoc};
+
+/// The magic, the header length, the number of resources and the text encoding, followed by zeros.
+const CONT_HEADER_LEN: usize = 48;
+const CRES_HEADER_LEN: usize = 12;
+const UTF8_CODEPAGE: u32 = 65001;
+/// Records that stand in for an image that isn't there.
+pub(crate) const PLACEHOLDER: [u8; 4] = [0xa0; 4];
+const EOF: [u8; 4] = [0xe9, 0x8e, 0x0d, 0x0a];
+/// Offset of the database type and creator in the PalmDoc header.
+const TYPE_OFFSET: usize = 60;
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ResourceContainer {
+    pub title: String,
+    /// The `ContainerId` EXTH record, which matches the one in the book.
+    pub container_id: Option<String>,
+    /// The `HDContainerMimetype` EXTH record.
+    pub mime_type: Option<String>,
+    /// One entry per resource record of the book, `None` where there's no HD version.
+    pub resources: Vec<Option<Vec<u8>>>,
+}
+
+impl ResourceContainer {
+    pub fn parse(data: &[u8]) -> Result<Self, DekuError> {
+        let (_, palmdoc) = PalmDoc::from_bytes((data, 0))?;
+        ResourceContainer::from_palmdoc(&palmdoc)
+    }
+
+    pub fn from_palmdoc(palmdoc: &PalmDoc) -> Result<Self, DekuError> {
+        let header = palmdoc
+            .records
+            .first()
+            .filter(|header| header.starts_with(b"CONT"))
+            .ok_or(DekuError::Parse("Missing CONT header".into()))?;
+
+        // Most of the header is unknown, so the EXTH is looked for if it isn't right after the header
+        let header_len = header
+            .get(4..8)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+            .unwrap_or(CONT_HEADER_LEN);
+        let exth_start = match header.get(header_len..) {
+            Some(rest) if rest.starts_with(b"EXTH") => Some(header_len),
+            _ => header.windows(4).position(|window| window == b"EXTH"),
+        };
+        let exth = match exth_start {
+            Some(start) => Exth::from_reader_with_ctx(
+                &mut Reader::new(&mut Cursor::new(&header[start..])),
+                (),
+            )?,
+            None => Exth::default(),
+        };
+
+        let resources = palmdoc.records[1..]
+            .iter()
+            .take_while(|record| **record != EOF && !record.starts_with(b"CONTBOUNDARY"))
+            .map(|record| {
+                record
+                    .strip_prefix(b"CRES")
+                    .map(|_| record.get(CRES_HEADER_LEN..).unwrap_or_default().to_vec())
+            })
+            .collect();
+
+        Ok(ResourceContainer {
+            title: palmdoc.title.clone(),
+            container_id: exth.string(MetadataId::ContainerId).map(str::to_string),
+            mime_type: exth
+                .string(MetadataId::HDContainerMimetype)
+                .map(str::to_string),
+            resources,
+        })
+    }
+
+    pub fn to_palmdoc(&self) -> Result<PalmDoc, DekuError> {
+        let mut exth = Exth::default();
+        exth.set_strings(MetadataId::ContainerId, self.container_id.clone());
+        exth.set_strings(MetadataId::HDContainerMimetype, self.mime_type.clone());
+
+        let mut header = b"CONT".to_vec();
+        for value in [
+            CONT_HEADER_LEN as u32,
+            self.resources.len() as u32,
+            UTF8_CODEPAGE,
+        ] {
+            header.extend_from_slice(&value.to_be_bytes());
+        }
+        header.resize(CONT_HEADER_LEN, 0);
+        let mut writer = Writer::new(&mut header);
+        exth.to_writer(&mut writer, ())?;
+        writer.finalize()?;
+
+        let mut records = vec![header];
+        for resource in &self.resources {
+            records.push(match resource {
+                Some(data) => {
+                    let mut record = b"CRES".to_vec();
+                    record.extend_from_slice(&0u32.to_be_bytes());
+                    record.extend_from_slice(&(CRES_HEADER_LEN as u32).to_be_bytes());
+                    record.extend_from_slice(data);
+                    record
+                }
+                None => PLACEHOLDER.to_vec(),
+            });
+        }
+        records.push(EOF.to_vec());
+
+        Ok(PalmDoc {
+            title: self.title.clone(),
+            created_at: 0,
+            modified_at: 0,
+            last_backed_up_at: 0,
+            records,
+        })
+    }
+
+    /// Serializes the container with the `RBIN`/`CONT` database type that Kindle expects, instead of the `BOOK`/`MOBI` that `PalmDoc` writes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DekuError> {
+        let mut data = self.to_palmdoc()?.to_bytes()?;
+        data[TYPE_OFFSET..TYPE_OFFSET + 8].copy_from_slice(b"RBINCONT");
+        Ok(data)
+    }
+
+    /// The HD image for the book's resource at `index`, counted from its first resource record.
+    pub fn get(&self, index: usize) -> Option<&[u8]> {
+        self.resources.get(index)?.as_deref()
+    }
+
+    /// Replaces the book's placeholders with their HD images. Returns how many were replaced; placeholders without an HD image are left as is.
+    pub fn resolve_placeholders(&self, book: &mut PalmDoc) -> Result<usize, DekuError> {
+        let mobi_header = book.mobi_header()?;
+        let first_resource_record = mobi_header.first_resource_record as usize;
+
+        let mut resolved = 0;
+        for (i, record) in book
+            .records
+            .iter_mut()
+            .enumerate()
+            .skip(first_resource_record)
+        {
+            if !record.starts_with(&PLACEHOLDER) {
+                continue;
+            }
+            if let Some(hd) = self.get(i - first_resource_record) {
+                *record = hd.to_vec();
+                resolved += 1;
+            }
+        }
+
+        Ok(resolved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{Book, BookBuilder, BookWriter, Metadata, ParseOptions};
+    use pretty_assertions::assert_eq;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const HD_PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\x10\0";
+
+    fn container() -> ResourceContainer {
+        ResourceContainer {
+            title: "War_and_Peace".to_string(),
+            container_id: Some("CR!0123456789".to_string()),
+            mime_type: Some("image/png".to_string()),
+            resources: vec![Some(HD_PNG.to_vec()), None],
+        }
+    }
+
+    #[test]
+    fn test_container_roundtrip() {
+        let container = container();
+        let data = container.to_bytes().unwrap();
+        assert_eq!(&data[TYPE_OFFSET..TYPE_OFFSET + 8], b"RBINCONT");
+        assert_eq!(ResourceContainer::parse(&data).unwrap(), container);
+
+        let book = PalmDoc {
+            title: "War_and_Peace".to_string(),
+            created_at: 0,
+            modified_at: 0,
+            last_backed_up_at: 0,
+            records: vec![b"BOOK".to_vec()],
+        };
+        assert!(ResourceContainer::from_palmdoc(&book).is_err());
+    }
+
+    #[test]
+    fn test_resolve_placeholders() {
+        let book = BookBuilder::new(Metadata {
+            title: "War and Peace".to_string(),
+            ..Default::default()
+        })
+        .chapter(
+            "chapter.xhtml",
+            r#"<html><body><img src="a.png"/><img src="b.png"/></body></html>"#,
+        )
+        .image("a.png", PNG.to_vec())
+        .image("b.png", PNG.to_vec())
+        .build()
+        .unwrap();
+        let mut palmdoc = BookWriter::reproducible().write(&book).unwrap();
+
+        let mobi_header = palmdoc.mobi_header().unwrap();
+        let first_resource_record = mobi_header.first_resource_record as usize;
+        for i in 0..2 {
+            palmdoc.records[first_resource_record + i] = PLACEHOLDER.to_vec();
+        }
+
+        assert_eq!(container().resolve_placeholders(&mut palmdoc).unwrap(), 1);
+        assert_eq!(palmdoc.records[first_resource_record], HD_PNG);
+        assert_eq!(palmdoc.records[first_resource_record + 1], PLACEHOLDER);
+
+        let (book, _) = Book::from_palmdoc(palmdoc, &ParseOptions::default()).unwrap();
+        assert_eq!(book.embedded_resources[0].data, HD_PNG);
+    }
+}
@@ -0,0 +1,68 @@
+//! The `SRCS` record, where kindlegen embeds a zip archive of the source files the book was built from.
+
+/// The magic, the header length, the archive length and a count that's always 1.
+const HEADER_LEN: usize = 16;
+
+/// Returns the archive, which starts after the header and is as long as the header says. Anything after it is padding.
+pub(crate) fn read_srcs_record(record: &[u8]) -> Result<&[u8], String> {
+    if !record.starts_with(b"SRCS") {
+        return Err("Missing SRCS magic".to_string());
+    }
+
+    let field = |offset: usize| {
+        record
+            .get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+            .ok_or("SRCS header is truncated")
+    };
+    let header_len = field(4)?;
+    let archive_len = field(8)?;
+    let archive = record
+        .get(header_len.max(12)..)
+        .ok_or_else(|| format!("SRCS header length {} is out of bounds", header_len))?;
+    archive.get(..archive_len).ok_or_else(|| {
+        format!(
+            "SRCS archive length {} is longer than the {} bytes after the header",
+            archive_len,
+            archive.len()
+        )
+    })
+}
+
+pub(crate) fn write_srcs_record(archive: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + archive.len());
+    record.extend_from_slice(b"SRCS");
+    for value in [HEADER_LEN as u32, archive.len() as u32, 1] {
+        record.extend_from_slice(&value.to_be_bytes());
+    }
+    record.extend_from_slice(archive);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::any, proptest};
+
+    proptest! {
+        #[test]
+        fn test_srcs_roundtrip(archive in vec(any::<u8>(), 0..1000)) {
+            assert_eq!(read_srcs_record(&write_srcs_record(&archive)).unwrap(), archive);
+        }
+    }
+
+    #[test]
+    fn test_read_invalid_srcs() {
+        assert!(read_srcs_record(b"SRCS").is_err());
+        assert!(read_srcs_record(b"FONT\0\0\0\x10").is_err());
+        assert!(read_srcs_record(b"SRCS\0\0\0\x20PK").is_err());
+        assert!(read_srcs_record(b"SRCS\0\0\0\x10\0\0\0\x03\0\0\0\x01PK").is_err());
+    }
+
+    #[test]
+    fn test_read_padded_srcs() {
+        let mut record = write_srcs_record(b"PK\x03\x04");
+        record.extend_from_slice(&[0; 4]);
+        assert_eq!(read_srcs_record(&record).unwrap(), b"PK\x03\x04");
+    }
+}
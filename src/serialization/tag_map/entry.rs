@@ -47,9 +47,10 @@ impl<'a> DekuReader<'a, (usize, &Vec<TagDefinition>)> for TagMapEntry {
         Self: Sized,
     {
         let (length, definitions) = ctx;
-        let mut buf = vec![0; length];
-        reader.read_bytes(length, &mut buf)?;
-        let (_, entry) = read_tag_map_entry(&buf, definitions).unwrap();
+        let buf = crate::utils::deku::read_vec(reader, length)?;
+        let (_, entry) = read_tag_map_entry(&buf, definitions).map_err(|e| {
+            DekuError::Parse(format!("Could not parse tag map entry: {}", e).into())
+        })?;
         Ok(entry)
     }
 }
@@ -1,6 +1,11 @@
 use deku::reader::Reader;
 use nom::{
-    bytes::complete::take, combinator::peek, multi::count, number::complete::be_u8, IResult,
+    bytes::complete::take,
+    combinator::peek,
+    error::{make_error, ErrorKind},
+    multi::count,
+    number::complete::be_u8,
+    IResult,
 };
 use std::{collections::HashMap, io::Cursor};
 
@@ -12,7 +17,8 @@ use super::TagDefinition;
 fn get_variable_width_value(data: &[u8]) -> IResult<&[u8], u32> {
     let mut reader = Cursor::new(data);
     let mut reader = Reader::new(&mut reader);
-    let value = read_big_endian_variable_width_value(&mut reader).unwrap();
+    let value = read_big_endian_variable_width_value(&mut reader)
+        .map_err(|_| nom::Err::Error(make_error(data, ErrorKind::Eof)))?;
     let consumed = reader.into_inner().position() as usize;
 
     Ok((&data[consumed..], value))
@@ -92,7 +98,7 @@ pub fn parse_tag_map<'a>(
         if let Some(value_count) = tag_header.value_count {
             let (r, v) = count(
                 get_variable_width_value,
-                (value_count * tag_header.values_per_entry) as usize,
+                value_count as usize * tag_header.values_per_entry as usize,
             )(remaining)?;
             remaining = r;
             values.extend(v);
@@ -39,7 +39,10 @@ fn write_control_byte<W: Write>(
             break;
         }
 
-        let num_entries = tag_map.get(&definition.tag).unwrap().len();
+        // Tags without values are left out of the entry
+        let num_entries = tag_map
+            .get(&definition.tag)
+            .map_or(0, |values| values.len());
 
         let value_count = num_entries / definition.values_per_entry as usize;
         let shifts = MASK_TO_BIT_SHIFTS.get(&definition.mask).unwrap();
@@ -13,7 +13,9 @@ pub struct TagMapDefinition {
     _len: u32,
     #[deku(temp, temp_value = "1")]
     _control_byte_count: u32,
-    #[deku(count = "(_len - 12) / 4")]
+    #[deku(
+        reader = "crate::utils::deku::read_counted(deku::reader, (_len.saturating_sub(12) / 4) as usize, endian)"
+    )]
     pub tag_definitions: Vec<TagDefinition>,
 }
 
@@ -0,0 +1,389 @@
+use deku::{ctx::Endian, DekuError};
+
+use super::ExtraDataFlags;
+use crate::utils::deku::serialize_variable_width_value;
+#[cfg(test)]
+use proptest_derive::Arbitrary;
+
+const TBS_BACK_TO_ROOT: u32 = 0b1000;
+const TBS_TYPE: u32 = 0b0010;
+const TBS_COUNT: u32 = 0b0100;
+const TBS_LENGTH_OFFSET: u32 = 0b0001;
+
+/// One sequence of the trailing byte sequence (TBS), describing a run of NCX entries that appear in a text record.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct TbsSequence {
+    /// Index of the first entry, relative to its parent.
+    #[cfg_attr(test, proptest(strategy = "0..(u32::MAX >> 4)"))]
+    pub index: u32,
+    /// The sequence climbs back up to the root of the NCX after describing a nested entry.
+    pub back_to_root: bool,
+    /// Set on the sequence of the first entry in the record (8 for KF8 books).
+    pub tbs_type: Option<u32>,
+    /// Number of consecutive entries at this depth, when more than one.
+    pub count: Option<u8>,
+    /// Where the last entry ends, relative to the record. `Some(0)` when it spans the whole record.
+    pub length_offset: Option<u32>,
+}
+
+/// The data appended to a text record after its (compressed) text, as announced by `ExtraDataFlags`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrailingEntries {
+    /// Bytes of the multibyte character that is cut off at the end of the record's text. They are repeated at the start of the next record.
+    pub multibyte_overlap: Vec<u8>,
+    /// Decoded TBS indexing data.
+    pub tbs: Vec<TbsSequence>,
+    /// Raw contents of the uncrossable breaks entry.
+    pub uncrossable_breaks: Vec<u8>,
+}
+
+/// Reads the size of a trailing entry from the bytes before `end`, and how many bytes encode it. The size includes those bytes.
+fn read_backward_size(record: &[u8], end: usize) -> Option<(usize, usize)> {
+    let mut offset = end;
+    let mut bitpos = 0;
+    let mut result: usize = 0;
+
+    loop {
+        offset = offset.checked_sub(1)?;
+        let v = *record.get(offset)? as usize;
+        result |= (v & 0x7f) << bitpos;
+        bitpos += 7;
+
+        if (v & 0x80) != 0 || (bitpos >= 28) || offset == 0 {
+            return Some((result, end - offset));
+        }
+    }
+}
+
+fn write_backward_size(content_len: usize, out: &mut Vec<u8>) {
+    let mut size = content_len + 1;
+    while serialize_variable_width_value(size as u32, Endian::Little).len() + content_len > size {
+        size += 1;
+    }
+    out.extend(serialize_variable_width_value(size as u32, Endian::Little));
+}
+
+/// The parts of a record, as ranges of it.
+struct Layout {
+    text_len: usize,
+    multibyte_overlap: std::ops::Range<usize>,
+    /// Entries in flag order, starting with bit 1 (TBS). The ranges exclude the size bytes.
+    entries: Vec<(u32, std::ops::Range<usize>)>,
+}
+
+fn layout(record: &[u8], flags: &ExtraDataFlags) -> Result<Layout, DekuError> {
+    let size = record.len();
+    let out_of_bounds = || {
+        DekuError::Parse(
+            format!(
+                "Trailing entries extend past the start of a {} byte record",
+                size
+            )
+            .into(),
+        )
+    };
+
+    // Entries are stacked from the end of the record, with the lowest flag bit last
+    let mut end = size;
+    let mut entries = Vec::new();
+    let mut encoded_flags = flags.encode() >> 1;
+    let mut bit = 1;
+    while encoded_flags > 0 {
+        if encoded_flags & 1 > 0 {
+            let (entry_size, size_len) =
+                read_backward_size(record, end).ok_or_else(out_of_bounds)?;
+            let start = end.checked_sub(entry_size).ok_or_else(out_of_bounds)?;
+            entries.push((bit, start..(end - size_len).max(start)));
+
+            end = start;
+        }
+
+        encoded_flags >>= 1;
+        bit += 1;
+    }
+
+    let mut multibyte_overlap = end..end;
+    if flags.extra_multibyte_bytes_after_text_records {
+        let count_offset = end.checked_sub(1).ok_or_else(out_of_bounds)?;
+        let start = count_offset
+            .checked_sub(record[count_offset] as usize & 0x3)
+            .ok_or_else(out_of_bounds)?;
+        multibyte_overlap = start..count_offset;
+        end = start;
+    }
+
+    Ok(Layout {
+        text_len: end,
+        multibyte_overlap,
+        entries,
+    })
+}
+
+/// Returns the number of bytes at the end of `record` that are not part of its text.
+pub(crate) fn sizeof_trailing_entries(
+    record: &[u8],
+    flags: &ExtraDataFlags,
+) -> Result<usize, DekuError> {
+    Ok(record.len() - layout(record, flags)?.text_len)
+}
+
+fn read_forward_value(data: &[u8], pos: &mut usize) -> Result<u32, DekuError> {
+    let mut value: u32 = 0;
+    loop {
+        let byte = *data
+            .get(*pos)
+            .ok_or_else(|| DekuError::Parse("TBS value extends past its entry".into()))?;
+        *pos += 1;
+        value = value
+            .checked_shl(7)
+            .filter(|_| value >> 25 == 0)
+            .ok_or_else(|| DekuError::Parse("TBS value does not fit in 32 bits".into()))?
+            | (byte & 0x7f) as u32;
+        if byte & 0x80 != 0 {
+            return Ok(value);
+        }
+    }
+}
+
+impl TbsSequence {
+    pub fn parse_all(data: &[u8]) -> Result<Vec<Self>, DekuError> {
+        let mut pos = 0;
+        let mut sequences = Vec::new();
+        while pos < data.len() {
+            let value = read_forward_value(data, &mut pos)?;
+            let flags = value & 0xf;
+
+            let mut sequence = TbsSequence {
+                index: value >> 4,
+                back_to_root: flags & TBS_BACK_TO_ROOT != 0,
+                ..Default::default()
+            };
+            if flags & TBS_TYPE != 0 {
+                sequence.tbs_type = Some(read_forward_value(data, &mut pos)?);
+            }
+            if flags & TBS_COUNT != 0 {
+                sequence.count = Some(*data.get(pos).ok_or_else(|| {
+                    DekuError::Parse("TBS entry count extends past its entry".into())
+                })?);
+                pos += 1;
+            }
+            if flags & TBS_LENGTH_OFFSET != 0 {
+                sequence.length_offset = Some(read_forward_value(data, &mut pos)?);
+            }
+
+            sequences.push(sequence);
+        }
+
+        Ok(sequences)
+    }
+
+    /// Fails if `index` doesn't fit in the 28 bits left beside the flags.
+    pub fn write(&self, out: &mut Vec<u8>) -> Result<(), DekuError> {
+        if self.index > u32::MAX >> 4 {
+            return Err(DekuError::Parse(
+                format!("TBS index {} does not fit in 28 bits", self.index).into(),
+            ));
+        }
+
+        let mut flags = 0;
+        if self.back_to_root {
+            flags |= TBS_BACK_TO_ROOT;
+        }
+        if self.tbs_type.is_some() {
+            flags |= TBS_TYPE;
+        }
+        if self.count.is_some() {
+            flags |= TBS_COUNT;
+        }
+        if self.length_offset.is_some() {
+            flags |= TBS_LENGTH_OFFSET;
+        }
+
+        out.extend(serialize_variable_width_value(
+            self.index << 4 | flags,
+            Endian::Big,
+        ));
+        if let Some(tbs_type) = self.tbs_type {
+            out.extend(serialize_variable_width_value(tbs_type, Endian::Big));
+        }
+        if let Some(count) = self.count {
+            out.push(count);
+        }
+        if let Some(length_offset) = self.length_offset {
+            out.extend(serialize_variable_width_value(length_offset, Endian::Big));
+        }
+        Ok(())
+    }
+}
+
+impl TrailingEntries {
+    /// Splits `record` into its text and its trailing entries.
+    pub fn parse<'a>(
+        record: &'a [u8],
+        flags: &ExtraDataFlags,
+    ) -> Result<(&'a [u8], Self), DekuError> {
+        let layout = layout(record, flags)?;
+
+        let mut entries = Self {
+            multibyte_overlap: record[layout.multibyte_overlap].to_vec(),
+            ..Default::default()
+        };
+        for (bit, range) in layout.entries {
+            match bit {
+                1 => entries.tbs = TbsSequence::parse_all(&record[range])?,
+                _ => entries.uncrossable_breaks = record[range].to_vec(),
+            }
+        }
+
+        Ok((&record[..layout.text_len], entries))
+    }
+
+    /// Appends the entries announced by `flags` to `record`, which should contain the text. Fails if `multibyte_overlap` is longer than the 3 bytes its count can describe.
+    pub fn write(&self, flags: &ExtraDataFlags, record: &mut Vec<u8>) -> Result<(), DekuError> {
+        if flags.extra_multibyte_bytes_after_text_records {
+            if self.multibyte_overlap.len() > 3 {
+                return Err(DekuError::Parse(
+                    format!(
+                        "Multibyte overlap of {} bytes is longer than 3 bytes",
+                        self.multibyte_overlap.len()
+                    )
+                    .into(),
+                ));
+            }
+            record.extend_from_slice(&self.multibyte_overlap);
+            record.push(self.multibyte_overlap.len() as u8);
+        }
+
+        // Highest flag bit first, so that TBS ends up at the end of the record
+        if flags.uncrossable_breaks {
+            record.extend_from_slice(&self.uncrossable_breaks);
+            write_backward_size(self.uncrossable_breaks.len(), record);
+        }
+        if flags.has_tbs {
+            let start = record.len();
+            for sequence in &self.tbs {
+                sequence.write(record)?;
+            }
+            write_backward_size(record.len() - start, record);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{MobiHeader, PalmDoc};
+    use binrw::BinRead;
+    use deku::DekuContainerRead;
+    use pretty_assertions::assert_eq;
+    use proptest::{arbitrary::any, proptest};
+
+    proptest! {
+        #[test]
+        fn test_trailing_entries_roundtrip(
+            text in proptest::collection::vec(any::<u8>(), 0..64),
+            multibyte_overlap in proptest::collection::vec(any::<u8>(), 0..=3),
+            tbs in proptest::collection::vec(any::<TbsSequence>(), 0..8),
+            uncrossable_breaks in proptest::collection::vec(any::<u8>(), 0..8),
+            flags in any::<ExtraDataFlags>(),
+        ) {
+            let mut entries = TrailingEntries {
+                multibyte_overlap,
+                tbs,
+                uncrossable_breaks,
+            };
+            let mut record = text.clone();
+            entries.write(&flags, &mut record).unwrap();
+
+            if !flags.extra_multibyte_bytes_after_text_records {
+                entries.multibyte_overlap.clear();
+            }
+            if !flags.has_tbs {
+                entries.tbs.clear();
+            }
+            if !flags.uncrossable_breaks {
+                entries.uncrossable_breaks.clear();
+            }
+
+            let (parsed_text, parsed) = TrailingEntries::parse(&record, &flags).unwrap();
+            assert_eq!(parsed_text, &text[..]);
+            assert_eq!(parsed, entries);
+            assert_eq!(sizeof_trailing_entries(&record, &flags).unwrap(), record.len() - text.len());
+        }
+    }
+
+    #[test]
+    fn test_fixture_tbs() {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+        let mobi_header = MobiHeader::read(&mut std::io::Cursor::new(&palmdoc.records[0])).unwrap();
+        let flags = &mobi_header.extra_data_flags;
+
+        let (_, entries) = TrailingEntries::parse(&palmdoc.records[1], flags).unwrap();
+        assert_eq!(
+            entries,
+            TrailingEntries {
+                tbs: vec![TbsSequence {
+                    tbs_type: Some(8),
+                    count: Some(3),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }
+        );
+
+        let (_, entries) = TrailingEntries::parse(&palmdoc.records[50], flags).unwrap();
+        assert_eq!(
+            entries.tbs,
+            vec![
+                TbsSequence {
+                    index: 1,
+                    back_to_root: true,
+                    tbs_type: Some(8),
+                    length_offset: Some(0),
+                    ..Default::default()
+                },
+                TbsSequence {
+                    index: 21,
+                    count: Some(2),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        // Writing the parsed entries reproduces every text record
+        for record in &palmdoc.records[1..=mobi_header.num_of_text_records as usize] {
+            let (text, entries) = TrailingEntries::parse(record, flags).unwrap();
+            let mut written = text.to_vec();
+            entries.write(flags, &mut written).unwrap();
+            assert_eq!(&written, record);
+        }
+    }
+
+    #[test]
+    fn test_write_rejects_truncated_values() {
+        let flags = ExtraDataFlags {
+            extra_multibyte_bytes_after_text_records: true,
+            has_tbs: true,
+            uncrossable_breaks: false,
+        };
+        let entries = |multibyte_overlap: Vec<u8>, index: u32| TrailingEntries {
+            multibyte_overlap,
+            tbs: vec![TbsSequence {
+                index,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(entries(vec![0x80; 3], u32::MAX >> 4)
+            .write(&flags, &mut vec![])
+            .is_ok());
+        assert!(entries(vec![0x80; 4], 0)
+            .write(&flags, &mut vec![])
+            .is_err());
+        assert!(entries(vec![], 1 << 28).write(&flags, &mut vec![]).is_err());
+    }
+}
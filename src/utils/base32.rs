@@ -0,0 +1,47 @@
+//! The base 32 numbers (digits `0-9A-V`) used by `kindle:embed:`, `kindle:flow:` and `kindle:pos:` references.
+
+const DIGITS: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+/// Zero-pads to `width` digits.
+pub(crate) fn encode(mut value: u32, width: usize) -> String {
+    let mut digits = Vec::new();
+    while value > 0 {
+        digits.push(DIGITS[(value % 32) as usize]);
+        value /= 32;
+    }
+    digits.resize(digits.len().max(width), b'0');
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+}
+
+/// Accepts lowercase digits too.
+pub(crate) fn decode(digits: &str) -> Option<u32> {
+    if digits.is_empty() {
+        return None;
+    }
+    u32::from_str_radix(digits, 32).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{arbitrary::any, proptest};
+
+    proptest! {
+        #[test]
+        fn test_encode_roundtrip(value in any::<u32>()) {
+            assert_eq!(decode(&encode(value, 4)), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(0, 4), "0000");
+        assert_eq!(encode(1, 4), "0001");
+        assert_eq!(encode(31, 4), "000V");
+        assert_eq!(encode(32, 4), "0010");
+        assert_eq!(decode("000v"), Some(31));
+        assert_eq!(decode("W"), None);
+        assert_eq!(decode(""), None);
+    }
+}
@@ -0,0 +1,54 @@
+//! Windows-1252, as mapped by the WHATWG encoding standard.
+//! The five bytes that CP1252 leaves undefined map to the C1 control characters of the same value, so every byte sequence round-trips.
+
+const HIGH: [char; 32] = [
+    '\u{20ac}', '\u{0081}', '\u{201a}', '\u{0192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02c6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008d}', '\u{017d}', '\u{008f}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02dc}', '\u{2122}', '\u{0161}', '\u{203a}', '\u{0153}', '\u{009d}', '\u{017e}', '\u{0178}',
+];
+
+pub(crate) fn decode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            0x80..=0x9f => HIGH[(b - 0x80) as usize],
+            _ => b as char,
+        })
+        .collect()
+}
+
+/// Returns the first character that has no CP1252 encoding on failure.
+pub(crate) fn encode(text: &str) -> Result<Vec<u8>, char> {
+    text.chars()
+        .map(|c| match c as u32 {
+            0..=0x7f | 0xa0..=0xff => Ok(c as u8),
+            _ => HIGH
+                .iter()
+                .position(|&h| h == c)
+                .map(|i| 0x80 + i as u8)
+                .ok_or(c),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use proptest::{arbitrary::any, proptest};
+
+    proptest! {
+        #[test]
+        fn test_cp1252_roundtrip(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+            assert_eq!(encode(&decode(&bytes)).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_cp1252() {
+        assert_eq!(decode(b"caf\xe9 \x80\x96"), "café €–");
+        assert_eq!(encode("日"), Err('日'));
+        assert_eq!(encode("\u{80}"), Err('\u{80}'));
+    }
+}
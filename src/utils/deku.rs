@@ -10,6 +10,43 @@ pub(crate) fn write_string<W: Write>(
     writer.write_bytes(s.as_bytes())
 }
 
+/// Reads `len` bytes in fixed-size chunks, so a corrupt length field fails with an EOF error instead of allocating the full (untrusted) length up front.
+pub(crate) fn read_vec<R: std::io::Read>(
+    reader: &mut deku::reader::Reader<R>,
+    len: usize,
+) -> Result<Vec<u8>, DekuError> {
+    const CHUNK_SIZE: usize = 4096;
+
+    let mut buf = Vec::with_capacity(len.min(CHUNK_SIZE));
+    let mut chunk = [0; CHUNK_SIZE];
+    while buf.len() < len {
+        let chunk_len = (len - buf.len()).min(CHUNK_SIZE);
+        reader.read_bytes(chunk_len, &mut chunk[..chunk_len])?;
+        buf.extend_from_slice(&chunk[..chunk_len]);
+    }
+
+    Ok(buf)
+}
+
+/// Like deku's `count` attribute, but without reserving capacity for the (untrusted) count up front.
+pub(crate) fn read_counted<'a, T, Ctx, R>(
+    reader: &mut deku::reader::Reader<R>,
+    count: usize,
+    ctx: Ctx,
+) -> Result<Vec<T>, DekuError>
+where
+    T: deku::DekuReader<'a, Ctx>,
+    Ctx: Copy,
+    R: std::io::Read,
+{
+    let mut items = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        items.push(T::from_reader_with_ctx(reader, ctx)?);
+    }
+
+    Ok(items)
+}
+
 pub(crate) fn read_string<R: std::io::Read>(
     reader: &mut deku::reader::Reader<R>,
     len: usize,
@@ -18,8 +55,7 @@ pub(crate) fn read_string<R: std::io::Read>(
         return Ok("".to_string());
     }
 
-    let mut buf = vec![0; len];
-    reader.read_bytes(len, &mut buf)?;
+    let buf = read_vec(reader, len)?;
 
     if buf[0] == 0 {
         return Ok("".to_string());
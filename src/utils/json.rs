@@ -0,0 +1,97 @@
+//! Just enough JSON for the flat objects in APNX headers and `data-app-amzn-magnify` attributes.
+
+use std::fmt::Write;
+
+pub(crate) fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reads the string that `rest` starts with, after its opening quote. Returns it unescaped, along with what follows the closing quote.
+fn read_string(rest: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &rest[i + 1..])),
+            '\\' => value.push(match chars.next()?.1 {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'u' => {
+                    let code = chars.by_ref().take(4).map(|(_, c)| c).collect::<String>();
+                    char::from_u32(u32::from_str_radix(&code, 16).ok()?)?
+                }
+                c => c,
+            }),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// The value of `key` in a flat JSON object. Strings are unescaped; anything else, like a number, is returned as written.
+pub(crate) fn value(json: &str, key: &str) -> Option<String> {
+    let mut rest = json.trim_start().strip_prefix('{')?;
+    // Walk the members in order, so that a key quoted inside another member's value isn't taken for a key
+    loop {
+        let (name, after_name) = read_string(rest.trim_start().strip_prefix('"')?)?;
+        let after_colon = after_name.trim_start().strip_prefix(':')?.trim_start();
+        let (value, after_value) = match after_colon.strip_prefix('"') {
+            Some(string) => read_string(string)?,
+            None => {
+                let end = after_colon.find([',', '}']).unwrap_or(after_colon.len());
+                let value = after_colon[..end].trim().to_string();
+                (value, &after_colon[end..])
+            }
+        };
+        if name == key {
+            return Some(value).filter(|value| !value.is_empty() || after_colon.starts_with('"'));
+        }
+        rest = after_value.trim_start().strip_prefix(',')?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{arbitrary::any, proptest};
+
+    proptest! {
+        #[test]
+        fn test_escape_roundtrip(string in any::<String>()) {
+            let json = format!("{{\"key\":\"{}\"}}", escape(&string));
+            assert_eq!(value(&json, "key"), Some(string));
+        }
+    }
+
+    #[test]
+    fn test_value() {
+        let json = r#"{"targetId": "panel-1\"a\\b", "ordinal":1 , "last": true}"#;
+        assert_eq!(value(json, "targetId").as_deref(), Some("panel-1\"a\\b"));
+        assert_eq!(value(json, "ordinal").as_deref(), Some("1"));
+        assert_eq!(value(json, "last").as_deref(), Some("true"));
+        assert_eq!(value(json, "missing"), None);
+        assert_eq!(
+            value(r#"{"key":"\u00e9\n"}"#, "key").as_deref(),
+            Some("é\n")
+        );
+        assert_eq!(value(r#"{"key":"unterminated"#, "key"), None);
+        assert_eq!(value(r#"{"a":"k","k":"v"}"#, "k").as_deref(), Some("v"));
+        assert_eq!(value(r#"{"a":"\"k\":1","k":2}"#, "k").as_deref(), Some("2"));
+        assert_eq!(value(r#"{"key":""}"#, "key").as_deref(), Some(""));
+    }
+}
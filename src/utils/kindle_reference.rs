@@ -0,0 +1,67 @@
+//! What `kindle:embed:` and `kindle:flow:` references have in common: a base 32 index, optionally followed by `?mime=...`.
+
+use super::base32;
+
+/// Splits a whole reference like `kindle:flow:0001?mime=text/css` into its index and `mime` hint. `None` if it doesn't start with `prefix` or the index isn't base 32.
+pub(crate) fn parse(reference: &str, prefix: &str) -> Option<(u32, Option<String>)> {
+    let rest = reference.trim().strip_prefix(prefix)?;
+    let (index, query) = match rest.split_once('?') {
+        Some((index, query)) => (index, Some(query)),
+        None => (rest, None),
+    };
+
+    let mime_type = query.and_then(|query| {
+        query
+            .split('&')
+            .find_map(|parameter| parameter.strip_prefix("mime="))
+            .map(str::to_string)
+    });
+    Some((base32::decode(index)?, mime_type))
+}
+
+/// Every reference in `text` that starts with `prefix`, with the offset it starts at. A reference ends at a quote, a parenthesis, an angle bracket or whitespace, so that it can be found in both HTML attributes and CSS `url()`s.
+pub(crate) fn find_all<'a>(
+    text: &'a str,
+    prefix: &'a str,
+) -> impl Iterator<Item = (usize, &'a str)> + 'a {
+    let mut position = 0;
+    std::iter::from_fn(move || {
+        let start = position + text[position..].find(prefix)?;
+        let rest = &text[start..];
+        let end = rest
+            .find(|c: char| matches!(c, '"' | '\'' | ')' | '<' | '>') || c.is_whitespace())
+            .unwrap_or(rest.len());
+        position = start + end;
+        Some((start, &rest[..end]))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(" kindle:flow:000A?foo=1&mime=text/css", "kindle:flow:"),
+            Some((10, Some("text/css".to_string())))
+        );
+        assert_eq!(parse("kindle:flow:0001", "kindle:flow:"), Some((1, None)));
+        assert_eq!(parse("kindle:flow:?mime=text/css", "kindle:flow:"), None);
+        assert_eq!(parse("kindle:embed:0001", "kindle:flow:"), None);
+    }
+
+    #[test]
+    fn test_find_all() {
+        let text = r#"<img src="kindle:embed:0001?mime=image/png"/> url(kindle:embed:0002) kindle:embed:0003"#;
+        assert_eq!(
+            find_all(text, "kindle:embed:").collect::<Vec<_>>(),
+            [
+                (10, "kindle:embed:0001?mime=image/png"),
+                (50, "kindle:embed:0002"),
+                (69, "kindle:embed:0003"),
+            ]
+        );
+    }
+}
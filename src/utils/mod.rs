@@ -1 +1,6 @@
+pub(crate) mod base32;
+pub(crate) mod cp1252;
 pub(crate) mod deku;
+pub(crate) mod json;
+pub(crate) mod kindle_reference;
+pub(crate) mod parallel;
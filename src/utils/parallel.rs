@@ -0,0 +1,22 @@
+//! Maps over text records in parallel when the `rayon` feature is enabled, and serially otherwise.
+//! Results are always returned in input order, so output doesn't depend on the feature.
+
+#[cfg(feature = "rayon")]
+pub(crate) fn map<T, U, F>(items: &[T], f: F) -> Vec<U>
+where
+    T: Sync,
+    U: Send,
+    F: Fn(&T) -> U + Sync + Send,
+{
+    use rayon::prelude::*;
+
+    items.par_iter().map(f).collect()
+}
+
+#[cfg(not(feature = "rayon"))]
+pub(crate) fn map<T, U, F>(items: &[T], f: F) -> Vec<U>
+where
+    F: Fn(&T) -> U,
+{
+    items.iter().map(f).collect()
+}
@@ -0,0 +1,676 @@
+use std::fmt;
+
+use deku::DekuContainerRead;
+
+use crate::{
+    compression::palmdoc,
+    constants::MetadataIdValue,
+    serialization::{
+        header_len, ChunkTagMapEntry, CompressionType, FDSTTable, MobiHeader, PalmDoc, PalmDocRef,
+        SkeletonTagMapEntry, TotalIndexEntry,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Unusual, but readers are expected to cope.
+    Info,
+    /// Likely to cause problems on some readers.
+    Warning,
+    /// The file is structurally broken.
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    Header,
+    RecordCount,
+    TextLength,
+    RecordSize,
+    FdstCoverage,
+    PartGeometry,
+    IndexPointer,
+    ExthOffset,
+    Alignment,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub check: Check,
+    /// The record the finding is about, if it is about a single record.
+    pub record: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} [{:?}]", self.severity, self.check)?;
+        if let Some(record) = self.record {
+            write!(f, " record {}", record)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn push(&mut self, severity: Severity, check: Check, record: Option<usize>, message: String) {
+        self.0.push(Finding {
+            severity,
+            check,
+            record,
+            message,
+        });
+    }
+}
+
+/// Checks that the records of a KF8 file are consistent with each other, e.g. before shipping a file produced by the writer.
+/// Findings are ordered by the check that produced them; an empty result means no problems were found.
+///
+/// Records are checked at the offsets `PalmDoc`'s writer puts them at. Use `validate_bytes` to check the offsets of an existing file.
+pub fn validate(palmdoc: &PalmDoc) -> Vec<Finding> {
+    let mut offset = header_len(palmdoc.records.len() as u16);
+    let offsets = palmdoc
+        .records
+        .iter()
+        .map(|record| {
+            let start = offset;
+            offset += record.len();
+            start
+        })
+        .collect::<Vec<_>>();
+    validate_records(palmdoc, &offsets)
+}
+
+/// Like `validate`, but checks the records at the offsets the file's record table gives them.
+pub fn validate_bytes(data: &[u8]) -> Vec<Finding> {
+    let palmdoc = PalmDocRef::parse(data).and_then(|palmdoc_ref| {
+        let offsets = (0..palmdoc_ref.records().len())
+            .filter_map(|i| palmdoc_ref.record_range(i))
+            .map(|range| range.start)
+            .collect::<Vec<_>>();
+        Ok((palmdoc_ref.to_palmdoc()?, offsets))
+    });
+    match palmdoc {
+        Ok((palmdoc, offsets)) => validate_records(&palmdoc, &offsets),
+        Err(e) => vec![Finding {
+            severity: Severity::Error,
+            check: Check::Header,
+            record: None,
+            message: format!("Could not parse PalmDoc header: {}", e),
+        }],
+    }
+}
+
+/// `offsets` holds where each record starts in the file.
+fn validate_records(palmdoc: &PalmDoc, offsets: &[usize]) -> Vec<Finding> {
+    let mut findings = Findings(Vec::new());
+
+    if palmdoc.records.is_empty() {
+        findings.push(
+            Severity::Error,
+            Check::Header,
+            None,
+            "File has no records".to_string(),
+        );
+        return findings.0;
+    }
+    let mobi_header = match palmdoc.mobi_header() {
+        Ok(header) => header,
+        Err(e) => {
+            findings.push(Severity::Error, Check::Header, Some(0), e.to_string());
+            return findings.0;
+        }
+    };
+
+    check_record_count(palmdoc, &mobi_header, &mut findings);
+    let text = check_text_records(palmdoc, &mobi_header, &mut findings);
+    let text_flow_len = check_fdst(palmdoc, &mobi_header, text.len(), &mut findings);
+    if let Some(text_flow_len) = text_flow_len {
+        check_part_geometry(palmdoc, &mobi_header, text_flow_len, &mut findings);
+    }
+    check_index_pointers(palmdoc, &mobi_header, &mut findings);
+    check_exth_offsets(palmdoc, &mobi_header, &mut findings);
+    check_alignment(palmdoc, &mobi_header, offsets, &mut findings);
+
+    findings.0
+}
+
+fn check_record_count(palmdoc: &PalmDoc, mobi_header: &MobiHeader, findings: &mut Findings) {
+    let last_text_record = mobi_header.num_of_text_records as usize;
+    if last_text_record >= palmdoc.records.len() {
+        findings.push(
+            Severity::Error,
+            Check::RecordCount,
+            None,
+            format!(
+                "Header declares {} text records, but the file only has {} records after the header",
+                last_text_record,
+                palmdoc.records.len() - 1
+            ),
+        );
+    }
+
+    if (mobi_header.first_non_text_record as usize) <= last_text_record {
+        findings.push(
+            Severity::Error,
+            Check::RecordCount,
+            None,
+            format!(
+                "First non-text record ({}) overlaps the text records",
+                mobi_header.first_non_text_record
+            ),
+        );
+    }
+}
+
+fn check_text_records(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    findings: &mut Findings,
+) -> Vec<u8> {
+    let mut text = Vec::new();
+
+    for (i, record) in palmdoc
+        .records
+        .iter()
+        .enumerate()
+        .skip(1)
+        .take(mobi_header.num_of_text_records as usize)
+    {
+        let Ok(trailing_entries_len) = mobi_header.sizeof_trailing_section_entries(record) else {
+            findings.push(
+                Severity::Error,
+                Check::TextLength,
+                Some(i),
+                "Trailing entries extend past the start of the record".to_string(),
+            );
+            continue;
+        };
+        let record_data = &record[..record.len() - trailing_entries_len];
+
+        let decompressed = match mobi_header.compression_type {
+            CompressionType::None => record_data.to_vec(),
+            CompressionType::PalmDoc => match palmdoc::decompress(record_data) {
+                Ok(decompressed) => decompressed,
+                Err(_) => {
+                    findings.push(
+                        Severity::Error,
+                        Check::TextLength,
+                        Some(i),
+                        "Record could not be decompressed".to_string(),
+                    );
+                    continue;
+                }
+            },
+            CompressionType::HuffCdic => {
+                findings.push(
+                    Severity::Info,
+                    Check::TextLength,
+                    None,
+                    "HUFF/CDIC compressed text is not checked".to_string(),
+                );
+                return text;
+            }
+        };
+
+        if decompressed.len() > mobi_header.text_record_size as usize {
+            findings.push(
+                Severity::Error,
+                Check::RecordSize,
+                Some(i),
+                format!(
+                    "Record decompresses to {} bytes, more than the text record size of {}",
+                    decompressed.len(),
+                    mobi_header.text_record_size
+                ),
+            );
+        }
+
+        text.extend_from_slice(&decompressed);
+    }
+
+    if text.len() != mobi_header.text_length as usize {
+        findings.push(
+            Severity::Error,
+            Check::TextLength,
+            None,
+            format!(
+                "Header declares {} bytes of text, but the text records decompress to {}",
+                mobi_header.text_length,
+                text.len()
+            ),
+        );
+    }
+
+    text
+}
+
+/// Returns the length of the first (text) flow if the FDST table could be read.
+fn check_fdst(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    text_len: usize,
+    findings: &mut Findings,
+) -> Option<usize> {
+    let record_i = mobi_header.fdst_record as usize;
+    // A missing FDST record is reported by `check_index_pointers`
+    let record = palmdoc.records.get(record_i)?;
+    let fdst_table = match FDSTTable::from_bytes((record, 0)) {
+        Ok((_, table)) => table,
+        Err(e) => {
+            findings.push(
+                Severity::Error,
+                Check::FdstCoverage,
+                Some(record_i),
+                format!("Could not parse FDST record: {}", e),
+            );
+            return None;
+        }
+    };
+
+    if fdst_table.entries.len() != mobi_header.fdst_count as usize {
+        findings.push(
+            Severity::Warning,
+            Check::FdstCoverage,
+            Some(record_i),
+            format!(
+                "Header declares {} flows, but the FDST record has {}",
+                mobi_header.fdst_count,
+                fdst_table.entries.len()
+            ),
+        );
+    }
+
+    let mut expected_start = 0;
+    for (i, entry) in fdst_table.entries.iter().enumerate() {
+        if entry.start != expected_start || entry.end < entry.start {
+            findings.push(
+                Severity::Error,
+                Check::FdstCoverage,
+                Some(record_i),
+                format!(
+                    "Flow {} spans {}..{}, expected it to start at {}",
+                    i, entry.start, entry.end, expected_start
+                ),
+            );
+        }
+        expected_start = entry.end;
+    }
+
+    if expected_start as usize != text_len {
+        findings.push(
+            Severity::Error,
+            Check::FdstCoverage,
+            Some(record_i),
+            format!(
+                "Flows end at {}, but the text is {} bytes long",
+                expected_start, text_len
+            ),
+        );
+    }
+
+    fdst_table
+        .entries
+        .first()
+        .map(|entry| entry.end.saturating_sub(entry.start) as usize)
+}
+
+fn read_index(
+    palmdoc: &PalmDoc,
+    name: &str,
+    record: u32,
+    findings: &mut Findings,
+) -> Option<TotalIndexEntry> {
+    let index = palmdoc
+        .records
+        .get(record as usize..)
+        .ok_or_else(|| "record does not exist".to_string())
+        .and_then(|records| TotalIndexEntry::from_records(records).map_err(|e| e.to_string()));
+
+    match index {
+        Ok(index) => Some(index),
+        Err(e) => {
+            findings.push(
+                Severity::Error,
+                Check::PartGeometry,
+                Some(record as usize),
+                format!("Could not parse {} index: {}", name, e),
+            );
+            None
+        }
+    }
+}
+
+fn check_part_geometry(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    text_flow_len: usize,
+    findings: &mut Findings,
+) {
+    let skeleton_index = read_index(palmdoc, "skeleton", mobi_header.skel_index, findings);
+    let chunk_index = read_index(palmdoc, "chunk", mobi_header.chunk_index, findings);
+    let (Some(skeleton_index), Some(chunk_index)) = (skeleton_index, chunk_index) else {
+        return;
+    };
+
+    let (Ok(skeletons), Ok(chunks)) = (
+        skeleton_index.parse_as::<SkeletonTagMapEntry>(),
+        chunk_index.parse_as::<ChunkTagMapEntry>(),
+    ) else {
+        findings.push(
+            Severity::Error,
+            Check::PartGeometry,
+            None,
+            "Skeleton or chunk index entries are missing tags".to_string(),
+        );
+        return;
+    };
+
+    let chunk_count = skeletons
+        .iter()
+        .map(|skeleton| skeleton.chunk_count as usize)
+        .sum::<usize>();
+    if chunk_count != chunks.len() {
+        findings.push(
+            Severity::Error,
+            Check::PartGeometry,
+            None,
+            format!(
+                "Skeletons reference {} chunks, but the chunk index has {}",
+                chunk_count,
+                chunks.len()
+            ),
+        );
+    }
+
+    let mut expected_start = 0;
+    let mut chunks = chunks.iter();
+    for skeleton in &skeletons {
+        if skeleton.start_offset as usize != expected_start {
+            findings.push(
+                Severity::Error,
+                Check::PartGeometry,
+                None,
+                format!(
+                    "Skeleton {} starts at {}, expected {}",
+                    skeleton.name, skeleton.start_offset, expected_start
+                ),
+            );
+        }
+
+        let skeleton_start = skeleton.start_offset as usize;
+        let mut part_len = skeleton.length as usize;
+        for chunk in chunks.by_ref().take(skeleton.chunk_count as usize) {
+            let insert_position = chunk.insert_position as usize;
+            if insert_position < skeleton_start || insert_position > skeleton_start + part_len {
+                findings.push(
+                    Severity::Error,
+                    Check::PartGeometry,
+                    None,
+                    format!(
+                        "Chunk {} of skeleton {} is inserted outside of the skeleton",
+                        chunk.sequence_number, skeleton.name
+                    ),
+                );
+            }
+            part_len += chunk.length as usize;
+        }
+
+        expected_start = skeleton_start + part_len;
+    }
+
+    if expected_start != text_flow_len {
+        findings.push(
+            Severity::Error,
+            Check::PartGeometry,
+            None,
+            format!(
+                "Skeletons and chunks cover {} bytes, but the text flow is {} bytes long",
+                expected_start, text_flow_len
+            ),
+        );
+    }
+}
+
+fn check_index_pointers(palmdoc: &PalmDoc, mobi_header: &MobiHeader, findings: &mut Findings) {
+    let pointers: [(&str, u32, &[u8; 4], Severity); 9] = [
+        (
+            "Chunk index",
+            mobi_header.chunk_index,
+            b"INDX",
+            Severity::Error,
+        ),
+        (
+            "Skeleton index",
+            mobi_header.skel_index,
+            b"INDX",
+            Severity::Error,
+        ),
+        ("NCX index", mobi_header.ncx_index, b"INDX", Severity::Error),
+        (
+            "Guide index",
+            mobi_header.guide_index,
+            b"INDX",
+            Severity::Error,
+        ),
+        (
+            "FDST record",
+            mobi_header.fdst_record,
+            b"FDST",
+            Severity::Error,
+        ),
+        (
+            "FLIS record",
+            mobi_header.flis_record,
+            b"FLIS",
+            Severity::Warning,
+        ),
+        (
+            "FCIS record",
+            mobi_header.fcis_record,
+            b"FCIS",
+            Severity::Warning,
+        ),
+        (
+            "SRCS record",
+            mobi_header.srcs_record,
+            b"SRCS",
+            Severity::Warning,
+        ),
+        (
+            "DATP record",
+            mobi_header.datp_index,
+            b"DATP",
+            Severity::Warning,
+        ),
+    ];
+
+    for (name, record, magic, severity) in pointers {
+        // Unset pointers are u32::MAX
+        if record == u32::MAX {
+            continue;
+        }
+
+        match palmdoc.records.get(record as usize) {
+            Some(data) if data.starts_with(magic) => {}
+            Some(_) => findings.push(
+                severity,
+                Check::IndexPointer,
+                Some(record as usize),
+                format!(
+                    "{} does not point to a {} record",
+                    name,
+                    String::from_utf8_lossy(magic)
+                ),
+            ),
+            None => findings.push(
+                severity,
+                Check::IndexPointer,
+                None,
+                format!("{} points past the last record ({})", name, record),
+            ),
+        }
+    }
+}
+
+fn check_exth_offsets(palmdoc: &PalmDoc, mobi_header: &MobiHeader, findings: &mut Findings) {
+    let Some(exth) = &mobi_header.exth else {
+        return;
+    };
+
+    for id in [MetadataIdValue::CoverOffset, MetadataIdValue::ThumbOffset] {
+        let Some(offset) = exth.value(id.clone()) else {
+            continue;
+        };
+
+        let record = mobi_header.first_resource_record as usize + offset as usize;
+        let is_image = palmdoc
+            .records
+            .get(record)
+            .and_then(|data| infer::get(data))
+            .is_some_and(|kind| kind.matcher_type() == infer::MatcherType::Image);
+
+        if !is_image {
+            findings.push(
+                Severity::Error,
+                Check::ExthOffset,
+                Some(record),
+                format!("{} ({}) does not point to an image", id, offset),
+            );
+        }
+    }
+}
+
+fn check_alignment(
+    palmdoc: &PalmDoc,
+    mobi_header: &MobiHeader,
+    offsets: &[usize],
+    findings: &mut Findings,
+) {
+    for (i, (record, &offset)) in palmdoc.records.iter().zip(offsets).enumerate() {
+        let is_index = record.starts_with(b"INDX");
+
+        if i == mobi_header.first_non_text_record as usize && !offset.is_multiple_of(4) {
+            findings.push(
+                Severity::Warning,
+                Check::Alignment,
+                Some(i),
+                format!(
+                    "First non-text record starts at offset {}, which is not 4-byte aligned",
+                    offset
+                ),
+            );
+        }
+
+        if is_index && !record.len().is_multiple_of(4) {
+            findings.push(
+                Severity::Warning,
+                Check::Alignment,
+                Some(i),
+                format!(
+                    "Index record is {} bytes long, which is not a multiple of 4",
+                    record.len()
+                ),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{Book, BookWriter, ParseOptions};
+    use deku::DekuContainerWrite;
+    use pretty_assertions::assert_eq;
+
+    fn read_fixture() -> PalmDoc {
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        let (_, palmdoc) = PalmDoc::from_bytes((&data, 0)).unwrap();
+        palmdoc
+    }
+
+    #[test]
+    fn test_validate_fixture() {
+        assert_eq!(validate(&read_fixture()), vec![]);
+
+        let data = std::fs::read("resources/war_and_peace.azw3").unwrap();
+        assert_eq!(validate_bytes(&data), vec![]);
+    }
+
+    #[test]
+    fn test_validate_bytes_uses_record_table() {
+        let (book, _) = Book::from_palmdoc(read_fixture(), &ParseOptions::default()).unwrap();
+        let palmdoc = BookWriter::reproducible().write(&book).unwrap();
+        let mut data = palmdoc.to_bytes().unwrap();
+        assert_eq!(validate_bytes(&data), vec![]);
+
+        // Insert a byte before record 0, moving every record along by one
+        let num_records = palmdoc.records.len();
+        for i in 0..num_records {
+            let entry = 78 + 8 * i;
+            let offset = u32::from_be_bytes(data[entry..entry + 4].try_into().unwrap());
+            data[entry..entry + 4].copy_from_slice(&(offset + 1).to_be_bytes());
+        }
+        data.insert(header_len(num_records as u16), 0);
+
+        let first_non_text_record = palmdoc.mobi_header().unwrap().first_non_text_record;
+        assert_eq!(
+            validate_bytes(&data),
+            [Finding {
+                severity: Severity::Warning,
+                check: Check::Alignment,
+                record: Some(first_non_text_record as usize),
+                message: format!(
+                    "First non-text record starts at offset {}, which is not 4-byte aligned",
+                    header_len(num_records as u16)
+                        + palmdoc.records[..first_non_text_record as usize]
+                            .iter()
+                            .map(Vec::len)
+                            .sum::<usize>()
+                        + 1
+                ),
+            }]
+        );
+        assert!(validate(&PalmDoc::from_bytes((&data, 0)).unwrap().1).is_empty());
+    }
+
+    #[test]
+    fn test_validate_written_book() {
+        let (book, _) = Book::from_palmdoc(read_fixture(), &ParseOptions::default()).unwrap();
+        let palmdoc = BookWriter::reproducible().write(&book).unwrap();
+        assert_eq!(validate(&palmdoc), vec![]);
+    }
+
+    #[test]
+    fn test_validate_broken_pointers() {
+        let mut palmdoc = read_fixture();
+        // Swap the FDST and FCIS records
+        palmdoc.records.swap(1104, 1106);
+
+        let findings = validate(&palmdoc);
+        assert!(findings
+            .iter()
+            .any(|finding| finding.check == Check::IndexPointer
+                && finding.record == Some(1104)
+                && finding.severity == Severity::Error));
+        assert!(findings
+            .iter()
+            .any(|finding| finding.check == Check::IndexPointer
+                && finding.record == Some(1106)
+                && finding.severity == Severity::Warning));
+    }
+
+    #[test]
+    fn test_validate_truncated_text() {
+        let mut palmdoc = read_fixture();
+        palmdoc.records.remove(1);
+
+        let findings = validate(&palmdoc);
+        assert!(findings
+            .iter()
+            .any(|finding| finding.check == Check::TextLength));
+    }
+}